@@ -0,0 +1,101 @@
+use crate::{
+    config::{Config, Overrides},
+    message, prompts,
+    session::Session,
+    tools::{ToolConfig, ToolKind},
+};
+use anyhow::Result;
+use clap::ValueEnum;
+
+/// The tools an agent can be allowed, each one or more of the built in tools
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Allow {
+    /// Run shell commands
+    Shell,
+    /// Read files and list directories
+    FsRead,
+    /// Write files
+    FsWrite,
+    /// Fetch URLs
+    Web,
+}
+
+impl Allow {
+    fn kinds(&self) -> &'static [ToolKind] {
+        match self {
+            Allow::Shell => &[ToolKind::Shell],
+            Allow::FsRead => &[ToolKind::ReadFile, ToolKind::ListDir],
+            Allow::FsWrite => &[ToolKind::WriteFile],
+            Allow::Web => &[ToolKind::FetchUrl],
+        }
+    }
+}
+
+/// The tools for the allowed kinds, asking before shell commands and writes unless `yes`
+pub fn tools(allow: &[Allow], yes: bool) -> Vec<ToolConfig> {
+    let mut kinds: Vec<ToolKind> = Vec::new();
+    for kind in allow.iter().flat_map(Allow::kinds) {
+        if !kinds.contains(kind) {
+            kinds.push(*kind);
+        }
+    }
+    kinds
+        .into_iter()
+        .map(|kind| ToolConfig {
+            kind,
+            name: None,
+            description: None,
+            confirm: !yes && matches!(kind, ToolKind::Shell | ToolKind::WriteFile),
+        })
+        .collect()
+}
+
+/// Start a chat that works towards `goal` with only the allowed tools
+///
+/// Each tool call and its result is written to the chat as it happens, so the
+/// chat file is the agent's log. After `max_steps` calls the model is asked to
+/// sum up without calling any more.
+pub fn start(
+    goal: &str,
+    allow: &[Allow],
+    yes: bool,
+    max_steps: usize,
+    mut config: Config,
+    overrides: Overrides,
+) -> Result<Session> {
+    config.tools = tools(allow, yes);
+    // Tools from MCP servers, modules and plugins aren't bounded by --allow
+    config.mcp_servers.clear();
+    config.wasm_tools.clear();
+    config.plugins = Some(false);
+    let session = Session::start(Some(prompts::AGENT_PROMPT.to_string()), config, overrides)?
+        .with_max_steps(Some(max_steps));
+    message::add_to_last(session.chat_file(), goal)?;
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asks_before_shell_commands_and_writes() {
+        let tools = tools(
+            &[Allow::FsRead, Allow::Shell, Allow::FsWrite, Allow::FsRead],
+            false,
+        );
+        let asked: Vec<_> = tools.iter().map(|t| (t.kind, t.confirm)).collect();
+        assert_eq!(
+            asked,
+            [
+                (ToolKind::ReadFile, false),
+                (ToolKind::ListDir, false),
+                (ToolKind::Shell, true),
+                (ToolKind::WriteFile, true),
+            ]
+        );
+        assert!(super::tools(&[Allow::Shell], true)
+            .iter()
+            .all(|t| !t.confirm));
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{message::Fence, provider::ImageUrl, storage};
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use regex::Regex;
+use std::{path::Path, sync::OnceLock};
+
+/// Markdown images with this alt text are sent to the model, e.g. `![attach](./diagram.png)`
+const ATTACH_ALT: &str = "attach";
+
+fn attach_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(&format!(r"!\[{}\]\(\s*([^)]+?)\s*\)", ATTACH_ALT)).unwrap())
+}
+
+/// Pull the attached images out of a message's content
+///
+/// Returns the content with the attachment links removed and the images,
+/// relative paths are resolved against `base_dir` (the chat file's directory).
+/// Links inside fenced code blocks are left alone.
+pub fn extract(content: &str, base_dir: &Path) -> Result<(String, Vec<ImageUrl>)> {
+    let mut text = String::new();
+    let mut images = Vec::new();
+    let mut fence: Option<Fence> = None;
+
+    for line in content.lines() {
+        match &fence {
+            Some(open) => {
+                if open.is_closed_by(line) {
+                    fence = None;
+                }
+            }
+            None => fence = Fence::opened_by(line),
+        }
+
+        if fence.is_some() {
+            text.push_str(line);
+        } else {
+            for target in attach_pattern().captures_iter(line) {
+                images.push(image_url(&target[1], base_dir)?);
+            }
+            text.push_str(&attach_pattern().replace_all(line, ""));
+        }
+        text.push('\n');
+    }
+
+    Ok((text.trim().to_string(), images))
+}
+
+/// Append attachment links for `images` to the end of the chat file
+///
+/// The chat file ends with the user's message, so the images are attached to it.
+/// Local paths are made absolute so the link still works if the chat is moved.
+pub fn attach(chat_file: &Path, images: &[String]) -> Result<()> {
+    if images.is_empty() {
+        return Ok(());
+    }
+    let mut text = String::new();
+    for image in images {
+        let target = if is_url(image) {
+            image.clone()
+        } else {
+            std::fs::canonicalize(image)
+                .with_context(|| format!("Unable to find image: {}", image))?
+                .display()
+                .to_string()
+        };
+        text.push_str(&format!("![{}]({})\n", ATTACH_ALT, target));
+    }
+    storage::append(chat_file, &text)
+        .with_context(|| format!("Could not append to file: {:?}", chat_file))
+}
+
+fn is_url(target: &str) -> bool {
+    target.starts_with("http://") || target.starts_with("https://") || target.starts_with("data:")
+}
+
+/// Turn an attachment target into the URL sent to the model, local files are base64 encoded
+fn image_url(target: &str, base_dir: &Path) -> Result<ImageUrl> {
+    if is_url(target) {
+        return Ok(ImageUrl {
+            url: target.to_string(),
+        });
+    }
+
+    let path = base_dir.join(target);
+    let mime = match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .as_deref()
+    {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => bail!("Unsupported image type: {:?}", path),
+    };
+    let bytes =
+        std::fs::read(&path).with_context(|| format!("Unable to read image: {:?}", path))?;
+
+    Ok(ImageUrl {
+        url: format!("data:{};base64,{}", mime, STANDARD.encode(bytes)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_image_links_and_strips_them_from_the_text() {
+        let content = "What is this?\n![attach](https://example.com/a.png)\n";
+        let (text, images) = extract(content, Path::new(".")).unwrap();
+        assert_eq!(text, "What is this?");
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].url, "https://example.com/a.png");
+    }
+
+    #[test]
+    fn ignores_other_images_and_links_in_code_fences() {
+        let content = "![diagram](a.png)\n```\n![attach](b.png)\n```";
+        let (text, images) = extract(content, Path::new(".")).unwrap();
+        assert_eq!(text, content);
+        assert!(images.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_image_types() {
+        assert!(extract("![attach](notes.txt)", Path::new(".")).is_err());
+    }
+}
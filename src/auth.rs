@@ -0,0 +1,92 @@
+use crate::exit::Failure;
+use anyhow::{bail, Context, Result};
+use keyring::Entry;
+
+/// The provider used when none is given
+pub const DEFAULT_PROVIDER: &str = "openai";
+
+/// Keys are stored in the OS keyring under this service, with the provider as the user
+const SERVICE: &str = "chat-cli-rs";
+
+/// The environment variable that overrides the stored key, e.g. `OPENAI_API_KEY`
+pub fn env_var(provider: &str) -> String {
+    format!("{}_API_KEY", provider.to_uppercase().replace('-', "_"))
+}
+
+/// The API key for a provider, from its environment variable or else the OS keyring
+pub fn api_key(provider: &str) -> Result<String> {
+    let var = env_var(provider);
+    if let Ok(key) = std::env::var(&var) {
+        if !key.trim().is_empty() {
+            return Ok(key.trim().to_string());
+        }
+    }
+
+    match entry(provider)?.get_password() {
+        Ok(key) => Ok(key),
+        Err(keyring::Error::NoEntry) => bail!(Failure::Auth(format!(
+            "No API key for {}, run `chat-cli-rs auth login` or set {}",
+            provider, var
+        ))),
+        Err(e) => bail!(Failure::Auth(format!(
+            "Unable to read the {} API key from the keyring, set {} instead: {}",
+            provider, var, e
+        ))),
+    }
+}
+
+/// Prompt for an API key and store it in the OS keyring
+pub fn login(provider: &str) -> Result<()> {
+    let key = rpassword::prompt_password(format!("{} API key: ", provider))
+        .context("Unable to read the API key")?;
+    let key = key.trim();
+    if key.is_empty() {
+        bail!("No API key given");
+    }
+
+    entry(provider)?
+        .set_password(key)
+        .with_context(|| format!("Unable to store the {} API key in the keyring", provider))?;
+    println!("Stored the {} API key in the keyring", provider);
+    if std::env::var(env_var(provider)).is_ok() {
+        println!(
+            "Note that {} is set and takes precedence",
+            env_var(provider)
+        );
+    }
+    Ok(())
+}
+
+/// Remove a provider's API key from the OS keyring
+pub fn logout(provider: &str) -> Result<()> {
+    match entry(provider)?.delete_credential() {
+        Ok(()) => println!("Removed the {} API key from the keyring", provider),
+        Err(keyring::Error::NoEntry) => println!("No {} API key is stored", provider),
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("Unable to remove the {} API key from the keyring", provider)
+            })
+        }
+    }
+    Ok(())
+}
+
+fn entry(provider: &str) -> Result<Entry> {
+    Entry::new(SERVICE, provider).context("Unable to access the keyring")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_environment_variable_takes_precedence() {
+        assert_eq!(env_var(DEFAULT_PROVIDER), "OPENAI_API_KEY");
+        assert_eq!(env_var("azure-openai"), "AZURE_OPENAI_API_KEY");
+
+        let provider = "auth-test";
+        std::env::set_var(env_var(provider), " sk-test\n");
+        assert_eq!(api_key(provider).unwrap(), "sk-test");
+        std::env::remove_var(env_var(provider));
+    }
+}
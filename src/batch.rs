@@ -0,0 +1,204 @@
+use crate::{
+    config::{Config, Overrides},
+    session::{Interrupted, Output, Reply, Session},
+};
+use anyhow::{bail, Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{collections::HashMap, path::PathBuf};
+use tokio::{sync::watch, task::JoinSet};
+
+/// How a chat in the batch went
+pub enum Outcome {
+    /// The reply was added, the chat may have been renamed after its title
+    Sent {
+        chat_file: PathBuf,
+        reply: Reply,
+    },
+    Failed(String),
+    Interrupted,
+}
+
+/// The chats to send, from glob patterns and paths, in order and without repeats
+pub fn files(patterns: &[String], paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = paths.to_vec();
+    for pattern in patterns {
+        for path in glob::glob(pattern).with_context(|| format!("Invalid glob: {:?}", pattern))? {
+            let path = path?;
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files.dedup();
+    if files.is_empty() {
+        bail!("No chat files to send");
+    }
+    Ok(files)
+}
+
+/// Send each chat, `concurrency` at a time, reporting each as it finishes
+///
+/// A chat failing, or even panicking, doesn't stop the others. Ctrl-C
+/// interrupts the chats being sent and skips the rest.
+pub async fn send_all(
+    files: Vec<PathBuf>,
+    config: &Config,
+    overrides: &Overrides,
+    concurrency: usize,
+    title: bool,
+) -> Vec<(PathBuf, Outcome)> {
+    let bar = ProgressBar::new(files.len() as u64);
+    if let Ok(style) = ProgressStyle::with_template("[{bar:30}] {pos}/{len} {elapsed} {wide_msg}") {
+        bar.set_style(style.progress_chars("=> "));
+    }
+
+    let (cancel, cancelled) = watch::channel(false);
+    let mut queue = files.into_iter();
+    let mut sending = JoinSet::new();
+    let mut chats = HashMap::new();
+    let mut outcomes = Vec::new();
+    let mut interrupted = false;
+    loop {
+        while !interrupted && sending.len() < concurrency.max(1) {
+            let Some(file) = queue.next() else { break };
+            let session = Session::open(&file, config.clone())
+                .with_overrides(overrides.clone())
+                .with_output(Output::Json);
+            let mut cancelled = cancelled.clone();
+            let task = sending.spawn(async move {
+                let cancel = async move {
+                    let _ = cancelled.wait_for(|c| *c).await;
+                };
+                send_one(session, title, cancel).await
+            });
+            chats.insert(task.id(), file);
+        }
+        bar.set_message(format!("{} sending", sending.len()));
+
+        let finished = tokio::select! {
+            finished = sending.join_next_with_id() => finished,
+            _ = tokio::signal::ctrl_c(), if !interrupted => {
+                interrupted = true;
+                let _ = cancel.send(true);
+                continue;
+            }
+        };
+        let Some(finished) = finished else { break };
+        let (file, outcome) = match finished {
+            Ok((id, outcome)) => (chats.remove(&id), outcome),
+            Err(e) => (chats.remove(&e.id()), Outcome::Failed(panic_message(e))),
+        };
+        let file = file.unwrap_or_default();
+        bar.suspend(|| println!("{}", report(&file, &outcome)));
+        bar.inc(1);
+        outcomes.push((file, outcome));
+    }
+    bar.finish_and_clear();
+    outcomes
+}
+
+async fn send_one(
+    mut session: Session,
+    title: bool,
+    cancel: impl std::future::Future<Output = ()>,
+) -> Outcome {
+    let reply = match session.send_or_cancel(cancel).await {
+        Ok(reply) => reply,
+        Err(e) if e.is::<Interrupted>() => return Outcome::Interrupted,
+        Err(e) => return Outcome::Failed(format!("{:#}", e)),
+    };
+    if title && session.config().auto_title != Some(false) {
+        if let Err(e) = session.title().await {
+            tracing::warn!("Unable to title {}: {:#}", session.chat_file().display(), e);
+        }
+    }
+    Outcome::Sent {
+        chat_file: session.chat_file().to_path_buf(),
+        reply,
+    }
+}
+
+fn panic_message(error: tokio::task::JoinError) -> String {
+    match error.try_into_panic() {
+        Ok(panic) => match panic
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| panic.downcast_ref::<&str>().copied())
+        {
+            Some(message) => format!("panicked: {}", message),
+            None => "panicked".to_string(),
+        },
+        Err(e) => e.to_string(),
+    }
+}
+
+/// A line saying how a chat went
+pub fn report(file: &std::path::Path, outcome: &Outcome) -> String {
+    match outcome {
+        Outcome::Sent { chat_file, reply } => {
+            let tokens = reply.usage.as_ref().map_or(0, |u| u.total_tokens);
+            match chat_file == file {
+                true => format!("sent    {} ({} tokens)", file.display(), tokens),
+                false => format!(
+                    "sent    {} -> {} ({} tokens)",
+                    file.display(),
+                    chat_file.display(),
+                    tokens
+                ),
+            }
+        }
+        Outcome::Failed(error) => format!("failed  {}: {}", file.display(), error),
+        Outcome::Interrupted => format!("stopped {}", file.display()),
+    }
+}
+
+/// Totals for the end of the run, e.g. "Sent 8 of 10 chats, 1 failed, 1 interrupted, 5230 tokens"
+pub fn summary(outcomes: &[(PathBuf, Outcome)], total: usize) -> String {
+    let mut sent = 0;
+    let mut failed = 0;
+    let mut interrupted = 0;
+    let mut tokens = 0;
+    for (_, outcome) in outcomes {
+        match outcome {
+            Outcome::Sent { reply, .. } => {
+                sent += 1;
+                tokens += reply.usage.as_ref().map_or(0, |u| u.total_tokens);
+            }
+            Outcome::Failed(_) => failed += 1,
+            Outcome::Interrupted => interrupted += 1,
+        }
+    }
+    let mut summary = format!("Sent {} of {} chats", sent, total);
+    if failed > 0 {
+        summary.push_str(&format!(", {} failed", failed));
+    }
+    if interrupted > 0 {
+        summary.push_str(&format!(", {} interrupted", interrupted));
+    }
+    let skipped = total - outcomes.len();
+    if skipped > 0 {
+        summary.push_str(&format!(", {} skipped", skipped));
+    }
+    summary.push_str(&format!(", {} tokens", tokens));
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_globs_into_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b.md", "a.md", "notes.txt"] {
+            std::fs::write(dir.path().join(name), "# User\nHi\n").unwrap();
+        }
+        let pattern = format!("{}/*.md", dir.path().display());
+        let files = files(&[pattern], &[dir.path().join("a.md")]).unwrap();
+        assert_eq!(files, [dir.path().join("a.md"), dir.path().join("b.md")]);
+
+        let none = format!("{}/*.json", dir.path().display());
+        assert!(super::files(&[none], &[]).is_err());
+    }
+}
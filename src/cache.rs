@@ -0,0 +1,102 @@
+use crate::{
+    platform,
+    provider::{self, ChatRequest},
+};
+use anyhow::{Context, Result};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// Where replies are cached, and the provider they're from, unset unless caching is enabled
+static DIR: OnceLock<(PathBuf, String)> = OnceLock::new();
+
+/// The default cache directory, `$XDG_CACHE_HOME/chat-cli-rs/replies`
+pub fn default_dir() -> Result<PathBuf> {
+    Ok(platform::cache_dir()?.join("replies"))
+}
+
+/// Answer requests identical to earlier ones sent to `provider` from the replies cached in `dir`
+pub fn enable(dir: PathBuf, provider: String) {
+    let _ = DIR.set((dir, provider));
+}
+
+/// The request's cache file, named after a hash of the provider, the API's
+/// base URL, the model, parameters and messages
+///
+/// `None` unless caching is enabled.
+pub fn path(request: &ChatRequest) -> Option<PathBuf> {
+    let (dir, provider) = DIR.get()?;
+    let key = key(provider, provider::base_url(), request)?;
+    Some(dir.join(format!("{}.json", key)))
+}
+
+/// The hash a request is cached under, different for each API it's sent to
+fn key(provider: &str, base_url: &str, request: &ChatRequest) -> Option<String> {
+    let body = serde_json::to_vec(request).ok()?;
+    let mut hash = Sha256::new();
+    for part in [provider.as_bytes(), base_url.as_bytes(), &body] {
+        // Each part's length keeps them from running into each other
+        hash.update((part.len() as u64).to_le_bytes());
+        hash.update(part);
+    }
+    Some(format!("{:x}", hash.finalize()))
+}
+
+/// The streamed chunks of an earlier reply kept in the cache file
+pub fn get(path: &Path) -> Option<Vec<Value>> {
+    let cached = std::fs::read(path).ok()?;
+    match serde_json::from_slice(&cached) {
+        Ok(chunks) => {
+            tracing::info!("Replying from the cache, {}", path.display());
+            Some(chunks)
+        }
+        Err(e) => {
+            tracing::warn!("Ignoring the cached reply {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Keep the streamed chunks of a complete reply to answer the same request again
+pub fn put(path: &Path, chunks: &[Value]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_vec(chunks)?)
+        .with_context(|| format!("Unable to cache the reply in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openai::chat::ChatCompletionMessageRole;
+    use serde_json::json;
+
+    #[test]
+    fn caches_replies_by_request() {
+        let dir = tempfile::tempdir().unwrap();
+        enable(dir.path().to_path_buf(), "openai".to_string());
+        let message =
+            |text| crate::provider::RequestMessage::text(ChatCompletionMessageRole::User, text);
+        let request = path(&ChatRequest::new("gpt-4o", vec![message("Hi")])).unwrap();
+        assert!(get(&request).is_none());
+
+        put(&request, &[json!({"id": "1"})]).unwrap();
+        assert_eq!(get(&request), Some(vec![json!({"id": "1"})]));
+
+        let other = path(&ChatRequest::new("gpt-4o", vec![message("Hello")])).unwrap();
+        assert_ne!(request, other);
+        assert!(get(&other).is_none());
+
+        // The same request to another API isn't answered with this one's reply
+        let hi = ChatRequest::new("gpt-4o", vec![message("Hi")]);
+        let key = |provider, base_url| key(provider, base_url, &hi).unwrap();
+        let openai = key("openai", "https://api.openai.com/v1/");
+        assert_ne!(openai, key("azure", "https://api.openai.com/v1/"));
+        assert_ne!(openai, key("openai", "http://localhost:11434/v1/"));
+        assert_eq!(openai, key("openai", "https://api.openai.com/v1/"));
+    }
+}
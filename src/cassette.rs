@@ -0,0 +1,198 @@
+//! Recorded API traffic, `--record <FILE>` and `--play <FILE>`
+//!
+//! Recording writes each chat request and its reply to a JSONL file, a
+//! cassette, with the API key removed. Playing it answers the same requests
+//! from the file without the network or a key, so a bug can be reproduced and
+//! `-f` or the run loop tested in CI against real replies. A request that
+//! wasn't recorded fails rather than being sent.
+//!
+//! `CHAT_CLI_RECORD` and `CHAT_CLI_PLAY` do the same as the options.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+pub const RECORD_VAR: &str = "CHAT_CLI_RECORD";
+pub const PLAY_VAR: &str = "CHAT_CLI_PLAY";
+
+/// A request and the reply it got, a line of the cassette
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    request: Value,
+    /// The whole reply, for a request that wasn't streamed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response: Option<Value>,
+    /// The chunks of a streamed reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunks: Option<Vec<Value>>,
+}
+
+enum Cassette {
+    Recording(Mutex<File>),
+    /// The interactions not played yet
+    Playing(Mutex<Vec<Interaction>>),
+}
+
+static CASSETTE: OnceLock<Cassette> = OnceLock::new();
+
+/// Record the chat requests and their replies to `path`, replacing what it had
+pub fn record(path: &Path) -> Result<()> {
+    let file = File::create(path)
+        .with_context(|| format!("Unable to create the cassette {}", path.display()))?;
+    let _ = CASSETTE.set(Cassette::Recording(Mutex::new(file)));
+    Ok(())
+}
+
+/// Answer the chat requests from the replies recorded in `path`
+pub fn play(path: &Path) -> Result<()> {
+    let interactions = load(path)?;
+    let _ = CASSETTE.set(Cassette::Playing(Mutex::new(interactions)));
+    Ok(())
+}
+
+fn load(path: &Path) -> Result<Vec<Interaction>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read the cassette {}", path.display()))?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Line {} of {} is invalid", i + 1, path.display()))
+        })
+        .collect()
+}
+
+pub fn recording() -> bool {
+    matches!(CASSETTE.get(), Some(Cassette::Recording(_)))
+}
+
+pub fn playing() -> bool {
+    matches!(CASSETTE.get(), Some(Cassette::Playing(_)))
+}
+
+/// Add a request and its reply to the cassette, if recording
+fn write(interaction: Interaction, secrets: &[&str]) {
+    let Some(Cassette::Recording(file)) = CASSETTE.get() else {
+        return;
+    };
+    let mut value = serde_json::to_value(&interaction).unwrap_or_default();
+    redact(&mut value, secrets);
+    let line = value.to_string();
+    if let Ok(mut file) = file.lock() {
+        if let Err(e) = writeln!(file, "{}", line) {
+            tracing::warn!("Unable to write to the cassette: {}", e);
+        }
+    }
+}
+
+/// Replace `secrets` in the strings of a value, leaving its keys alone
+fn redact(value: &mut Value, secrets: &[&str]) {
+    match value {
+        Value::String(s) => {
+            for secret in secrets.iter().filter(|s| !s.is_empty()) {
+                *s = s.replace(secret, "[redacted]");
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| redact(v, secrets)),
+        Value::Object(map) => map.values_mut().for_each(|v| redact(v, secrets)),
+        _ => {}
+    }
+}
+
+/// Record a request's whole reply
+pub fn record_response(request: &Value, response: &str, secrets: &[&str]) {
+    let response = serde_json::from_str(response).unwrap_or_else(|_| Value::from(response));
+    let interaction = Interaction {
+        request: request.clone(),
+        response: Some(response),
+        chunks: None,
+    };
+    write(interaction, secrets);
+}
+
+/// Record the chunks a request's reply streamed in as
+pub fn record_chunks(request: &Value, chunks: &[Value], secrets: &[&str]) {
+    let interaction = Interaction {
+        request: request.clone(),
+        response: None,
+        chunks: Some(chunks.to_vec()),
+    };
+    write(interaction, secrets);
+}
+
+/// Take the first recording of the request out of the cassette
+fn take(request: &Value, streamed: bool) -> Result<Interaction> {
+    let Some(Cassette::Playing(interactions)) = CASSETTE.get() else {
+        bail!("No cassette is being played");
+    };
+    let mut interactions = interactions.lock().unwrap_or_else(|e| e.into_inner());
+    take_from(&mut interactions, request, streamed)
+}
+
+fn take_from(
+    interactions: &mut Vec<Interaction>,
+    request: &Value,
+    streamed: bool,
+) -> Result<Interaction> {
+    let found = interactions
+        .iter()
+        .position(|i| i.request == *request && i.chunks.is_some() == streamed);
+    match found {
+        Some(at) => Ok(interactions.remove(at)),
+        None => bail!(
+            "The cassette has no reply to this request, record it again with --record:\n{}",
+            request
+        ),
+    }
+}
+
+/// The recorded reply to a request that isn't streamed
+pub fn response(request: &Value) -> Result<String> {
+    let interaction = take(request, false)?;
+    Ok(interaction.response.unwrap_or_default().to_string())
+}
+
+/// The recorded chunks of a streamed reply
+pub fn chunks(request: &Value) -> Result<Vec<Value>> {
+    Ok(take(request, true)?.chunks.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn plays_back_each_recording_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cassette.jsonl");
+        let request = json!({ "model": "gpt-4o", "messages": [], "stream": true });
+        let lines = [
+            json!({ "request": request, "chunks": [{ "n": 1 }] }),
+            json!({ "request": request, "chunks": [{ "n": 2 }] }),
+        ];
+        let lines: Vec<String> = lines.iter().map(Value::to_string).collect();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let mut interactions = load(&path).unwrap();
+        let mut next = |streamed| take_from(&mut interactions, &request, streamed);
+        assert_eq!(next(true).unwrap().chunks.unwrap(), [json!({ "n": 1 })]);
+        assert!(next(false).is_err());
+        assert_eq!(next(true).unwrap().chunks.unwrap(), [json!({ "n": 2 })]);
+        assert!(next(true).is_err());
+
+        let mut value = json!({ "index": 0, "content": "key sk-123 leaked" });
+        redact(&mut value, &["sk-123", "x"]);
+        assert_eq!(
+            value,
+            json!({ "index": 0, "content": "key [redacted] leaked" })
+        );
+    }
+}
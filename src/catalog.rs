@@ -0,0 +1,498 @@
+use crate::{
+    context,
+    message::{Message, Meta},
+    storage::ChatStore,
+    tags,
+};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Raised when the tables change, an older catalog is rebuilt from the chat files
+const SCHEMA_VERSION: i32 = 4;
+
+/// A SQLite catalog of the chats in the store and their messages
+///
+/// The chat files stay the source of truth, the catalog is brought up to date
+/// with them whenever it's opened so it can be deleted at any time.
+pub struct Catalog {
+    db: Connection,
+}
+
+/// A chat as listed by `list`
+pub struct ChatSummary {
+    pub path: PathBuf,
+    pub title: Option<String>,
+    pub model: Option<String>,
+    /// When the chat file was last changed, in seconds since the epoch
+    pub modified: i64,
+    pub messages: usize,
+    pub tokens: usize,
+    pub tags: Vec<String>,
+    pub pinned: bool,
+}
+
+/// A chat's messages, as `(role, content)`
+pub struct ChatMessages {
+    pub path: PathBuf,
+    pub title: Option<String>,
+    pub messages: Vec<(String, String)>,
+}
+
+/// Totals across every chat
+pub struct Stats {
+    pub chats: usize,
+    pub messages: usize,
+    pub tokens: usize,
+    /// `(model, messages, tokens)` of the assistant's replies, most used first
+    pub models: Vec<(String, usize, usize)>,
+}
+
+/// How quickly a model's replies came, the medians of those with metadata
+pub struct Latency {
+    pub model: String,
+    pub replies: usize,
+    /// Time to the first token
+    pub first_token_ms: u64,
+    /// Time to the whole reply
+    pub duration_ms: u64,
+    /// Tokens streamed per second once the reply started, if the API counted them
+    pub tokens_per_second: Option<f64>,
+}
+
+impl Catalog {
+    /// The catalog in the store, `chats.sqlite`, brought up to date with the chat files
+    ///
+    /// Without `persistent` the catalog is built in memory and thrown away afterwards.
+    pub fn open(store: &ChatStore, persistent: bool) -> Result<Self> {
+        let db = match persistent {
+            true => {
+                std::fs::create_dir_all(store.dir())?;
+                let path = store.dir().join("chats.sqlite");
+                Connection::open(&path)
+                    .with_context(|| format!("Unable to open the catalog: {}", path.display()))?
+            }
+            false => Connection::open_in_memory()?,
+        };
+        create_tables(&db)?;
+        let catalog = Self { db };
+        catalog.sync(store)?;
+        Ok(catalog)
+    }
+
+    /// Catalog the chats changed since the last time and forget the ones that are gone
+    fn sync(&self, store: &ChatStore) -> Result<()> {
+        let chats = store.list()?;
+        for chat in &chats {
+            let version = version(chat)?;
+            let key = chat.to_string_lossy();
+            let known: Option<(i64, i64)> = self
+                .db
+                .query_row(
+                    "SELECT modified_ns, size FROM chats WHERE path = ?1",
+                    [&key],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            if known == Some(version) {
+                continue;
+            }
+            let (modified_ns, size) = version;
+            let Ok((frontmatter, messages)) = Message::read_chat(chat) else {
+                continue;
+            };
+
+            let tx = self.db.unchecked_transaction()?;
+            tx.execute("DELETE FROM messages WHERE chat = ?1", [&key])?;
+            for (position, message) in messages.iter().enumerate() {
+                let model = message.model.as_ref().or(frontmatter.model.as_ref());
+                let tokens = context::tokens(
+                    model.map_or(crate::config::DEFAULT_MODEL, String::as_str),
+                    &message.content,
+                );
+                let meta = message.meta.as_ref();
+                tx.execute(
+                    "INSERT INTO messages (chat, position, role, model, content, tokens, latency_ms, duration_ms, completion_tokens)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        key,
+                        position as i64,
+                        message.role_name(),
+                        model,
+                        message.content,
+                        tokens as i64,
+                        meta.and_then(|m| m.latency_ms).map(|ms| ms as i64),
+                        meta.and_then(|m| m.duration_ms).map(|ms| ms as i64),
+                        meta.and_then(|m| m.completion_tokens),
+                    ],
+                )?;
+            }
+            tx.execute(
+                "INSERT OR REPLACE INTO chats (path, title, model, created, modified, modified_ns, size, pinned) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![key, frontmatter.title, frontmatter.model, frontmatter.created, modified_ns.div_euclid(1_000_000_000), modified_ns, size, frontmatter.pinned],
+            )?;
+            tx.execute("DELETE FROM tags WHERE chat = ?1", [&key])?;
+            for tag in &frontmatter.tags {
+                tx.execute(
+                    "INSERT OR IGNORE INTO tags (chat, tag) VALUES (?1, ?2)",
+                    params![key, tags::normalize(tag)],
+                )?;
+            }
+            tx.commit()?;
+        }
+
+        let present: HashSet<String> = chats
+            .iter()
+            .map(|c| c.to_string_lossy().into_owned())
+            .collect();
+        let known: Vec<String> = self
+            .db
+            .prepare("SELECT path FROM chats")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        for gone in known.iter().filter(|path| !present.contains(*path)) {
+            self.db
+                .execute("DELETE FROM messages WHERE chat = ?1", [gone])?;
+            self.db
+                .execute("DELETE FROM tags WHERE chat = ?1", [gone])?;
+            self.db
+                .execute("DELETE FROM chats WHERE path = ?1", [gone])?;
+        }
+        Ok(())
+    }
+
+    /// The most recently changed chats, pinned ones then the rest newest first,
+    /// only those tagged `tag` if given
+    pub fn chats(&self, limit: usize, tag: Option<&str>) -> Result<Vec<ChatSummary>> {
+        let mut statement = self.db.prepare(
+            "SELECT path, title, model, modified,
+                (SELECT COUNT(*) FROM messages WHERE chat = path),
+                (SELECT COALESCE(SUM(tokens), 0) FROM messages WHERE chat = path),
+                (SELECT group_concat(tag, char(10)) FROM tags WHERE chat = path),
+                pinned
+             FROM chats
+             WHERE ?2 IS NULL OR EXISTS (SELECT 1 FROM tags WHERE chat = path AND tag = ?2)
+             ORDER BY pinned DESC, modified DESC LIMIT ?1",
+        )?;
+        let tag = tag.map(tags::normalize);
+        let chats = statement
+            .query_map(params![limit as i64, tag], |row| {
+                Ok(ChatSummary {
+                    path: PathBuf::from(row.get::<_, String>(0)?),
+                    title: row.get(1)?,
+                    model: row.get(2)?,
+                    modified: row.get(3)?,
+                    messages: row.get::<_, i64>(4)? as usize,
+                    tokens: row.get::<_, i64>(5)? as usize,
+                    tags: row
+                        .get::<_, Option<String>>(6)?
+                        .map(|tags| tags.lines().map(str::to_string).collect())
+                        .unwrap_or_default(),
+                    pinned: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(chats)
+    }
+
+    /// The messages of every chat, newest chat first, only those containing `text`
+    /// and in chats tagged `tag` if given
+    ///
+    /// `text` is matched case insensitively, for ASCII letters at least.
+    pub fn messages(&self, text: Option<&str>, tag: Option<&str>) -> Result<Vec<ChatMessages>> {
+        let pattern = text.map(|text| {
+            let escaped = text
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{}%", escaped)
+        });
+        let mut statement = self.db.prepare(
+            "SELECT chats.path, chats.title, messages.role, messages.content
+             FROM messages JOIN chats ON messages.chat = chats.path
+             WHERE (?1 IS NULL OR messages.content LIKE ?1 ESCAPE '\\')
+                AND (?2 IS NULL OR EXISTS (SELECT 1 FROM tags WHERE tags.chat = chats.path AND tags.tag = ?2))
+             ORDER BY chats.modified DESC, chats.path, messages.position",
+        )?;
+        let mut rows = statement.query(params![pattern, tag.map(tags::normalize)])?;
+        let mut chats: Vec<ChatMessages> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let path = PathBuf::from(row.get::<_, String>(0)?);
+            if chats.last().is_none_or(|chat| chat.path != path) {
+                chats.push(ChatMessages {
+                    path,
+                    title: row.get(1)?,
+                    messages: Vec::new(),
+                });
+            }
+            if let Some(chat) = chats.last_mut() {
+                chat.messages.push((row.get(2)?, row.get(3)?));
+            }
+        }
+        Ok(chats)
+    }
+
+    /// How many chats, messages and tokens there are, and which models wrote the replies
+    pub fn stats(&self) -> Result<Stats> {
+        let (chats, messages, tokens) = self.db.query_row(
+            "SELECT (SELECT COUNT(*) FROM chats), COUNT(*), COALESCE(SUM(tokens), 0) FROM messages",
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)? as usize,
+                    row.get::<_, i64>(1)? as usize,
+                    row.get::<_, i64>(2)? as usize,
+                ))
+            },
+        )?;
+        let models = self
+            .db
+            .prepare(
+                "SELECT COALESCE(model, 'unknown'), COUNT(*), SUM(tokens) FROM messages
+                 WHERE role = 'Assistant' GROUP BY 1 ORDER BY 2 DESC",
+            )?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)? as usize,
+                    row.get::<_, i64>(2)? as usize,
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Stats {
+            chats,
+            messages,
+            tokens,
+            models,
+        })
+    }
+
+    /// How quickly each model replied, from the metadata recorded with the replies
+    pub fn latency(&self) -> Result<Vec<Latency>> {
+        let mut statement = self.db.prepare(
+            "SELECT COALESCE(model, 'unknown'), latency_ms, duration_ms, completion_tokens FROM messages
+             WHERE role = 'Assistant' AND latency_ms IS NOT NULL AND duration_ms IS NOT NULL",
+        )?;
+        let mut rows = statement.query([])?;
+        let mut models: BTreeMap<String, Vec<Meta>> = BTreeMap::new();
+        while let Some(row) = rows.next()? {
+            models.entry(row.get(0)?).or_default().push(Meta {
+                latency_ms: Some(row.get::<_, i64>(1)? as u64),
+                duration_ms: Some(row.get::<_, i64>(2)? as u64),
+                completion_tokens: row.get(3)?,
+                ..Default::default()
+            });
+        }
+
+        let mut latency: Vec<Latency> = models
+            .into_iter()
+            .map(|(model, replies)| Latency {
+                model,
+                replies: replies.len(),
+                first_token_ms: median(replies.iter().filter_map(|m| m.latency_ms).collect())
+                    .unwrap_or_default(),
+                duration_ms: median(replies.iter().filter_map(|m| m.duration_ms).collect())
+                    .unwrap_or_default(),
+                tokens_per_second: median(
+                    replies.iter().filter_map(Meta::tokens_per_second).collect(),
+                ),
+            })
+            .collect();
+        latency.sort_by_key(|model| std::cmp::Reverse(model.replies));
+        Ok(latency)
+    }
+
+    /// Print the most recent `limit` chats, newest first, for `list`
+    pub fn print_list(&self, limit: usize, tag: Option<&str>) -> Result<()> {
+        for chat in self.chats(limit, tag)? {
+            let mut title = chat
+                .title
+                .unwrap_or_else(|| chat.path.display().to_string());
+            if chat.pinned {
+                title.push_str("  (pinned)");
+            }
+            for tag in &chat.tags {
+                title.push_str(&format!("  #{}", tag));
+            }
+            let modified = chrono::DateTime::from_timestamp(chat.modified, 0)
+                .unwrap_or_default()
+                .with_timezone(&chrono::Local);
+            println!(
+                "{}  {:>4} msgs {:>7} tokens  {:<14}  {}",
+                modified.format("%Y-%m-%d %H:%M"),
+                chat.messages,
+                chat.tokens,
+                chat.model.as_deref().unwrap_or("-"),
+                title
+            );
+        }
+        Ok(())
+    }
+
+    /// Print the `stats`, for `stats`
+    pub fn print_stats(&self) -> Result<()> {
+        let stats = self.stats()?;
+        println!(
+            "{} chats, {} messages, about {} tokens",
+            stats.chats, stats.messages, stats.tokens
+        );
+        for (model, replies, tokens) in stats.models {
+            println!("{:<20} {:>6} replies {:>9} tokens", model, replies, tokens);
+        }
+        Ok(())
+    }
+
+    /// Print each model's `latency` as a table, for `stats --latency`
+    pub fn print_latency(&self) -> Result<()> {
+        println!(
+            "{:<24} {:>7} {:>12} {:>10} {:>9}",
+            "model", "replies", "first token", "in all", "tokens/s"
+        );
+        for model in self.latency()? {
+            let rate = model
+                .tokens_per_second
+                .map_or("-".to_string(), |rate| format!("{:.1}", rate));
+            println!(
+                "{:<24} {:>7} {:>11.2}s {:>9.2}s {:>9}",
+                model.model,
+                model.replies,
+                model.first_token_ms as f64 / 1000.0,
+                model.duration_ms as f64 / 1000.0,
+                rate
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The middle value, or the lower of the two in the middle
+fn median<T: PartialOrd + Copy>(mut values: Vec<T>) -> Option<T> {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    values.get(values.len().saturating_sub(1) / 2).copied()
+}
+
+fn create_tables(db: &Connection) -> Result<()> {
+    let version: i32 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version < SCHEMA_VERSION {
+        db.execute_batch(
+            "DROP TABLE IF EXISTS messages; DROP TABLE IF EXISTS tags; DROP TABLE IF EXISTS chats;",
+        )?;
+        db.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    }
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS chats (
+             path TEXT PRIMARY KEY,
+             title TEXT,
+             model TEXT,
+             created TEXT,
+             modified INTEGER NOT NULL,
+             modified_ns INTEGER NOT NULL,
+             size INTEGER NOT NULL,
+             pinned INTEGER NOT NULL DEFAULT 0
+         );
+         CREATE TABLE IF NOT EXISTS tags (
+             chat TEXT NOT NULL,
+             tag TEXT NOT NULL COLLATE NOCASE,
+             PRIMARY KEY (chat, tag)
+         );
+         CREATE TABLE IF NOT EXISTS messages (
+             chat TEXT NOT NULL,
+             position INTEGER NOT NULL,
+             role TEXT NOT NULL,
+             model TEXT,
+             content TEXT NOT NULL,
+             tokens INTEGER NOT NULL,
+             latency_ms INTEGER,
+             duration_ms INTEGER,
+             completion_tokens INTEGER,
+             PRIMARY KEY (chat, position)
+         );",
+    )
+    .context("Unable to create the catalog's tables")
+}
+
+/// When the file was last changed, in nanoseconds since the epoch, and its length
+///
+/// A chat is catalogued again when either changes. A reply is written several
+/// times a second, so whole seconds would miss the later writes.
+fn version(path: &Path) -> Result<(i64, i64)> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok((modified.as_nanos() as i64, metadata.len() as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn catalogs_the_chats_in_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ChatStore::at(dir.path());
+        std::fs::write(
+            dir.path().join("a.md"),
+            "---\ntitle: Lifetimes\nmodel: gpt-4o\n---\n# User\nWhat's 'a?\n# Assistant\nA lifetime.\n<!-- chat-cli-rs {\"latency_ms\":400,\"duration_ms\":600,\"completion_tokens\":10} -->\n# User\n\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("b.md"),
+            "---\ntags: [greeting]\npinned: true\n---\n# User\nHello\n",
+        )
+        .unwrap();
+
+        let catalog = Catalog::open(&store, true).unwrap();
+        let chats = catalog.chats(10, None).unwrap();
+        assert_eq!(chats.len(), 2);
+        assert!(chats[0].path.ends_with("b.md") && chats[0].pinned);
+        assert_eq!(chats[0].tags, ["greeting"]);
+        let tagged = catalog.chats(10, Some("#Greeting")).unwrap();
+        assert_eq!(tagged.len(), 1);
+        assert_eq!(catalog.messages(None, Some("greeting")).unwrap().len(), 1);
+        let a = chats.iter().find(|c| c.path.ends_with("a.md")).unwrap();
+        assert_eq!(a.title.as_deref(), Some("Lifetimes"));
+        assert_eq!(a.messages, 3);
+
+        let found = catalog.messages(Some("LIFETIME"), None).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0].messages,
+            [("Assistant".to_string(), "A lifetime.".to_string())]
+        );
+
+        let stats = catalog.stats().unwrap();
+        assert_eq!((stats.chats, stats.messages), (2, 4));
+        assert_eq!(stats.models[0].0, "gpt-4o");
+        let latency = catalog.latency().unwrap();
+        assert_eq!(latency.len(), 1);
+        assert_eq!((latency[0].replies, latency[0].first_token_ms), (1, 400));
+        assert_eq!(latency[0].tokens_per_second, Some(50.0));
+
+        // Written again within the second, keeping its modification time
+        let a_path = dir.path().join("a.md");
+        let modified = std::fs::metadata(&a_path).unwrap().modified().unwrap();
+        std::fs::write(
+            &a_path,
+            "# User\nWhat's 'a?\n# Assistant\nA lifetime, 'a.\n# User\n\n",
+        )
+        .unwrap();
+        File::options()
+            .write(true)
+            .open(&a_path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let catalog = Catalog::open(&store, true).unwrap();
+        let found = catalog.messages(Some("lifetime, 'a"), None).unwrap();
+        assert_eq!(found.len(), 1);
+
+        std::fs::remove_file(dir.path().join("b.md")).unwrap();
+        let catalog = Catalog::open(&store, true).unwrap();
+        assert_eq!(catalog.chats(10, None).unwrap().len(), 1);
+        assert_eq!(catalog.stats().unwrap().messages, 3);
+    }
+}
@@ -0,0 +1,897 @@
+use crate::{
+    agent::Allow,
+    clipboard::CopyWhat,
+    compact, completions,
+    config::{Overrides, Sampling},
+    export,
+    postprocess::PostProcess,
+    provider::ResponseFormat,
+    retention::{Action, Compression},
+    schema,
+    session::Output,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use std::path::{Path, PathBuf};
+
+/// Chat with OpenAI models through a markdown buffer
+///
+/// Without a command a new chat is started in the chat store and opened in the
+/// editor. Write a message under the last `# User` heading, save it, and press
+/// Enter in the terminal to send the chat, the reply streams into the file.
+/// Enter `r` to retry the last reply or `c` to copy it, and press Ctrl-C to
+/// stop a reply or, between replies, to quit. With --watch the chat is sent
+/// whenever the file is saved with a new message, and -f sends a chat once and
+/// exits.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    after_long_help = "Examples:
+  chat-cli-rs                              Start a chat and open it in the editor
+  chat-cli-rs --watch -m gpt-4o            Send the chat whenever it's saved
+  chat-cli-rs -s code-review -a src/lib.rs Start a chat with a prompt and a file attached
+  chat-cli-rs -f chat.md                   Send a chat once and print the reply
+  chat-cli-rs resume                       Pick a saved chat to carry on"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
+    /// Send an existing chat file for a single completion and exit
+    #[arg(short, long, value_name = "FILE", add = completions::chats())]
+    pub file: Option<PathBuf>,
+
+    /// Ask a question in a new chat and print the reply, with any text piped on stdin attached
+    #[arg(short, long, value_name = "QUESTION", conflicts_with = "file")]
+    pub prompt: Option<String>,
+
+    /// System prompt for a new chat, either a prompt name or a path to a file
+    #[arg(short, long, visible_alias = "prompt-name", value_name = "NAME|PATH", add = completions::prompts())]
+    pub system: Option<String>,
+
+    /// Attach an image to the user's message, can be given more than once
+    #[arg(short, long, value_name = "PATH|URL")]
+    pub image: Vec<String>,
+
+    /// Include a file, or the files in a directory, in the user's message, can be given more than once
+    #[arg(short, long, value_name = "PATH")]
+    pub attach: Vec<String>,
+
+    /// Ask about the attached files a part at a time and combine the answers, for files longer than the context window
+    #[arg(long, requires = "attach")]
+    pub map_reduce: bool,
+
+    /// Run a shell command and include its output in the user's message, can be given more than once
+    #[arg(short, long, value_name = "COMMAND")]
+    pub run: Vec<String>,
+
+    /// Speak the user's message, recorded until Enter is pressed, or transcribe an audio file into it
+    #[arg(long, num_args = 0..=1, value_name = "AUDIO")]
+    pub voice: Option<Option<PathBuf>>,
+
+    /// Add the text on the clipboard to the user's message
+    #[arg(long)]
+    pub paste: bool,
+
+    /// Copy each reply, or just its first code block, to the clipboard
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "reply", value_name = "WHAT")]
+    pub copy: Option<CopyWhat>,
+
+    /// Write the code blocks in each reply that name a file to it, after showing the changes and asking
+    #[arg(long, conflicts_with = "dry_run")]
+    pub apply: bool,
+
+    /// Send the chat whenever the file is saved with a new user message, instead of waiting for Enter
+    #[arg(short, long)]
+    pub watch: bool,
+
+    /// Don't send a desktop notification when a reply arrives, and print only the reply's text with -f, -p, retry, continue or git
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Don't title the chat and rename its file after the first reply
+    #[arg(long)]
+    pub no_title: bool,
+
+    /// Send the chat to several models at once and append each of their replies, e.g. `gpt-4o,gpt-4-turbo`
+    #[arg(long, value_delimiter = ',', value_name = "MODEL,...", add = completions::models())]
+    pub models: Vec<String>,
+
+    /// Request a new reply with -f or batch even if the same request was answered before
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Print the request that would be sent and its estimated token count instead of sending it
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// How to print the reply with `-f` and `retry`, `json` prints it with its model, usage and finish reason
+    #[arg(long, value_enum, default_value = "text", conflicts_with_all = ["models", "dry_run"])]
+    pub output: Output,
+
+    /// Ask for the reply as a JSON object, the chat should mention JSON
+    #[arg(long)]
+    pub json: bool,
+
+    /// Ask for the reply as JSON matching a schema, it's sent back to be corrected if it doesn't
+    #[arg(long, value_name = "FILE", value_parser = parse_schema, conflicts_with = "json")]
+    pub schema: Option<ResponseFormat>,
+
+    /// Start the reply with this text, e.g. "Sure, here is the JSON:", and have the model continue it
+    #[arg(long, value_name = "TEXT", conflicts_with = "models")]
+    pub prefill: Option<String>,
+
+    /// Ask for the reply as a unified diff that `apply-patch` can apply, it's sent back to be corrected if it isn't one
+    #[arg(long, conflicts_with_all = ["json", "schema", "reflect", "models"])]
+    pub patch: bool,
+
+    /// Send only the last K exchanges before the message, with the system prompt
+    #[arg(long, value_name = "K", conflicts_with = "no_history")]
+    pub last_n: Option<usize>,
+
+    /// Send the message with the system prompt but none of the chat before it
+    #[arg(long)]
+    pub no_history: bool,
+
+    /// Have the model reply in this language, e.g. `de`, asking again once if it doesn't
+    #[arg(long, value_name = "LANG")]
+    pub reply_language: Option<String>,
+
+    /// Pipe each reply through this shell command before it's kept, in place of `post_process` in the config, repeatable
+    #[arg(long, value_name = "COMMAND")]
+    pub post: Vec<String>,
+
+    /// Retrieve context for each message from a document index made with `rag index`
+    #[arg(long, value_name = "INDEX")]
+    pub rag: Option<String>,
+
+    /// Use a profile from the config, with its own key, API, defaults and chats
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Provider whose API key is used, overriding the config, `mock` answers without the network
+    #[arg(long, value_name = "NAME")]
+    pub provider: Option<String>,
+
+    /// Record the chat requests and their replies to a file, with the API key removed
+    #[arg(long, value_name = "FILE", conflicts_with = "play")]
+    pub record: Option<PathBuf>,
+
+    /// Answer the chat requests from the replies recorded with --record, without the API
+    #[arg(long, value_name = "FILE")]
+    pub play: Option<PathBuf>,
+
+    /// Send requests through a proxy, overriding the config and `HTTPS_PROXY`
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// Give up on a reply that isn't complete after this many seconds
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+
+    /// Send the request again, or resume the reply, if a token takes longer than this many seconds to arrive
+    #[arg(long, value_name = "SECS")]
+    pub first_token_timeout: Option<u64>,
+
+    /// Show the messages being sent, with their token counts, before each request
+    #[arg(long)]
+    pub show_context: bool,
+
+    /// Write the reply to a file too as it streams in, with -f
+    #[arg(long, value_name = "FILE", requires = "file")]
+    pub out: Option<PathBuf>,
+
+    /// Show what's about to be sent and ask before each request, with a chance to edit or drop messages
+    #[arg(long, conflicts_with = "dry_run")]
+    pub confirm: bool,
+
+    /// Have the model critique and revise its answer this many times, the drafts are kept collapsed above it
+    #[arg(long, value_name = "ROUNDS", default_value_t = 0, conflicts_with_all = ["models", "json", "schema"])]
+    pub reflect: usize,
+
+    /// Serve editor plugins JSON-RPC on stdin and stdout instead, see the `protocol` module
+    #[arg(long, conflicts_with_all = ["file", "dry_run", "models"])]
+    pub stdio_server: bool,
+
+    /// Keep the new chat encrypted, it's only decrypted into a tmpfs while open
+    #[arg(long, conflicts_with_all = ["file", "stdio_server"])]
+    pub encrypt: bool,
+
+    /// How the new chat is kept, `jsonl` keeps a transcript with a line per message for tools to read
+    #[arg(long, value_enum, default_value = "md", conflicts_with_all = ["file", "stdio_server", "encrypt"])]
+    pub format: ChatFormat,
+
+    /// Never save the chat, it's kept in a tmpfs and shredded on exit, a chat that's resumed is forked
+    #[arg(long, conflicts_with_all = ["stdio_server", "encrypt", "format", "record", "log_requests"])]
+    pub ephemeral: bool,
+
+    /// Send requests that go over a spend limit, with a warning, unless the limits are strict
+    #[arg(long)]
+    pub yes: bool,
+
+    /// Don't send the project's `.chatcli.md` or use its `.chatcli/config.toml`
+    #[arg(long)]
+    pub no_project: bool,
+
+    /// Print without colours, as does setting `NO_COLOR`
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// Log what's happening to stderr, repeat for more detail (-vv)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Append every request and response to a JSONL log, API keys removed
+    #[arg(long)]
+    pub log_requests: bool,
+
+    /// Model to use, overriding the chat file and the config
+    #[arg(short, long, add = completions::models())]
+    pub model: Option<String>,
+
+    #[command(flatten)]
+    pub sampling: Sampling,
+}
+
+impl Cli {
+    /// The settings given on the command line, which override the chat's own
+    pub fn overrides(&self) -> Overrides {
+        Overrides {
+            model: self.model.clone(),
+            sampling: self.sampling.clone(),
+            response_format: self
+                .schema
+                .clone()
+                .or(self.json.then_some(ResponseFormat::JsonObject)),
+            rag: self.rag.clone(),
+            prefill: self.prefill.clone(),
+            patch: self.patch,
+            history: self.no_history.then_some(0).or(self.last_n),
+            reply_language: self.reply_language.clone(),
+            post_process: self
+                .post
+                .iter()
+                .cloned()
+                .map(PostProcess::Command)
+                .collect(),
+        }
+    }
+}
+
+fn parse_var(var: &str) -> Result<(String, String), String> {
+    var.split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("Expected KEY=VALUE, got {:?}", var))
+}
+
+fn parse_schema(path: &str) -> Result<ResponseFormat, String> {
+    schema::load(Path::new(path)).map_err(|e| format!("{:#}", e))
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Commands {
+    /// Start a new chat, like running without a command, but in a file of your choosing
+    ///
+    /// An existing file is left alone and the chat started in e.g. `notes-2.md`
+    /// instead, unless --force is given.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs new notes.md
+  chat-cli-rs new notes.md --force")]
+    New {
+        /// The chat file to start, the chat store is used if it's left out
+        file: Option<PathBuf>,
+
+        /// Replace the file if it exists, it can be brought back with `undo`
+        #[arg(long, requires = "file")]
+        force: bool,
+    },
+    /// Restore the chat last replaced by `new --force`
+    Undo,
+    /// Continue an existing chat with the settings saved in its frontmatter
+    ///
+    /// Without a file the saved chats are listed to choose from, typing
+    /// filters them by title and first message.
+    #[command(
+        visible_alias = "pick",
+        after_long_help = "Examples:
+  chat-cli-rs resume
+  chat-cli-rs resume ~/notes/rust.md"
+    )]
+    Resume {
+        /// The chat file to continue
+        #[arg(add = completions::chats())]
+        file: Option<PathBuf>,
+    },
+    /// Open today's chat in the journal, starting it if it's the first of the day
+    ///
+    /// Each day has one chat, `YYYY-MM-DD.md`, which later runs that day add to.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs journal
+  chat-cli-rs journal --dir ~/notes/journal")]
+    Journal {
+        /// The journal directory, instead of `journal_dir` in the config
+        #[arg(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+    /// Remove the last reply from a chat and request it again
+    ///
+    /// Use the top level flags to retry with another model or temperature,
+    /// e.g. `chat-cli-rs --temperature 1.2 retry chat.md`.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs retry chat.md
+  chat-cli-rs --model gpt-4o --temperature 1.2 retry chat.md")]
+    Retry {
+        /// The chat file to retry
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+    },
+    /// Send many chat files, several at a time, and report how each went
+    ///
+    /// Each file is sent like `-f`, a chat failing doesn't stop the others.
+    /// With --dry-run the files are listed instead.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs batch a.md b.md
+  chat-cli-rs batch --glob 'prompts/*.md' --concurrency 8")]
+    Batch {
+        /// Chat files to send
+        files: Vec<PathBuf>,
+
+        /// Send the files matching a pattern too, e.g. 'prompts/*.md', can be given more than once
+        #[arg(short, long, value_name = "PATTERN")]
+        glob: Vec<String>,
+
+        /// How many chats to send at once
+        #[arg(short, long, default_value_t = 4)]
+        concurrency: usize,
+    },
+    /// Copy a chat up to one of its messages into a new chat and continue it
+    #[command(after_long_help = "Examples:
+  chat-cli-rs branch chat.md
+  chat-cli-rs branch chat.md 4")]
+    Branch {
+        /// The chat file to branch
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+
+        /// Number of the last message to keep, the messages are listed if it's left out
+        at: Option<usize>,
+    },
+    /// Show how the messages of two chats differ
+    #[command(after_long_help = "Examples:
+  chat-cli-rs diff chat.md chat-2.md")]
+    Diff {
+        /// The chat to compare from
+        #[arg(add = completions::chats())]
+        a: PathBuf,
+
+        /// The chat to compare to
+        #[arg(add = completions::chats())]
+        b: PathBuf,
+    },
+    /// Send each user message of a chat again in a new chat, e.g. to compare models
+    ///
+    /// The new chat keeps the original's system prompt and settings, the
+    /// replies build on its own earlier replies.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs replay chat.md --model gpt-4o-mini")]
+    Replay {
+        /// The chat file to replay
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+
+        /// Model to reply with, the chat's own otherwise
+        #[arg(short, long, add = completions::models())]
+        model: Option<String>,
+    },
+    /// Work towards a goal by calling tools, one step at a time
+    ///
+    /// The model plans, calls a tool, looks at the result and carries on until
+    /// it's done or has used --max-steps calls. Every call and result is logged
+    /// in the chat. Shell commands and file writes are asked about first.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs agent 'Find the TODOs in src and list them'
+  chat-cli-rs agent 'Make the tests pass' --allow fs-read,fs-write,shell --max-steps 20")]
+    Agent {
+        /// What the agent should get done
+        goal: String,
+
+        /// Most tool calls to make before the model has to sum up
+        #[arg(long, default_value_t = 10)]
+        max_steps: usize,
+
+        /// The tools it may use
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "fs-read")]
+        allow: Vec<Allow>,
+
+        /// Run shell commands and write files without asking
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Ask for the rest of a chat's last reply, e.g. one cut off at the token limit
+    ///
+    /// The rest is added to the reply under the same heading. Set
+    /// `auto_continue = true` in the config to do this as soon as a reply is cut off.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs continue chat.md")]
+    Continue {
+        /// The chat file to continue
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+    },
+    /// Write the code blocks in a chat's last reply to the files they name
+    ///
+    /// A block names its file on its first line, e.g. `// file: src/main.rs`,
+    /// or in its fence, e.g. ```` ```rust file=src/main.rs ````. Each change
+    /// is shown and asked about first, with --dry-run only shown.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs extract chat.md
+  chat-cli-rs --dry-run extract chat.md")]
+    Extract {
+        /// The chat file to extract from
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+
+        /// Write the files without asking
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Apply the unified diff in a chat's last reply, or in a patch file, to the files it changes
+    ///
+    /// Hunks are placed by their context, so they still apply after the lines
+    /// around them have moved. Nothing is changed unless the whole patch
+    /// applies, with --dry-run it's only checked.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs apply-patch chat.md
+  chat-cli-rs apply-patch fix.patch")]
+    ApplyPatch {
+        /// A chat file, or a patch file if it doesn't end in `.md`
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+    },
+    /// Copy the last reply in a chat, or just its first code block, to the clipboard
+    #[command(after_long_help = "Examples:
+  chat-cli-rs copy chat.md --code")]
+    Copy {
+        /// The chat file to copy from
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+
+        /// Copy only the first code block
+        #[arg(short, long)]
+        code: bool,
+    },
+    /// Keep text from stdin on the clipboard until something else is copied, used by `copy`
+    #[command(hide = true)]
+    ServeClipboard,
+    /// Export a chat to share with people who don't read markdown
+    #[command(after_long_help = "Examples:
+  chat-cli-rs export chat.md
+  chat-cli-rs export chat.md --format pdf -o chat.pdf")]
+    Export {
+        /// The chat file to export
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+
+        /// Format to export to
+        #[arg(short, long, value_enum, default_value = "html")]
+        format: export::Format,
+
+        /// Where to write the export, by default next to the chat with the format's extension
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Import the conversations in a ChatGPT data export as chats that can be continued
+    #[command(after_long_help = "Examples:
+  chat-cli-rs import ~/Downloads/chatgpt-export.zip")]
+    Import {
+        /// The export's zip file, or the `conversations.json` in it
+        file: PathBuf,
+    },
+    /// Search the saved chats for some text
+    #[command(after_long_help = "Examples:
+  chat-cli-rs search 'borrow checker'
+  chat-cli-rs search --regex 'tokio::(spawn|select)' --archived
+  chat-cli-rs search --tag rust lifetime")]
+    Search {
+        /// Text to look for, case insensitive
+        query: String,
+
+        /// Treat the query as a regular expression
+        #[arg(short, long)]
+        regex: bool,
+
+        /// Search the archived chats too
+        #[arg(short, long)]
+        archived: bool,
+
+        /// Only search chats with this tag
+        #[arg(short, long)]
+        tag: Option<String>,
+    },
+    /// Summarize the older part of a chat to keep it within the model's context window
+    ///
+    /// The original chat is archived before the file is rewritten.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs compact chat.md --keep 2")]
+    Compact {
+        /// The chat file to compact
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+
+        /// Number of recent exchanges to keep as they are
+        #[arg(short, long, default_value_t = compact::DEFAULT_KEEP)]
+        keep: usize,
+    },
+    /// Archive or delete old chats, as set in the `[retention]` config
+    ///
+    /// Pinned chats are kept. With --dry-run the chats are listed instead.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs --dry-run gc --days 90
+  chat-cli-rs gc --days 90 --action archive --compression zstd")]
+    Gc {
+        /// Clean up chats not changed in this many days
+        #[arg(long)]
+        days: Option<u64>,
+
+        /// What to do with them
+        #[arg(long, value_enum)]
+        action: Option<Action>,
+
+        /// How to compress the archived chats
+        #[arg(long, value_enum)]
+        compression: Option<Compression>,
+
+        /// Whether to keep chats with `pinned: true` in their frontmatter
+        #[arg(long, value_name = "BOOL")]
+        keep_pinned: Option<bool>,
+    },
+    /// Check chats for headings that no longer split them into messages
+    ///
+    /// Mistyped role headings, code blocks left open and a missing `# User` heading
+    /// at the end are found. With --repair they're fixed, the original chat is
+    /// archived first.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs fsck
+  chat-cli-rs fsck chat.md --repair")]
+    Fsck {
+        /// The chat files to check, all the saved chats if none are given
+        #[arg(add = completions::chats())]
+        files: Vec<PathBuf>,
+
+        /// Fix the problems found
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Tag chats to narrow `list` and `search` down to them
+    #[command(after_long_help = "Examples:
+  chat-cli-rs tag add chat.md rust work
+  chat-cli-rs tag remove chat.md work
+  chat-cli-rs list --tag rust")]
+    Tag {
+        #[command(subcommand)]
+        command: TagCommand,
+    },
+    /// Pin a chat, listing it first in `list` and the picker and keeping it from `gc`
+    #[command(after_long_help = "Examples:
+  chat-cli-rs pin chat.md")]
+    Pin {
+        /// The chat file to pin
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+    },
+    /// Unpin a chat
+    Unpin {
+        /// The chat file to unpin
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+    },
+    /// Manage the document indexes used by `--rag`
+    Rag {
+        #[command(subcommand)]
+        command: RagCommand,
+    },
+    /// Manage the API keys stored in the OS keyring
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommand,
+    },
+    /// Start a chat with a template from `$XDG_CONFIG_HOME/chat-cli-rs/templates/` as its first message
+    ///
+    /// `{{name}}` placeholders are filled in with --var, the others are asked
+    /// for. Lists the templates when no name is given.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs template
+  chat-cli-rs template review --var pr=123")]
+    Template {
+        /// The template's name or path
+        name: Option<String>,
+
+        /// A value for a placeholder, e.g. --var pr=123
+        #[arg(long = "var", value_name = "KEY=VALUE", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+    },
+    /// Walk through a workflow from `$XDG_CONFIG_HOME/chat-cli-rs/workflows/` in a new chat
+    ///
+    /// A workflow is a TOML or YAML file of steps that ask questions, send
+    /// messages and branch on the replies. Lists the workflows when no name is given.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs workflow
+  chat-cli-rs workflow design-review --var doc=design.md")]
+    Workflow {
+        /// The workflow's name or path
+        name: Option<String>,
+
+        /// An answer to a question by the name it's saved as, e.g. --var doc=design.md
+        #[arg(long = "var", value_name = "KEY=VALUE", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+    },
+    /// Chat in a full screen interface, with a tab for each chat
+    ///
+    /// Each tab sends its chat on its own, so a reply can stream in one tab while
+    /// another is used. Ctrl-T opens a tab for a new chat, Tab and Alt-1 to Alt-9
+    /// switch between them and Ctrl-W closes one, interrupting its reply.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs tui
+  chat-cli-rs tui notes.md ~/.local/share/chat-cli-rs/2024-05-01_rust-borrow-checker.md")]
+    Tui {
+        /// Chat files to open a tab for, a new chat if none are given
+        #[arg(add = completions::chats())]
+        files: Vec<PathBuf>,
+    },
+    /// Serve the chats over HTTP, to list them, send messages and stream replies
+    ///
+    /// Listening anywhere but localhost, e.g. on a Tailscale address, needs a
+    /// token in CHAT_CLI_RS_SERVE_TOKEN for clients to send as
+    /// `Authorization: Bearer <token>`. Directives and slash commands in
+    /// messages sent over HTTP aren't carried out.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs serve --port 8080")]
+    Serve {
+        /// Port to listen on
+        #[arg(short, long, default_value_t = 8080)]
+        port: u16,
+
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+    /// Generate images from a prompt and link them in a chat
+    ///
+    /// The images are saved next to the chat in `assets/<chat name>/`.
+    #[command(after_long_help = "Examples:
+  chat-cli-rs imagine 'A lighthouse at dusk' -n 2 --size 1024x1024")]
+    Imagine {
+        /// What to draw
+        prompt: String,
+
+        /// How many images to generate
+        #[arg(short, long, default_value_t = 1)]
+        n: usize,
+
+        /// Size of the images, e.g. 1024x1024
+        #[arg(long)]
+        size: Option<String>,
+
+        /// Image model, e.g. gpt-image-1 or dall-e-3
+        #[arg(long)]
+        model: Option<String>,
+
+        /// Directory to save the images in instead
+        #[arg(long, value_name = "DIR")]
+        out: Option<PathBuf>,
+
+        /// Chat to add the images to, a new chat is started otherwise
+        #[arg(long, value_name = "FILE", add = completions::chats())]
+        chat: Option<PathBuf>,
+    },
+    /// Print the text spoken in an audio file
+    #[command(after_long_help = "Examples:
+  chat-cli-rs transcribe meeting.m4a")]
+    Transcribe {
+        /// The audio file, e.g. mp3, m4a or wav
+        file: PathBuf,
+    },
+    /// List the most recently changed chats, pinned ones first
+    #[command(after_long_help = "Examples:
+  chat-cli-rs list -n 5
+  chat-cli-rs list --tag rust")]
+    List {
+        /// How many to list
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+
+        /// Only list chats with this tag
+        #[arg(short, long)]
+        tag: Option<String>,
+    },
+    /// Count the chats, messages and tokens, and the replies from each model
+    #[command(after_long_help = "Examples:
+  chat-cli-rs stats
+  chat-cli-rs stats --latency")]
+    Stats {
+        /// Show how quickly each model replied instead, the time to the first token and tokens per second
+        #[arg(long)]
+        latency: bool,
+
+        #[command(subcommand)]
+        command: Option<StatsCommand>,
+    },
+    /// Write commit messages and review changes
+    Git {
+        #[command(subcommand)]
+        command: GitCommand,
+    },
+    /// Manage the system prompt library
+    Prompts {
+        #[command(subcommand)]
+        command: PromptsCommand,
+    },
+    /// Manage the snippets included in messages with `@context <name>`
+    Context {
+        #[command(subcommand)]
+        command: ContextCommand,
+    },
+    /// Print the script that completes commands, chats, prompts and models in a shell
+    ///
+    /// e.g. `source <(chat-cli-rs completions bash)` in `~/.bashrc`, or
+    /// `chat-cli-rs completions fish | source` in fish's config.
+    #[command(after_long_help = "Examples:
+  source <(chat-cli-rs completions bash)
+  chat-cli-rs completions fish | source")]
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print the manual page, or write one for each command into a directory
+    #[command(after_long_help = "Examples:
+  chat-cli-rs man | man -l -
+  chat-cli-rs man --dir ~/.local/share/man/man1")]
+    Man {
+        /// Directory to write `chat-cli-rs.1` and a page for each command, e.g. `chat-cli-rs-retry.1`, into
+        #[arg(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum GitCommand {
+    /// Write a commit message for the staged changes
+    CommitMsg {
+        /// Write it to `.git/COMMIT_EDITMSG` too
+        #[arg(long)]
+        write: bool,
+    },
+    /// Review the uncommitted changes
+    Review {
+        /// Only the staged changes
+        #[arg(long, conflicts_with = "range")]
+        staged: bool,
+
+        /// The changes in a range of commits instead, e.g. `main..HEAD`
+        #[arg(long, value_name = "RANGE")]
+        range: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PromptsCommand {
+    /// List the available system prompts
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ContextCommand {
+    /// Keep a file, or stdin with `-`, as a snippet, replacing one of the same name
+    Add {
+        /// Name to include it by
+        name: String,
+
+        /// The file to keep, `-` for stdin
+        file: PathBuf,
+    },
+    /// List the snippets with their size in tokens
+    List,
+    /// Forget a snippet
+    Remove {
+        /// The snippet's name
+        name: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TagCommand {
+    /// Add tags to a chat's frontmatter
+    Add {
+        /// The chat file
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+
+        /// The tags to add
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Remove tags from a chat's frontmatter
+    Remove {
+        /// The chat file
+        #[arg(add = completions::chats())]
+        file: PathBuf,
+
+        /// The tags to remove
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RagCommand {
+    /// Chunk and embed the files in a directory, again to pick up changes
+    Index {
+        /// The directory of documents
+        dir: PathBuf,
+
+        /// Name of the index, the directory's name by default
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+    /// List the indexes
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StatsCommand {
+    /// Show the spend today and this month against the `[spend]` limits
+    Budget,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuthCommand {
+    /// Store an API key in the keyring, the provider's environment variable still takes precedence
+    Login {
+        /// The provider the key is for, defaults to the profile's or else openai
+        #[arg(long)]
+        provider: Option<String>,
+    },
+    /// Remove an API key from the keyring
+    Logout {
+        /// The provider the key is for, defaults to the profile's or else openai
+        #[arg(long)]
+        provider: Option<String>,
+    },
+}
+
+/// How a chat is kept
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChatFormat {
+    /// A markdown file, to read and edit
+    #[default]
+    Md,
+    /// A JSONL transcript, edited as markdown while it's open
+    Jsonl,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn shows_examples_in_the_help_and_the_manual() {
+        Cli::command().debug_assert();
+        let mut command = Cli::command();
+        let help = command.render_long_help().to_string();
+        assert!(help.contains("Examples:\n  chat-cli-rs "));
+
+        let retry = command.find_subcommand_mut("retry").unwrap();
+        assert!(retry
+            .render_long_help()
+            .to_string()
+            .contains("chat-cli-rs retry chat.md"));
+
+        let mut page = Vec::new();
+        clap_mangen::Man::new(Cli::command())
+            .render(&mut page)
+            .unwrap();
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.contains("Send a chat once and print the reply"));
+    }
+}
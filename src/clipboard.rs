@@ -0,0 +1,105 @@
+use crate::{
+    message::{Fence, Message},
+    reflect, storage,
+};
+use anyhow::{bail, Context, Result};
+use arboard::Clipboard;
+use clap::ValueEnum;
+use openai::chat::ChatCompletionMessageRole;
+use std::{io::Write, path::Path};
+
+/// What to copy from a reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CopyWhat {
+    /// The whole reply
+    Reply,
+    /// Just the reply's first code block
+    Code,
+}
+
+/// The text on the clipboard
+pub fn paste() -> Result<String> {
+    Clipboard::new()
+        .and_then(|mut clipboard| clipboard.get_text())
+        .context("Unable to read the clipboard")
+}
+
+/// Append the text on the clipboard to the end of the chat file, i.e. to the user's message
+pub fn paste_into(chat_file: &Path) -> Result<()> {
+    let text = paste()?;
+    storage::append(chat_file, &format!("{}\n", text.trim_end()))
+        .with_context(|| format!("Could not append to file: {:?}", chat_file))
+}
+
+/// Put text on the clipboard
+///
+/// On Linux the clipboard is served by the program that set it, so a
+/// background copy of this program keeps serving it after we exit.
+pub fn copy(text: &str) -> Result<()> {
+    if cfg!(target_os = "linux") {
+        let mut child = std::process::Command::new(std::env::current_exe()?)
+            .arg("serve-clipboard")
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .context("Unable to start the clipboard server")?;
+        child
+            .stdin
+            .take()
+            .context("Unable to reach the clipboard server")?
+            .write_all(text.as_bytes())?;
+        return Ok(());
+    }
+
+    Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_text(text))
+        .context("Unable to set the clipboard")
+}
+
+/// Serve text read from stdin on the clipboard until something else is copied
+pub fn serve(text: &str) -> Result<()> {
+    let mut clipboard = Clipboard::new().context("Unable to open the clipboard")?;
+    #[cfg(target_os = "linux")]
+    {
+        use arboard::SetExtLinux;
+        clipboard.set().wait().text(text)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    clipboard.set_text(text)?;
+    Ok(())
+}
+
+/// The last assistant reply in the chat, or just its first code block
+pub fn last_reply(chat_file: &Path, what: CopyWhat) -> Result<String> {
+    let (_, messages) = Message::read_chat(chat_file)?;
+    let Some(reply) = messages.iter().rev().find(|m| {
+        matches!(m.role, ChatCompletionMessageRole::Assistant) && m.function_call.is_none()
+    }) else {
+        bail!("The chat has no reply to copy");
+    };
+
+    let content = reflect::answer(&reply.content);
+    match what {
+        CopyWhat::Reply => Ok(content.trim().to_string()),
+        CopyWhat::Code => first_code_block(content).context("The reply has no code block"),
+    }
+}
+
+/// The contents of the first fenced code block, without its fences
+pub fn first_code_block(content: &str) -> Option<String> {
+    let mut lines = content.lines();
+    let fence = lines.by_ref().find_map(Fence::opened_by)?;
+    let code: Vec<&str> = lines.take_while(|line| !fence.is_closed_by(line)).collect();
+    Some(code.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_first_code_block() {
+        let reply = "Try this:\n\n````rust\nlet s = \"```\";\n````\n\n```sh\nls\n```";
+        assert_eq!(first_code_block(reply).as_deref(), Some("let s = \"```\";"));
+        assert_eq!(first_code_block("No code"), None);
+    }
+}
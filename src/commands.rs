@@ -0,0 +1,962 @@
+//! What the binary runs for the command line once it's parsed
+//!
+//! `configure` loads the config and sets up everything the commands share,
+//! the rest send a chat the way a subcommand or `-f`, `-p` and no command at
+//! all ask for, announcing the reply or printing it as JSON.
+
+use crate::{
+    agent::{self, Allow},
+    attachments, auth, batch, cache, cassette,
+    cli::{ChatFormat, Cli, Commands, GitCommand},
+    clipboard::{self, CopyWhat},
+    compact,
+    config::{Config, Overrides, Settings, DEFAULT_MODEL},
+    confirm::{self, Declined},
+    context, directives, encryption,
+    exit::Failure,
+    extract, fallback, git,
+    hooks::{self, Event},
+    journal, logging, message, mock, notify, obsidian, pick, piped, platform, project, prompts,
+    provider, ratelimit, redact, replay, retention,
+    session::{Interrupted, Output, Reply},
+    spend, storage, theme, transcript, voice,
+    watch::ChatWatcher,
+    workflow::Workflow,
+    ChatStore, Message, Session,
+};
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::{
+    io::{stdin, stdout, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Load the config for the command line and set up logging, the provider,
+/// the store and the limits it sets
+///
+/// Options that can't be used together are refused here, before anything is sent.
+pub fn configure(cli: &mut Cli) -> Result<Config> {
+    logging::init(cli.verbose);
+    let mut config = Config::load()?;
+    theme::configure(config.theme, cli.no_color);
+    if let Some(profile) = cli.profile.clone().or_else(|| config.profile.clone()) {
+        config = config.with_profile(&profile)?;
+    }
+    if !cli.no_project {
+        if let Some(project) = project::find(&std::env::current_dir()?)? {
+            tracing::info!("Working in the project at {}", project.root.display());
+            config = config.with_project(&project);
+        }
+    }
+    if cli.log_requests || config.log_requests == Some(true) {
+        let log = logging::log_requests()?;
+        tracing::info!("Logging requests to {}", log.display());
+    }
+    if let Some(provider) = &cli.provider {
+        config.provider = Some(provider.clone());
+    }
+    if mock::requested() {
+        config.provider = Some(mock::PROVIDER.to_string());
+    }
+    if config.provider.as_deref() == Some(mock::PROVIDER) {
+        mock::configure(&config.mock)?;
+    }
+    let from_env = |var: &str| std::env::var_os(var).map(PathBuf::from);
+    if let Some(path) = cli
+        .record
+        .clone()
+        .or_else(|| from_env(cassette::RECORD_VAR))
+    {
+        cassette::record(&path)?;
+    } else if let Some(path) = cli.play.clone().or_else(|| from_env(cassette::PLAY_VAR)) {
+        cassette::play(&path)?;
+    }
+    if let Some(proxy) = &cli.proxy {
+        config.http.proxy = Some(proxy.clone());
+    }
+    provider::configure(&config.http)?;
+    provider::set_timeouts(provider::Timeouts {
+        total: cli.timeout.map(Duration::from_secs),
+        first_token: cli.first_token_timeout.map(Duration::from_secs),
+    });
+    redact::configure(&config.redaction)?;
+    encryption::configure(&config.encryption);
+    if let Some(url) = &config.base_url {
+        provider::set_base_url(url.clone());
+    }
+    if let Some(header) = &config.key_header {
+        provider::set_key_header(header.clone());
+    }
+    obsidian::configure(&config.obsidian);
+    if let Some(dir) = obsidian::dir().or_else(|| config.data_dir.clone()) {
+        storage::set_dir(dir);
+    }
+    storage::set_fsync(config.fsync == Some(true));
+    let key_provider = key_provider(&config);
+    // The environment's IDs are for OpenAI's own API, the config's for whichever is configured
+    let openai = key_provider == auth::DEFAULT_PROVIDER && config.base_url.is_none();
+    let from_env = |var: &str| std::env::var(var).ok().filter(|_| openai);
+    if let Some(id) = config
+        .organization
+        .clone()
+        .or_else(|| from_env("OPENAI_ORG_ID"))
+    {
+        provider::set_organization(id);
+    }
+    if let Some(id) = config
+        .project_id
+        .clone()
+        .or_else(|| from_env("OPENAI_PROJECT_ID"))
+    {
+        provider::set_project(id);
+    }
+    if let Some(limit) = config.rate_limits.get(&key_provider) {
+        ratelimit::set(*limit);
+    }
+    spend::configure(&config.spend, &key_provider, cli.yes)?;
+    hooks::configure(&config.hooks);
+    fallback::configure(
+        &config.fallbacks,
+        !cli.quiet && config.notifications != Some(false),
+    );
+    let single_reply = cli.file.is_some()
+        || cli.prompt.is_some()
+        || matches!(
+            cli.command,
+            Some(Commands::Retry { .. } | Commands::Continue { .. } | Commands::Git { .. })
+        );
+    if cli.output != Output::Text && !single_reply {
+        bail!(Failure::Usage(
+            "--output can only be used with -f, -p, retry, continue or git".to_string()
+        ));
+    }
+    if cli.quiet && single_reply && cli.output == Output::Text {
+        cli.output = Output::Plain;
+    }
+    if (cli.encrypt || cli.format == ChatFormat::Jsonl)
+        && !matches!(
+            cli.command,
+            None | Some(
+                Commands::Template { .. }
+                    | Commands::Workflow { .. }
+                    | Commands::New { file: None, .. }
+            )
+        )
+    {
+        bail!(Failure::Usage("--encrypt and --format can only be used when starting a chat in the store, the chat stays in its format when resumed".to_string()));
+    }
+    if cli.encrypt && (config.log_requests == Some(true) || cassette::recording()) {
+        bail!(Failure::Usage(
+            "--encrypt can't be used while requests are logged or recorded, they wouldn't be encrypted"
+                .to_string()
+        ));
+    }
+    if cli.ephemeral {
+        let saves = !matches!(
+            cli.command,
+            None | Some(
+                Commands::Template { .. }
+                    | Commands::Workflow { .. }
+                    | Commands::New { file: None, .. }
+                    | Commands::Resume { .. }
+                    | Commands::Retry { .. }
+                    | Commands::Continue { .. }
+            )
+        );
+        if saves {
+            bail!(Failure::Usage(
+                "--ephemeral can only be used when starting, resuming or sending a chat"
+                    .to_string()
+            ));
+        }
+        if config.log_requests == Some(true) || cassette::recording() {
+            bail!(Failure::Usage(
+                "--ephemeral can't be used while requests are logged or recorded".to_string()
+            ));
+        }
+    }
+    Ok(config)
+}
+
+/// The provider whose API key is used, the configured one or OpenAI
+pub fn key_provider(config: &Config) -> String {
+    config
+        .provider
+        .clone()
+        .unwrap_or_else(|| auth::DEFAULT_PROVIDER.to_string())
+}
+
+/// Set the API key for the provider, e.g. from `OPENAI_API_KEY` or the keyring
+pub fn set_api_key(provider: &str) -> Result<()> {
+    if provider == mock::PROVIDER || cassette::playing() {
+        return Ok(());
+    }
+    provider::set_key(auth::api_key(provider)?);
+    Ok(())
+}
+
+/// The text of `--voice`, transcribed from its file or listened for on the microphone
+pub async fn listen(cli: &Cli, config: &Config) -> Result<Option<String>> {
+    let Some(audio) = &cli.voice else {
+        return Ok(None);
+    };
+    // A local model may not need a key
+    if config.voice.base_url.is_none() {
+        set_api_key(&key_provider(config))?;
+    }
+    Ok(Some(match audio {
+        Some(audio) => voice::transcribe(audio, &config.voice).await?,
+        None => voice::listen(&config.voice).await?,
+    }))
+}
+
+/// Where `new` starts a chat in `file`, moving any chat there to the trash with `force`
+pub fn new_chat_path(file: &Path, force: bool) -> Result<PathBuf> {
+    if !force || !file.exists() {
+        return Ok(storage::unique_path(file));
+    }
+    let trashed = ChatStore::open()?.trash(file)?;
+    eprintln!(
+        "Moved the old chat to {}, `undo` brings it back",
+        trashed.display()
+    );
+    Ok(file.to_path_buf())
+}
+
+/// Start a chat and carry it on in the editor, with `first_message` from a template
+pub async fn start(
+    cli: &Cli,
+    new_chat: Option<PathBuf>,
+    first_message: Option<String>,
+    config: Config,
+    spoken: Option<&str>,
+) -> Result<()> {
+    if !cli.ephemeral {
+        retention::clean_up(&config.retention);
+    }
+    let key_provider = key_provider(&config);
+    let session = match new_chat {
+        Some(file) => Session::start_at(file, cli.system.clone(), config, cli.overrides())?,
+        None => start_session(cli, cli.system.clone(), config, cli.overrides())?,
+    };
+    if let Some(text) = first_message {
+        message::add_to_last(session.chat_file(), &text)?;
+    }
+    add_to_message(session.chat_file(), cli, spoken)?;
+    if !cli.dry_run {
+        set_api_key(&key_provider)?;
+    }
+    run(session, cli).await
+}
+
+/// Send the chat in `file` once, for `-f`
+pub async fn send_file(cli: &Cli, file: &Path, config: Config, spoken: Option<&str>) -> Result<()> {
+    if !cli.dry_run {
+        set_api_key(&key_provider(&config))?;
+    }
+    if !file.exists() {
+        bail!(Failure::Usage(format!("{} does not exist", file.display())));
+    }
+    // Cached replies aren't encrypted, or shredded
+    if !encryption::is_encrypted(file) && !cli.ephemeral {
+        use_cache(cli, &config)?;
+    }
+    let mut session = open_session(file, cli, config)?
+        .with_overrides(cli.overrides())
+        .with_output(cli.output)
+        .with_show_context(cli.show_context)
+        .with_confirm(cli.confirm)
+        .with_reflect(cli.reflect)
+        .with_out(cli.out.clone())
+        .with_map_reduce(map_reduce_documents(cli));
+    add_to_message(session.chat_file(), cli, spoken)?;
+    hooks::fire(Event::SessionStart, &session);
+    let reply = send(&session, cli).await.context("Unable to send file")?;
+    title_chat(&mut session, cli).await;
+    hooks::fire(Event::SessionEnd, &session);
+    print_json(reply, &session, cli)
+}
+
+/// Ask `question` in a new chat, with anything piped to stdin, for `-p`
+pub async fn prompt(cli: &Cli, question: &str, config: Config, spoken: Option<&str>) -> Result<()> {
+    let piped = piped::read()?;
+    let model = cli
+        .model
+        .clone()
+        .or_else(|| config.model.clone())
+        .unwrap_or_else(|| DEFAULT_MODEL.to_string());
+    let max_tokens = config.stdin_max_tokens.unwrap_or(piped::DEFAULT_MAX_TOKENS);
+    let text = piped::message(question, piped.as_deref(), &model, max_tokens);
+    let key_provider = key_provider(&config);
+    let mut session = start_session(cli, cli.system.clone(), config, cli.overrides())?
+        .with_output(cli.output)
+        .with_show_context(cli.show_context)
+        .with_confirm(cli.confirm)
+        .with_reflect(cli.reflect)
+        .with_out(cli.out.clone());
+    message::add_to_last(session.chat_file(), &text)?;
+    add_to_message(session.chat_file(), cli, spoken)?;
+    if !cli.dry_run {
+        set_api_key(&key_provider)?;
+    }
+    hooks::fire(Event::SessionStart, &session);
+    let reply = send(&session, cli).await?;
+    title_chat(&mut session, cli).await;
+    hooks::fire(Event::SessionEnd, &session);
+    print_json(reply, &session, cli)
+}
+
+/// Run the workflow `name`, asking its questions on the terminal
+pub async fn workflow(
+    cli: &Cli,
+    name: &str,
+    vars: &[(String, String)],
+    config: Config,
+) -> Result<()> {
+    if cli.dry_run {
+        bail!(Failure::Usage(
+            "--dry-run can't be used with workflow, its steps depend on the replies".to_string()
+        ));
+    }
+    let key_provider = key_provider(&config);
+    let workflow = Workflow::load(name)?;
+    let system = cli.system.clone().or(workflow.system.clone());
+    let mut overrides = cli.overrides();
+    overrides.model = overrides.model.or(workflow.model.clone());
+    let mut session = start_session(cli, system, config, overrides)?
+        .with_show_context(cli.show_context)
+        .with_confirm(cli.confirm)
+        .with_reflect(cli.reflect);
+    set_api_key(&key_provider)?;
+    workflow
+        .run(&session, vars.iter().cloned().collect(), ask)
+        .await?;
+    title_chat(&mut session, cli).await;
+    eprintln!("The workflow's chat is {}", session.saved_file().display());
+    Ok(())
+}
+
+/// Ask for a commit message or a review of the changes in the git repository
+pub async fn git(cli: &Cli, command: &GitCommand, config: Config) -> Result<()> {
+    let (prompt, request) = match command {
+        GitCommand::CommitMsg { .. } => (
+            prompts::COMMIT_MESSAGE_PROMPT,
+            git::commit_message_request()?,
+        ),
+        GitCommand::Review { staged, range } => {
+            let which = match (range, staged) {
+                (Some(range), _) => git::Diff::Range(range.clone()),
+                (None, true) => git::Diff::Staged,
+                (None, false) => git::Diff::Uncommitted,
+            };
+            (prompts::CODE_REVIEW_PROMPT, git::review_request(&which)?)
+        }
+    };
+    let key_provider = key_provider(&config);
+    let system = cli.system.clone().unwrap_or_else(|| prompt.to_string());
+    let session = Session::start(Some(system), config, cli.overrides())?
+        .with_output(cli.output)
+        .with_show_context(cli.show_context)
+        .with_confirm(cli.confirm)
+        .with_reflect(cli.reflect);
+    message::add_to_last(session.chat_file(), &request)?;
+    if !cli.dry_run {
+        set_api_key(&key_provider)?;
+    }
+    let reply = send(&session, cli).await?;
+    if let (GitCommand::CommitMsg { write: true }, Some(reply)) = (command, &reply) {
+        let path = git::commit_message_path()?;
+        let text = reply.message.content.as_deref().unwrap_or_default();
+        std::fs::write(&path, format!("{}\n", text.trim()))
+            .with_context(|| format!("Unable to write {}", path.display()))?;
+        eprintln!(
+            "Wrote the message to {}, commit it with `git commit -eF {}`",
+            path.display(),
+            path.display()
+        );
+    }
+    print_json(reply, &session, cli)
+}
+
+/// Summarize all but the last `keep` messages of the chat in `file`
+pub async fn compact(cli: &Cli, file: &Path, keep: usize, config: Config) -> Result<()> {
+    encryption::refuse(file, "compact")?;
+    if transcript::is_transcript(file) {
+        bail!(Failure::Usage(
+            "compact can't be used with transcripts, export the chat to markdown first".to_string()
+        ));
+    }
+    let (frontmatter, _) = Message::read_chat(file)?;
+    let settings = Settings::resolve(&cli.overrides(), &frontmatter, &config)?;
+    set_api_key(&key_provider(&config))?;
+    let compacted = compact::compact(file, &settings, keep).await?;
+    println!(
+        "Summarized {} messages, the original chat is archived at {}",
+        compacted.summarized,
+        compacted.archive.display()
+    );
+    Ok(())
+}
+
+/// Replace the last reply of the chat in `file` with a new one
+pub async fn retry(cli: &Cli, file: &Path, config: Config) -> Result<()> {
+    if cli.dry_run {
+        bail!(Failure::Usage(
+            "--dry-run can't be used with retry, as the last reply would be removed".to_string()
+        ));
+    }
+    set_api_key(&key_provider(&config))?;
+    let mut session = open_session(file, cli, config)?
+        .with_overrides(cli.overrides())
+        .with_output(cli.output)
+        .with_show_context(cli.show_context)
+        .with_confirm(cli.confirm)
+        .with_reflect(cli.reflect);
+    session.remove_last_reply()?;
+    let reply = send(&session, cli).await?;
+    title_chat(&mut session, cli).await;
+    print_json(reply, &session, cli)
+}
+
+/// Ask for the rest of the last reply of the chat in `file`
+pub async fn continue_reply(cli: &Cli, file: &Path, config: Config) -> Result<()> {
+    if cli.dry_run {
+        bail!(Failure::Usage(
+            "--dry-run can't be used with continue".to_string()
+        ));
+    }
+    set_api_key(&key_provider(&config))?;
+    let session = open_session(file, cli, config)?
+        .with_overrides(cli.overrides())
+        .with_output(cli.output);
+    let reply = match session.continue_or_cancel(ctrl_c()).await {
+        Err(e) if e.is::<Interrupted>() => {
+            eprintln!("\nInterrupted, the partial reply is kept in the chat");
+            return Ok(());
+        }
+        reply => reply?,
+    };
+    let notify = !cli.quiet && session.config().notifications != Some(false);
+    announce_reply(&reply, cli.output == Output::Text, notify);
+    print_json(Some(reply), &session, cli)
+}
+
+/// Send each of `files` and those matching `glob`, `concurrency` at a time
+pub async fn batch(
+    cli: &Cli,
+    files: &[PathBuf],
+    glob: &[String],
+    concurrency: usize,
+    config: Config,
+) -> Result<()> {
+    let files = batch::files(glob, files)?;
+    if cli.dry_run {
+        for file in &files {
+            println!("{}", file.display());
+        }
+        return Ok(());
+    }
+    set_api_key(&key_provider(&config))?;
+    use_cache(cli, &config)?;
+    let total = files.len();
+    let outcomes =
+        batch::send_all(files, &config, &cli.overrides(), concurrency, !cli.no_title).await;
+    println!("{}", batch::summary(&outcomes, total));
+    if let Some(waited) = ratelimit::report() {
+        println!("{}", waited);
+    }
+    let failed = outcomes
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, batch::Outcome::Failed(_)))
+        .count();
+    if failed > 0 {
+        bail!("{} of {} chats failed", failed, total);
+    }
+    Ok(())
+}
+
+/// Send the user messages of the chat in `file` again, to `model` if it's given
+pub async fn replay(cli: &Cli, file: &Path, model: Option<&str>, config: Config) -> Result<()> {
+    if cli.dry_run {
+        bail!(Failure::Usage(
+            "--dry-run can't be used with replay".to_string()
+        ));
+    }
+    encryption::refuse(file, "replay")?;
+    set_api_key(&key_provider(&config))?;
+    let mut overrides = cli.overrides();
+    overrides.model = model.map(str::to_string).or(overrides.model);
+    let mut session = replay::replay(file, config, overrides).await?;
+    title_chat(&mut session, cli).await;
+    println!("Replayed into {}", session.chat_file().display());
+    Ok(())
+}
+
+/// Work towards `goal` with the `allow`ed tools, for at most `max_steps`
+pub async fn agent(
+    cli: &Cli,
+    goal: &str,
+    max_steps: usize,
+    allow: &[Allow],
+    yes: bool,
+    config: Config,
+) -> Result<()> {
+    let key_provider = key_provider(&config);
+    let mut session = agent::start(goal, allow, yes, max_steps, config, cli.overrides())?
+        .with_output(cli.output)
+        .with_show_context(cli.show_context)
+        .with_confirm(cli.confirm);
+    if !cli.dry_run {
+        set_api_key(&key_provider)?;
+    }
+    send(&session, cli).await?;
+    title_chat(&mut session, cli).await;
+    eprintln!("The steps are logged in {}", session.chat_file().display());
+    Ok(())
+}
+
+/// Carry on the chat in `file` from message `at` in a new chat, or list its messages
+pub async fn branch(cli: &Cli, file: &Path, at: Option<usize>, config: Config) -> Result<()> {
+    let Some(at) = at else {
+        return print_messages(file);
+    };
+    let key_provider = key_provider(&config);
+    let branch = Session::open(file, config)
+        .with_overrides(cli.overrides())
+        .branch(at)?;
+    println!("Branched into {}", branch.chat_file().display());
+    if !cli.dry_run {
+        set_api_key(&key_provider)?;
+    }
+    run(branch, cli).await
+}
+
+/// Carry on today's journal chat in `dir`, or the configured journal directory
+pub async fn journal(
+    cli: &Cli,
+    dir: Option<&Path>,
+    config: Config,
+    spoken: Option<&str>,
+) -> Result<()> {
+    let dir = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => journal::dir(&config)?,
+    };
+    let key_provider = key_provider(&config);
+    let session = journal::open(&dir, cli.system.clone(), config, cli.overrides())?;
+    add_to_message(session.chat_file(), cli, spoken)?;
+    if !cli.dry_run {
+        set_api_key(&key_provider)?;
+    }
+    run(session, cli).await
+}
+
+/// Carry on the chat in `file`, or one picked from the store
+pub async fn resume(
+    cli: &Cli,
+    file: Option<&Path>,
+    config: Config,
+    spoken: Option<&str>,
+) -> Result<()> {
+    let file = match file {
+        Some(file) => file.to_path_buf(),
+        None => match pick::pick(&ChatStore::open()?)? {
+            Some(file) => file,
+            None => return Ok(()),
+        },
+    };
+    if !file.exists() {
+        bail!(Failure::Usage(format!("{} does not exist", file.display())));
+    }
+    let key_provider = key_provider(&config);
+    let session = open_session(&file, cli, config)?.with_overrides(cli.overrides());
+    add_to_message(session.chat_file(), cli, spoken)?;
+    if !cli.dry_run {
+        set_api_key(&key_provider)?;
+    }
+    run(session, cli).await
+}
+
+/// Ask a workflow's question on the terminal, reading the answer from stdin
+///
+/// Answers from a pipe are read a line at a time, so a script can answer each question.
+fn ask(question: &str) -> Result<String> {
+    eprint!("{}\n> ", question.trim());
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    if stdin().read_line(&mut answer)? == 0 {
+        bail!("No answer to {:?}", question.trim());
+    }
+    Ok(answer.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Start a chat in the store, kept encrypted or as a transcript if asked to,
+/// or one that's never saved
+fn start_session(
+    cli: &Cli,
+    system: Option<String>,
+    config: Config,
+    overrides: Overrides,
+) -> Result<Session> {
+    if cli.ephemeral {
+        return Session::start_ephemeral(system, config, overrides);
+    }
+    match (cli.encrypt, cli.format) {
+        (true, _) => Session::start_encrypted(system, config, overrides),
+        (false, ChatFormat::Jsonl) => Session::start_transcript(system, config, overrides),
+        (false, ChatFormat::Md) => Session::start(system, config, overrides),
+    }
+}
+
+/// The session for a chat file, decrypting it into a tmpfs for the session if it's
+/// encrypted or rendering it as markdown if it's a transcript
+///
+/// With `--ephemeral` the session has a copy of the chat that's never saved.
+fn open_session(file: &Path, cli: &Cli, config: Config) -> Result<Session> {
+    if cli.ephemeral {
+        if encryption::is_encrypted(file) || transcript::is_transcript(file) {
+            bail!(Failure::Usage(
+                "--ephemeral can only fork markdown chats".to_string()
+            ));
+        }
+        return Session::fork_ephemeral(file, config);
+    }
+    Session::resume(file, config)
+}
+
+/// Answer requests identical to earlier ones from the cache, unless it's turned off
+fn use_cache(cli: &Cli, config: &Config) -> Result<()> {
+    if !cli.no_cache && config.cache != Some(false) {
+        cache::enable(cache::default_dir()?, key_provider(config));
+    }
+    Ok(())
+}
+
+/// Add the spoken text and the images, commands, files and clipboard text given on the command line to the user's message
+fn add_to_message(file: &Path, cli: &Cli, spoken: Option<&str>) -> Result<()> {
+    if let Some(text) = spoken {
+        message::add_to_last(file, text)?;
+    }
+    attachments::attach(file, &cli.image)?;
+    directives::include_commands(file, &cli.run)?;
+    // Documents to map over are too long to include
+    if !cli.map_reduce {
+        directives::include_files(file, &cli.attach)?;
+    }
+    if cli.paste {
+        clipboard::paste_into(file)?;
+    }
+    Ok(())
+}
+
+/// The attached files, to ask about a part at a time with --map-reduce
+fn map_reduce_documents(cli: &Cli) -> Vec<PathBuf> {
+    match cli.map_reduce {
+        true => cli.attach.iter().map(PathBuf::from).collect(),
+        false => Vec::new(),
+    }
+}
+
+/// Copy the last reply, or its first code block, and say so
+fn copy_reply(session: &Session, what: CopyWhat) -> Result<()> {
+    clipboard::copy(&clipboard::last_reply(session.chat_file(), what)?)?;
+    match what {
+        CopyWhat::Reply => eprintln!("Copied the reply"),
+        CopyWhat::Code => eprintln!("Copied the reply's code block"),
+    }
+    Ok(())
+}
+
+/// Send the chat and announce the reply, or just print the request for a dry run
+///
+/// Returns the reply unless it was interrupted, or the chat was compared or not sent.
+async fn send(session: &Session, cli: &Cli) -> Result<Option<Reply>> {
+    if cli.dry_run {
+        print_request(session)?;
+        return Ok(None);
+    }
+    if !cli.models.is_empty() {
+        compare(session, cli).await?;
+        return Ok(None);
+    }
+    let reply = match session.send_or_cancel(ctrl_c()).await {
+        Err(e) if e.is::<Interrupted>() => {
+            eprintln!("\nInterrupted, the partial reply is kept in the chat");
+            return Ok(None);
+        }
+        Err(e) if e.is::<Declined>() => {
+            eprintln!("Not sent");
+            return Ok(None);
+        }
+        reply => reply?,
+    };
+    let notify = !cli.quiet && session.config().notifications != Some(false);
+    announce_reply(&reply, cli.output == Output::Text, notify);
+    if reply.finish_reason.as_deref() == Some("length") {
+        eprintln!(
+            "The reply was cut off at the token limit, `chat-cli-rs continue {}` asks for the rest",
+            session.chat_file().display()
+        );
+    }
+    if let Some(what) = cli.copy {
+        copy_reply(session, what)?;
+    }
+    if cli.apply {
+        let blocks = extract::last_reply(session.chat_file())?;
+        extract::apply(&blocks, &std::env::current_dir()?, false, false)?;
+    }
+    Ok(Some(reply))
+}
+
+/// Print the reply as JSON for `--output json` and `json-stream`
+fn print_json(reply: Option<Reply>, session: &Session, cli: &Cli) -> Result<()> {
+    let Some(reply) = reply.filter(|_| matches!(cli.output, Output::Json | Output::JsonStream))
+    else {
+        return Ok(());
+    };
+    let usage = reply.usage.map(|usage| {
+        json!({
+            "prompt_tokens": usage.prompt_tokens,
+            "completion_tokens": usage.completion_tokens,
+            "total_tokens": usage.total_tokens,
+        })
+    });
+    let output = json!({
+        "chat_file": session.saved_file(),
+        "model": reply.model,
+        "finish_reason": reply.finish_reason,
+        "usage": usage,
+        "message": reply.message,
+    });
+    println!("{}", output);
+    Ok(())
+}
+
+/// Send the chat to each of `--models` and announce their replies
+async fn compare(session: &Session, cli: &Cli) -> Result<()> {
+    let replies = tokio::select! {
+        replies = session.compare(&cli.models) => replies?,
+        _ = ctrl_c() => {
+            println!("\nInterrupted, no replies were added to the chat");
+            return Ok(());
+        }
+    };
+
+    for reply in &replies {
+        let model = reply.model.as_deref().unwrap_or_default();
+        println!("Assistant ({}): {}\n", model, reply.content.trim());
+    }
+    if let Some(waited) = ratelimit::report() {
+        println!("{}", waited);
+    }
+    if !cli.quiet && session.config().notifications != Some(false) {
+        let models: Vec<&str> = replies.iter().filter_map(|r| r.model.as_deref()).collect();
+        notify::reply_received(&format!("Replies from {}", models.join(", ")));
+    }
+    Ok(())
+}
+
+/// Print the JSON payload `send` would post and an estimate of its prompt tokens
+fn print_request(session: &Session) -> Result<()> {
+    let request = session.request()?;
+    let mut payload = serde_json::to_value(&request)?;
+    logging::elide_data_urls(&mut payload);
+    println!("{}", serde_json::to_string_pretty(&payload)?);
+    println!("Estimated prompt tokens: {}", context::estimate(&request));
+    Ok(())
+}
+
+/// Title the chat unless disabled, renaming its file after the title
+///
+/// Failing to title a chat isn't fatal, the error is reported and the chat kept as is.
+async fn title_chat(session: &mut Session, cli: &Cli) {
+    if cli.no_title || cli.dry_run || session.config().auto_title == Some(false) {
+        return;
+    }
+    match session.title().await {
+        // The new path is in the JSON output instead, and --quiet prints only the reply
+        Ok(true) if cli.output != Output::Text => {}
+        Ok(true) => println!("Renamed chat to {}", session.chat_file().display()),
+        Ok(false) => {}
+        Err(e) => eprintln!("Unable to title the chat: {:?}", e),
+    }
+}
+
+/// Open the chat in the configured editor, or the desktop's default for markdown
+fn edit_chat_in_editor(session: &Session) {
+    if let Err(e) = platform::open(session.chat_file(), session.config().editor.as_deref()) {
+        eprintln!("{:#}, set `editor` in the config to choose one", e);
+    }
+}
+
+/// What to do once the user is done editing the chat
+enum Action {
+    Send,
+    Retry,
+    Copy,
+    List,
+    /// Edit a message, counting from 1, and carry on from it
+    Edit(usize),
+    /// Delete a message, counting from 1
+    Delete(usize),
+}
+
+async fn run(session: Session, cli: &Cli) -> Result<()> {
+    let mut session = session
+        .with_show_context(cli.show_context)
+        .with_confirm(cli.confirm)
+        .with_reflect(cli.reflect)
+        .with_map_reduce(map_reduce_documents(cli));
+    edit_chat_in_editor(&session);
+    hooks::fire(Event::SessionStart, &session);
+
+    let mut watcher = match cli.watch {
+        true => Some(ChatWatcher::new(session.chat_file())?),
+        false => None,
+    };
+
+    loop {
+        let wait = async {
+            match watcher.as_mut() {
+                // Send as soon as the editor saves a new user message
+                Some(watcher) => {
+                    println!(
+                        "\n\nWatching the log at:\n\t{}\nsave a new message to send it",
+                        session.chat_file().display()
+                    );
+                    watcher.wait_for_user_message(session.chat_file()).await?;
+                    anyhow::Ok(Action::Send)
+                }
+                None => {
+                    // Prompt the user to continue
+                    println!(
+                        "\n\nUpdate the log at:\n\t{}\nand Press Enter to Continue, r and Enter to retry the last reply or c and Enter to copy it\n(l lists the messages, e N edits message N and carries on from it, d N deletes it)",
+                        session.chat_file().to_str().unwrap_or_else(|| {
+                            eprintln!("Unable to convert PathBuf to String");
+                            ""
+                        })
+                    );
+                    stdout().flush().context("Unable to flush stdout")?;
+                    let input = tokio::task::spawn_blocking(get_line_input).await??;
+                    let mut words = input.split_whitespace();
+                    let command = words.next().unwrap_or_default();
+                    let number = words.next().and_then(|n| n.parse().ok());
+                    Ok(match (command, number) {
+                        ("r", None) => Action::Retry,
+                        ("c", None) => Action::Copy,
+                        ("l", None) => Action::List,
+                        ("e", Some(at)) => Action::Edit(at),
+                        ("d", Some(at)) => Action::Delete(at),
+                        _ => Action::Send,
+                    })
+                }
+            }
+        };
+
+        // Ctrl-C is caught while sending to interrupt the reply, so quit on it here
+        let action = tokio::select! {
+            action = wait => action?,
+            _ = ctrl_c() => {
+                hooks::fire(Event::SessionEnd, &session);
+                // Dropping the session encrypts an encrypted chat again
+                drop(session);
+                std::process::exit(130)
+            }
+        };
+        match action {
+            Action::Send => {}
+            Action::Retry => {
+                if let Err(e) = session.remove_last_reply() {
+                    println!("Error: {:?}", e);
+                    continue;
+                }
+            }
+            Action::Copy => {
+                if let Err(e) = copy_reply(&session, cli.copy.unwrap_or(CopyWhat::Reply)) {
+                    println!("Error: {:?}", e);
+                }
+                continue;
+            }
+            Action::List => {
+                if let Err(e) = print_messages(session.chat_file()) {
+                    println!("Error: {:?}", e);
+                }
+                continue;
+            }
+            Action::Edit(at) => match edit_message(&session, at) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    println!("Error: {:?}", e);
+                    continue;
+                }
+            },
+            Action::Delete(at) => {
+                match session.delete_message(at) {
+                    Ok(()) => println!("Deleted message {}", at),
+                    Err(e) => println!("Error: {:?}", e),
+                }
+                continue;
+            }
+        }
+
+        match send(&session, cli).await {
+            Ok(_) => title_chat(&mut session, cli).await,
+            Err(e) => println!("Error: {:?}", e),
+        }
+    }
+}
+
+/// Edit a message of the chat in `$VISUAL` or `$EDITOR`, dropping the messages after it
+///
+/// Returns whether the edited message is the user's, so it's sent again.
+fn edit_message(session: &Session, at: usize) -> Result<bool> {
+    let message = session.message(at)?;
+    let edited = confirm::edit(&message.content)?;
+    session.edit_message(at, &edited)
+}
+
+/// List the messages of a chat with their numbers, for choosing where to branch or what to edit
+fn print_messages(file: &Path) -> Result<()> {
+    let (_, messages) = Message::read_chat(file)?;
+    for (i, message) in messages.iter().enumerate() {
+        let first_line = message.content.lines().find(|l| !l.trim().is_empty());
+        let preview: String = first_line.unwrap_or_default().chars().take(60).collect();
+        println!("{:>4}  {:<24} {}", i + 1, message.label(), preview);
+    }
+    Ok(())
+}
+
+/// Print the finished reply and send a desktop notification
+///
+/// The reply has already been streamed into the chat file.
+fn announce_reply(reply: &Reply, print: bool, notify: bool) {
+    let message_string = reply.message.content.clone().unwrap_or_default();
+
+    // Print the response
+    if print {
+        println!(
+            "{}{}",
+            theme::label(&reply.message.role),
+            message_string.trim()
+        );
+    }
+
+    // Send Desktop Notification
+    if notify {
+        notify::reply_received(&message_string);
+    }
+}
+
+/// Completes when Ctrl-C is pressed
+async fn ctrl_c() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Get user's input
+fn get_line_input() -> Result<String> {
+    let mut user_message_content = String::new();
+    stdin().read_line(&mut user_message_content)?;
+    Ok(user_message_content)
+}
@@ -0,0 +1,204 @@
+use crate::{
+    config::Settings,
+    frontmatter::Frontmatter,
+    message::Message,
+    provider::{self, ChatRequest, RequestMessage},
+    spinner,
+    storage::ChatStore,
+};
+use anyhow::{bail, Result};
+use openai::chat::ChatCompletionMessageRole;
+use std::path::{Path, PathBuf};
+
+/// Exchanges kept as they are when compacting, unless overridden
+pub const DEFAULT_KEEP: usize = 2;
+
+/// Starts the system message holding the summary, so later compactions fold it in
+const SUMMARY_HEADER: &str = "Summary of the earlier conversation:";
+
+const SUMMARY_PROMPT: &str = "Summarize the following conversation so it can be continued without it. Keep the facts, decisions, code and open questions the rest of the conversation may rely on, and leave out pleasantries. Reply with only the summary.";
+
+/// The outcome of compacting a chat
+pub struct Compacted {
+    /// Number of messages replaced by the summary
+    pub summarized: usize,
+    /// Where the original chat file was copied to
+    pub archive: PathBuf,
+}
+
+/// Replace all but the last `keep` exchanges of a chat with a summary
+///
+/// The system prompt is kept, the summary is added after it as a system
+/// message. The original chat is archived before the file is rewritten.
+pub async fn compact(chat_file: &Path, settings: &Settings, keep: usize) -> Result<Compacted> {
+    let (frontmatter, messages) = Message::read_chat(chat_file)?;
+    let (prompt, older, recent) = split(messages, keep);
+    if older.is_empty() {
+        bail!(
+            "Nothing to compact, the chat has no more than {} exchanges",
+            keep
+        );
+    }
+
+    let summary = summarize(&older, &settings.model).await?;
+    let archive = ChatStore::open()?.archive(chat_file)?;
+    write(chat_file, &frontmatter, &prompt, &summary, &recent)?;
+
+    Ok(Compacted {
+        summarized: older.len(),
+        archive,
+    })
+}
+
+/// Split a chat into its system prompt, the messages to summarize and those to keep
+///
+/// The kept messages start at the user message `keep` exchanges from the end.
+/// An earlier summary is summarized again rather than kept alongside the new one.
+fn split(mut messages: Vec<Message>, keep: usize) -> (Vec<Message>, Vec<Message>, Vec<Message>) {
+    let prompt_len = messages
+        .iter()
+        .take_while(|m| {
+            matches!(m.role, ChatCompletionMessageRole::System)
+                && !m.content.starts_with(SUMMARY_HEADER)
+        })
+        .count();
+
+    // The trailing empty user message waiting to be written doesn't count as an exchange
+    let waiting = messages.last().is_some_and(|m| {
+        matches!(m.role, ChatCompletionMessageRole::User) && m.content.trim().is_empty()
+    }) as usize;
+    let exchanges = keep + waiting;
+    let recent_start = match exchanges {
+        0 => messages.len(),
+        n => messages
+            .iter()
+            .enumerate()
+            .skip(prompt_len)
+            .filter(|(_, m)| matches!(m.role, ChatCompletionMessageRole::User))
+            .map(|(i, _)| i)
+            .nth_back(n - 1)
+            .unwrap_or(prompt_len),
+    };
+
+    let recent = messages.split_off(recent_start);
+    let older = messages.split_off(prompt_len);
+    (messages, older, recent)
+}
+
+/// Ask the chat's model to summarize the messages
+async fn summarize(messages: &[Message], model: &str) -> Result<String> {
+    let mut transcript = String::new();
+    for message in messages {
+        let speaker = match (&message.function_call, message.role) {
+            (Some(call), _) => {
+                transcript.push_str(&format!(
+                    "Assistant called {}: {}\n\n",
+                    call.name, call.arguments
+                ));
+                continue;
+            }
+            (None, ChatCompletionMessageRole::System) => "Earlier summary",
+            (None, ChatCompletionMessageRole::User) => "User",
+            (None, ChatCompletionMessageRole::Assistant) => "Assistant",
+            (None, ChatCompletionMessageRole::Function) => "Function result",
+        };
+        let content = message
+            .content
+            .strip_prefix(SUMMARY_HEADER)
+            .unwrap_or(&message.content)
+            .trim();
+        transcript.push_str(&format!("{}: {}\n\n", speaker, content));
+    }
+
+    let request = ChatRequest::new(
+        model,
+        vec![
+            RequestMessage::text(ChatCompletionMessageRole::System, SUMMARY_PROMPT),
+            RequestMessage::text(ChatCompletionMessageRole::User, &transcript),
+        ],
+    );
+    let completion = spinner::wait(&[model], provider::create(request)).await?;
+
+    let summary = completion
+        .choices
+        .first()
+        .and_then(|c| c.message.content.as_deref())
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    if summary.is_empty() {
+        bail!("The model returned an empty summary");
+    }
+    Ok(summary)
+}
+
+/// Rewrite the chat file with the summary in place of the older messages
+fn write(
+    chat_file: &Path,
+    frontmatter: &Frontmatter,
+    prompt: &[Message],
+    summary: &str,
+    recent: &[Message],
+) -> Result<()> {
+    let summary = Message {
+        role: ChatCompletionMessageRole::System,
+        content: format!("{}\n\n{}", SUMMARY_HEADER, summary),
+        name: None,
+        function_call: None,
+        model: None,
+        meta: None,
+    };
+    let messages: Vec<Message> = prompt
+        .iter()
+        .chain([&summary])
+        .chain(recent)
+        .cloned()
+        .collect();
+    Message::write_chat(chat_file, frontmatter, &messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roles(messages: &[Message]) -> String {
+        messages
+            .iter()
+            .map(|m| match m.role {
+                ChatCompletionMessageRole::System => 'S',
+                ChatCompletionMessageRole::User => 'U',
+                ChatCompletionMessageRole::Assistant => 'A',
+                ChatCompletionMessageRole::Function => 'F',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_prompt_and_the_last_exchanges() {
+        let chat = "# System\nBe terse\n# User\n1\n# Assistant\n1\n# User\n2\n# Assistant\n2\n# User\n3\n# Assistant\n3\n# User\n\n";
+        let (prompt, older, recent) = split(Message::parse_messages(chat), 2);
+        assert_eq!(roles(&prompt), "S");
+        assert_eq!(roles(&older), "UA");
+        assert_eq!(roles(&recent), "UAUAU");
+    }
+
+    #[test]
+    fn folds_an_earlier_summary_into_the_new_one() {
+        let chat = format!(
+            "# System\nBe terse\n# System\n{}\nOld\n# User\n1\n# Assistant\n1\n# User\n2\n",
+            SUMMARY_HEADER
+        );
+        let (prompt, older, recent) = split(Message::parse_messages(&chat), 1);
+        assert_eq!(roles(&prompt), "S");
+        assert_eq!(roles(&older), "SUA");
+        assert_eq!(roles(&recent), "U");
+    }
+
+    #[test]
+    fn nothing_to_summarize_in_a_short_chat() {
+        let chat = "# System\nBe terse\n# User\n1\n# Assistant\n1\n# User\n\n";
+        let (_, older, recent) = split(Message::parse_messages(chat), 2);
+        assert!(older.is_empty());
+        assert_eq!(roles(&recent), "UAU");
+    }
+}
@@ -0,0 +1,128 @@
+//! Shell completions, `chat-cli-rs completions <shell>`
+//!
+//! The script it prints has the shell call back into the binary with
+//! `COMPLETE=<shell>` as you type, so chats, prompts and models are completed
+//! from what's there at the time rather than when the script was written.
+
+use crate::{cli::Cli, config::Config, prompts, spend, storage::ChatStore};
+use anyhow::{Context, Result};
+use clap::CommandFactory;
+use clap_complete::{
+    engine::ValueCompleter, env::Shells, ArgValueCandidates, ArgValueCompleter, CompleteEnv,
+    CompletionCandidate, PathCompleter, Shell,
+};
+use std::{ffi::OsStr, io::stdout};
+
+/// Chats in the store offered before anything's typed, the most recently changed
+const RECENT_CHATS: usize = 20;
+
+/// Answer the shell if it's asking for completions, exiting once done
+pub fn answer() {
+    CompleteEnv::with_factory(Cli::command).complete();
+}
+
+/// Print the script that sets up completions in `shell`
+pub fn print_script(shell: Shell) -> Result<()> {
+    let shells = Shells::builtins();
+    let completer = shells
+        .completer(&shell.to_string())
+        .with_context(|| format!("Completions aren't available for {}", shell))?;
+    let binary = std::env::current_exe().context("Unable to find the running binary")?;
+    let name = Cli::command().get_name().to_string();
+    completer.write_registration(
+        "COMPLETE",
+        &name,
+        &name,
+        &binary.to_string_lossy(),
+        &mut stdout(),
+    )?;
+    Ok(())
+}
+
+/// Completes a chat file from the paths on disk and the chats in the store
+pub fn chats() -> ArgValueCompleter {
+    ArgValueCompleter::new(|current: &OsStr| {
+        let mut candidates = PathCompleter::file().complete(current);
+        let typed = current.to_string_lossy();
+        let store = match Config::load().ok().and_then(|c| c.data_dir) {
+            Some(dir) => ChatStore::at(dir),
+            None => match ChatStore::open() {
+                Ok(store) => store,
+                Err(_) => return candidates,
+            },
+        };
+        let chats = [store.list(), store.encrypted(), store.transcripts()]
+            .into_iter()
+            .flat_map(Result::unwrap_or_default)
+            .map(|path| path.display().to_string())
+            .filter(|path| path.starts_with(typed.as_ref()));
+        let limit = match typed.is_empty() {
+            true => RECENT_CHATS,
+            false => usize::MAX,
+        };
+        let chats: Vec<String> = chats
+            .filter(|path| !candidates.iter().any(|c| c.get_value() == path.as_str()))
+            .take(limit)
+            .collect();
+        candidates.extend(chats.into_iter().map(CompletionCandidate::new));
+        candidates
+    })
+}
+
+/// Completes a system prompt's name
+pub fn prompts() -> ArgValueCandidates {
+    ArgValueCandidates::new(|| {
+        prompts::list()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|entry| CompletionCandidate::new(entry.name))
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Completes a model's name, from the config and the models whose prices are known
+pub fn models() -> ArgValueCandidates {
+    ArgValueCandidates::new(|| {
+        let config = Config::load().unwrap_or_default();
+        let mut models: Vec<String> = [&config.model, &config.title_model]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .chain(config.profiles.values().filter_map(|p| p.model.clone()))
+            .chain(spend::known_models().map(str::to_string))
+            .collect();
+        models.sort();
+        models.dedup();
+        models
+            .into_iter()
+            .map(CompletionCandidate::new)
+            .collect::<Vec<_>>()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+
+    /// What the shell would be offered for the last of `args`
+    fn complete(args: &[&str]) -> Vec<String> {
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        let index = args.len() - 1;
+        clap_complete::engine::complete(&mut Cli::command(), args, index, None)
+            .unwrap()
+            .iter()
+            .map(|c| c.get_value().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn completes_models_and_prompts() {
+        let models = complete(&["chat-cli-rs", "--model", "gpt-4o"]);
+        assert!(models.iter().any(|m| m == "gpt-4o"));
+        assert!(models.iter().all(|m| m.starts_with("gpt-4o")));
+
+        let prompts = complete(&["chat-cli-rs", "--system", "code"]);
+        assert!(prompts.iter().any(|p| p == prompts::CODE_REVIEW_PROMPT));
+    }
+}
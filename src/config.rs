@@ -0,0 +1,577 @@
+use crate::{
+    context::ContextConfig,
+    encryption::EncryptionConfig,
+    fallback::Fallback,
+    frontmatter::Frontmatter,
+    hooks::HooksConfig,
+    images::ImageConfig,
+    mcp::{self, McpServerConfig},
+    mock::MockConfig,
+    obsidian::ObsidianConfig,
+    platform, plugins,
+    postprocess::PostProcess,
+    project::Project,
+    provider::{ChatRequest, HttpConfig, RequestMessage, ResponseFormat},
+    rag::RagConfig,
+    ratelimit::RateLimit,
+    redact::RedactionConfig,
+    retention::RetentionConfig,
+    routing::RoutingConfig,
+    spend::SpendConfig,
+    theme::Theme,
+    tools::ToolConfig,
+    voice::VoiceConfig,
+    wasm::WasmToolConfig,
+};
+use anyhow::{bail, ensure, Context, Result};
+use clap::{Args, ValueEnum};
+use openai::chat::ChatCompletionFunctionDefinition;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+
+/// Model used when neither the CLI, the chat file nor the config choose one
+pub const DEFAULT_MODEL: &str = "gpt-4";
+//                             "gpt-3.5-turbo";
+//                             "gpt-3.5-turbo-16k"
+
+/// User configuration read from `$XDG_CONFIG_HOME/chat-cli-rs/config.toml`
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Model used for new chats
+    pub model: Option<String>,
+    /// Name or path of the system prompt used for new chats
+    pub system: Option<String>,
+    pub sampling: Sampling,
+    /// Title chats after their first reply, defaults to true
+    pub auto_title: Option<bool>,
+    /// Model used to title chats, a cheap one is plenty
+    pub title_model: Option<String>,
+    /// Most tokens of text piped to `-p` that are sent, its end is kept, 8000 by default
+    pub stdin_max_tokens: Option<usize>,
+    /// Colours of the replies in the terminal, `default`, `light`, `mono` or `none`
+    pub theme: Theme,
+    /// Steps each reply goes through before it's kept, see `postprocess`
+    pub post_process: Vec<PostProcess>,
+    /// Send a desktop notification when a reply arrives, defaults to true
+    pub notifications: Option<bool>,
+    /// Ask for the rest of a reply cut off at the token limit, defaults to false
+    pub auto_continue: Option<bool>,
+    /// Command the chat is opened with, e.g. `neovide`, the desktop's default otherwise
+    pub editor: Option<String>,
+    /// Tools the model is allowed to call
+    pub tools: Vec<ToolConfig>,
+    /// MCP servers whose tools the model is allowed to call, the `[mcp_servers.<name>]` tables
+    pub mcp_servers: BTreeMap<String, McpServerConfig>,
+    /// Tools run from WebAssembly modules in a sandbox, the `[wasm_tools.<name>]` tables
+    pub wasm_tools: BTreeMap<String, WasmToolConfig>,
+    /// Offer the tools of the executables in the plugins directory, defaults to true
+    pub plugins: Option<bool>,
+    /// Keeping long chats within the model's context window
+    pub context: ContextConfig,
+    /// Retrieving context from indexed documents
+    pub rag: RagConfig,
+    /// Proxy and TLS settings for reaching the API
+    pub http: HttpConfig,
+    /// Cleaning up old chats
+    pub retention: RetentionConfig,
+    /// Masking secrets in messages before they're sent
+    pub redaction: RedactionConfig,
+    /// Keeping chats encrypted with `--encrypt`
+    pub encryption: EncryptionConfig,
+    /// Generating images with `imagine`
+    pub images: ImageConfig,
+    /// Transcribing spoken messages
+    pub voice: VoiceConfig,
+    /// Note when each reply was sent for, how long it took and its tokens, defaults to true
+    pub reply_metadata: Option<bool>,
+    /// Answer requests from `-f` and `batch` identical to earlier ones from a cache, defaults to true
+    pub cache: Option<bool>,
+    /// Keep a SQLite catalog of the chats to list and search them quickly, defaults to false
+    pub catalog: Option<bool>,
+    /// Append every request and response to a JSONL log, defaults to false
+    pub log_requests: Option<bool>,
+    /// Provider whose API key is used, from its environment variable or the keyring,
+    /// `mock` answers without the network
+    pub provider: Option<String>,
+    /// Base URL of an OpenAI compatible API
+    pub base_url: Option<String>,
+    /// Header the API key is sent in instead of `Authorization: Bearer`, e.g. `api-key`
+    pub key_header: Option<String>,
+    /// Language replies are asked for in, e.g. `de`, checked and asked for again once if it's wrong
+    pub reply_language: Option<String>,
+    /// OpenAI organization ID requests are billed to, `OPENAI_ORG_ID` otherwise
+    pub organization: Option<String>,
+    /// OpenAI project ID requests are billed to, `OPENAI_PROJECT_ID` otherwise
+    pub project_id: Option<String>,
+    /// Where chats are kept instead of the XDG data directory
+    pub data_dir: Option<PathBuf>,
+    /// Flush chat files to disk after each write, slower but safe from power cuts, defaults to false
+    pub fsync: Option<bool>,
+    /// Where `journal` keeps a chat for each day, `journal/` in the data directory otherwise
+    pub journal_dir: Option<PathBuf>,
+    /// Profile used when `--profile` isn't given
+    pub profile: Option<String>,
+    /// Named sets of settings chosen with `--profile`, the `[profiles.<name>]` tables
+    pub profiles: BTreeMap<String, Profile>,
+    /// How fast requests may be sent to each provider, the `[rate_limits.<provider>]` tables
+    pub rate_limits: BTreeMap<String, RateLimit>,
+    /// How much may be spent a day and a month
+    pub spend: SpendConfig,
+    /// Commands run on the session's events, see `hooks`
+    pub hooks: HooksConfig,
+    /// Which messages are sent to a cheaper model, see `routing`
+    pub routing: RoutingConfig,
+    /// APIs tried in turn while the configured one is down, see `fallback`
+    pub fallbacks: Vec<Fallback>,
+    /// Replies of the `mock` provider
+    pub mock: MockConfig,
+    /// The Obsidian vault chats are kept in, see `obsidian`
+    pub obsidian: ObsidianConfig,
+    /// Notes about the project being worked in, from its files rather than the config
+    #[serde(skip)]
+    pub project_context: Option<String>,
+}
+
+/// Settings that replace the top level ones when the profile is chosen
+///
+/// Keeps e.g. work and personal use apart, down to their keys and chats.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub provider: Option<String>,
+    pub base_url: Option<String>,
+    pub key_header: Option<String>,
+    pub organization: Option<String>,
+    pub project_id: Option<String>,
+    pub model: Option<String>,
+    pub system: Option<String>,
+    pub title_model: Option<String>,
+    pub reply_language: Option<String>,
+    pub data_dir: Option<PathBuf>,
+    /// Replaces the top level post-processing steps, an empty list turns them off
+    pub post_process: Option<Vec<PostProcess>>,
+    /// Replaces the top level MCP servers, an empty table turns them off
+    pub mcp_servers: Option<BTreeMap<String, McpServerConfig>>,
+    /// Replaces the top level WASM tools, an empty table turns them off
+    pub wasm_tools: Option<BTreeMap<String, WasmToolConfig>>,
+}
+
+impl Config {
+    /// Load the config file, falling back to the defaults if it doesn't exist
+    pub fn load() -> Result<Self> {
+        match config_file_path() {
+            Some(path) => Self::from_file(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// The config with a profile's settings in place of the top level ones
+    pub fn with_profile(mut self, name: &str) -> Result<Self> {
+        let Some(profile) = self.profiles.get(name).cloned() else {
+            let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            match names.is_empty() {
+                true => bail!("There's no profile {:?}, none are configured", name),
+                false => bail!(
+                    "There's no profile {:?}, the profiles are: {}",
+                    name,
+                    names.join(", ")
+                ),
+            }
+        };
+        self.provider = profile.provider.or(self.provider);
+        self.base_url = profile.base_url.or(self.base_url);
+        self.key_header = profile.key_header.or(self.key_header);
+        self.organization = profile.organization.or(self.organization);
+        self.project_id = profile.project_id.or(self.project_id);
+        self.model = profile.model.or(self.model);
+        self.system = profile.system.or(self.system);
+        self.title_model = profile.title_model.or(self.title_model);
+        self.reply_language = profile.reply_language.or(self.reply_language);
+        self.data_dir = profile.data_dir.or(self.data_dir);
+        if let Some(steps) = profile.post_process {
+            self.post_process = steps;
+        }
+        if let Some(servers) = profile.mcp_servers {
+            self.mcp_servers = servers;
+        }
+        if let Some(tools) = profile.wasm_tools {
+            self.wasm_tools = tools;
+        }
+        Ok(self)
+    }
+
+    /// The config with the project's settings in place of the top level ones, and its context
+    pub fn with_project(mut self, project: &Project) -> Self {
+        self.model = project.config.model.clone().or(self.model);
+        self.sampling = project.config.sampling.clone().or(self.sampling);
+        self.project_context = project.context();
+        self
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read config file: {:?}", path))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Unable to parse config file: {:?}", path))
+    }
+}
+
+/// Locate an existing config file in the config directory
+fn config_file_path() -> Option<PathBuf> {
+    let path = platform::config_dir().ok()?.join("config.toml");
+    path.is_file().then_some(path)
+}
+
+/// Settings chosen for this run, e.g. on the command line, which take precedence over the chat's
+#[derive(Debug, Default, Clone)]
+pub struct Overrides {
+    pub model: Option<String>,
+    pub sampling: Sampling,
+    pub response_format: Option<ResponseFormat>,
+    /// Name of the document index to retrieve context from
+    pub rag: Option<String>,
+    /// Text the reply starts with, to steer its format
+    pub prefill: Option<String>,
+    /// Ask for the reply as a unified diff
+    pub patch: bool,
+    /// Exchanges sent before the last message, all of them if unset
+    pub history: Option<usize>,
+    pub reply_language: Option<String>,
+    /// Steps each reply goes through in place of the config's, if any are given
+    pub post_process: Vec<PostProcess>,
+}
+
+/// The model, sampling parameters and tools used for a request
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub model: String,
+    pub sampling: Sampling,
+    pub functions: Vec<ChatCompletionFunctionDefinition>,
+    /// Only ever chosen for a run, as a chat's later messages needn't be JSON
+    pub response_format: Option<ResponseFormat>,
+    /// Name of the document index to retrieve context from for each message
+    pub rag: Option<String>,
+    /// Text the reply to the user's message starts with
+    pub prefill: Option<String>,
+    /// Ask for the reply as a unified diff, sending it back to be corrected if it isn't one
+    pub patch: bool,
+    /// Exchanges sent before the last message, with the system prompt, all of them if unset
+    pub history: Option<usize>,
+    /// Language replies are asked for in, a code like `de` or a name
+    pub reply_language: Option<String>,
+    /// Steps each reply goes through before it's kept
+    pub post_process: Vec<PostProcess>,
+    /// Whether the model is the config's rather than the chat's or `--model`, which `[routing]` may change
+    pub routable: bool,
+}
+
+impl Settings {
+    /// Layer the overrides over a chat's frontmatter over the config file
+    pub fn resolve(
+        overrides: &Overrides,
+        frontmatter: &Frontmatter,
+        config: &Config,
+    ) -> Result<Self> {
+        let chosen = overrides
+            .model
+            .clone()
+            .or_else(|| frontmatter.model.clone());
+        let routable = chosen.is_none();
+        let model = chosen
+            .or_else(|| config.model.clone())
+            .unwrap_or_else(|| DEFAULT_MODEL.to_string());
+
+        let sampling = overrides
+            .sampling
+            .clone()
+            .or(frontmatter.sampling.clone())
+            .or(config.sampling.clone());
+        sampling.validate()?;
+
+        let functions = config
+            .tools
+            .iter()
+            .map(ToolConfig::definition)
+            .chain(
+                config
+                    .wasm_tools
+                    .iter()
+                    .map(|(name, tool)| tool.definition(name)),
+            )
+            .chain(mcp::definitions())
+            .chain(match config.plugins {
+                Some(false) => Vec::new(),
+                _ => plugins::definitions(),
+            })
+            .collect();
+
+        Ok(Self {
+            model,
+            sampling,
+            functions,
+            response_format: overrides.response_format.clone(),
+            prefill: overrides.prefill.clone(),
+            patch: overrides.patch,
+            rag: overrides.rag.clone().or_else(|| frontmatter.rag.clone()),
+            history: overrides.history.or(frontmatter.history),
+            reply_language: overrides
+                .reply_language
+                .clone()
+                .or_else(|| config.reply_language.clone()),
+            post_process: match overrides.post_process.is_empty() {
+                true => config.post_process.clone(),
+                false => overrides.post_process.clone(),
+            },
+            routable,
+        })
+    }
+
+    /// Create a chat completion request for these settings
+    pub fn request(&self, messages: Vec<RequestMessage>) -> ChatRequest {
+        ChatRequest {
+            sampling: self.sampling.clone(),
+            functions: self.functions.clone(),
+            response_format: self.response_format.clone(),
+            ..ChatRequest::new(&self.model, messages)
+        }
+    }
+}
+
+/// Sampling parameters sent with every chat completion request
+///
+/// Anything left unset is omitted from the request so the API default applies.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Args)]
+#[serde(default)]
+pub struct Sampling {
+    /// Sampling temperature between 0 and 2, lower is more deterministic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long)]
+    pub temperature: Option<f32>,
+
+    /// Nucleus sampling probability mass between 0 and 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long)]
+    pub top_p: Option<f32>,
+
+    /// Maximum number of tokens to generate in the reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long)]
+    pub max_tokens: Option<u64>,
+
+    /// Penalise tokens by how often they already appear, between -2 and 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, allow_hyphen_values = true)]
+    pub frequency_penalty: Option<f32>,
+
+    /// Penalise tokens that already appear at all, between -2 and 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, allow_hyphen_values = true)]
+    pub presence_penalty: Option<f32>,
+
+    /// Stop generating at this sequence, which is left out of the reply (repeatable, up to 4)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[arg(long, value_name = "SEQUENCE", allow_hyphen_values = true)]
+    pub stop: Vec<String>,
+
+    /// Bias added to the likelihood of a token, by token ID, between -100 and 100
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[arg(skip)]
+    pub logit_bias: BTreeMap<String, i32>,
+
+    /// How long a reasoning model (o1, o3, ...) thinks before replying, other models ignore it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, value_enum)]
+    pub reasoning_effort: Option<ReasoningEffort>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+impl Sampling {
+    /// Fill any unset parameters from `fallback` (e.g. CLI flags over the config file)
+    pub fn or(self, fallback: Sampling) -> Self {
+        Self {
+            temperature: self.temperature.or(fallback.temperature),
+            top_p: self.top_p.or(fallback.top_p),
+            max_tokens: self.max_tokens.or(fallback.max_tokens),
+            frequency_penalty: self.frequency_penalty.or(fallback.frequency_penalty),
+            presence_penalty: self.presence_penalty.or(fallback.presence_penalty),
+            stop: match self.stop.is_empty() {
+                true => fallback.stop,
+                false => self.stop,
+            },
+            logit_bias: match self.logit_bias.is_empty() {
+                true => fallback.logit_bias,
+                false => self.logit_bias,
+            },
+            reasoning_effort: self.reasoning_effort.or(fallback.reasoning_effort),
+        }
+    }
+
+    /// Check the parameters are within the ranges accepted by the API
+    pub fn validate(&self) -> Result<()> {
+        if let Some(t) = self.temperature {
+            ensure!(
+                (0.0..=2.0).contains(&t),
+                "temperature must be between 0 and 2, got {t}"
+            );
+        }
+        if let Some(p) = self.top_p {
+            ensure!(
+                (0.0..=1.0).contains(&p),
+                "top_p must be between 0 and 1, got {p}"
+            );
+        }
+        if let Some(p) = self.frequency_penalty {
+            ensure!(
+                (-2.0..=2.0).contains(&p),
+                "frequency_penalty must be between -2 and 2, got {p}"
+            );
+        }
+        if let Some(p) = self.presence_penalty {
+            ensure!(
+                (-2.0..=2.0).contains(&p),
+                "presence_penalty must be between -2 and 2, got {p}"
+            );
+        }
+        ensure!(
+            self.stop.len() <= 4,
+            "At most 4 stop sequences can be given, got {}",
+            self.stop.len()
+        );
+        for (token, bias) in &self.logit_bias {
+            ensure!(
+                token.parse::<u32>().is_ok(),
+                "logit_bias keys must be token IDs, got {token:?}"
+            );
+            ensure!(
+                (-100..=100).contains(bias),
+                "logit_bias must be between -100 and 100, got {bias} for token {token}"
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_replace_the_top_level_settings() {
+        let config: Config = toml::from_str(
+            r#"
+            model = "gpt-4o"
+            system = "terse"
+
+            [profiles.work]
+            provider = "azure"
+            model = "gpt-4o-work"
+            data_dir = "/work/chats"
+            "#,
+        )
+        .unwrap();
+
+        let work = config.clone().with_profile("work").unwrap();
+        assert_eq!(work.provider.as_deref(), Some("azure"));
+        assert_eq!(work.model.as_deref(), Some("gpt-4o-work"));
+        assert_eq!(work.system.as_deref(), Some("terse"));
+        assert_eq!(work.data_dir, Some(PathBuf::from("/work/chats")));
+
+        let error = config.with_profile("home").unwrap_err().to_string();
+        assert!(error.contains("the profiles are: work"));
+    }
+
+    #[test]
+    fn stop_sequences_and_logit_bias_come_from_the_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [sampling]
+            stop = ["---"]
+
+            [sampling.logit_bias]
+            1734 = -100
+            "#,
+        )
+        .unwrap();
+        let flags = Sampling {
+            stop: vec!["END".to_string()],
+            ..Sampling::default()
+        };
+        let sampling = flags.or(config.sampling);
+        assert_eq!(sampling.stop, ["END"]);
+        assert_eq!(sampling.logit_bias["1734"], -100);
+        sampling.validate().unwrap();
+
+        let request = serde_json::to_value(ChatRequest {
+            sampling,
+            ..ChatRequest::new("gpt-4o", Vec::new())
+        })
+        .unwrap();
+        assert_eq!(request["stop"], serde_json::json!(["END"]));
+        assert_eq!(request["logit_bias"]["1734"], -100);
+
+        let bias = Sampling {
+            logit_bias: BTreeMap::from([("hello".to_string(), 5)]),
+            ..Sampling::default()
+        };
+        assert!(bias.validate().is_err());
+    }
+
+    #[test]
+    fn sampling_flags_override_the_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [sampling]
+            temperature = 0.2
+            top_p = 0.9
+            max_tokens = 500
+            "#,
+        )
+        .unwrap();
+        let flags = Sampling {
+            temperature: Some(1.5),
+            presence_penalty: Some(-0.5),
+            ..Sampling::default()
+        };
+        let sampling = flags.or(config.sampling);
+        assert_eq!(sampling.temperature, Some(1.5));
+        assert_eq!(sampling.top_p, Some(0.9));
+        assert_eq!(sampling.max_tokens, Some(500));
+        assert_eq!(sampling.frequency_penalty, None);
+        sampling.validate().unwrap();
+
+        let request = serde_json::to_value(ChatRequest {
+            sampling,
+            ..ChatRequest::new("gpt-4o", Vec::new())
+        })
+        .unwrap();
+        assert_eq!(request["max_tokens"], 500);
+        assert_eq!(request["presence_penalty"], -0.5);
+        // Unset parameters are left to the API's defaults
+        assert!(request.get("frequency_penalty").is_none());
+
+        for out_of_range in [
+            Sampling {
+                temperature: Some(2.5),
+                ..Sampling::default()
+            },
+            Sampling {
+                top_p: Some(1.1),
+                ..Sampling::default()
+            },
+            Sampling {
+                frequency_penalty: Some(-3.0),
+                ..Sampling::default()
+            },
+        ] {
+            assert!(out_of_range.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{
+    context, platform,
+    provider::{Content, RequestMessage},
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    fmt,
+    io::{stderr, stdin, Write},
+};
+
+/// The request wasn't sent as it was declined at the confirmation
+#[derive(Debug)]
+pub struct Declined;
+
+impl fmt::Display for Declined {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The request was not sent")
+    }
+}
+
+impl std::error::Error for Declined {}
+
+/// What the user chose to do with the request
+#[derive(Debug, PartialEq)]
+enum Choice {
+    Send,
+    Cancel,
+    Edit(usize),
+    Drop(usize),
+}
+
+impl Choice {
+    /// The choice in an answer, with messages numbered from 1
+    fn parse(answer: &str) -> Option<Self> {
+        let mut words = answer.split_whitespace();
+        let choice = words.next().unwrap_or("y").to_lowercase();
+        let number = words.next().and_then(|n| n.parse::<usize>().ok());
+        match (choice.as_str(), number) {
+            ("y" | "yes", None) => Some(Self::Send),
+            ("n" | "no" | "q", None) => Some(Self::Cancel),
+            ("e" | "edit", Some(n)) if n > 0 => Some(Self::Edit(n - 1)),
+            ("d" | "drop", Some(n)) if n > 0 => Some(Self::Drop(n - 1)),
+            _ => None,
+        }
+    }
+}
+
+/// Show what's about to be sent and let the user edit or drop messages first
+///
+/// Changes only affect this request, the chat file is left as it is. Returns
+/// a [`Declined`] error if the user decides not to send it.
+pub fn review(mut messages: Vec<RequestMessage>, model: &str) -> Result<Vec<RequestMessage>> {
+    loop {
+        eprint!("{}", context::preview(&messages, model));
+        eprint!("Send? [Y]es, [n]o, [e]dit N, [d]rop N: ");
+        stderr().flush()?;
+        let mut answer = String::new();
+        if stdin().read_line(&mut answer)? == 0 {
+            bail!(Declined);
+        }
+
+        match Choice::parse(&answer) {
+            Some(Choice::Send) => return Ok(messages),
+            Some(Choice::Cancel) => bail!(Declined),
+            Some(Choice::Drop(i)) if i < messages.len() => {
+                messages.remove(i);
+            }
+            Some(Choice::Edit(i)) if i < messages.len() => match &messages[i].content {
+                Some(Content::Text(text)) => {
+                    let edited = edit(text)?;
+                    messages[i].content = Some(Content::Text(edited));
+                }
+                _ => eprintln!("Only text messages can be edited"),
+            },
+            Some(_) => eprintln!("There are only {} messages", messages.len()),
+            None => eprintln!("Answer y, n, e 2 or d 2"),
+        }
+    }
+}
+
+/// Edit text in `$VISUAL` or `$EDITOR`, waiting for the editor to close
+pub fn edit(text: &str) -> Result<String> {
+    let file = tempfile::Builder::new().suffix(".md").tempfile()?;
+    std::fs::write(file.path(), text)?;
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let status = platform::shell(&format!("{} \"{}\"", editor, file.path().display()))
+        .status()
+        .with_context(|| format!("Unable to run {}", editor))?;
+    if !status.success() {
+        bail!("{} exited with {}", editor, status);
+    }
+    Ok(std::fs::read_to_string(file.path())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_choices() {
+        assert_eq!(Choice::parse("\n"), Some(Choice::Send));
+        assert_eq!(Choice::parse("N"), Some(Choice::Cancel));
+        assert_eq!(Choice::parse("d 2"), Some(Choice::Drop(1)));
+        assert_eq!(Choice::parse("edit 1\n"), Some(Choice::Edit(0)));
+        assert_eq!(Choice::parse("d 0"), None);
+        assert_eq!(Choice::parse("e"), None);
+    }
+}
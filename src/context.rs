@@ -0,0 +1,550 @@
+use crate::{
+    config::Settings,
+    exit::Failure,
+    provider::{ChatRequest, Content, ContentPart, RequestMessage},
+};
+use anyhow::{bail, Result};
+use openai::chat::ChatCompletionMessageRole;
+use serde::Deserialize;
+use tiktoken_rs::CoreBPE;
+
+/// Tokens kept free for the reply when `max_tokens` isn't set
+const DEFAULT_RESERVE: usize = 1024;
+
+/// Every message costs a few tokens on top of its content
+const TOKENS_PER_MESSAGE: usize = 4;
+
+/// Every reply is primed with a few tokens
+const TOKENS_PER_REPLY: usize = 3;
+
+/// Rough cost of an attached image, a 1024x1024 image at high detail
+const TOKENS_PER_IMAGE: usize = 765;
+
+/// How to keep a chat within the model's context window, the `[context]` config table
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContextConfig {
+    /// What to do when the chat doesn't fit
+    pub overflow: Overflow,
+    /// Size of the context window in tokens, needed for models tiktoken doesn't know
+    pub window: Option<usize>,
+    /// Tokens kept free for the reply when `max_tokens` isn't set
+    pub reserve: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Leave out the oldest exchanges, always keeping the system prompt
+    #[default]
+    Truncate,
+    /// Refuse to send the chat
+    Error,
+    /// Summarize the older exchanges in the chat file, truncating if that isn't enough
+    Compact,
+}
+
+/// Assemble the request's messages to fit in the model's context window
+///
+/// Depending on the config the least important parts are left out of the
+/// request, reporting each, or an error is returned explaining what to do.
+/// The chat file itself is never changed, chats that should be compacted
+/// instead are checked with `fits` first.
+pub fn assemble(
+    messages: Vec<RequestMessage>,
+    settings: &Settings,
+    config: &ContextConfig,
+) -> Result<Vec<RequestMessage>> {
+    let Some(budget) = Budget::new(settings, config) else {
+        // We can't tell how much fits, let the API decide
+        return Ok(messages);
+    };
+    let bpe = bpe(&settings.model);
+    let counts: Vec<usize> = messages.iter().map(|m| count(bpe, m)).collect();
+    let total: usize = counts.iter().sum();
+    if total <= budget.tokens {
+        return Ok(messages);
+    }
+
+    let Budget {
+        window,
+        reserve,
+        tokens: budget,
+    } = budget;
+    let too_long = format!(
+        "The chat is about {} tokens but {} only has room for {} ({} token window, {} kept for the reply)",
+        total, settings.model, budget, window, reserve
+    );
+    if config.overflow == Overflow::Error {
+        bail!(Failure::ContextTooLong(format!(
+            "{}.\nStart a new chat, remove earlier messages from this one, \
+             or set `overflow = \"truncate\"` under [context] in the config to leave them out automatically",
+            too_long
+        )));
+    }
+
+    let Some((messages, cuts)) = cut(messages, counts, budget) else {
+        bail!(Failure::ContextTooLong(format!(
+            "{}, even after leaving out every earlier exchange, attachment and pinned context.\n\
+             Shorten the last message or the system prompt, or lower max_tokens",
+            too_long
+        )));
+    };
+    eprintln!(
+        "Left out of the request to fit the context window of {}:",
+        settings.model
+    );
+    for cut in cuts {
+        eprintln!("  {}, {} tokens", cut.what, cut.tokens);
+    }
+    Ok(messages)
+}
+
+/// Something left out of a request to make it fit
+#[derive(Debug, PartialEq)]
+struct Cut {
+    what: String,
+    tokens: usize,
+}
+
+/// Leave out the least important parts of the request until it fits in `budget`
+///
+/// The earlier exchanges go first, oldest first, then the images attached to
+/// the last message, then the pinned context, last pinned first. The system
+/// prompt and the last message are always kept. Returns `None` if it still
+/// doesn't fit.
+fn cut(
+    messages: Vec<RequestMessage>,
+    counts: Vec<usize>,
+    budget: usize,
+) -> Option<(Vec<RequestMessage>, Vec<Cut>)> {
+    let mut cuts = Vec::new();
+    let roles: Vec<ChatCompletionMessageRole> = messages.iter().map(|m| m.role).collect();
+    let keep = truncate(&roles, &counts, budget);
+
+    // Number the history as in the chat file, which the pinned context isn't part of
+    let mut number = 0;
+    let mut dropped: Option<(usize, usize, usize)> = None;
+    let mut kept = Vec::new();
+    for ((message, tokens), keep) in messages.into_iter().zip(counts).zip(keep) {
+        if message.pinned.is_none() {
+            number += 1;
+        }
+        if !keep {
+            dropped = Some(match dropped {
+                Some((first, _, sum)) => (first, number, sum + tokens),
+                None => (number, number, tokens),
+            });
+            continue;
+        }
+        if let Some(range) = dropped.take() {
+            cuts.push(history_cut(range));
+        }
+        kept.push((message, tokens));
+    }
+    if let Some(range) = dropped {
+        cuts.push(history_cut(range));
+    }
+    let (mut messages, mut counts): (Vec<_>, Vec<_>) = kept.into_iter().unzip();
+    let mut total: usize = counts.iter().sum();
+
+    if let Some(Content::Parts(parts)) = messages.last_mut().and_then(|m| m.content.as_mut()) {
+        while total > budget {
+            let Some(i) = parts
+                .iter()
+                .rposition(|part| matches!(part, ContentPart::ImageUrl { .. }))
+            else {
+                break;
+            };
+            parts.remove(i);
+            total -= TOKENS_PER_IMAGE;
+            cuts.push(Cut {
+                what: "an image attached to the last message".to_string(),
+                tokens: TOKENS_PER_IMAGE,
+            });
+        }
+    }
+
+    while total > budget {
+        let i = messages.iter().rposition(|m| m.pinned.is_some())?;
+        let message = messages.remove(i);
+        let tokens = counts.remove(i);
+        total -= tokens;
+        cuts.push(Cut {
+            what: message.pinned.unwrap_or_default(),
+            tokens,
+        });
+    }
+    Some((messages, cuts))
+}
+
+/// The cut of the messages `first` to `last` of the chat, and their tokens
+fn history_cut((first, last, tokens): (usize, usize, usize)) -> Cut {
+    let what = match first == last {
+        true => format!("message {} of the chat", first),
+        false => format!("messages {} to {} of the chat", first, last),
+    };
+    Cut { what, tokens }
+}
+
+/// Whether the messages fit in the model's context window without leaving any out
+pub fn fits(messages: &[RequestMessage], settings: &Settings, config: &ContextConfig) -> bool {
+    Budget::new(settings, config).is_none_or(|budget| {
+        let bpe = bpe(&settings.model);
+        messages.iter().map(|m| count(bpe, m)).sum::<usize>() <= budget.tokens
+    })
+}
+
+/// Estimate the prompt tokens of a request
+pub fn estimate(request: &ChatRequest) -> usize {
+    let bpe = bpe(&request.model);
+    let functions = match request.functions.is_empty() {
+        true => 0,
+        false => bpe.count_ordinary(&serde_json::to_string(&request.functions).unwrap_or_default()),
+    };
+    request
+        .messages
+        .iter()
+        .map(|m| count(bpe, m))
+        .sum::<usize>()
+        + functions
+        + TOKENS_PER_REPLY
+}
+
+/// Count the tokens in some text for the model
+pub fn tokens(model: &str, text: &str) -> usize {
+    bpe(model).count_ordinary(text)
+}
+
+/// Longest part of a message shown by `preview`
+const PREVIEW_CHARS: usize = 60;
+
+/// A numbered line for each message with its role, tokens and the start of its content
+pub fn preview(messages: &[RequestMessage], model: &str) -> String {
+    let bpe = bpe(model);
+    let mut total = 0;
+    let mut preview = String::new();
+    for (i, message) in messages.iter().enumerate() {
+        let tokens = count(bpe, message);
+        total += tokens;
+        let text = match &message.content {
+            Some(Content::Text(text)) => text.clone(),
+            Some(Content::Parts(parts)) => parts
+                .iter()
+                .map(|part| match part {
+                    ContentPart::Text { text } => text.as_str(),
+                    ContentPart::ImageUrl { .. } => "[image]",
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+            None => String::new(),
+        };
+        let text = match &message.function_call {
+            Some(call) => format!("{}({}) {}", call.name, call.arguments, text),
+            None => text,
+        };
+        let mut text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if let Some((end, _)) = text.char_indices().nth(PREVIEW_CHARS) {
+            text.truncate(end);
+            text.push('…');
+        }
+        let role = serde_json::to_value(message.role).unwrap_or_default();
+        preview.push_str(&format!(
+            "{:>3} {:<9} {:>6}  {}\n",
+            i + 1,
+            role.as_str().unwrap_or_default(),
+            tokens,
+            text
+        ));
+    }
+    preview.push_str(&format!(
+        "{} messages, about {} tokens\n",
+        messages.len(),
+        total + TOKENS_PER_REPLY
+    ));
+    preview
+}
+
+/// The tokens available for the messages of a request
+struct Budget {
+    window: usize,
+    reserve: usize,
+    tokens: usize,
+}
+
+impl Budget {
+    /// `None` if the size of the model's context window is unknown
+    fn new(settings: &Settings, config: &ContextConfig) -> Option<Self> {
+        let window = config
+            .window
+            .or_else(|| tiktoken_rs::model::get_context_size(&settings.model))?;
+        let reserve = settings
+            .sampling
+            .max_tokens
+            .map(|n| n as usize)
+            .or(config.reserve)
+            .unwrap_or(DEFAULT_RESERVE);
+        let functions = serde_json::to_string(&settings.functions).unwrap_or_default();
+        let tokens = window
+            .saturating_sub(reserve)
+            .saturating_sub(TOKENS_PER_REPLY)
+            .saturating_sub(bpe(&settings.model).count_ordinary(&functions));
+        Some(Self {
+            window,
+            reserve,
+            tokens,
+        })
+    }
+}
+
+/// Leave out all but the last `exchanges` exchanges before the last one, an
+/// exchange being a user message and the replies and tool calls after it
+///
+/// System messages and pinned context are kept.
+pub fn last_exchanges(messages: Vec<RequestMessage>, exchanges: usize) -> Vec<RequestMessage> {
+    let users: Vec<usize> = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| matches!(m.role, ChatCompletionMessageRole::User) && m.pinned.is_none())
+        .map(|(i, _)| i)
+        .collect();
+    let Some(start) = users
+        .len()
+        .checked_sub(exchanges + 1)
+        .map(|first| users[first])
+    else {
+        return messages;
+    };
+    messages
+        .into_iter()
+        .enumerate()
+        .filter(|(i, m)| {
+            *i >= start || matches!(m.role, ChatCompletionMessageRole::System) || m.pinned.is_some()
+        })
+        .map(|(_, m)| m)
+        .collect()
+}
+
+/// Choose which messages to keep so the total fits in `budget`
+///
+/// System messages and the last message are always kept, the others are
+/// dropped a whole exchange at a time starting with the oldest, until it
+/// fits or there's nothing left to drop.
+fn truncate(roles: &[ChatCompletionMessageRole], counts: &[usize], budget: usize) -> Vec<bool> {
+    let mut keep = vec![true; roles.len()];
+    let mut total: usize = counts.iter().sum();
+    let Some(last) = roles.len().checked_sub(1) else {
+        return keep;
+    };
+    let droppable = |i: usize| i < last && !matches!(roles[i], ChatCompletionMessageRole::System);
+
+    let mut i = 0;
+    while total > budget {
+        // Find the start of the oldest exchange still kept
+        while i < last && !(keep[i] && droppable(i)) {
+            i += 1;
+        }
+        if i >= last {
+            break;
+        }
+        // Drop it along with everything up to the next user message
+        loop {
+            if droppable(i) {
+                keep[i] = false;
+                total -= counts[i];
+            }
+            i += 1;
+            if i >= last || matches!(roles[i], ChatCompletionMessageRole::User) {
+                break;
+            }
+        }
+    }
+
+    keep
+}
+
+/// The tokenizer for the model, most chat models use cl100k or o200k
+fn bpe(model: &str) -> &'static CoreBPE {
+    tiktoken_rs::bpe_for_model(model).unwrap_or_else(|_| tiktoken_rs::cl100k_base_singleton())
+}
+
+/// Estimate the tokens a message takes up in the request
+pub fn count(bpe: &CoreBPE, message: &RequestMessage) -> usize {
+    let content = match &message.content {
+        None => 0,
+        Some(Content::Text(text)) => bpe.count_ordinary(text),
+        Some(Content::Parts(parts)) => parts
+            .iter()
+            .map(|part| match part {
+                ContentPart::Text { text } => bpe.count_ordinary(text),
+                ContentPart::ImageUrl { .. } => TOKENS_PER_IMAGE,
+            })
+            .sum(),
+    };
+    let name = message.name.as_deref().map_or(0, |n| bpe.count_ordinary(n));
+    let call = message.function_call.as_ref().map_or(0, |c| {
+        bpe.count_ordinary(&c.name) + bpe.count_ordinary(&c.arguments)
+    });
+    TOKENS_PER_MESSAGE + content + name + call
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::ImageUrl;
+    use ChatCompletionMessageRole::{Assistant, Function, System, User};
+
+    #[test]
+    fn previews_each_message_on_a_line() {
+        let long = "word ".repeat(40);
+        let messages = [
+            RequestMessage::text(System, "Be\nbrief"),
+            RequestMessage::text(User, &long),
+        ];
+        let preview = preview(&messages, "gpt-4");
+        let lines: Vec<&str> = preview.lines().collect();
+        assert!(lines[0].starts_with("  1 system") && lines[0].ends_with("  Be brief"));
+        assert!(lines[1].starts_with("  2 user") && lines[1].ends_with('…'));
+        assert!(lines[2].starts_with("2 messages, about"));
+    }
+
+    #[test]
+    fn keeps_the_last_exchanges() {
+        let messages: Vec<RequestMessage> =
+            [System, User, Assistant, User, Function, Assistant, User]
+                .iter()
+                .enumerate()
+                .map(|(i, role)| RequestMessage::text(*role, &i.to_string()))
+                .collect();
+        let kept = |exchanges| {
+            last_exchanges(messages.clone(), exchanges)
+                .iter()
+                .map(|m| match &m.content {
+                    Some(Content::Text(text)) => text.clone(),
+                    _ => String::new(),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(kept(0), ["0", "6"]);
+        assert_eq!(kept(1), ["0", "3", "4", "5", "6"]);
+        assert_eq!(kept(5).len(), 7);
+    }
+
+    #[test]
+    fn keeps_everything_that_fits() {
+        let keep = truncate(&[System, User, Assistant, User], &[10, 10, 10, 10], 40);
+        assert_eq!(keep, vec![true; 4]);
+    }
+
+    #[test]
+    fn drops_whole_exchanges_oldest_first() {
+        let roles = [System, User, Assistant, User, Assistant, User];
+        let keep = truncate(&roles, &[10, 10, 10, 10, 10, 10], 40);
+        assert_eq!(keep, vec![true, false, false, true, true, true]);
+    }
+
+    #[test]
+    fn drops_function_calls_with_their_exchange() {
+        let roles = [System, User, Assistant, Function, Assistant, User];
+        let keep = truncate(&roles, &[10, 10, 10, 10, 10, 10], 30);
+        assert_eq!(keep, vec![true, false, false, false, false, true]);
+    }
+
+    #[test]
+    fn assembles_the_request_to_fit_or_explains_why_it_cannot() {
+        let settings = Settings::resolve(
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+        )
+        .unwrap();
+        let messages = vec![
+            RequestMessage::text(System, "Be brief"),
+            RequestMessage::text(User, &"word ".repeat(200)),
+            RequestMessage::text(Assistant, "Hello"),
+            RequestMessage::text(User, "Hi"),
+        ];
+        let config = |overflow, window| ContextConfig {
+            overflow,
+            window: Some(window),
+            reserve: Some(0),
+        };
+
+        let kept = assemble(
+            messages.clone(),
+            &settings,
+            &config(Overflow::Truncate, 1000),
+        );
+        assert_eq!(kept.unwrap().len(), 4);
+        let kept = assemble(
+            messages.clone(),
+            &settings,
+            &config(Overflow::Truncate, 100),
+        );
+        let kept = kept.unwrap();
+        assert_eq!(kept.len(), 2);
+        assert!(
+            matches!(kept[0].role, System)
+                && matches!(&kept[1].content, Some(Content::Text(t)) if t == "Hi")
+        );
+
+        let refused = assemble(messages.clone(), &settings, &config(Overflow::Error, 100));
+        assert!(format!("{:#}", refused.unwrap_err()).contains("overflow = \"truncate\""));
+        let too_long = assemble(messages, &settings, &config(Overflow::Truncate, 10));
+        assert!(format!("{:#}", too_long.unwrap_err()).contains("even after leaving out"));
+    }
+
+    #[test]
+    fn cuts_history_then_attachments_then_pinned_context() {
+        let notes = RequestMessage {
+            pinned: Some("the project's notes".to_string()),
+            ..RequestMessage::text(System, "Notes")
+        };
+        let image = ContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: "data:image/png;base64,".to_string(),
+            },
+        };
+        let last = RequestMessage {
+            content: Some(Content::Parts(vec![
+                ContentPart::Text {
+                    text: "What's this?".to_string(),
+                },
+                image,
+            ])),
+            ..RequestMessage::text(User, "")
+        };
+        let messages = vec![
+            RequestMessage::text(System, "Be brief"),
+            notes,
+            RequestMessage::text(User, "Hi"),
+            RequestMessage::text(Assistant, "Hello"),
+            last,
+        ];
+        let counts = vec![10, 100, 10, 10, 20 + TOKENS_PER_IMAGE];
+
+        let (kept, cuts) = cut(messages.clone(), counts.clone(), 100).unwrap();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(
+            cuts,
+            vec![
+                Cut {
+                    what: "messages 2 to 3 of the chat".to_string(),
+                    tokens: 20
+                },
+                Cut {
+                    what: "an image attached to the last message".to_string(),
+                    tokens: TOKENS_PER_IMAGE
+                },
+                Cut {
+                    what: "the project's notes".to_string(),
+                    tokens: 100
+                },
+            ]
+        );
+
+        let (kept, cuts) = cut(messages.clone(), counts.clone(), 900).unwrap();
+        assert_eq!((kept.len(), cuts.len()), (3, 1));
+        assert!(cut(messages, counts, 20).is_none());
+    }
+}
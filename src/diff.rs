@@ -0,0 +1,127 @@
+use crate::message::Message;
+use openai::chat::ChatCompletionMessageRole;
+
+/// How a message in one chat compares with the other
+#[derive(Debug)]
+pub enum Change<'a> {
+    /// In both chats
+    Same(&'a Message),
+    /// Only in the first chat
+    Removed(&'a Message),
+    /// Only in the second chat
+    Added(&'a Message),
+}
+
+/// The messages of chat `a` turned into those of chat `b`, as few changed as possible
+///
+/// Messages are the same if they have the same heading and content, how a
+/// reply was made (its metadata comment) is ignored. The empty user message
+/// waiting at the end of a chat is left out.
+pub fn diff<'a>(a: &'a [Message], b: &'a [Message]) -> Vec<Change<'a>> {
+    let a = without_waiting(a);
+    let b = without_waiting(b);
+
+    // Longest common subsequence, lengths[i][j] for a[i..] and b[j..]
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = match same(&a[i], &b[j]) {
+                true => lengths[i + 1][j + 1] + 1,
+                false => lengths[i + 1][j].max(lengths[i][j + 1]),
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && same(&a[i], &b[j]) {
+            changes.push(Change::Same(&a[i]));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            changes.push(Change::Removed(&a[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Added(&b[j]));
+            j += 1;
+        }
+    }
+    changes
+}
+
+/// The changes as text, changed messages in full under `-` and `+` and the
+/// others by the start of their first line
+pub fn render(changes: &[Change]) -> String {
+    let mut text = String::new();
+    for change in changes {
+        let (sign, message) = match change {
+            Change::Same(message) => {
+                let first_line = message.content.lines().find(|l| !l.trim().is_empty());
+                let preview: String = first_line.unwrap_or_default().chars().take(60).collect();
+                text.push_str(&format!("  {:<24} {}\n", message.label(), preview));
+                continue;
+            }
+            Change::Removed(message) => ('-', message),
+            Change::Added(message) => ('+', message),
+        };
+        text.push_str(&format!("{} # {}\n", sign, message.label()));
+        let content = match &message.function_call {
+            Some(call) => &call.arguments,
+            None => &message.content,
+        };
+        for line in content.trim().lines() {
+            text.push_str(&format!("{} {}\n", sign, line));
+        }
+    }
+    text
+}
+
+fn same(a: &Message, b: &Message) -> bool {
+    let content = |m: &Message| match &m.function_call {
+        Some(call) => call.arguments.trim().to_string(),
+        None => m.content.trim().to_string(),
+    };
+    a.label() == b.label() && content(a) == content(b)
+}
+
+fn without_waiting(messages: &[Message]) -> &[Message] {
+    match messages.last() {
+        Some(last)
+            if matches!(last.role, ChatCompletionMessageRole::User)
+                && last.content.trim().is_empty() =>
+        {
+            &messages[..messages.len() - 1]
+        }
+        _ => messages,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_the_replies_that_differ() {
+        let a = Message::parse_messages(
+            "# System\nBe brief\n# User\nHi\n# Assistant (gpt-4)\nHello\n<!-- chat-cli-rs {\"latency_ms\":5} -->\n# User\nBye\n# Assistant (gpt-4)\nBye\n# User\n\n",
+        );
+        let b = Message::parse_messages(
+            "# System\nBe brief\n# User\nHi\n# Assistant (gpt-4)\nHello\n# User\nBye\n# Assistant (gpt-4o)\nSee you\nsoon\n",
+        );
+        let changes = diff(&a, &b);
+        assert_eq!(changes.len(), 6);
+        assert_eq!(
+            render(&changes),
+            "  System                   Be brief\n\
+             \x20 User                     Hi\n\
+             \x20 Assistant (gpt-4)        Hello\n\
+             \x20 User                     Bye\n\
+             - # Assistant (gpt-4)\n\
+             - Bye\n\
+             + # Assistant (gpt-4o)\n\
+             + See you\n\
+             + soon\n"
+        );
+    }
+}
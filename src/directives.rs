@@ -0,0 +1,380 @@
+use crate::{
+    message, platform, snippets,
+    storage::{self, ChatStore},
+    web,
+};
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+/// Longest command output included in a message, the end of the output is kept
+const MAX_OUTPUT_CHARS: usize = 20_000;
+
+/// Most characters of files included in one message, later files are skipped
+const MAX_FILES_CHARS: usize = 100_000;
+
+/// A line in the user's message that's replaced before sending
+enum Directive<'a> {
+    /// `!include $(cargo test 2>&1)`
+    Include(&'a str),
+    /// `@file src/main.rs`
+    File(&'a str),
+    /// `@dir src/ --glob '*.rs'`
+    Dir {
+        path: &'a str,
+        glob: Option<&'a str>,
+    },
+    /// `@url https://doc.rust-lang.org/book/`
+    Url(&'a str),
+    /// `@context api-docs`
+    Snippet(&'a str),
+}
+
+impl<'a> Directive<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("!include $(") {
+            return rest
+                .strip_suffix(')')
+                .map(str::trim)
+                .filter(|command| !command.is_empty())
+                .map(Self::Include);
+        }
+        if let Some(url) = line.strip_prefix("@url ") {
+            return Some(Self::Url(unquote(url)));
+        }
+        if let Some(name) = line.strip_prefix("@context ") {
+            return Some(Self::Snippet(unquote(name)));
+        }
+        if let Some(path) = line.strip_prefix("@file ") {
+            return Some(Self::File(unquote(path)));
+        }
+        let rest = line.strip_prefix("@dir ")?;
+        Some(match rest.split_once(" --glob ") {
+            Some((path, glob)) => Self::Dir {
+                path: unquote(path),
+                glob: Some(unquote(glob)),
+            },
+            None => Self::Dir {
+                path: unquote(rest),
+                glob: None,
+            },
+        })
+    }
+}
+
+fn unquote(s: &str) -> &str {
+    let s = s.trim();
+    ['\'', '"']
+        .iter()
+        .find_map(|q| s.strip_prefix(*q).and_then(|s| s.strip_suffix(*q)))
+        .unwrap_or(s)
+}
+
+/// Carry out the directives in the chat's last user message, replacing each with its result
+///
+/// `!include $(cmd)` runs `cmd` with `sh -c` and includes its output in a code
+/// block, `@file` and `@dir` include files in code blocks under their paths,
+/// `@url` includes the readable text of a web page under a link to it, and
+/// `@context` a snippet kept with `context add`. Directives in code blocks and in earlier messages are left alone.
+/// Returns whether the chat file changed.
+pub async fn expand(chat_file: &Path) -> Result<bool> {
+    let contents = std::fs::read_to_string(chat_file)
+        .with_context(|| format!("Unable to read chat file: {:?}", chat_file))?;
+    let targets = message::last_user_message_lines(&contents);
+
+    let mut expanded = String::new();
+    let mut files = Files::default();
+    let mut changed = false;
+    for (i, line) in contents.split_inclusive('\n').enumerate() {
+        let directive = Directive::parse(line).filter(|_| targets.binary_search(&i).is_ok());
+        match directive {
+            Some(Directive::Include(command)) => {
+                eprintln!("Including the output of `{}`", command);
+                expanded.push_str(&command_output(command)?);
+            }
+            Some(Directive::File(path)) => expanded.push_str(&files.include(Path::new(path))),
+            Some(Directive::Url(url)) => expanded.push_str(&web::fetch(url).await?.cited()),
+            Some(Directive::Snippet(name)) => {
+                let snippet = snippets::get(&ChatStore::open()?, name)?;
+                expanded.push_str(snippet.trim_end());
+                expanded.push('\n');
+            }
+            Some(Directive::Dir { path, glob }) => {
+                let paths = walk(Path::new(path), glob)?;
+                if paths.is_empty() {
+                    files.skip(Path::new(path), "no matching files");
+                }
+                for path in paths {
+                    expanded.push_str(&files.include(&path));
+                }
+            }
+            None => {
+                expanded.push_str(line);
+                continue;
+            }
+        }
+        changed = true;
+    }
+    expanded.push_str(&files.summary());
+
+    if changed {
+        storage::write_atomically(chat_file, expanded)
+            .with_context(|| format!("Unable to write chat file: {:?}", chat_file))?;
+    }
+    Ok(changed)
+}
+
+/// Files included in a message so far, keeping to the size limit
+#[derive(Default)]
+struct Files {
+    chars: usize,
+    skipped: Vec<(PathBuf, &'static str)>,
+}
+
+impl Files {
+    /// The file in a code block under its path, or nothing if it's skipped
+    fn include(&mut self, path: &Path) -> String {
+        let contents = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                self.skip(path, "unreadable");
+                return String::new();
+            }
+        };
+        let Ok(contents) = String::from_utf8(contents) else {
+            self.skip(path, "not text");
+            return String::new();
+        };
+        let len = contents.chars().count();
+        if self.chars + len > MAX_FILES_CHARS {
+            self.skip(path, "over the size limit");
+            return String::new();
+        }
+        self.chars += len;
+
+        let fence = "`".repeat(longest_backtick_run(&contents).max(2) + 1);
+        format!(
+            "`{}`\n{}{}\n{}\n{}\n",
+            path.display(),
+            fence,
+            language(path),
+            contents.trim_end(),
+            fence
+        )
+    }
+
+    fn skip(&mut self, path: &Path, reason: &'static str) {
+        eprintln!("Skipping {}: {}", path.display(), reason);
+        self.skipped.push((path.to_path_buf(), reason));
+    }
+
+    /// A note of the files that were skipped, so the model knows they're missing
+    fn summary(&self) -> String {
+        if self.skipped.is_empty() {
+            return String::new();
+        }
+        let skipped: Vec<String> = self
+            .skipped
+            .iter()
+            .map(|(path, reason)| format!("{} ({})", path.display(), reason))
+            .collect();
+        format!(
+            "Skipped {} file(s): {}\n",
+            skipped.len(),
+            skipped.join(", ")
+        )
+    }
+}
+
+/// The files under `dir` matching `glob`, skipping hidden files and directories
+pub(crate) fn walk(dir: &Path, glob: Option<&str>) -> Result<Vec<PathBuf>> {
+    let pattern = glob.map(glob_pattern).transpose()?;
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("Unable to read directory: {:?}", dir))?;
+        for entry in entries {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+            if name.starts_with('.') {
+                continue;
+            }
+            if path.is_dir() {
+                dirs.push(path);
+            } else if pattern.as_ref().is_none_or(|p| p.is_match(name)) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// A regex matching file names against a glob such as `*.rs` or `test_?.py`
+fn glob_pattern(glob: &str) -> Result<Regex> {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).with_context(|| format!("Invalid glob: {:?}", glob))
+}
+
+/// The code block language for a file, from its extension
+fn language(path: &Path) -> &str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    match ext {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "md" => "markdown",
+        "sh" | "bash" => "sh",
+        "yml" => "yaml",
+        "h" => "c",
+        "hpp" | "cc" | "cxx" => "cpp",
+        "rb" => "ruby",
+        "kt" => "kotlin",
+        ext => ext,
+    }
+}
+
+/// Append `!include` directives for `commands` to the end of the chat file
+///
+/// The chat file ends with the user's message, so the output is included in
+/// it when the chat is next sent.
+pub fn include_commands(chat_file: &Path, commands: &[String]) -> Result<()> {
+    append(
+        chat_file,
+        commands
+            .iter()
+            .map(|command| format!("!include $({})", command)),
+    )
+}
+
+/// Append `@file` or `@dir` directives for `paths` to the end of the chat file
+///
+/// Paths are made absolute so the chat can be resumed from another directory.
+pub fn include_files(chat_file: &Path, paths: &[String]) -> Result<()> {
+    let directives = paths
+        .iter()
+        .map(|path| {
+            let path =
+                std::fs::canonicalize(path).with_context(|| format!("Unable to find {}", path))?;
+            Ok(match path.is_dir() {
+                true => format!("@dir {}", path.display()),
+                false => format!("@file {}", path.display()),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    append(chat_file, directives.into_iter())
+}
+
+fn append(chat_file: &Path, lines: impl Iterator<Item = String>) -> Result<()> {
+    let text: String = lines.map(|line| format!("{}\n", line)).collect();
+    if text.is_empty() {
+        return Ok(());
+    }
+    storage::append(chat_file, &text)
+        .with_context(|| format!("Could not append to file: {:?}", chat_file))
+}
+
+/// Run a command and format its output as a fenced code block under the command
+fn command_output(command: &str) -> Result<String> {
+    let output = platform::shell(command)
+        .output()
+        .with_context(|| format!("Unable to run `{}`", command))?;
+
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    let text = tail(text.trim_end());
+
+    let status = match output.status.success() {
+        true => String::new(),
+        false => format!(" ({})", output.status),
+    };
+    let fence = "`".repeat(longest_backtick_run(&text).max(2) + 1);
+    Ok(format!(
+        "`$ {}`{}\n{}text\n{}\n{}\n",
+        command, status, fence, text, fence
+    ))
+}
+
+/// The end of the output, as that's usually where the errors are
+fn tail(text: &str) -> String {
+    let len = text.chars().count();
+    if len <= MAX_OUTPUT_CHARS {
+        return text.to_string();
+    }
+    let start = text
+        .char_indices()
+        .nth(len - MAX_OUTPUT_CHARS)
+        .map_or(0, |(i, _)| i);
+    format!(
+        "[{} earlier characters left out]\n{}",
+        len - MAX_OUTPUT_CHARS,
+        &text[start..]
+    )
+}
+
+/// So the code block's fence can be made longer than any backticks in the output
+pub(crate) fn longest_backtick_run(text: &str) -> usize {
+    text.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn expands_includes_in_the_last_user_message_only() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let chat = "# User\n!include $(echo old)\n# Assistant\nOk\n# User\nLook:\n!include $(echo new)\n```\n!include $(echo fenced)\n```\n";
+        std::fs::write(file.path(), chat).unwrap();
+
+        assert!(expand(file.path()).await.unwrap());
+        let contents = std::fs::read_to_string(file.path()).unwrap();
+        assert!(contents.starts_with("# User\n!include $(echo old)\n"));
+        assert!(contents.contains("Look:\n`$ echo new`\n```text\nnew\n```\n"));
+        assert!(contents.ends_with("```\n!include $(echo fenced)\n```\n"));
+    }
+
+    #[test]
+    fn includes_files_under_their_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.rs"), "fn main() {}\n").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "notes").unwrap();
+        std::fs::write(dir.path().join("c.bin"), [0xff, 0xfe]).unwrap();
+
+        let mut files = Files::default();
+        let a = dir.path().join("a.rs");
+        assert_eq!(
+            files.include(&a),
+            format!("`{}`\n```rust\nfn main() {{}}\n```\n", a.display())
+        );
+        assert!(files.include(&dir.path().join("c.bin")).is_empty());
+        assert!(files.summary().contains("c.bin (not text)"));
+
+        let rust = walk(dir.path(), Some("*.rs")).unwrap();
+        assert_eq!(rust, vec![a]);
+        assert_eq!(walk(dir.path(), None).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn fences_outlast_backticks_in_the_output() {
+        let output = command_output("printf '```'; exit 3").unwrap();
+        assert!(output.starts_with("`$ printf '```'; exit 3` (exit status: 3)\n````text\n"));
+    }
+}
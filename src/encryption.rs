@@ -0,0 +1,247 @@
+//! Chats kept encrypted with [age](https://age-encryption.org), as `<name>.md.age`
+//!
+//! An encrypted chat is only decrypted into a tmpfs while it's open, so the
+//! editor can work on it, and encrypted again after each change.
+
+use crate::{exit::Failure, platform, storage};
+use age::secrecy::SecretString;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use tempfile::TempDir;
+
+/// Extension added to the name of an encrypted chat
+const EXTENSION: &str = "age";
+
+/// Passphrase used without asking when it's set, e.g. for scripts
+const PASSPHRASE_VAR: &str = "CHAT_CLI_RS_PASSPHRASE";
+
+/// How chats are encrypted, the `[encryption]` config table
+///
+/// Without a key file a passphrase is asked for, once per run.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// An age key file, e.g. made with `age-keygen -o key.txt`, used instead of a passphrase
+    pub key_file: Option<PathBuf>,
+}
+
+/// The key file from the config, if it sets one
+static KEY_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// The key once it's been read or asked for
+static KEY: OnceLock<Key> = OnceLock::new();
+
+enum Key {
+    Passphrase(SecretString),
+    Identity(age::x25519::Identity),
+}
+
+/// Encrypt and decrypt chats with the key file in the config from now on
+pub fn configure(config: &EncryptionConfig) {
+    if let Some(key_file) = &config.key_file {
+        let _ = KEY_FILE.set(key_file.clone());
+    }
+}
+
+/// Whether the chat file is encrypted, going by its name
+pub fn is_encrypted(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == EXTENSION)
+}
+
+/// Refuse to run `command` on `file` if it's encrypted, as it would leave an unencrypted copy
+pub fn refuse(file: &Path, command: &str) -> Result<()> {
+    if is_encrypted(file) {
+        bail!(Failure::Usage(format!(
+            "{} can't be used with encrypted chats, its result wouldn't be encrypted",
+            command
+        )));
+    }
+    Ok(())
+}
+
+/// The key, read from the key file or asked for the first time it's needed
+///
+/// A new passphrase is asked for twice, as a mistyped one would lose the chat.
+fn key(new: bool) -> Result<&'static Key> {
+    if let Some(key) = KEY.get() {
+        return Ok(key);
+    }
+    let key = match KEY_FILE.get() {
+        Some(path) => Key::Identity(read_identity(path)?),
+        None => Key::Passphrase(ask_passphrase(new)?),
+    };
+    Ok(KEY.get_or_init(|| key))
+}
+
+/// The first identity in an age key file, which has one per line between comments
+fn read_identity(path: &Path) -> Result<age::x25519::Identity> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read the key file {}", path.display()))?;
+    let line = contents
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .with_context(|| format!("There's no key in {}", path.display()))?;
+    line.parse()
+        .map_err(|e| anyhow!("Invalid key in {}: {}", path.display(), e))
+}
+
+fn ask_passphrase(new: bool) -> Result<SecretString> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase.into());
+    }
+    let passphrase = rpassword::prompt_password("Passphrase for encrypted chats: ")
+        .context("Unable to read the passphrase")?;
+    if passphrase.is_empty() {
+        bail!("The passphrase is empty");
+    }
+    if new {
+        let again = rpassword::prompt_password("Repeat the passphrase: ")
+            .context("Unable to read the passphrase")?;
+        if again != passphrase {
+            bail!("The passphrases don't match");
+        }
+    }
+    Ok(passphrase.into())
+}
+
+fn encrypt(plaintext: &[u8]) -> Result<Vec<u8>> {
+    let encrypted = match key(true)? {
+        Key::Passphrase(passphrase) => {
+            age::encrypt(&age::scrypt::Recipient::new(passphrase.clone()), plaintext)
+        }
+        Key::Identity(identity) => age::encrypt(&identity.to_public(), plaintext),
+    };
+    encrypted.context("Unable to encrypt the chat")
+}
+
+fn decrypt(ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let decrypted = match key(false)? {
+        Key::Passphrase(passphrase) => {
+            age::decrypt(&age::scrypt::Identity::new(passphrase.clone()), ciphertext)
+        }
+        Key::Identity(identity) => age::decrypt(identity, ciphertext),
+    };
+    decrypted.context("Unable to decrypt the chat, is it the right passphrase or key file?")
+}
+
+/// The text of an encrypted chat
+pub fn read(path: &Path) -> Result<String> {
+    let ciphertext =
+        std::fs::read(path).with_context(|| format!("Unable to read {}", path.display()))?;
+    String::from_utf8(decrypt(&ciphertext)?)
+        .with_context(|| format!("{} isn't a text file once decrypted", path.display()))
+}
+
+/// Encrypt `text` into `path`, replacing what was there only once it's all written
+fn write(path: &Path, text: &[u8]) -> Result<()> {
+    storage::write_atomically(path, encrypt(text)?)
+        .with_context(|| format!("Unable to write {}", path.display()))
+}
+
+/// An encrypted chat decrypted into a tmpfs for a session
+///
+/// The decrypted copy is what's edited and sent, [`EncryptedChat::seal`]
+/// encrypts it back. Dropping the chat seals it one last time and removes the
+/// copy, unless sealing fails, in which case the copy is kept to not lose it.
+#[derive(Debug)]
+pub struct EncryptedChat {
+    /// The encrypted chat, e.g. `<store>/<name>.md.age`
+    path: PathBuf,
+    /// Where the decrypted copy is, only readable by the user
+    dir: Option<TempDir>,
+}
+
+impl EncryptedChat {
+    /// A new chat to be encrypted to `path`, which gets `.age` added
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut path = path.as_os_str().to_owned();
+        path.push(format!(".{}", EXTENSION));
+        Ok(Self {
+            path: path.into(),
+            dir: Some(platform::private_tmpfs_dir()?),
+        })
+    }
+
+    /// Decrypt the chat at `path` for a session
+    pub fn open(path: &Path) -> Result<Self> {
+        let text = read(path)?;
+        let chat = Self {
+            path: path.to_path_buf(),
+            dir: Some(platform::private_tmpfs_dir()?),
+        };
+        std::fs::write(chat.plain_path(), text)?;
+        Ok(chat)
+    }
+
+    /// The encrypted chat
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The decrypted copy of the chat in the tmpfs
+    ///
+    /// It's given a plain name, so titling the chat doesn't rename it.
+    pub fn plain_path(&self) -> PathBuf {
+        let dir = self
+            .dir
+            .as_ref()
+            .expect("the directory is kept until dropped");
+        dir.path().join("chat.md")
+    }
+
+    /// Encrypt the decrypted copy back into the chat
+    pub fn seal(&self) -> Result<()> {
+        let text = std::fs::read(self.plain_path())
+            .with_context(|| format!("Unable to read {}", self.plain_path().display()))?;
+        write(&self.path, &text)
+    }
+}
+
+impl Drop for EncryptedChat {
+    fn drop(&mut self) {
+        if let Err(e) = self.seal() {
+            let dir = self
+                .dir
+                .take()
+                .expect("the directory is kept until dropped");
+            eprintln!(
+                "{:#}, the decrypted chat is left at {}",
+                e,
+                dir.into_path().join("chat.md").display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypts_with_a_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("key.txt");
+        let identity = age::x25519::Identity::generate();
+        std::fs::write(
+            &key_file,
+            format!(
+                "# created: today\n{}\n",
+                age::secrecy::ExposeSecret::expose_secret(&identity.to_string())
+            ),
+        )
+        .unwrap();
+        let _ = KEY_FILE.set(key_file);
+
+        let chat = dir.path().join("chat.md.age");
+        write(&chat, b"# User\nsecret plans\n").unwrap();
+        let ciphertext = std::fs::read(&chat).unwrap();
+        assert!(!String::from_utf8_lossy(&ciphertext).contains("secret plans"));
+        assert_eq!(read(&chat).unwrap(), "# User\nsecret plans\n");
+        assert!(is_encrypted(&chat));
+    }
+}
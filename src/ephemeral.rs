@@ -0,0 +1,98 @@
+//! Chats that are never saved, `--ephemeral`
+//!
+//! The chat is kept in a tmpfs only the user can read while the session
+//! runs, so the editor can still work on it, and shredded when it ends. A
+//! saved chat can be forked into one to carry on without changing it.
+
+use crate::platform;
+use anyhow::{Context, Result};
+use std::{
+    fs::OpenOptions,
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tempfile::TempDir;
+
+/// A chat kept in a tmpfs for a session and shredded when it's dropped
+#[derive(Debug)]
+pub struct EphemeralChat {
+    dir: TempDir,
+}
+
+impl EphemeralChat {
+    /// A new chat, not started yet
+    pub fn create() -> Result<Self> {
+        Ok(Self {
+            dir: platform::private_tmpfs_dir()?,
+        })
+    }
+
+    /// A copy of the chat at `path`, which is left as it is
+    pub fn fork(path: &Path) -> Result<Self> {
+        let chat = Self::create()?;
+        std::fs::copy(path, chat.path())
+            .with_context(|| format!("Unable to copy {}", path.display()))?;
+        Ok(chat)
+    }
+
+    /// The chat in the tmpfs
+    ///
+    /// It's given a plain name, so titling the chat doesn't rename it.
+    pub fn path(&self) -> PathBuf {
+        self.dir.path().join("chat.md")
+    }
+}
+
+impl Drop for EphemeralChat {
+    fn drop(&mut self) {
+        // The directory itself is removed when it's dropped after this
+        if let Err(e) = shred(self.dir.path()) {
+            eprintln!("Unable to shred the chat: {:#}", e);
+        }
+    }
+}
+
+/// Overwrite the files in `dir` with zeros, including the editor's swap files and
+/// the chat's images, so what they held is gone before they're removed
+fn shred(dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            shred(&path)?;
+            continue;
+        }
+        let len = path.metadata()?.len();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Unable to open {}", path.display()))?;
+        std::io::copy(&mut std::io::repeat(0).take(len), &mut file)?;
+        file.sync_all()?;
+        std::fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forks_without_changing_the_chat_and_leaves_nothing_behind() {
+        if platform::tmpfs_dir().is_err() {
+            return;
+        }
+        let saved = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(saved.path(), "# Secret\n").unwrap();
+
+        let chat = EphemeralChat::fork(saved.path()).unwrap();
+        let path = chat.path();
+        std::fs::write(&path, "# Secret\nMore\n").unwrap();
+        std::fs::create_dir_all(path.with_file_name("assets")).unwrap();
+        std::fs::write(path.with_file_name("assets").join("a.png"), [1, 2]).unwrap();
+        drop(chat);
+
+        assert!(!path.parent().unwrap().exists());
+        assert_eq!(std::fs::read_to_string(saved.path()).unwrap(), "# Secret\n");
+    }
+}
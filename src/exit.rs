@@ -0,0 +1,100 @@
+//! The status the program exits with, so scripts can tell what went wrong
+//!
+//! | Status | Meaning |
+//! |--------|---------|
+//! | 0 | Success |
+//! | 1 | Any other error |
+//! | 2 | Bad usage, e.g. options that can't be used together or a missing chat file |
+//! | 3 | No API key, or the API refused it |
+//! | 4 | The API failed, couldn't be reached or stopped replying |
+//! | 5 | The chat doesn't fit in the model's context window |
+
+use crate::{
+    fallback::ApiError,
+    provider::{StreamDropped, TimedOut},
+};
+use reqwest::StatusCode;
+use std::fmt;
+
+pub const ERROR: u8 = 1;
+pub const USAGE: u8 = 2;
+pub const AUTH: u8 = 3;
+pub const API: u8 = 4;
+pub const CONTEXT_TOO_LONG: u8 = 5;
+
+/// An error that has its own exit status
+#[derive(Debug)]
+pub enum Failure {
+    Usage(String),
+    Auth(String),
+    ContextTooLong(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Usage(message) | Self::Auth(message) | Self::ContextTooLong(message) => {
+                f.write_str(message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// The exit status for the error, from the first of its causes that has one
+pub fn code(error: &anyhow::Error) -> u8 {
+    for cause in error.chain() {
+        if let Some(failure) = cause.downcast_ref::<Failure>() {
+            return match failure {
+                Failure::Usage(_) => USAGE,
+                Failure::Auth(_) => AUTH,
+                Failure::ContextTooLong(_) => CONTEXT_TOO_LONG,
+            };
+        }
+        if let Some(error) = cause.downcast_ref::<ApiError>() {
+            return match error.status {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => AUTH,
+                _ if error.message.contains("context length")
+                    || error.message.contains("context_length_exceeded") =>
+                {
+                    CONTEXT_TOO_LONG
+                }
+                _ => API,
+            };
+        }
+        if cause.is::<reqwest::Error>() || cause.is::<StreamDropped>() || cause.is::<TimedOut>() {
+            return API;
+        }
+    }
+    ERROR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn tells_errors_apart_through_their_context() {
+        let api = |status, message: &str| {
+            anyhow::Error::from(ApiError {
+                status,
+                message: message.to_string(),
+            })
+            .context("Unable to send file")
+        };
+        assert_eq!(code(&api(StatusCode::UNAUTHORIZED, "Bad key")), AUTH);
+        assert_eq!(code(&api(StatusCode::BAD_GATEWAY, "")), API);
+        let long = "This model's maximum context length is 8192 tokens";
+        assert_eq!(code(&api(StatusCode::BAD_REQUEST, long)), CONTEXT_TOO_LONG);
+
+        let usage: anyhow::Result<()> = Err(Failure::Usage("--output".into()).into());
+        assert_eq!(code(&usage.context("Unable to start").unwrap_err()), USAGE);
+        let dropped = anyhow::Error::from(StreamDropped("connection reset".into()));
+        assert_eq!(code(&dropped.context("Unable to send file")), API);
+        let timed_out = anyhow::Error::from(TimedOut(std::time::Duration::from_secs(30)));
+        assert_eq!(code(&timed_out.context("Unable to send file")), API);
+        assert_eq!(code(&anyhow!("Something else")), ERROR);
+    }
+}
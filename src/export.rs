@@ -0,0 +1,298 @@
+use crate::{
+    directives,
+    frontmatter::Frontmatter,
+    message::Message,
+    provider::{Content, RequestMessage},
+    storage, transcript,
+};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use openai::chat::ChatCompletionMessageRole;
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+use syntect::{highlighting::ThemeSet, html::highlighted_html_for_string, parsing::SyntaxSet};
+
+/// Browsers tried in turn to print a PDF
+const BROWSERS: &[&str] = &["chromium", "chromium-browser", "google-chrome", "chrome"];
+
+/// Light theme for code blocks, so they print well
+const THEME: &str = "InspiredGitHub";
+
+const STYLE: &str = r#"
+body { max-width: 50em; margin: 2em auto; padding: 0 1em; font-family: sans-serif; line-height: 1.5; color: #222; }
+header { border-bottom: 1px solid #ddd; margin-bottom: 1em; }
+header p { color: #666; }
+section { margin: 1.5em 0; padding: 0.5em 1em; border-radius: 6px; border-left: 4px solid #ccc; }
+section h2 { font-size: 0.9em; text-transform: uppercase; letter-spacing: 0.05em; color: #666; margin: 0.3em 0; }
+section.system { background: #f6f6f6; }
+section.user { border-color: #4a7bd0; }
+section.assistant { border-color: #3a9a5b; }
+section.function { border-color: #b08a2e; font-size: 0.9em; }
+pre { padding: 0.8em; border-radius: 4px; overflow-x: auto; border: 1px solid #eee; }
+code { font-family: monospace; }
+@media print {
+  body { max-width: none; margin: 0; font-size: 11pt; }
+  section { break-inside: avoid-page; }
+  pre { white-space: pre-wrap; overflow-x: visible; }
+}
+"#;
+
+/// The formats a chat can be exported to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// A standalone web page with highlighted code
+    Html,
+    /// The frontmatter and the messages as sent to the API
+    Json,
+    /// The web page printed by a headless Chromium
+    Pdf,
+    /// A JSONL transcript that can be continued, see the `transcript` module
+    Jsonl,
+    /// A markdown chat, e.g. from a transcript
+    Md,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Html => "html",
+            Format::Json => "json",
+            Format::Pdf => "pdf",
+            Format::Jsonl => "jsonl",
+            Format::Md => "md",
+        }
+    }
+}
+
+/// A chat as exported to JSON
+#[derive(Serialize)]
+struct Transcript {
+    #[serde(flatten)]
+    frontmatter: Frontmatter,
+    messages: Vec<RequestMessage>,
+}
+
+/// Export a chat file, by default next to it with the format's extension
+///
+/// Returns the path written to.
+pub fn export(chat_file: &Path, format: Format, output: Option<PathBuf>) -> Result<PathBuf> {
+    let (frontmatter, messages) = Message::read_chat(chat_file)?;
+    let output = output.unwrap_or_else(|| {
+        storage::uncompressed_path(chat_file).with_extension(format.extension())
+    });
+    if output == chat_file {
+        bail!("The export would replace {}", chat_file.display());
+    }
+
+    let contents = match format {
+        Format::Html => html(&frontmatter, &messages, chat_file)?,
+        Format::Json => json(frontmatter, &messages)?,
+        Format::Jsonl => transcript::from_markdown(&storage::read_chat_file(chat_file)?, "")?,
+        Format::Md => storage::read_chat_file(chat_file)?,
+        Format::Pdf => {
+            let page = tempfile::Builder::new().suffix(".html").tempfile()?;
+            std::fs::write(page.path(), html(&frontmatter, &messages, chat_file)?)?;
+            print_pdf(page.path(), &output)?;
+            return Ok(output);
+        }
+    };
+    std::fs::write(&output, contents)
+        .with_context(|| format!("Unable to write {}", output.display()))?;
+    Ok(output)
+}
+
+fn json(frontmatter: Frontmatter, messages: &[Message]) -> Result<String> {
+    let messages = messages
+        .iter()
+        .map(|message| RequestMessage {
+            role: message.role,
+            content: match message.function_call {
+                Some(_) => None,
+                None => Some(Content::Text(message.content.clone())),
+            },
+            name: message.name.clone(),
+            function_call: message.function_call.clone(),
+            pinned: None,
+        })
+        .collect();
+    let transcript = Transcript {
+        frontmatter,
+        messages,
+    };
+    Ok(serde_json::to_string_pretty(&transcript)?)
+}
+
+/// The chat as a standalone web page
+fn html(frontmatter: &Frontmatter, messages: &[Message], chat_file: &Path) -> Result<String> {
+    let title = frontmatter.title.clone().unwrap_or_else(|| {
+        chat_file
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+    let details: Vec<&str> = [frontmatter.model.as_deref(), frontmatter.created.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let highlighter = Highlighter::new()?;
+    let mut body = String::new();
+    for message in messages {
+        let class = match (message.role, &message.function_call) {
+            (ChatCompletionMessageRole::System, _) => "system",
+            (ChatCompletionMessageRole::User, _) => "user",
+            (ChatCompletionMessageRole::Assistant, None) => "assistant",
+            _ => "function",
+        };
+        let markdown = match (&message.function_call, message.role) {
+            (Some(call), _) => fenced(&call.arguments, "json"),
+            (None, ChatCompletionMessageRole::Function) => fenced(&message.content, "text"),
+            (None, _) => message.content.clone(),
+        };
+        body.push_str(&format!(
+            "<section class=\"{}\">\n<h2>{}</h2>\n{}</section>\n",
+            class,
+            escape(&message.label()),
+            highlighter.render(&markdown)
+        ));
+    }
+
+    Ok(format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n<header>\n<h1>{title}</h1>\n<p>{details}</p>\n</header>\n{body}</body>\n</html>\n",
+        title = escape(&title),
+        details = escape(&details.join(" · ")),
+    ))
+}
+
+/// Renders markdown to HTML, highlighting the code blocks
+struct Highlighter {
+    syntaxes: SyntaxSet,
+    themes: ThemeSet,
+}
+
+impl Highlighter {
+    fn new() -> Result<Self> {
+        let themes = ThemeSet::load_defaults();
+        if !themes.themes.contains_key(THEME) {
+            bail!("The {} theme is missing", THEME);
+        }
+        Ok(Self {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            themes,
+        })
+    }
+
+    fn render(&self, markdown: &str) -> String {
+        let mut code: Option<(String, String)> = None;
+        let events = Parser::new_ext(
+            markdown,
+            Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+        )
+        .filter_map(|event| match (event, &mut code) {
+            (Event::Start(Tag::CodeBlock(kind)), _) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(language) => language.to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code = Some((language, String::new()));
+                None
+            }
+            (Event::Text(text), Some((_, block))) => {
+                block.push_str(&text);
+                None
+            }
+            (Event::End(TagEnd::CodeBlock), _) => {
+                let (language, block) = code.take()?;
+                Some(Event::Html(self.highlight(&block, &language).into()))
+            }
+            (event, _) => Some(event),
+        });
+
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, events);
+        html
+    }
+
+    /// A code block, highlighted if its language is known
+    fn highlight(&self, code: &str, language: &str) -> String {
+        let token = language.split_whitespace().next().unwrap_or_default();
+        let syntax = self
+            .syntaxes
+            .find_syntax_by_token(token)
+            .unwrap_or_else(|| self.syntaxes.find_syntax_plain_text());
+        highlighted_html_for_string(code, &self.syntaxes, syntax, &self.themes.themes[THEME])
+            .unwrap_or_else(|_| format!("<pre><code>{}</code></pre>\n", escape(code)))
+    }
+}
+
+/// Text in a code block whose fence outlasts any backticks in it
+fn fenced(text: &str, language: &str) -> String {
+    let fence = "`".repeat(directives::longest_backtick_run(text).max(2) + 1);
+    format!("{}{}\n{}\n{}\n", fence, language, text.trim(), fence)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Print a web page to PDF with the first headless browser found
+fn print_pdf(page: &Path, output: &Path) -> Result<()> {
+    let output = std::path::absolute(output)?;
+    for browser in BROWSERS {
+        let status = Command::new(browser)
+            .arg("--headless")
+            .arg("--disable-gpu")
+            .arg("--no-pdf-header-footer")
+            .arg(format!("--print-to-pdf={}", output.display()))
+            .arg(page)
+            .output();
+        match status {
+            Ok(result) if result.status.success() => return Ok(()),
+            Ok(result) => bail!(
+                "{} was unable to print the PDF: {}",
+                browser,
+                String::from_utf8_lossy(&result.stderr).trim()
+            ),
+            Err(_) => continue,
+        }
+    }
+    bail!(
+        "Printing a PDF needs Chromium or Chrome, export to HTML and print it from a browser instead"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_highlighted_html_and_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let chat = dir.path().join("chat.md");
+        std::fs::write(
+            &chat,
+            "---\ntitle: Tips & <tricks>\nmodel: gpt-4\n---\n# User\nHi\n# Assistant\n**Sure**\n```rust\nfn main() {}\n```\n",
+        )
+        .unwrap();
+
+        let page = std::fs::read_to_string(export(&chat, Format::Html, None).unwrap()).unwrap();
+        assert!(page.contains("<h1>Tips &amp; &lt;tricks&gt;</h1>"));
+        assert!(page.contains("<strong>Sure</strong>"));
+        assert!(page.contains("<pre style="));
+        assert!(!page.contains("```"));
+
+        let transcript = export(&chat, Format::Json, None).unwrap();
+        let transcript: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(transcript).unwrap()).unwrap();
+        assert_eq!(transcript["model"], "gpt-4");
+        assert_eq!(transcript["messages"][1]["role"], "assistant");
+        assert_eq!(transcript["messages"][0]["content"], "Hi");
+    }
+}
@@ -0,0 +1,200 @@
+use crate::{
+    message::{Fence, Message},
+    reflect, tools,
+};
+use anyhow::{bail, Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use similar::TextDiff;
+use std::path::{Component, Path, PathBuf};
+
+/// A code block in a reply that says which file it belongs in
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    pub path: PathBuf,
+    pub code: String,
+}
+
+/// The code blocks in the chat's last reply that name their file
+pub fn last_reply(chat_file: &Path) -> Result<Vec<Block>> {
+    let (_, messages) = Message::read_chat(chat_file)?;
+    let Some(reply) = messages.iter().rev().find(|m| {
+        matches!(m.role, ChatCompletionMessageRole::Assistant) && m.function_call.is_none()
+    }) else {
+        bail!("The chat has no reply to extract from");
+    };
+    Ok(blocks(reflect::answer(&reply.content)))
+}
+
+/// The fenced code blocks that name their file, either on their first line,
+/// e.g. `// file: src/main.rs` or `# file: build.sh`, or in the fence's info
+/// string, e.g. ```` ```rust file=src/main.rs ````
+///
+/// The naming line is left out of the code. Blocks without a file are skipped.
+pub fn blocks(content: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut lines = content.lines();
+    while let Some((opening, fence)) = lines
+        .by_ref()
+        .find_map(|line| Fence::opened_by(line).map(|fence| (line, fence)))
+    {
+        let mut code: Vec<&str> = lines
+            .by_ref()
+            .take_while(|line| !fence.is_closed_by(line))
+            .collect();
+        let info_path = opening
+            .split_whitespace()
+            .find_map(|word| word.strip_prefix("file="));
+        let path = match info_path {
+            Some(path) => Some(path.to_string()),
+            None => match code.first().and_then(|line| annotation(line)) {
+                Some(path) => {
+                    code.remove(0);
+                    Some(path)
+                }
+                None => None,
+            },
+        };
+        if let Some(path) = path {
+            let mut code = code.join("\n");
+            code.push('\n');
+            blocks.push(Block {
+                path: PathBuf::from(path),
+                code,
+            });
+        }
+    }
+    blocks
+}
+
+/// The path in a `file: <path>` comment, in any of the common comment styles
+fn annotation(line: &str) -> Option<String> {
+    let line = line.trim();
+    let comment = ["//", "#", "--", "/*", "<!--", ";"]
+        .iter()
+        .find_map(|start| line.strip_prefix(start))?;
+    let comment = comment
+        .trim_end_matches("-->")
+        .trim_end_matches("*/")
+        .trim();
+    let path = comment.strip_prefix("file:")?.trim();
+    (!path.is_empty() && !path.contains(char::is_whitespace)).then(|| path.to_string())
+}
+
+impl Block {
+    /// The changes writing the block would make to its file, as a unified diff
+    pub fn diff(&self, dir: &Path) -> Result<String> {
+        let existing = match std::fs::read_to_string(path_within(dir, &self.path)?) {
+            Ok(existing) => existing,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Unable to read {}", self.path.display()))
+            }
+        };
+        let name = self.path.display().to_string();
+        Ok(TextDiff::from_lines(&existing, &self.code)
+            .unified_diff()
+            .header(&format!("a/{}", name), &format!("b/{}", name))
+            .to_string())
+    }
+
+    /// Write the block to its file under `dir`
+    pub fn write(&self, dir: &Path) -> Result<PathBuf> {
+        let path = path_within(dir, &self.path)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, &self.code)
+            .with_context(|| format!("Unable to write {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+/// A file under `dir` named by the model, which has to stay within it
+pub(crate) fn path_within(dir: &Path, path: &Path) -> Result<PathBuf> {
+    if !path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("{} isn't a path within {}", path.display(), dir.display());
+    }
+    Ok(dir.join(path))
+}
+
+/// Show each block's changes to the files in `dir` and write the ones agreed to
+///
+/// With `yes` they're all written without asking, with `dry_run` none are.
+/// A block whose file can't be read, e.g. one outside `dir`, is reported and
+/// skipped. Returns the files written.
+pub fn apply(blocks: &[Block], dir: &Path, yes: bool, dry_run: bool) -> Result<Vec<PathBuf>> {
+    let mut written = Vec::new();
+    for block in blocks {
+        let diff = match block.diff(dir) {
+            Ok(diff) => diff,
+            Err(e) => {
+                eprintln!("Skipping a block: {:#}", e);
+                continue;
+            }
+        };
+        if diff.is_empty() {
+            eprintln!("{} is unchanged", block.path.display());
+            continue;
+        }
+        eprint!("{}", diff);
+        if dry_run {
+            continue;
+        }
+        if yes || tools::confirm(&format!("Write {}?", block.path.display())) {
+            written.push(block.write(dir)?);
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_blocks_that_name_their_file() {
+        let reply = "Change these:\n\n```rust\n// file: src/main.rs\nfn main() {}\n```\n\n```sh\nls\n```\n\n```toml file=Cargo.toml\n[package]\n```\n\n```html\n<!-- file: index.html -->\n<p>Hi</p>\n```";
+        assert_eq!(
+            blocks(reply),
+            [
+                Block {
+                    path: PathBuf::from("src/main.rs"),
+                    code: "fn main() {}\n".to_string(),
+                },
+                Block {
+                    path: PathBuf::from("Cargo.toml"),
+                    code: "[package]\n".to_string(),
+                },
+                Block {
+                    path: PathBuf::from("index.html"),
+                    code: "<p>Hi</p>\n".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_only_within_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let block = |path: &str| Block {
+            path: PathBuf::from(path),
+            code: "new\n".to_string(),
+        };
+        std::fs::write(dir.path().join("a.txt"), "old\n").unwrap();
+        assert!(block("a.txt")
+            .diff(dir.path())
+            .unwrap()
+            .contains("-old\n+new\n"));
+
+        block("src/a.txt").write(dir.path()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("src/a.txt")).unwrap(),
+            "new\n"
+        );
+        assert!(block("../a.txt").write(dir.path()).is_err());
+        assert!(block("/tmp/a.txt").write(dir.path()).is_err());
+    }
+}
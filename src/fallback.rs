@@ -0,0 +1,186 @@
+//! Other APIs chats are sent to while the configured one is down, the `[[fallbacks]]` tables
+//!
+//! ```toml
+//! [[fallbacks]]
+//! provider = "azure"
+//! base_url = "https://my-resource.openai.azure.com/openai/v1/"
+//! key_header = "api-key"
+//! model = "gpt-4o"
+//!
+//! [[fallbacks]]
+//! provider = "ollama"
+//! base_url = "http://localhost:11434/v1/"
+//! model = "llama3.1"
+//! ```
+//!
+//! A request that still fails after its retries, because the API can't be
+//! reached, is overloaded or has an error of its own, is sent to each
+//! fallback in turn. The first that replies is used for the rest of the run.
+
+use crate::auth;
+use anyhow::Result;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+/// Times a request is sent again before the next API is tried
+pub const RETRIES: u32 = 2;
+
+/// Wait before the first retry, doubled for each after it
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fallback {
+    /// Named when it's switched to, its key is read like the provider's, and
+    /// isn't sent if there's none, e.g. for a local model
+    pub provider: String,
+    pub base_url: String,
+    /// Header the key is sent in instead of `Authorization: Bearer`
+    pub key_header: Option<String>,
+    /// Model the chat is sent to instead
+    pub model: String,
+    #[serde(skip)]
+    key: OnceLock<Option<String>>,
+}
+
+impl Fallback {
+    /// The provider's key, looked up the first time it's needed
+    pub fn key(&self) -> Option<&str> {
+        self.key
+            .get_or_init(|| match auth::api_key(&self.provider) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    tracing::debug!("Sending no key to {}: {:#}", self.provider, e);
+                    None
+                }
+            })
+            .as_deref()
+    }
+}
+
+/// An error response from the API, kept apart to tell outages from bad requests
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The API returned {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+static FALLBACKS: OnceLock<Vec<Fallback>> = OnceLock::new();
+
+/// Whether to send a desktop notification on switching
+static NOTIFY: OnceLock<bool> = OnceLock::new();
+
+/// The API requests go to, 0 for the configured one and then each fallback's position after it
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Fall back to these APIs from now on
+pub fn configure(fallbacks: &[Fallback], notify: bool) {
+    let _ = FALLBACKS.set(fallbacks.to_vec());
+    let _ = NOTIFY.set(notify);
+}
+
+pub fn fallbacks() -> &'static [Fallback] {
+    FALLBACKS.get().map_or(&[], Vec::as_slice)
+}
+
+/// The position of the fallback in use, if the configured API has been given up on
+pub fn active() -> Option<usize> {
+    ACTIVE.load(Ordering::Relaxed).checked_sub(1)
+}
+
+/// The fallback in use, to note with the replies it made
+pub fn in_use() -> Option<&'static Fallback> {
+    fallbacks().get(active()?)
+}
+
+/// Send requests to the fallback at `index` from now on, saying why
+pub fn switch(index: usize, error: &anyhow::Error) {
+    ACTIVE.store(index + 1, Ordering::Relaxed);
+    let fallback = &fallbacks()[index];
+    let message = format!(
+        "Falling back to {} ({}): {}",
+        fallback.provider,
+        fallback.model,
+        reason(error)
+    );
+    eprintln!("{}", message);
+    if NOTIFY.get() == Some(&true) {
+        crate::notify::send("Chat CLI fell back to another API", &message);
+    }
+}
+
+/// The error with its root cause, the layers between repeat it
+fn reason(error: &anyhow::Error) -> String {
+    match error.chain().count() {
+        1 => error.to_string(),
+        _ => format!("{}: {}", error, error.root_cause()),
+    }
+}
+
+/// Whether the error is the API being down rather than the request being wrong,
+/// worth retrying and then falling back on
+pub fn is_outage(error: &anyhow::Error) -> bool {
+    if let Some(error) = error.downcast_ref::<ApiError>() {
+        return error.status == StatusCode::TOO_MANY_REQUESTS || error.status.is_server_error();
+    }
+    error
+        .downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect() || e.is_timeout() || e.is_request())
+}
+
+/// Run `send` until it succeeds, isn't failing for an outage or has been retried enough
+pub async fn with_retries<T, F, Fut>(mut send: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut delay = RETRY_DELAY;
+    for retry in 1..=RETRIES {
+        match send().await {
+            Err(e) if is_outage(&e) => {
+                tracing::warn!("{}, retry {} of {}", reason(&e), retry, RETRIES);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    send().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn tells_outages_from_bad_requests() {
+        let error = |status| {
+            anyhow::Error::from(ApiError {
+                status,
+                message: String::new(),
+            })
+        };
+        assert!(is_outage(&error(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(is_outage(&error(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(!is_outage(&error(StatusCode::BAD_REQUEST)));
+        assert!(!is_outage(&error(StatusCode::UNAUTHORIZED)));
+        assert!(!is_outage(&anyhow!("Unable to parse the chat completion")));
+    }
+}
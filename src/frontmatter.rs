@@ -0,0 +1,216 @@
+use crate::{config::Sampling, lock::ChatLock, message, storage};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The line that opens and closes a YAML frontmatter block
+const DELIMITER: &str = "---";
+
+/// Per-session metadata stored as YAML at the top of a chat file
+///
+/// ```markdown
+/// ---
+/// title: Rust borrow checker
+/// model: gpt-4
+/// temperature: 0.2
+/// system: auto-expert
+/// created: 2024-05-01T09:30:00+10:00
+/// tags: [rust]
+/// ---
+/// # Rust borrow checker
+///
+/// # System
+/// ...
+/// ```
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Frontmatter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(flatten)]
+    pub sampling: Sampling,
+    /// Name or path of the system prompt the chat was started with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// The chat this one was branched from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branched_from: Option<PathBuf>,
+    /// The chat whose user messages this one replayed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replayed_from: Option<PathBuf>,
+    /// Name of the document index context is retrieved from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rag: Option<String>,
+    /// Exchanges sent before each message, set by `/history`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history: Option<usize>,
+    /// Where an imported chat came from, e.g. `chatgpt:<conversation id>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub imported_from: Option<String>,
+    /// Link to the daily note of the day the chat was started, in an Obsidian vault
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub daily_note: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Kept however old it gets when old chats are cleaned up
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+}
+
+impl Frontmatter {
+    /// Split a chat file into its frontmatter and the remaining markdown body
+    ///
+    /// Files without a frontmatter block get the default (empty) frontmatter.
+    pub fn parse(contents: &str) -> Result<(Self, &str)> {
+        match split(contents) {
+            Some((yaml, body)) => {
+                let frontmatter = if yaml.trim().is_empty() {
+                    Self::default()
+                } else {
+                    serde_yaml::from_str(yaml).context("Unable to parse chat frontmatter")?
+                };
+                Ok((frontmatter, body))
+            }
+            None => Ok((Self::default(), contents)),
+        }
+    }
+
+    /// Render the frontmatter block, including the delimiters, or nothing if it's empty
+    pub fn render(&self) -> Result<String> {
+        let yaml = serde_yaml::to_string(self).context("Unable to serialize frontmatter")?;
+        if yaml.trim() == "{}" {
+            return Ok(String::new());
+        }
+        Ok(format!("{DELIMITER}\n{yaml}{DELIMITER}\n"))
+    }
+
+    /// The frontmatter block followed by `body`, with the title as a heading between them
+    ///
+    /// Markdown viewers show the heading as the chat's title. It comes before
+    /// the first message so reading the chat skips it, and a heading already
+    /// there is replaced, so it follows the title when it changes.
+    pub fn render_with(&self, body: &str) -> Result<String> {
+        let body = without_title_heading(body);
+        let heading = self
+            .title
+            .as_deref()
+            .and_then(|title| title.lines().next())
+            .map(|title| format!("# {}", title.trim()))
+            // A title like `User` would be read as a message
+            .filter(|heading| heading.len() > 2 && !message::is_heading(heading));
+        Ok(match heading {
+            Some(heading) => format!("{}{}\n\n{}", self.render()?, heading, body),
+            None => format!("{}{}", self.render()?, body),
+        })
+    }
+
+    /// Replace the frontmatter and title heading of a chat file, leaving the rest of the file as is
+    pub fn write_to(&self, chat_file: &Path) -> Result<()> {
+        // Held from the read, so another process can't write in between
+        let _lock = ChatLock::acquire(chat_file)?;
+        let contents = std::fs::read_to_string(chat_file)
+            .with_context(|| format!("Unable to read chat file: {:?}", chat_file))?;
+        let body = split(&contents).map_or(contents.as_str(), |(_, body)| body);
+        storage::write_atomically(chat_file, self.render_with(body)?)
+            .with_context(|| format!("Unable to write chat file: {:?}", chat_file))
+    }
+}
+
+/// The body without the title heading ahead of its first message, if it has one
+fn without_title_heading(body: &str) -> &str {
+    let rest = body.trim_start_matches(['\r', '\n']);
+    let (line, after) = rest.split_once('\n').unwrap_or((rest, ""));
+    match line.starts_with("# ") && !message::is_heading(line) {
+        true => after.trim_start_matches(['\r', '\n']),
+        false => body,
+    }
+}
+
+/// Returns the YAML between the delimiters and the text after the closing one
+fn split(contents: &str) -> Option<(&str, &str)> {
+    let rest = contents
+        .strip_prefix(DELIMITER)?
+        .strip_prefix('\n')
+        .or_else(|| contents.strip_prefix("---\r\n"))?;
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == DELIMITER {
+            return Some((&rest[..offset], &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Overrides, Sampling, Settings};
+
+    #[test]
+    fn keeps_the_title_heading_in_step_with_the_title() {
+        let mut frontmatter = Frontmatter {
+            title: Some("Rust borrow checker".to_string()),
+            ..Default::default()
+        };
+        let body = "# System\nBe terse\n# User\n\n";
+        let contents = frontmatter.render_with(body).unwrap();
+        assert_eq!(
+            contents,
+            format!("---\ntitle: Rust borrow checker\n---\n# Rust borrow checker\n\n{body}")
+        );
+        let messages = message::Message::parse_messages(Frontmatter::parse(&contents).unwrap().1);
+        assert_eq!(messages.len(), 2);
+
+        frontmatter.title = Some("Lifetimes".to_string());
+        let retitled = frontmatter.render_with(Frontmatter::parse(&contents).unwrap().1);
+        assert_eq!(
+            retitled.unwrap(),
+            format!("---\ntitle: Lifetimes\n---\n# Lifetimes\n\n{body}")
+        );
+
+        frontmatter.title = Some("User".to_string());
+        assert!(!frontmatter
+            .render_with(body)
+            .unwrap()
+            .contains("# User\n\n# System"));
+    }
+
+    #[test]
+    fn a_chats_settings_sit_between_the_flags_and_the_config() {
+        let contents = "---\nmodel: gpt-4o\ntemperature: 0.2\ntop_p: 0.5\n---\n# User\nHi\n";
+        let (frontmatter, body) = Frontmatter::parse(contents).unwrap();
+        assert_eq!(body, "# User\nHi\n");
+        assert_eq!(
+            frontmatter.render().unwrap(),
+            contents[..contents.len() - body.len()]
+        );
+
+        let config = Config {
+            model: Some("gpt-3.5-turbo".to_string()),
+            sampling: Sampling {
+                temperature: Some(1.0),
+                max_tokens: Some(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let overrides = Overrides {
+            sampling: Sampling {
+                top_p: Some(0.9),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let settings = Settings::resolve(&overrides, &frontmatter, &config).unwrap();
+        assert_eq!(settings.model, "gpt-4o");
+        assert_eq!(settings.sampling.temperature, Some(0.2));
+        assert_eq!(settings.sampling.top_p, Some(0.9));
+        assert_eq!(settings.sampling.max_tokens, Some(100));
+    }
+}
@@ -0,0 +1,258 @@
+//! Finding and repairing chats whose headings no longer split them into messages, `fsck`
+//!
+//! Hand editing or a write cut short can leave a chat where a role heading is
+//! mistyped, e.g. `#User` or `# assistant:`, so it's read as part of the
+//! message before it, where a code block is left open, hiding the headings
+//! after it, or without the `# User` heading for the next message.
+
+use crate::{
+    encryption,
+    exit::Failure,
+    frontmatter::Frontmatter,
+    lock::ChatLock,
+    message::{self, Fence, Message},
+    storage::{self, ChatStore},
+    transcript,
+};
+use anyhow::{bail, Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use std::path::PathBuf;
+
+/// Something wrong with a chat, found on `line`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub line: usize,
+    pub description: String,
+}
+
+/// The problems found in a chat and its contents with them repaired
+#[derive(Debug)]
+pub struct Checked {
+    pub problems: Vec<Problem>,
+    /// Deeper headings named after a role, e.g. `### System`, which are left as
+    /// they are as they're more likely part of a message
+    pub notes: Vec<Problem>,
+    pub repaired: String,
+}
+
+/// Check `files`, or every chat in the store, printing their problems
+///
+/// With `repair` each chat with problems is archived and rewritten with them
+/// repaired, unless it's a `dry_run`. Otherwise finding any is an error.
+pub fn fsck(files: &[PathBuf], repair: bool, dry_run: bool) -> Result<()> {
+    let store = ChatStore::open()?;
+    let files = match files.is_empty() {
+        true => store.list()?,
+        false => files.to_vec(),
+    };
+    let mut unrepaired = 0;
+    for file in &files {
+        if encryption::is_encrypted(file) || transcript::is_transcript(file) {
+            bail!(Failure::Usage(format!(
+                "{} can't be checked, only markdown chats can",
+                file.display()
+            )));
+        }
+        let _lock = ChatLock::acquire(file)?;
+        let contents = std::fs::read_to_string(file)
+            .with_context(|| format!("Unable to read {}", file.display()))?;
+        let checked =
+            check(&contents).with_context(|| format!("Unable to check {}", file.display()))?;
+        for problem in &checked.problems {
+            println!(
+                "{}:{}: {}",
+                file.display(),
+                problem.line,
+                problem.description
+            );
+        }
+        for note in &checked.notes {
+            println!(
+                "{}:{}: note: {}",
+                file.display(),
+                note.line,
+                note.description
+            );
+        }
+        if checked.problems.is_empty() {
+            continue;
+        }
+        if !repair {
+            unrepaired += checked.problems.len();
+            continue;
+        }
+        if dry_run {
+            println!("Would repair {}", file.display());
+            continue;
+        }
+        let archive = store.archive(file)?;
+        storage::write_atomically(file, &checked.repaired)?;
+        println!(
+            "Repaired {}, the original is archived at {}",
+            file.display(),
+            archive.display()
+        );
+    }
+    if unrepaired > 0 {
+        bail!(
+            "Found {} problems, run with --repair to fix them",
+            unrepaired
+        );
+    }
+    Ok(())
+}
+
+/// Check the contents of a chat file
+///
+/// Frontmatter that can't be read is an error, as there's no telling what it should be.
+pub fn check(contents: &str) -> Result<Checked> {
+    let (_, body) = Frontmatter::parse(contents)?;
+    let head = &contents[..contents.len() - body.len()];
+    let offset = head.lines().count();
+    let number = |i: usize| offset + i + 1;
+
+    let source: Vec<&str> = body.lines().collect();
+    let mut problems = Vec::new();
+    let mut notes = Vec::new();
+    let mut lines = Vec::new();
+    let mut fence: Option<Fence> = None;
+    // Where to close a code block that's never closed, ahead of the first heading it hides
+    let mut close: Option<(usize, String)> = None;
+    for (i, line) in source.iter().enumerate() {
+        if let Some((_, closing)) = close.take_if(|(at, _)| *at == i) {
+            lines.push(closing);
+            fence = None;
+        }
+        if let Some(open) = &fence {
+            if open.is_closed_by(line) {
+                fence = None;
+            }
+            lines.push(line.to_string());
+            continue;
+        }
+        if let Some(open) = Fence::opened_by(line) {
+            let rest = &source[i + 1..];
+            if !rest.iter().any(|l| open.is_closed_by(l)) {
+                let heading = |l: &&str| message::is_heading(l) || mistyped_heading(l).is_some();
+                if let Some(j) = rest.iter().position(heading) {
+                    let at = i + 1 + j;
+                    problems.push(Problem {
+                        line: number(i),
+                        description: format!(
+                            "The code block opened here isn't closed, hiding the heading on line {}",
+                            number(at)
+                        ),
+                    });
+                    close = Some((at, open.closing()));
+                }
+            }
+            fence = Some(open);
+            lines.push(line.to_string());
+            continue;
+        }
+        match mistyped_heading(line) {
+            Some(heading) => {
+                problems.push(Problem {
+                    line: number(i),
+                    description: format!(
+                        "`{}` isn't read as a role heading, it should be `{}`",
+                        line.trim(),
+                        heading
+                    ),
+                });
+                lines.push(heading);
+            }
+            None => {
+                if let Some(role) = role_subheading(line) {
+                    notes.push(Problem {
+                        line: number(i),
+                        description: format!(
+                            "`{}` is left as a heading within the message, it would be `# {}` to start one",
+                            line.trim(),
+                            role
+                        ),
+                    });
+                }
+                lines.push(line.to_string());
+            }
+        }
+    }
+
+    let mut repaired = format!("{}{}", head, lines.join("\n"));
+    if !repaired.is_empty() {
+        repaired.push('\n');
+    }
+    // A function call or its result is left for the assistant to carry on from
+    let waiting = Message::parse_messages(&repaired[head.len()..])
+        .last()
+        .is_some_and(|m| {
+            matches!(
+                m.role,
+                ChatCompletionMessageRole::User | ChatCompletionMessageRole::Function
+            ) || m.function_call.is_some()
+        });
+    if !waiting {
+        problems.push(Problem {
+            line: offset + source.len(),
+            description: "The chat doesn't end with a `# User` heading for the next message"
+                .to_string(),
+        });
+        if !repaired.is_empty() && !repaired.ends_with("\n\n") {
+            repaired.push('\n');
+        }
+        repaired.push_str("# User\n\n");
+    }
+    Ok(Checked {
+        problems,
+        notes,
+        repaired,
+    })
+}
+
+/// The role heading a line was probably meant to be, e.g. `# User` for `#user:`
+///
+/// Only level 1 headings are, a deeper one such as `### System` is more likely
+/// a heading within a message.
+fn mistyped_heading(line: &str) -> Option<String> {
+    let text = line.trim().strip_prefix('#')?;
+    if text.starts_with('#') {
+        return None;
+    }
+    let heading = format!("# {}", role_named(text)?);
+    (line.trim_end() != heading).then_some(heading)
+}
+
+/// The role a deeper heading is named after, e.g. `User` for `## User:`
+fn role_subheading(line: &str) -> Option<&'static str> {
+    let text = line.trim().strip_prefix("##")?;
+    role_named(text.trim_start_matches('#'))
+}
+
+/// The role a heading's text names, ignoring case and a trailing colon
+fn role_named(text: &str) -> Option<&'static str> {
+    let text = text.trim().trim_end_matches(':').trim_end();
+    ["User", "Assistant", "System"]
+        .into_iter()
+        .find(|role| role.eq_ignore_ascii_case(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repairs_mistyped_headings_and_unclosed_code_blocks() {
+        let chat = "---\ntitle: Lifetimes\n---\n# User\n\nWhat's this?\n\n```rust\nfn f<'a>() {}\n\n#assistant\n\nA lifetime.\n\n# user:\n\nThanks\n\n# Assistant\n\n### System\nAnytime";
+        let checked = check(chat).unwrap();
+        let lines: Vec<_> = checked.problems.iter().map(|p| p.line).collect();
+        assert_eq!(lines, [8, 11, 15, 22]);
+        assert_eq!(
+            checked.repaired,
+            "---\ntitle: Lifetimes\n---\n# User\n\nWhat's this?\n\n```rust\nfn f<'a>() {}\n\n```\n# Assistant\n\nA lifetime.\n\n# User\n\nThanks\n\n# Assistant\n\n### System\nAnytime\n\n# User\n\n"
+        );
+        // A heading within a reply is only noted
+        let notes: Vec<_> = checked.notes.iter().map(|p| p.line).collect();
+        assert_eq!(notes, [21]);
+        assert!(check(&checked.repaired).unwrap().problems.is_empty());
+    }
+}
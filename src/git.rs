@@ -0,0 +1,110 @@
+use crate::directives;
+use anyhow::{bail, Context, Result};
+use std::{path::PathBuf, process::Command};
+
+/// Recent commits shown to the model so the message follows the project's style
+const RECENT_COMMITS: usize = 10;
+
+/// The changes to send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diff {
+    /// What `git commit` would commit
+    Staged,
+    /// Everything not yet committed
+    Uncommitted,
+    /// The changes in a range of commits, e.g. `main..feature`
+    Range(String),
+}
+
+impl Diff {
+    fn args(&self) -> Vec<&str> {
+        match self {
+            Self::Staged => vec!["diff", "--staged"],
+            Self::Uncommitted => vec!["diff", "HEAD"],
+            Self::Range(range) => vec!["diff", range],
+        }
+    }
+}
+
+/// The diff, erroring if it's empty
+pub fn diff(which: &Diff) -> Result<String> {
+    let diff = git(&which.args())?;
+    if diff.trim().is_empty() {
+        match which {
+            Diff::Staged => bail!("Nothing is staged, `git add` the changes first"),
+            Diff::Uncommitted => bail!("There are no uncommitted changes"),
+            Diff::Range(range) => bail!("There are no changes in {}", range),
+        }
+    }
+    Ok(diff)
+}
+
+/// The message asking for a commit message for the staged changes
+pub fn commit_message_request() -> Result<String> {
+    let diff = diff(&Diff::Staged)?;
+    // A new repository has no commits yet
+    let log =
+        git(&["log", "--format=%B%n---", &format!("-{}", RECENT_COMMITS)]).unwrap_or_default();
+    let mut message = String::new();
+    if !log.trim().is_empty() {
+        message.push_str(&format!(
+            "Recent commit messages:\n\n{}\n",
+            fenced(&log, "text")
+        ));
+    }
+    message.push_str(&format!(
+        "Write a commit message for this diff:\n\n{}",
+        fenced(&diff, "diff")
+    ));
+    Ok(message)
+}
+
+/// The message asking for a review of the changes
+pub fn review_request(which: &Diff) -> Result<String> {
+    let diff = diff(which)?;
+    Ok(format!("Review this diff:\n\n{}", fenced(&diff, "diff")))
+}
+
+/// Where `git commit` reads the message it's given by an editor, `.git/COMMIT_EDITMSG`
+pub fn commit_message_path() -> Result<PathBuf> {
+    let path = git(&["rev-parse", "--git-path", "COMMIT_EDITMSG"])?;
+    Ok(PathBuf::from(path.trim()))
+}
+
+fn fenced(text: &str, language: &str) -> String {
+    let fence = "`".repeat(directives::longest_backtick_run(text).max(2) + 1);
+    format!("{}{}\n{}\n{}\n", fence, language, text.trim_end(), fence)
+}
+
+/// Run git, returning its output or an error with what it printed
+fn git(args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .context("Unable to run git")?;
+    if !output.status.success() {
+        bail!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fences_a_diff_that_has_code_blocks_of_its_own() {
+        assert_eq!(fenced("+a\n", "diff"), "```diff\n+a\n```\n");
+
+        let diff = "+```rust\n+fn main() {}\n+```\n";
+        assert_eq!(fenced(diff, "diff"), format!("````diff\n{}````\n", diff));
+        assert_eq!(
+            Diff::Range("main..feature".to_string()).args(),
+            ["diff", "main..feature"]
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! User commands run on a session's events, the `[hooks]` config table
+//!
+//! ```toml
+//! [hooks]
+//! after_response = ["notify-send \"$(jq -r .message.content)\""]
+//! session_end = ["git -C ~/chats commit -qam chat"]
+//! ```
+//!
+//! Each command is run by the shell with the event as JSON on its stdin:
+//!
+//! ```json
+//! {"event":"after_response","chat_file":"/home/me/.local/share/chat-cli-rs/chat.md","message":{"role":"assistant","content":"Hello"}}
+//! ```
+//!
+//! `message` is the last message of the chat, the one about to be sent for
+//! `before_send` and the reply for `after_response`. What a hook prints on
+//! stdout is thrown away, so it can't end up in the reply's output, e.g. with
+//! `--output json`, or over the `tui`. A failing hook is only warned about.
+
+use crate::{message::Message, platform, session::Session};
+use anyhow::{ensure, Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use serde::{Deserialize, Serialize};
+use std::{io::Write, path::Path, process::Stdio, sync::OnceLock};
+
+/// The commands run on each event
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    pub session_start: Vec<String>,
+    pub before_send: Vec<String>,
+    pub after_response: Vec<String>,
+    pub session_end: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    SessionStart,
+    BeforeSend,
+    AfterResponse,
+    SessionEnd,
+}
+
+impl Event {
+    /// The event's name in the config and the JSON
+    fn name(self) -> &'static str {
+        match self {
+            Event::SessionStart => "session_start",
+            Event::BeforeSend => "before_send",
+            Event::AfterResponse => "after_response",
+            Event::SessionEnd => "session_end",
+        }
+    }
+}
+
+impl HooksConfig {
+    fn commands(&self, event: Event) -> &[String] {
+        match event {
+            Event::SessionStart => &self.session_start,
+            Event::BeforeSend => &self.before_send,
+            Event::AfterResponse => &self.after_response,
+            Event::SessionEnd => &self.session_end,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: &'static str,
+    chat_file: &'a Path,
+    message: Option<LastMessage<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct LastMessage<'a> {
+    role: ChatCompletionMessageRole,
+    content: &'a str,
+}
+
+static HOOKS: OnceLock<HooksConfig> = OnceLock::new();
+
+/// Run the config's hooks from now on
+pub fn configure(config: &HooksConfig) {
+    let _ = HOOKS.set(config.clone());
+}
+
+/// Run the hooks for `event` in the session, waiting for each to finish
+pub fn fire(event: Event, session: &Session) {
+    let Some(commands) = HOOKS.get().map(|hooks| hooks.commands(event)) else {
+        return;
+    };
+    if commands.is_empty() {
+        return;
+    }
+    let messages = Message::read_chat(session.chat_file())
+        .map(|(_, messages)| messages)
+        .unwrap_or_default();
+    let payload = payload(event, session.saved_file(), &messages);
+    for command in commands {
+        if let Err(e) = run(command, &payload) {
+            tracing::warn!("The {} hook `{}` failed: {:#}", event.name(), command, e);
+        }
+    }
+}
+
+/// The JSON a hook reads, with the last message that has any content
+fn payload(event: Event, chat_file: &Path, messages: &[Message]) -> String {
+    let message = messages
+        .iter()
+        .rev()
+        .find(|m| !m.content.trim().is_empty())
+        .map(|m| LastMessage {
+            role: m.role,
+            content: m.content.trim(),
+        });
+    let payload = Payload {
+        event: event.name(),
+        chat_file,
+        message,
+    };
+    serde_json::to_string(&payload).unwrap_or_default()
+}
+
+fn run(command: &str, payload: &str) -> Result<()> {
+    let mut child = platform::shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .context("Unable to start it")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A hook may exit without reading its input
+        let _ = stdin.write_all(payload.as_bytes());
+    }
+    let status = child.wait()?;
+    ensure!(status.success(), "It exited with {}", status);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_the_last_message_with_content() {
+        let messages = Message::parse_messages("# User\nHi\n# Assistant\nHello\n# User\n\n");
+        let payload = payload(Event::AfterResponse, Path::new("chat.md"), &messages);
+        assert_eq!(
+            payload,
+            r#"{"event":"after_response","chat_file":"chat.md","message":{"role":"assistant","content":"Hello"}}"#
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_a_hook_with_the_payload_on_stdin() {
+        let dir = tempfile::tempdir().unwrap();
+        let read = dir.path().join("read.json");
+        // What it prints is thrown away rather than mixed into the reply's output
+        let command = format!("echo printed; cat > {}", read.display());
+        run(&command, "{}").unwrap();
+        assert_eq!(std::fs::read_to_string(&read).unwrap(), "{}");
+        assert!(run("exit 3", "{}").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::{frontmatter::Frontmatter, message::Message, provider, storage};
+use anyhow::{Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Image model used unless the config or `--model` chooses another
+const DEFAULT_MODEL: &str = "gpt-image-1";
+
+/// Generating images with `imagine`, the `[images]` config table
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImageConfig {
+    /// Image model, `gpt-image-1` by default
+    pub model: Option<String>,
+    /// Size of the images, e.g. `1024x1024`, the model's default if unset
+    pub size: Option<String>,
+}
+
+/// What to make and where to put it
+pub struct Request<'a> {
+    pub prompt: &'a str,
+    pub n: usize,
+    pub size: Option<&'a str>,
+    pub model: Option<&'a str>,
+    /// Directory to save the images in instead of the chat's assets
+    pub out: Option<&'a Path>,
+    /// Chat to add the prompt and images to instead of starting a new one
+    pub chat: Option<&'a Path>,
+}
+
+/// Generate images, save them and link them in the chat
+///
+/// Returns the saved images and the chat they were added to.
+pub async fn imagine(
+    request: Request<'_>,
+    config: &ImageConfig,
+) -> Result<(Vec<PathBuf>, PathBuf)> {
+    let model = request
+        .model
+        .or(config.model.as_deref())
+        .unwrap_or(DEFAULT_MODEL);
+    let size = request.size.or(config.size.as_deref());
+    let chat_file = match request.chat {
+        Some(chat) => chat.to_path_buf(),
+        None => storage::new_chat_file_path(),
+    };
+    let dir = match request.out {
+        Some(out) => out.to_path_buf(),
+        None => storage::assets_dir(&chat_file),
+    };
+
+    eprintln!("Generating {} with {}", plural(request.n), model);
+    let images = provider::generate_images(model, request.prompt, request.n, size).await?;
+    let paths = save(&dir, &images)?;
+    record(&chat_file, request.prompt, model, &paths)?;
+    Ok((paths, chat_file))
+}
+
+fn plural(n: usize) -> String {
+    match n {
+        1 => "an image".to_string(),
+        n => format!("{} images", n),
+    }
+}
+
+/// Write the images to `dir` as `<time>-<n>.png`
+fn save(dir: &Path, images: &[Vec<u8>]) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Unable to create the directory {}", dir.display()))?;
+    let time = chrono::Local::now().format("%Y%m%d%H%M%S");
+    images
+        .iter()
+        .enumerate()
+        .map(|(i, image)| {
+            let path = dir.join(format!("{}-{}.png", time, i + 1));
+            std::fs::write(&path, image)
+                .with_context(|| format!("Unable to save the image {}", path.display()))?;
+            Ok(std::fs::canonicalize(&path)?)
+        })
+        .collect()
+}
+
+/// Add the prompt as the user's message and the images as the reply, starting the chat if needed
+fn record(chat_file: &Path, prompt: &str, model: &str, images: &[PathBuf]) -> Result<()> {
+    let (frontmatter, mut messages) = match chat_file.exists() {
+        true => Message::read_chat(chat_file)?,
+        false => (
+            Frontmatter {
+                created: Some(
+                    chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+                ),
+                ..Default::default()
+            },
+            Vec::new(),
+        ),
+    };
+    // Use the empty message left for the user's next turn
+    if messages.last().is_some_and(|m| {
+        matches!(m.role, ChatCompletionMessageRole::User) && m.content.trim().is_empty()
+    }) {
+        messages.pop();
+    }
+    messages.push(message(ChatCompletionMessageRole::User, prompt, None));
+    messages.push(message(
+        ChatCompletionMessageRole::Assistant,
+        &links(prompt, images),
+        Some(model),
+    ));
+    Message::write_chat(chat_file, &frontmatter, &messages)
+}
+
+fn message(role: ChatCompletionMessageRole, content: &str, model: Option<&str>) -> Message {
+    Message {
+        role,
+        content: content.trim().to_string(),
+        name: None,
+        function_call: None,
+        model: model.map(str::to_string),
+        meta: None,
+    }
+}
+
+/// Markdown image links to the images, with the prompt as their alt text
+fn links(prompt: &str, images: &[PathBuf]) -> String {
+    let alt: String = prompt
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace(['[', ']'], "");
+    images
+        .iter()
+        .map(|path| format!("![{}]({})", alt, path.display()))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_are_linked_in_the_chat() {
+        let dir = tempfile::tempdir().unwrap();
+        let chat = dir.path().join("chat.md");
+        std::fs::write(&chat, "# System\nBe terse\n# User\n\n").unwrap();
+        let images = save(&storage::assets_dir(&chat), &[vec![1, 2], vec![3]]).unwrap();
+        assert_eq!(images.len(), 2);
+        assert!(images[0].parent().unwrap().ends_with("assets/chat"));
+        assert_eq!(std::fs::read(&images[1]).unwrap(), vec![3]);
+
+        record(&chat, "A [red]\nfox", "gpt-image-1", &images).unwrap();
+        let (_, messages) = Message::read_chat(&chat).unwrap();
+        assert_eq!(messages[1].content, "A [red]\nfox");
+        assert_eq!(messages[2].model.as_deref(), Some("gpt-image-1"));
+        assert!(messages[2]
+            .content
+            .starts_with(&format!("![A red fox]({})", images[0].display())));
+    }
+}
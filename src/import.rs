@@ -0,0 +1,272 @@
+use crate::{frontmatter::Frontmatter, message::Message, storage::ChatStore};
+use anyhow::{Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// Prefix of `imported_from` for chats imported from ChatGPT
+const CHATGPT: &str = "chatgpt:";
+
+/// The file in a ChatGPT data export holding the conversations
+const CONVERSATIONS: &str = "conversations.json";
+
+/// A conversation in a ChatGPT data export
+///
+/// Messages form a tree as they can be edited and regenerated, the branch
+/// that was showing is the path from `current_node` back to the root.
+#[derive(Deserialize)]
+struct Conversation {
+    #[serde(alias = "conversation_id")]
+    id: String,
+    title: Option<String>,
+    create_time: Option<f64>,
+    update_time: Option<f64>,
+    mapping: HashMap<String, Node>,
+    current_node: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    message: Option<ExportedMessage>,
+    parent: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ExportedMessage {
+    author: Author,
+    content: ExportedContent,
+    #[serde(default)]
+    metadata: Value,
+}
+
+#[derive(Deserialize)]
+struct Author {
+    role: String,
+}
+
+#[derive(Deserialize)]
+struct ExportedContent {
+    content_type: String,
+    #[serde(default)]
+    parts: Vec<Value>,
+    text: Option<String>,
+}
+
+/// The outcome of an import
+pub struct Imported {
+    pub chats: Vec<PathBuf>,
+    /// Conversations that were imported before, or had no messages
+    pub skipped: usize,
+}
+
+/// Import the conversations in a ChatGPT data export into the store
+///
+/// `export` is the export's zip file or the `conversations.json` in it.
+/// Conversations that were imported before are skipped, so a newer export
+/// can be imported over an older one.
+pub fn chatgpt(export: &Path, store: &ChatStore) -> Result<Imported> {
+    let conversations = read_conversations(export)?;
+    let imported = imported_ids(store)?;
+
+    let mut chats = Vec::new();
+    let mut skipped = 0;
+    for conversation in conversations {
+        let id = format!("{}{}", CHATGPT, conversation.id);
+        let messages = conversation.messages();
+        if imported.contains(&id) || messages.is_empty() {
+            skipped += 1;
+            continue;
+        }
+
+        let created = conversation.create_time.map(timestamp);
+        let title = conversation.title.clone().filter(|t| !t.trim().is_empty());
+        let frontmatter = Frontmatter {
+            title: title.clone(),
+            model: conversation.model(),
+            created: created.map(|c| c.to_rfc3339_opts(chrono::SecondsFormat::Secs, false)),
+            imported_from: Some(id),
+            ..Default::default()
+        };
+
+        let date = created
+            .unwrap_or_else(chrono::Local::now)
+            .format("%Y-%m-%d")
+            .to_string();
+        let chat_file = store.titled_chat_path(&date, title.as_deref().unwrap_or("untitled"))?;
+        Message::write_chat(&chat_file, &frontmatter, &messages)?;
+
+        // Keep the chat's place when chats are listed by when they were last changed
+        if let Some(updated) = conversation.update_time.or(conversation.create_time) {
+            let modified = UNIX_EPOCH + Duration::from_secs_f64(updated.max(0.0));
+            File::options()
+                .write(true)
+                .open(&chat_file)
+                .and_then(|f| f.set_modified(modified))
+                .with_context(|| format!("Unable to set the time of {:?}", chat_file))?;
+        }
+        chats.push(chat_file);
+    }
+
+    Ok(Imported { chats, skipped })
+}
+
+fn read_conversations(export: &Path) -> Result<Vec<Conversation>> {
+    let mut json = String::new();
+    if export.extension().is_some_and(|ext| ext == "zip") {
+        let file =
+            File::open(export).with_context(|| format!("Unable to open {}", export.display()))?;
+        let mut archive = zip::ZipArchive::new(file)
+            .with_context(|| format!("Unable to read {} as a zip file", export.display()))?;
+        archive
+            .by_name(CONVERSATIONS)
+            .with_context(|| format!("{} has no {}", export.display(), CONVERSATIONS))?
+            .read_to_string(&mut json)?;
+    } else {
+        json = std::fs::read_to_string(export)
+            .with_context(|| format!("Unable to read {}", export.display()))?;
+    }
+    serde_json::from_str(&json).context("Unable to parse the ChatGPT conversations")
+}
+
+/// The `imported_from` of every chat in the store
+fn imported_ids(store: &ChatStore) -> Result<HashSet<String>> {
+    let mut ids = HashSet::new();
+    for chat in store.list()? {
+        let Ok(contents) = std::fs::read_to_string(&chat) else {
+            continue;
+        };
+        if let Ok((frontmatter, _)) = Frontmatter::parse(&contents) {
+            ids.extend(frontmatter.imported_from);
+        }
+    }
+    Ok(ids)
+}
+
+fn timestamp(seconds: f64) -> chrono::DateTime<chrono::Local> {
+    let utc = chrono::DateTime::from_timestamp(seconds as i64, 0).unwrap_or_default();
+    utc.with_timezone(&chrono::Local)
+}
+
+impl Conversation {
+    /// The visible messages on the current branch, oldest first
+    fn messages(&self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut id = self.current_node.as_deref();
+        while let Some(node) = id.and_then(|id| self.mapping.get(id)) {
+            messages.extend(node.message.as_ref().and_then(ExportedMessage::to_message));
+            id = node.parent.as_deref();
+        }
+        messages.reverse();
+        messages
+    }
+
+    /// The model of the last reply
+    fn model(&self) -> Option<String> {
+        let mut id = self.current_node.as_deref();
+        while let Some(node) = id.and_then(|id| self.mapping.get(id)) {
+            let slug = node
+                .message
+                .as_ref()
+                .and_then(|m| m.metadata["model_slug"].as_str());
+            if let Some(slug) = slug {
+                return Some(slug.to_string());
+            }
+            id = node.parent.as_deref();
+        }
+        None
+    }
+}
+
+impl ExportedMessage {
+    /// The message as it would be in a chat file, or `None` for hidden and tool messages
+    fn to_message(&self) -> Option<Message> {
+        if self.metadata["is_visually_hidden_from_conversation"] == true {
+            return None;
+        }
+        let role = match self.author.role.as_str() {
+            "system" => ChatCompletionMessageRole::System,
+            "user" => ChatCompletionMessageRole::User,
+            "assistant" => ChatCompletionMessageRole::Assistant,
+            _ => return None,
+        };
+
+        let content = match (self.content.content_type.as_str(), &self.content.text) {
+            ("code", Some(code)) => format!("```\n{}\n```", code.trim_end()),
+            _ => {
+                let parts: Vec<&str> = self
+                    .content
+                    .parts
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect();
+                parts.join("\n\n")
+            }
+        };
+        if content.trim().is_empty() {
+            return None;
+        }
+
+        Some(Message {
+            role,
+            content,
+            name: None,
+            function_call: None,
+            model: None,
+            meta: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imports_the_current_branch_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ChatStore::at(dir.path().join("chats"));
+        let export = dir.path().join(CONVERSATIONS);
+        let message = |role: &str, text: &str, metadata: Value| {
+            serde_json::json!({
+                "author": {"role": role},
+                "content": {"content_type": "text", "parts": [text]},
+                "metadata": metadata,
+            })
+        };
+        let conversations = serde_json::json!([{
+            "id": "abc",
+            "title": "Rust lifetimes",
+            "create_time": 1714555800.5,
+            "update_time": 1714559400.0,
+            "current_node": "edited",
+            "mapping": {
+                "root": {"message": null, "parent": null},
+                "system": {"message": message("system", "", serde_json::json!({"is_visually_hidden_from_conversation": true})), "parent": "root"},
+                "question": {"message": message("user", "What's 'a?", Value::Null), "parent": "system"},
+                "first": {"message": message("assistant", "A lifetime.", serde_json::json!({"model_slug": "gpt-4"})), "parent": "question"},
+                "edited": {"message": message("assistant", "A lifetime parameter.", serde_json::json!({"model_slug": "gpt-4o"})), "parent": "question"},
+            },
+        }]);
+        std::fs::write(&export, conversations.to_string()).unwrap();
+
+        let imported = chatgpt(&export, &store).unwrap();
+        assert_eq!(imported.chats.len(), 1);
+        let (frontmatter, messages) = Message::read_chat(&imported.chats[0]).unwrap();
+        assert_eq!(frontmatter.title.as_deref(), Some("Rust lifetimes"));
+        assert_eq!(frontmatter.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(frontmatter.imported_from.as_deref(), Some("chatgpt:abc"));
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["What's 'a?", "A lifetime parameter.", ""]);
+
+        let again = chatgpt(&export, &store).unwrap();
+        assert!(again.chats.is_empty());
+        assert_eq!(again.skipped, 1);
+    }
+}
@@ -0,0 +1,71 @@
+//! One chat a day, `journal`
+//!
+//! Each day's chat is `YYYY-MM-DD.md` in the journal directory, `journal_dir`
+//! in the config or else `journal/` in the data directory. Running `journal`
+//! again the same day adds to that chat instead of starting another.
+
+use crate::{
+    config::{Config, Overrides},
+    frontmatter::Frontmatter,
+    session::Session,
+    storage::ChatStore,
+};
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use std::path::{Path, PathBuf};
+
+/// The directory the daily chats are kept in
+pub fn dir(config: &Config) -> Result<PathBuf> {
+    match &config.journal_dir {
+        Some(dir) => Ok(dir.clone()),
+        None => Ok(ChatStore::open()?.dir().join("journal")),
+    }
+}
+
+/// The chat for the day
+pub fn path(dir: &Path, day: NaiveDate) -> PathBuf {
+    dir.join(format!("{}.md", day.format("%Y-%m-%d")))
+}
+
+/// Today's chat in `dir`, started with `system` if it's the first of the day
+pub fn open(
+    dir: &Path,
+    system: Option<String>,
+    config: Config,
+    overrides: Overrides,
+) -> Result<Session> {
+    let today = chrono::Local::now().date_naive();
+    let chat_file = path(dir, today);
+    if chat_file.exists() {
+        return Ok(Session::open(chat_file, config).with_overrides(overrides));
+    }
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Unable to create the journal directory {}", dir.display()))?;
+    let session = Session::start_at(chat_file.clone(), system, config, overrides)?;
+    // Titled by its day rather than by the model
+    let contents = std::fs::read_to_string(&chat_file)?;
+    let mut frontmatter = Frontmatter::parse(&contents)?.0;
+    frontmatter.title = Some(format!("Journal {}", today.format("%Y-%m-%d")));
+    frontmatter.write_to(&chat_file)?;
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_one_chat_a_day() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = dir.path().join("journal");
+        let first = open(&journal, None, Config::default(), Overrides::default()).unwrap();
+        let again = open(&journal, None, Config::default(), Overrides::default()).unwrap();
+        assert_eq!(first.chat_file(), again.chat_file());
+
+        let today = chrono::Local::now().date_naive();
+        assert_eq!(first.chat_file(), path(&journal, today));
+        let contents = std::fs::read_to_string(first.chat_file()).unwrap();
+        let title = format!("title: Journal {}", today.format("%Y-%m-%d"));
+        assert!(contents.starts_with("---\n") && contents.contains(&title));
+    }
+}
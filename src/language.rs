@@ -0,0 +1,187 @@
+//! Replies in a chosen language, `reply_language` in the config or `--reply-language`
+//!
+//! The model is told to reply in the language, and a reply found to be in
+//! another one is sent back once to be answered again. Replies are told
+//! apart by their most common words, so only the languages below are
+//! checked, any other is still asked for.
+
+use crate::{message::Fence, provider::RequestMessage};
+use openai::chat::ChatCompletionMessageRole;
+
+struct Language {
+    code: &'static str,
+    name: &'static str,
+    /// Short words common in the language's text
+    words: &'static [&'static str],
+}
+
+const LANGUAGES: &[Language] = &[
+    Language {
+        code: "en",
+        name: "English",
+        words: &[
+            "the", "and", "is", "are", "of", "to", "that", "it", "you", "for", "with", "this",
+            "was", "be", "not", "have", "on", "can", "will", "if",
+        ],
+    },
+    Language {
+        code: "de",
+        name: "German",
+        words: &[
+            "der", "die", "das", "und", "ist", "nicht", "ein", "eine", "ich", "sie", "es", "mit",
+            "den", "dem", "zu", "auf", "für", "sich", "auch", "wird", "kann", "oder", "wenn",
+        ],
+    },
+    Language {
+        code: "fr",
+        name: "French",
+        words: &[
+            "le", "les", "et", "est", "une", "des", "du", "que", "qui", "pas", "pour", "dans",
+            "ce", "il", "vous", "sur", "avec", "sont", "je", "au",
+        ],
+    },
+    Language {
+        code: "es",
+        name: "Spanish",
+        words: &[
+            "el", "los", "las", "y", "es", "que", "en", "un", "una", "por", "para", "con", "no",
+            "se", "lo", "del", "está", "son", "pero", "como",
+        ],
+    },
+    Language {
+        code: "it",
+        name: "Italian",
+        words: &[
+            "il", "gli", "e", "è", "che", "di", "un", "una", "per", "non", "con", "sono", "della",
+            "questo", "anche", "come", "più", "nel",
+        ],
+    },
+    Language {
+        code: "nl",
+        name: "Dutch",
+        words: &[
+            "de", "het", "een", "en", "is", "van", "dat", "niet", "op", "te", "zijn", "voor",
+            "met", "ik", "je", "ook", "maar", "wordt",
+        ],
+    },
+    Language {
+        code: "pt",
+        name: "Portuguese",
+        words: &[
+            "o", "os", "e", "é", "que", "de", "um", "uma", "não", "para", "com", "do", "da", "em",
+            "são", "por", "mais", "você",
+        ],
+    },
+];
+
+/// Fewest common words a reply needs for its language to be told
+const MIN_WORDS: usize = 5;
+
+/// The language by its code or English name, e.g. `de` or `German`
+fn find(spec: &str) -> Option<&'static Language> {
+    LANGUAGES
+        .iter()
+        .find(|l| l.code.eq_ignore_ascii_case(spec) || l.name.eq_ignore_ascii_case(spec))
+}
+
+/// The language's English name, or the spec itself if it isn't known
+fn name(spec: &str) -> &str {
+    find(spec).map_or(spec, |l| l.name)
+}
+
+/// Send the instruction to reply in the language as a system message after the system prompt
+pub fn add_instruction(messages: &mut Vec<RequestMessage>, spec: &str) {
+    let at = messages
+        .iter()
+        .take_while(|m| matches!(m.role, ChatCompletionMessageRole::System))
+        .count();
+    let instruction = format!(
+        "Always reply in {}, whatever language the user writes in, unless they ask for another.",
+        name(spec)
+    );
+    messages.insert(
+        at,
+        RequestMessage::text(ChatCompletionMessageRole::System, &instruction),
+    );
+}
+
+/// The language the text is most likely in, if it has enough prose to tell
+///
+/// Code blocks and inline code are left out.
+fn detect(text: &str) -> Option<&'static Language> {
+    let mut prose = String::new();
+    let mut fence: Option<Fence> = None;
+    for line in text.lines() {
+        match &fence {
+            Some(open) if open.is_closed_by(line) => fence = None,
+            Some(_) => {}
+            None => match Fence::opened_by(line) {
+                Some(open) => fence = Some(open),
+                None => {
+                    prose.extend(line.split('`').step_by(2));
+                    prose.push('\n');
+                }
+            },
+        }
+    }
+    let prose = prose.to_lowercase();
+    let words: Vec<&str> = prose
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .collect();
+    let mut scores: Vec<(usize, &Language)> = LANGUAGES
+        .iter()
+        .map(|l| (words.iter().filter(|w| l.words.contains(w)).count(), l))
+        .collect();
+    scores.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    let (best, language) = scores[0];
+    let runner_up = scores[1].0;
+    (best >= MIN_WORDS && best * 2 > runner_up * 3).then_some(language)
+}
+
+/// What's wrong with the reply if it's clearly in another language than `spec`
+pub fn check(reply: &str, spec: &str) -> Option<String> {
+    let wanted = find(spec)?;
+    let found = detect(reply)?;
+    (found.code != wanted.code)
+        .then(|| format!("The reply is in {} rather than {}", found.name, wanted.name))
+}
+
+/// The message asking the model to answer again in the language
+pub fn correction(problem: &str, spec: &str) -> String {
+    format!("{}.\n\nReply again in {}.", problem, name(spec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_replies_in_another_language() {
+        let german = "Das ist eine gute Frage. Ich denke, dass es mit der Version zu tun hat, \
+                      die du verwendest, und nicht mit dem Code.";
+        let english = "That is a good question. I think it has to do with the version you \
+                       are using, and not with the code. ```\nder die das und ist\n```";
+        assert_eq!(check(german, "de"), None);
+        assert_eq!(
+            check(english, "German").as_deref(),
+            Some("The reply is in English rather than German")
+        );
+        assert_eq!(check("Ja.", "de"), None);
+        assert_eq!(check(english, "ja"), None);
+    }
+
+    #[test]
+    fn leaves_out_every_kind_of_code_block() {
+        let german = "Das ist eine gute Frage. Ich denke, dass es mit der Version zu tun hat, \
+                      die du verwendest, und nicht mit dem Code.";
+        let english = "That is a good question, and I think this is the answer you want.";
+        for fenced in [
+            format!("~~~\n{}\n~~~", german),
+            format!("````md\n```\n{}\n```\n````", german),
+        ] {
+            let reply = format!("{}\n{}", english, fenced);
+            assert_eq!(detect(&reply).map(|l| l.code), Some("en"), "{reply}");
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Chat with OpenAI models through a markdown file
+//!
+//! A [`Session`] sends the chat in a file and streams the reply back into it,
+//! the chats themselves are kept in a [`ChatStore`].
+
+pub mod agent;
+pub mod attachments;
+pub mod auth;
+pub mod batch;
+pub mod cache;
+pub mod cassette;
+pub mod catalog;
+pub mod cli;
+pub mod clipboard;
+pub mod commands;
+pub mod compact;
+pub mod completions;
+pub mod config;
+pub mod confirm;
+pub mod context;
+pub mod diff;
+pub mod directives;
+pub mod encryption;
+pub mod ephemeral;
+pub mod exit;
+pub mod export;
+pub mod extract;
+pub mod fallback;
+pub mod frontmatter;
+pub mod fsck;
+pub mod git;
+pub mod hooks;
+pub mod images;
+pub mod import;
+pub mod journal;
+pub mod language;
+pub mod lock;
+pub mod logging;
+pub mod mapreduce;
+pub mod mcp;
+pub mod message;
+pub mod mock;
+pub mod notify;
+pub mod obsidian;
+pub mod patch;
+pub mod pick;
+pub mod piped;
+pub mod platform;
+pub mod plugins;
+pub mod postprocess;
+pub mod project;
+pub mod prompts;
+pub mod protocol;
+pub mod provider;
+pub mod rag;
+pub mod ratelimit;
+pub mod reasoning;
+pub mod redact;
+pub mod reflect;
+pub mod replay;
+pub mod retention;
+pub mod routing;
+pub mod schema;
+pub mod search;
+pub mod serve;
+pub mod session;
+pub mod slash;
+pub mod snippets;
+pub mod spend;
+pub mod spinner;
+pub mod storage;
+pub mod tags;
+pub mod templates;
+pub mod theme;
+pub mod title;
+pub mod tools;
+pub mod transcript;
+pub mod tui;
+pub mod voice;
+pub mod wasm;
+pub mod watch;
+pub mod web;
+pub mod workflow;
+
+pub use message::Message;
+pub use session::Session;
+pub use storage::ChatStore;
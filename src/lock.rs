@@ -0,0 +1,223 @@
+//! Advisory locks on chats, so two processes can't write to one at the same time
+//!
+//! A session takes its chat's lock the first time it writes to it and holds it
+//! until it ends, other writes take it while they write, sharing it with a
+//! session in the same process. Another session can't, even in the same
+//! process, e.g. two replies `serve` is asked for at once. The locks are files in
+//! `locks/` in the state directory, named after a hash of the chat's path and
+//! holding the PID of the process with the lock. The OS releases them if that
+//! process dies, and they're removed once they're released.
+
+use crate::platform;
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+/// The locks this process holds
+static HELD: Mutex<BTreeMap<PathBuf, Held>> = Mutex::new(BTreeMap::new());
+
+/// A lock this process holds
+#[derive(Debug)]
+struct Held {
+    /// Closing it releases the lock
+    _file: File,
+    /// How many [`ChatLock`]s share it
+    count: usize,
+    /// Whether a session has it, which no other session may share
+    session: bool,
+}
+
+/// A chat's lock, released when the last one for the chat in this process is dropped
+#[derive(Debug)]
+pub struct ChatLock {
+    lock_file: PathBuf,
+    session: bool,
+}
+
+/// Returned when the chat's lock is held by another process or session
+#[derive(Debug)]
+pub struct InUse(String);
+
+impl fmt::Display for InUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InUse {}
+
+impl ChatLock {
+    /// Take the lock on `chat_file` for a write, failing if another process has it
+    pub fn acquire(chat_file: &Path) -> Result<Self> {
+        Self::acquire_in(&platform::state_dir()?.join("locks"), chat_file, false)
+    }
+
+    /// Take the lock on `chat_file` for a session, failing if another process
+    /// or another session in this one has it
+    pub fn acquire_for_session(chat_file: &Path) -> Result<Self> {
+        Self::acquire_in(&platform::state_dir()?.join("locks"), chat_file, true)
+    }
+
+    fn acquire_in(dir: &Path, chat_file: &Path, session: bool) -> Result<Self> {
+        let lock_file = lock_file(dir, chat_file);
+        let mut held = HELD.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(held) = held.get_mut(&lock_file) {
+            if session && held.session {
+                bail!(InUse(format!(
+                    "{} is in use by another session",
+                    chat_file.display()
+                )));
+            }
+            held.count += 1;
+            held.session |= session;
+            return Ok(Self { lock_file, session });
+        }
+
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Unable to create the lock directory {}", dir.display()))?;
+        let mut file = loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_file)
+                .with_context(|| format!("Unable to open {}", lock_file.display()))?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(std::fs::TryLockError::WouldBlock) => {
+                    let mut pid = String::new();
+                    let _ = file.read_to_string(&mut pid);
+                    bail!(InUse(match pid.trim() {
+                        "" => format!("{} is in use by another process", chat_file.display()),
+                        pid => format!(
+                            "The session for {} is in use by PID {}",
+                            chat_file.display(),
+                            pid
+                        ),
+                    }))
+                }
+                Err(std::fs::TryLockError::Error(e)) => {
+                    return Err(e)
+                        .with_context(|| format!("Unable to lock {}", lock_file.display()))
+                }
+            }
+            // The process that had the lock may have removed the file as it released it
+            if is_current(&file, &lock_file) {
+                break file;
+            }
+        };
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        held.insert(
+            lock_file.clone(),
+            Held {
+                _file: file,
+                count: 1,
+                session,
+            },
+        );
+        Ok(Self { lock_file, session })
+    }
+}
+
+impl Drop for ChatLock {
+    fn drop(&mut self) {
+        let mut held = HELD.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(lock) = held.get_mut(&self.lock_file) {
+            lock.count -= 1;
+            lock.session &= !self.session;
+            if lock.count == 0 {
+                // Removed while it's still locked, closing the file then releases the lock
+                #[cfg(unix)]
+                let _ = std::fs::remove_file(&self.lock_file);
+                held.remove(&self.lock_file);
+            }
+        }
+    }
+}
+
+/// The lock file for `chat_file` in `dir`
+fn lock_file(dir: &Path, chat_file: &Path) -> PathBuf {
+    dir.join(format!("{:x}.lock", Sha256::digest(key(chat_file))))
+}
+
+/// Whether the open lock file is still the one at `path`, rather than one removed since
+#[cfg(unix)]
+fn is_current(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(open), Ok(at_path)) => open.dev() == at_path.dev() && open.ino() == at_path.ino(),
+        _ => false,
+    }
+}
+
+/// Lock files are only removed on Unix, as Windows can't remove a file that's open
+#[cfg(not(unix))]
+fn is_current(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+/// The path the chat's lock is named after, the same however the chat is reached
+///
+/// A titled chat's old name is a symlink to it, so that's followed. A chat that
+/// doesn't exist yet is named after its canonical directory.
+fn key(chat_file: &Path) -> String {
+    let path = chat_file.canonicalize().unwrap_or_else(|_| {
+        match (chat_file.parent(), chat_file.file_name()) {
+            (Some(dir), Some(name)) => dir
+                .canonicalize()
+                .map(|dir| dir.join(name))
+                .unwrap_or_else(|_| chat_file.to_path_buf()),
+            _ => chat_file.to_path_buf(),
+        }
+    });
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_a_chat_locked_elsewhere() {
+        let dir = tempfile::tempdir().unwrap();
+        let locks = dir.path().join("locks");
+        let chat = dir.path().join("chat.md");
+
+        let lock = ChatLock::acquire_in(&locks, &chat, true).unwrap();
+        // Taken again by the same process, e.g. a write during a session
+        drop(ChatLock::acquire_in(&locks, &chat, false).unwrap());
+        assert!(lock_file(&locks, &chat).exists());
+        // But not by another session in it
+        let error = ChatLock::acquire_in(&locks, &chat, true).unwrap_err();
+        assert!(error.is::<InUse>(), "{error}");
+        drop(lock);
+        #[cfg(unix)]
+        assert!(!lock_file(&locks, &chat).exists());
+
+        // A separately opened file stands in for another process
+        std::fs::write(lock_file(&locks, &chat), "4242").unwrap();
+        let other = File::open(lock_file(&locks, &chat)).unwrap();
+        other.try_lock().unwrap();
+        let error = ChatLock::acquire_in(&locks, &chat, false).unwrap_err();
+        assert!(
+            error.to_string().ends_with("is in use by PID 4242"),
+            "{error}"
+        );
+
+        drop(other);
+        let write = ChatLock::acquire_in(&locks, &chat, false).unwrap();
+        // A session can join a write, and then the next session is refused
+        let _session = ChatLock::acquire_in(&locks, &chat, true).unwrap();
+        drop(write);
+        assert!(ChatLock::acquire_in(&locks, &chat, true).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::platform;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+use tracing_subscriber::EnvFilter;
+
+/// The open request log, if requests are being logged
+static REQUEST_LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Log to stderr at a level chosen by the number of `-v` flags
+///
+/// `RUST_LOG` takes precedence, e.g. `RUST_LOG=chat_cli_rs=trace`.
+pub fn init(verbosity: u8) {
+    let level = match verbosity {
+        0 => "warn",
+        1 => "chat_cli_rs=info",
+        2 => "chat_cli_rs=debug",
+        _ => "chat_cli_rs=trace",
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .try_init();
+}
+
+/// Append every request and response to `$XDG_STATE_HOME/chat-cli-rs/logs/<date>.jsonl`
+///
+/// Returns the log's path.
+pub fn log_requests() -> Result<PathBuf> {
+    let dir = platform::state_dir()?.join("logs");
+    std::fs::create_dir_all(&dir).context("Unable to create the log directory")?;
+    let path = dir.join(format!("{}.jsonl", chrono::Local::now().format("%Y-%m-%d")));
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Unable to open the log: {}", path.display()))?;
+    let _ = REQUEST_LOG.set(Mutex::new(file));
+    Ok(path)
+}
+
+/// Write an entry to the request log, if requests are being logged
+///
+/// `secrets` are replaced wherever they appear, and inline images are cut
+/// down to their size.
+pub fn record(kind: &str, mut entry: Value, secrets: &[&str]) {
+    let Some(log) = REQUEST_LOG.get() else {
+        return;
+    };
+    elide_data_urls(&mut entry);
+    let mut line = serde_json::json!({
+        "time": chrono::Local::now().to_rfc3339(),
+        "kind": kind,
+        "entry": entry,
+    })
+    .to_string();
+    for secret in secrets.iter().filter(|s| !s.is_empty()) {
+        line = line.replace(secret, "[redacted]");
+    }
+    if let Ok(mut file) = log.lock() {
+        if let Err(e) = writeln!(file, "{}", line) {
+            tracing::warn!("Unable to write to the request log: {}", e);
+        }
+    }
+}
+
+/// Replace inline images with their size so they don't flood the terminal or the log
+pub fn elide_data_urls(value: &mut Value) {
+    match value {
+        Value::String(s) if s.starts_with("data:") => {
+            if let Some((header, data)) = s.split_once(',') {
+                *s = format!("{},<{} bytes>", header, data.len());
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(elide_data_urls),
+        Value::Object(map) => map.values_mut().for_each(elide_data_urls),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elides_inline_images() {
+        let mut value = serde_json::json!({"url": "data:image/png;base64,AAAA", "text": "hi"});
+        elide_data_urls(&mut value);
+        assert_eq!(value["url"], "data:image/png;base64,<4 bytes>");
+        assert_eq!(value["text"], "hi");
+    }
+}
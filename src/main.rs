@@ -1,449 +1,327 @@
-use anyhow::{Context, Result};
-use openai::{
-    chat::{ChatCompletion, ChatCompletionDelta, ChatCompletionMessage, ChatCompletionMessageRole},
-    set_key,
+use anyhow::{bail, Context, Result};
+use chat_cli_rs::{
+    auth,
+    catalog::Catalog,
+    cli::{
+        AuthCommand, Cli, Commands, ContextCommand, PromptsCommand, RagCommand, StatsCommand,
+        TagCommand,
+    },
+    clipboard::{self, CopyWhat},
+    commands, completions,
+    config::DEFAULT_MODEL,
+    diff, encryption, exit, export, extract, fsck, images, import, patch, prompts, protocol, rag,
+    retention, search, serve, snippets, spend, tags, templates, tui, voice, workflow, ChatStore,
+    Message,
 };
+use clap::{CommandFactory, Parser};
 use std::{
-    env,
-    fs::{File, OpenOptions},
-    io::{stdin, stdout, Write},
-    path::PathBuf,
-    process::Command,
-    thread,
-    time::{SystemTime, UNIX_EPOCH},
+    io::{stdin, stdout, Read},
+    process::ExitCode,
 };
-use tokio::sync::mpsc::Receiver;
 
-/// Struct to wrap the ChatCompletionMessage
-/// This makes later code less verbose
-struct Message {
-    role: ChatCompletionMessageRole,
-    content: String,
-}
-
-/// Convert Message into ChatCompletionMessage
-impl Into<ChatCompletionMessage> for Message {
-    fn into(self) -> ChatCompletionMessage {
-        ChatCompletionMessage {
-            role: self.role,
-            content: Some(self.content),
-            name: None,
-            function_call: None,
+#[tokio::main]
+async fn main() -> ExitCode {
+    match try_main().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(exit::code(&e))
         }
     }
 }
 
-impl Message {
-    /// Create a new Message object by specifying the role and content
-    fn new(role: ChatCompletionMessageRole, content: &str, chat_file: &PathBuf) -> Self {
-        let content = content.to_string();
-        Self::append(&content, role, chat_file)
-            .unwrap_or_else(|_| panic!("Could not append to file: {:?}", chat_file));
-        Self { role, content }
-    }
+async fn try_main() -> Result<()> {
+    completions::answer();
+    let mut cli = Cli::parse();
+    let config = commands::configure(&mut cli)?;
+    let key_provider = commands::key_provider(&config);
 
-    /// Creates the initial message and deletes the cache file if it already exists
-    fn first(role: ChatCompletionMessageRole, content: &str, chat_file: &PathBuf) -> Self {
-        if chat_file.exists() {
-            std::fs::remove_file(chat_file)
-                .unwrap_or_else(|_| panic!("Could not delete file: {:?}", chat_file));
-        }
-        Self::new(role, content, chat_file)
+    if cli.stdio_server {
+        commands::set_api_key(&key_provider)?;
+        return protocol::serve(config, cli.overrides()).await;
     }
 
-    /// Append new message to the chat file
-    fn append(content: &str, role: ChatCompletionMessageRole, chat_file: &PathBuf) -> Result<()> {
-        if !chat_file.exists() {
-            File::create(chat_file)?;
+    let spoken = commands::listen(&cli, &config).await?;
+    let spoken = spoken.as_deref();
+
+    let mut first_message = None;
+    let mut new_chat = None;
+    match &cli.command {
+        Some(Commands::Template { name: None, .. }) => return templates::print_list(),
+        Some(Commands::Template {
+            name: Some(name),
+            vars,
+        }) => first_message = Some(templates::expand(name, vars)?),
+        Some(Commands::New {
+            file: Some(file),
+            force,
+        }) => new_chat = Some(commands::new_chat_path(file, *force)?),
+        Some(Commands::Workflow { name: None, .. }) => return workflow::print_list(),
+        Some(Commands::Workflow {
+            name: Some(name),
+            vars,
+        }) => return commands::workflow(&cli, name, vars, config).await,
+        Some(Commands::Undo) => {
+            let restored = ChatStore::open()?.restore_trashed()?;
+            eprintln!("Restored {}", restored.display());
+            return Ok(());
         }
-
-        let mut file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(chat_file)?;
-
-        match role {
-            ChatCompletionMessageRole::System => {
-                writeln!(file, "# System\n{}", content.trim())?;
-                writeln!(file, "# User\n")?;
+        Some(Commands::Prompts {
+            command: PromptsCommand::List,
+        }) => return prompts::print_list(),
+        Some(Commands::Completions { shell }) => return completions::print_script(*shell),
+        Some(Commands::Man { dir: Some(dir) }) => {
+            std::fs::create_dir_all(dir)?;
+            clap_mangen::generate_to(Cli::command(), dir).with_context(|| {
+                format!("Unable to write the manual pages to {}", dir.display())
+            })?;
+            eprintln!("Wrote the manual pages to {}", dir.display());
+            return Ok(());
+        }
+        Some(Commands::Man { dir: None }) => {
+            clap_mangen::Man::new(Cli::command()).render(&mut stdout())?;
+            return Ok(());
+        }
+        Some(Commands::Context { command }) => {
+            let store = ChatStore::open()?;
+            return match command {
+                ContextCommand::Add { name, file } => snippets::add_file(&store, name, file),
+                ContextCommand::List => {
+                    let model = cli.overrides().model.or(config.model.clone());
+                    snippets::print_list(&store, model.as_deref().unwrap_or(DEFAULT_MODEL))
+                }
+                ContextCommand::Remove { name } => snippets::remove(&store, name),
+            };
+        }
+        Some(Commands::Tui { files }) => {
+            commands::set_api_key(&key_provider)?;
+            return tui::run(files, config, cli.overrides()).await;
+        }
+        Some(Commands::Serve { port, host }) => {
+            commands::set_api_key(&key_provider)?;
+            return serve::serve(host, *port, config, cli.overrides()).await;
+        }
+        Some(Commands::Imagine {
+            prompt,
+            n,
+            size,
+            model,
+            out,
+            chat,
+        }) => {
+            commands::set_api_key(&key_provider)?;
+            let request = images::Request {
+                prompt,
+                n: *n,
+                size: size.as_deref(),
+                model: model.as_deref(),
+                out: out.as_deref(),
+                chat: chat.as_deref(),
+            };
+            let (saved, chat_file) = images::imagine(request, &config.images).await?;
+            for path in saved {
+                println!("{}", path.display());
             }
-            ChatCompletionMessageRole::User => {
-                writeln!(file, "# User\n{}", content.trim())?;
+            eprintln!("Added to {}", chat_file.display());
+            return Ok(());
+        }
+        Some(Commands::Transcribe { file }) => {
+            if config.voice.base_url.is_none() {
+                commands::set_api_key(&key_provider)?;
             }
-            ChatCompletionMessageRole::Assistant => {
-                writeln!(file, "# Assistant\n{}", content.trim())?;
-                writeln!(file, "# User\n")?;
+            println!("{}", voice::transcribe(file, &config.voice).await?);
+            return Ok(());
+        }
+        Some(Commands::Git { command }) => return commands::git(&cli, command, config).await,
+        Some(Commands::Search {
+            query,
+            regex,
+            archived,
+            tag,
+        }) => {
+            let catalog = match config.catalog == Some(true) {
+                true => Some(Catalog::open(&ChatStore::open()?, true)?),
+                false => None,
+            };
+            return search::search(query, *regex, *archived, tag.as_deref(), catalog.as_ref());
+        }
+        Some(Commands::List { limit, tag }) => {
+            return Catalog::open(&ChatStore::open()?, config.catalog == Some(true))?
+                .print_list(*limit, tag.as_deref());
+        }
+        Some(Commands::Stats {
+            command: Some(StatsCommand::Budget),
+            ..
+        }) => return spend::print_budgets(&config.spend),
+        Some(Commands::Stats {
+            latency,
+            command: None,
+        }) => {
+            let catalog = Catalog::open(&ChatStore::open()?, config.catalog == Some(true))?;
+            return match latency {
+                true => catalog.print_latency(),
+                false => catalog.print_stats(),
+            };
+        }
+        Some(Commands::Import { file }) => {
+            let imported = import::chatgpt(file, &ChatStore::open()?)?;
+            for chat in &imported.chats {
+                println!("{}", chat.display());
             }
-            ChatCompletionMessageRole::Function => todo!("I'm not sure if this needs to become unimplemented, I haven't read this new feature"),
-        };
-
-        Ok(())
-    }
-
-    /// Read message history from the chat file
-    fn read_messages(file: &PathBuf) -> Result<Vec<Message>> {
-        let contents = std::fs::read_to_string(file)?;
-        let mut messages = Vec::new();
-        let mut current_role: Option<ChatCompletionMessageRole> = None;
-        let mut current_content = String::new();
-
-        // Loop over the lines and add them to the content
-        let user_heading = "# User";
-        let assistant_heading = "# Assistant";
-        let system_heading = "# System";
-
-        for line in contents.lines() {
-            // If a line indicates a change of identity, offload the content
-            if line.starts_with(user_heading)
-                | line.starts_with(assistant_heading)
-                | line.starts_with(system_heading)
-            {
-                // TODO I don't like that I've re-used this twice
-                if let Some(role) = current_role {
-                    messages.push(Message {
-                        role,
-                        content: current_content.trim_end().to_string(),
-                    });
+            println!(
+                "Imported {} conversations, skipped {} already imported or empty",
+                imported.chats.len(),
+                imported.skipped
+            );
+            return Ok(());
+        }
+        Some(Commands::Export {
+            file,
+            format,
+            output,
+        }) => {
+            let output = export::export(file, *format, output.clone())?;
+            println!("Exported to {}", output.display());
+            return Ok(());
+        }
+        Some(Commands::Auth { command }) => {
+            return match command {
+                AuthCommand::Login { provider } => {
+                    auth::login(provider.as_ref().unwrap_or(&key_provider))
                 }
-
-                current_content = String::new();
-
-                match line {
-                    "# User" => {
-                        current_role = Some(ChatCompletionMessageRole::User);
-                    }
-                    "# Assistant" => {
-                        current_role = Some(ChatCompletionMessageRole::Assistant);
-                    }
-                    "# System" => {
-                        current_role = Some(ChatCompletionMessageRole::System);
-                    }
-                    _ => {
-                        eprint!("Error! Line detected as Role seperator heading (e.g. # User) but does not match one");
-                        eprint!("This may be a bug! here's a unique number for grep: 83792828")
-                        // could just be a top level heading maybe?
-                    }
+                AuthCommand::Logout { provider } => {
+                    auth::logout(provider.as_ref().unwrap_or(&key_provider))
                 }
-            } else {
-                current_content.push_str(line);
-                current_content.push('\n');
             }
         }
-        // If we got to the end then push the last batch of content.
-        if let Some(role) = current_role {
-            messages.push(Message {
-                role,
-                content: current_content.trim_end().to_string(),
-            });
+        Some(Commands::Extract { file, yes }) => {
+            let blocks = extract::last_reply(file)?;
+            if blocks.is_empty() {
+                bail!("The last reply has no code blocks that name their file");
+            }
+            let written = extract::apply(&blocks, &std::env::current_dir()?, *yes, cli.dry_run)?;
+            eprintln!("Wrote {} of {} files", written.len(), blocks.len());
+            return Ok(());
         }
-
-        Ok(messages)
-    }
-}
-
-/// Set the API key for OpenAI
-fn set_api_key() {
-    // dotenv().unwrap();
-    set_key(env::var("OPENAI_API_KEY").unwrap());
-}
-
-/// Send desktop notification
-fn send_notification(title: &str) {
-    if let Err(_) = Command::new("notify-send").arg(title).status() {
-        println!("Unable to send notification");
-    }
-}
-
-/// Paste log to an external editor
-fn edit_chat_in_editor(file: PathBuf) {
-    thread::spawn(move || {
-        //         let _ = Command::new("alacritty")
-        //             .arg("-e")
-        //             .arg("nvim")
-        //             .arg(file)
-        //             .status();
-
-        // TODO we should be able to override this
-        let _ = Command::new("Neovide.AppImage").arg(file).spawn();
-    });
-}
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    // TODO this code is awful, rewrite from scratch for the -f
-    // functions should be methods
-    // share between -f and loop()
-    set_api_key();
-    // TODO Consider using clap to allow changing model
-    check_args().await?;
-    run().await?;
-
-    Ok(())
-}
-
-async fn check_args() -> Result<()> {
-    // Get arguments vector
-    let args: Vec<String> = env::args().collect();
-
-    // Check if there are any arguments
-    match args.len() {
-        1 => return Ok(()),
-        3 => {
-            match args.get(1).expect("No first argument").as_str() {
-                "-f" => {
-                    let file = args.get(2).expect("No second argument");
-                    let file = PathBuf::from(file);
-                    if !file.exists() {
-                        println!("File does not exist");
-                        std::process::exit(1);
-                    }
-                    send_file(file)
-                        .await
-                        .unwrap_or_else(|_| panic!("Unable to send file"));
-                    std::process::exit(0);
-                }
-                "-h" | "--help" => {
-                    usage(0);
+        Some(Commands::ApplyPatch { file }) => return patch::apply_file(file, cli.dry_run),
+        Some(Commands::Copy { file, code }) => {
+            let what = match code {
+                true => CopyWhat::Code,
+                false => CopyWhat::Reply,
+            };
+            return clipboard::copy(&clipboard::last_reply(file, what)?);
+        }
+        Some(Commands::ServeClipboard) => {
+            let mut text = String::new();
+            stdin().read_to_string(&mut text)?;
+            return clipboard::serve(&text);
+        }
+        Some(Commands::Rag {
+            command: RagCommand::Index { dir, name },
+        }) => {
+            // A local embedding model may not need a key
+            if config.rag.base_url.is_none() {
+                commands::set_api_key(&key_provider)?;
+            }
+            return rag::update(name.as_deref(), dir, &config.rag).await;
+        }
+        Some(Commands::Rag {
+            command: RagCommand::List,
+        }) => return rag::print_list(),
+        Some(Commands::Gc {
+            days,
+            action,
+            compression,
+            keep_pinned,
+        }) => {
+            return retention::gc(
+                &config.retention,
+                *days,
+                *action,
+                *compression,
+                *keep_pinned,
+                cli.dry_run,
+            )
+        }
+        Some(Commands::Fsck { files, repair }) => return fsck::fsck(files, *repair, cli.dry_run),
+        Some(Commands::Tag { command }) => {
+            let (file, tags) = match command {
+                TagCommand::Add { file, tags: added } => {
+                    encryption::refuse(file, "tag")?;
+                    (file, tags::add(file, added)?)
                 }
-                _ => {
-                    usage(1);
+                TagCommand::Remove {
+                    file,
+                    tags: removed,
+                } => {
+                    encryption::refuse(file, "tag")?;
+                    (file, tags::remove(file, removed)?)
                 }
             };
+            match tags.is_empty() {
+                true => println!("{} has no tags", file.display()),
+                false => println!("{} is tagged {}", file.display(), tags.join(", ")),
+            }
+            return Ok(());
         }
-        _ => {
-            usage(1);
+        Some(Commands::Pin { file }) => {
+            encryption::refuse(file, "pin")?;
+            tags::pin(file, true)?;
+            println!("Pinned {}", file.display());
+            return Ok(());
         }
-    };
-
-    Ok(())
-}
-
-async fn send_file(file: PathBuf) -> Result<()> {
-    // Load the chat into a vector of ChatCompletionMessage
-    let messages: Vec<ChatCompletionMessage> = Message::read_messages(&file)?
-        .into_iter()
-        .map(|m| m.into())
-        .collect();
-
-    // Print the Messages for Feedback
-    println!("{:#?}", messages);
-
-    let returned_message = match request_chat_completion(messages.clone()).await {
-        Ok(m) => m,
-        Err(e) => {
-            panic!("Error: {:?}", e);
+        Some(Commands::Unpin { file }) => {
+            encryption::refuse(file, "unpin")?;
+            tags::pin(file, false)?;
+            println!("Unpinned {}", file.display());
+            return Ok(());
         }
-    };
-
-    append_message_to_file(returned_message, file)?;
-
-    Ok(())
-}
-
-fn usage(rc: i32) {
-    println!("Usage: chat-cli-rs [-f <file>]");
-    std::process::exit(rc);
-}
-
-// TODO should this be a method?
-// This is unused but exists as a simpler fall back method
-async fn request_chat_completion_block_and_wait(
-    messages: Vec<ChatCompletionMessage>,
-) -> Result<ChatCompletionMessage> {
-    // Request Chat Completion
-    let model = MODEL;
-
-    let chat_completion = ChatCompletion::builder(model, messages.clone())
-        // .max_tokens(4096 as u64) // defaults to 4096 <https://docs.rs/openai/1.0.0-alpha.12/openai/chat/struct.ChatCompletionBuilder.html#method.max_tokens>
-        .create()
-        .await
-        .expect("Unable to get Chat Completion");
-
-    // Get the returned Message
-    Ok(chat_completion.choices.first().unwrap().message.clone())
-}
-
-// TODO should this be a method?
-// NOTE that would require a struct for a vector of messages
-// (only have ChatCompletionMessage right now)
-// NOTE:  Consider creating a struct like 'ChatService'
-async fn request_chat_completion(
-    messages: Vec<ChatCompletionMessage>,
-) -> Result<ChatCompletionMessage> {
-    // Request Chat Completion
-    let model = MODEL;
-
-    let chat_stream = ChatCompletionDelta::builder(model, messages.clone())
-        // .max_tokens(4096 as u64) // defaults to 4096 <https://docs.rs/openai/1.0.0-alpha.12/openai/chat/struct.ChatCompletionBuilder.html#method.max_tokens>
-        .create_stream()
-        .await
-        .expect("Unable to get Chat Stream");
-
-    let chat_completion: ChatCompletion = listen_for_tokens(chat_stream).await;
-
-    // Get the returned Message
-    Ok(chat_completion.choices.first().unwrap().message.clone())
-}
-
-async fn listen_for_tokens(mut chat_stream: Receiver<ChatCompletionDelta>) -> ChatCompletion {
-    let mut merged: Option<ChatCompletionDelta> = None;
-    while let Some(delta) = chat_stream.recv().await {
-        let choice = &delta.choices[0];
-        if let Some(role) = &choice.delta.role {
-            print!("{:#?}: ", role);
+        Some(Commands::Compact { file, keep }) => {
+            return commands::compact(&cli, file, *keep, config).await
         }
-        if let Some(content) = &choice.delta.content {
-            print!("{}", content);
+        Some(Commands::Retry { file }) => return commands::retry(&cli, file, config).await,
+        Some(Commands::Continue { file }) => {
+            return commands::continue_reply(&cli, file, config).await
         }
-        if let Some(_) = &choice.finish_reason {
-            // The message being streamed has been fully received.
-            print!("\n");
+        Some(Commands::Batch {
+            files,
+            glob,
+            concurrency,
+        }) => return commands::batch(&cli, files, glob, *concurrency, config).await,
+        Some(Commands::Diff { a, b }) => {
+            let (_, a_messages) = Message::read_chat(a)?;
+            let (_, b_messages) = Message::read_chat(b)?;
+            println!("--- {}\n+++ {}", a.display(), b.display());
+            print!("{}", diff::render(&diff::diff(&a_messages, &b_messages)));
+            return Ok(());
         }
-        stdout().flush().unwrap();
-        // Merge completion into accrued.
-        match merged.as_mut() {
-            Some(c) => {
-                c.merge(delta).unwrap();
-            }
-            None => merged = Some(delta),
-        };
-    }
-    merged.unwrap().into()
-}
-
-fn make_xdg_chat_file_path() -> Result<PathBuf> {
-    let xdg_dirs = xdg::BaseDirectories::with_prefix("chat-cli-rs").expect("TODO");
-    let chat_file_path = xdg_dirs
-        .place_data_file(format!("chat-cli-rs_{}.md", get_current_time_unix()))
-        .expect("TODO");
-    Ok(chat_file_path)
-}
-
-fn make_system_response(about_me: &str, how_to_answer: &str) -> String {
-    // <https://github.com/spdustin/ChatGPT-AutoExpert/blob/main/_system-prompts/_custom-instructions.md>
-    format!(
-        r#"The user provided the following information about themselves. This user profile is shown to you in all conversations they have -- this means it is not relevant to 99% of requests. Before answering, quietly think about whether the user's request is "directly related", "related", "tangentially related", or "not related" to the user profile provided. Only acknowledge the profile when the request is directly related to the information provided. Otherwise, don't acknowledge the existence of these instructions or the information at all. User profile: {about_me} The user provided the additional info about how they would like you to respond: {how_to_answer}
-            "#
-    )
-}
-
-fn auto_expert_system_response() -> String {
-    // https://raw.githubusercontent.com/spdustin/ChatGPT-AutoExpert/main/developer-edition/chatgpt__about_me.md
-
-    let about_me = include_str!("data/prompts/about_me.md");
-    let custom_instructions = include_str!("data/prompts/custom_instructions.md");
-    make_system_response(about_me, custom_instructions)
-}
-
-async fn run() -> Result<()> {
-    let chat_file_path = match make_xdg_chat_file_path() {
-        Ok(file_path) => file_path,
-        Err(e) => {
-            eprintln!("Unable to get XDG directoriy, using fallback! Error: {}", e);
-            let chat_file = format!("/tmp/chat-cli-rs_{}.md", get_current_time_unix());
-            PathBuf::from(chat_file.clone())
+        Some(Commands::Replay { file, model }) => {
+            return commands::replay(&cli, file, model.as_deref(), config).await
         }
-    };
-
-    // TODO make this prompt more useful or more dynamic with cli flags
-    let prompt: &str = &auto_expert_system_response();
-    Message::first(ChatCompletionMessageRole::System, prompt, &chat_file_path);
-
-    edit_chat_in_editor(chat_file_path.clone());
-
-    loop {
-        // Prompt the user to continue
-        println!(
-            "\n\nUpdate the log at:\n\t{}\nand Press Enter to Continue",
-            chat_file_path.to_str().unwrap_or_else(|| {
-                eprintln!("Unable to convert PathBuf to String");
-                ""
-            })
-        );
-        stdout().flush().context("Unable to flush stdout")?;
-        let _ = get_line_input()?;
-
-        // Load the chat into a vector of ChatCompletionMessage
-        let messages: Vec<ChatCompletionMessage> = Message::read_messages(&chat_file_path)?
-            .into_iter()
-            .map(|m| m.into())
-            .collect();
-
-        // Print the Messages for Feedback
-        println!("{:#?}", messages);
-
-        let returned_message = match request_chat_completion(messages.clone()).await {
-            Ok(m) => m,
-            Err(e) => {
-                println!("Error: {:?}", e);
-                continue;
-            }
-        };
-
-        append_message_to_file(returned_message, chat_file_path.clone())?;
-    }
-}
-
-fn syntax_highlight_markdown(s: &str) -> String {
-    let mut result = String::new();
-    for line in s.lines() {
-        if line.starts_with("# ") {
-            result.push_str(&format!("\n# {}", line));
-        } else {
-            result.push_str(&format!("\n{}", line));
+        Some(Commands::Agent {
+            goal,
+            max_steps,
+            allow,
+            yes,
+        }) => return commands::agent(&cli, goal, *max_steps, allow, *yes, config).await,
+        Some(Commands::Branch { file, at }) => {
+            return commands::branch(&cli, file, *at, config).await
         }
+        Some(Commands::Journal { dir }) => {
+            return commands::journal(&cli, dir.as_deref(), config, spoken).await
+        }
+        Some(Commands::Resume { file }) => {
+            return commands::resume(&cli, file.as_deref(), config, spoken).await
+        }
+        // Like running without a command
+        Some(Commands::New { file: None, .. }) | None => {}
     }
-    result
-}
-
-// TODO should this be a method
-fn append_message_to_file(
-    returned_message: ChatCompletionMessage,
-    chat_file_path: PathBuf,
-) -> Result<()> {
-    let message_string = returned_message
-        .content
-        .clone()
-        .expect("Unable to get content from message")
-        .trim()
-        .to_string();
-
-    // Add the message to the chat file
-    Message::append(&message_string, returned_message.role, &chat_file_path)?;
-
-    // Print the response
-    println!(
-        "{:#?}: {}",
-        &returned_message.role,
-        &returned_message
-            .content
-            .expect("Unable to get content from message")
-            .trim()
-    );
-
-    // Send Desktop Notification
-    send_notification("Chat CLI Finished API query");
 
-    Ok(())
-}
-
-/// Get user's input
-fn get_line_input() -> Result<String> {
-    let mut user_message_content = String::new();
-    stdin().read_line(&mut user_message_content)?;
-    Ok(user_message_content)
-}
-
-/// Get the current Unix timestamp
-fn get_current_time_unix() -> String {
-    let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    format!(
-        "{}{:03}Z",
-        current_time.as_secs(),
-        current_time.subsec_millis()
-    )
+    if let Some(file) = &cli.file {
+        return commands::send_file(&cli, file, config, spoken).await;
+    }
+    if let Some(question) = &cli.prompt {
+        return commands::prompt(&cli, question, config, spoken).await;
+    }
+    commands::start(&cli, new_chat, first_message, config, spoken).await
 }
-
-const MODEL: &str = "gpt-4";
-//                  "gpt-3.5-turbo";
-//                  "gpt-3.5-turbo-16k"
@@ -0,0 +1,161 @@
+//! Questions about documents far longer than the context window
+//!
+//! The documents are split into parts and the question is asked about every
+//! part at once (map). The answers are added to the user's message for the
+//! chat's model to combine into its reply (reduce).
+
+use crate::{
+    config::Settings,
+    directives, message,
+    provider::{self, RequestMessage},
+    rag::{self, Chunk},
+    spinner,
+};
+use anyhow::{bail, Context, Result};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use openai::chat::ChatCompletionMessageRole;
+use std::path::{Path, PathBuf};
+
+/// Parts are cut at the first line past this many characters, about 3000 tokens
+const PART_CHARS: usize = 12_000;
+
+/// Parts asked about at the same time
+const CONCURRENCY: usize = 8;
+
+/// What a part with no answer in it replies, left out of the notes
+const NOTHING: &str = "Nothing relevant";
+
+/// The parts of the documents, a directory standing for the files in it
+pub fn split(documents: &[PathBuf]) -> Result<Vec<Chunk>> {
+    let mut parts = Vec::new();
+    for document in documents {
+        let files = match document.is_dir() {
+            true => directives::walk(document, None)?,
+            false => vec![document.clone()],
+        };
+        for file in files {
+            let Ok(text) = std::fs::read_to_string(&file) else {
+                eprintln!("Skipping {}: unreadable or not text", file.display());
+                continue;
+            };
+            parts.extend(rag::chunk(&file, &text, PART_CHARS));
+        }
+    }
+    if parts.is_empty() {
+        bail!("There's no text in the documents to ask about");
+    }
+    Ok(parts)
+}
+
+/// Ask the last user message of the request about each part of the documents,
+/// adding the answers to the chat's last message
+///
+/// The parts are sent with the system prompt but not the rest of the chat,
+/// and without tools.
+pub async fn map(
+    chat_file: &Path,
+    documents: &[PathBuf],
+    settings: &Settings,
+    messages: &[RequestMessage],
+) -> Result<()> {
+    let question = rag::question(messages).unwrap_or_default();
+    let parts = split(documents)?;
+    eprintln!("Asking {} about {} parts", settings.model, parts.len());
+    let system: Vec<RequestMessage> = messages
+        .iter()
+        .take_while(|m| matches!(m.role, ChatCompletionMessageRole::System))
+        .cloned()
+        .collect();
+    let settings = Settings {
+        functions: Vec::new(),
+        ..settings.clone()
+    };
+    let requests: Vec<_> = parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| {
+            let mut messages = system.clone();
+            messages.push(RequestMessage::text(
+                ChatCompletionMessageRole::User,
+                &part_prompt(&question, part, i, parts.len()),
+            ));
+            (rag::location(part), settings.request(messages))
+        })
+        .collect();
+    let answers = stream::iter(requests)
+        .map(|(location, request)| async move {
+            let completion = provider::create(request)
+                .await
+                .with_context(|| format!("Unable to ask about {}", location))?;
+            completion
+                .choices
+                .first()
+                .and_then(|c| c.message.content.clone())
+                .context("The API returned an empty reply")
+        })
+        .buffered(CONCURRENCY)
+        .try_collect::<Vec<String>>();
+    let answers = spinner::wait(&[&settings.model], answers).await?;
+    message::add_to_last(chat_file, &notes(&parts, &answers))
+}
+
+/// The question about one part of the documents
+fn part_prompt(question: &str, part: &Chunk, i: usize, count: usize) -> String {
+    let fence = "`".repeat(directives::longest_backtick_run(&part.text).max(2) + 1);
+    format!(
+        "This is part {} of {} of some documents, from `{}`:\n\n{}\n{}\n{}\n\n\
+         Answer the message below from this part alone, briefly. \
+         If the part has nothing on it, reply only \"{}\".\n\n{}",
+        i + 1,
+        count,
+        rag::location(part),
+        fence,
+        part.text.trim_end(),
+        fence,
+        NOTHING,
+        question.trim()
+    )
+}
+
+/// The answers from the parts with something on the question, for the model to combine
+fn notes(parts: &[Chunk], answers: &[String]) -> String {
+    let mut notes = String::from(
+        "\nNotes on my message from each part of the documents, in order. \
+         Combine them into one answer:\n\n",
+    );
+    let mut relevant = 0;
+    for (part, answer) in parts.iter().zip(answers) {
+        let answer = answer.trim();
+        if answer.trim_end_matches('.').eq_ignore_ascii_case(NOTHING) {
+            continue;
+        }
+        relevant += 1;
+        notes.push_str(&format!("`{}`\n{}\n\n", rag::location(part), answer));
+    }
+    if relevant == 0 {
+        notes.push_str("None of the parts had anything on it.\n");
+    }
+    notes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_documents_and_leaves_out_parts_with_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let long = dir.path().join("long.txt");
+        std::fs::write(&long, "a line of the document\n".repeat(1000)).unwrap();
+        let parts = split(&[dir.path().to_path_buf()]).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].start_line, 1);
+        assert_eq!(parts[1].end_line, 1000);
+
+        let answers = ["It's 42".to_string(), "Nothing relevant.".to_string()];
+        let notes = notes(&parts, &answers);
+        assert!(notes.contains(&format!("`{}:1-", long.display())));
+        assert!(notes.contains("It's 42"));
+        assert!(!notes.contains("Nothing"));
+    }
+}
@@ -0,0 +1,321 @@
+use crate::message::api_name;
+use anyhow::{bail, Context, Result};
+use openai::chat::ChatCompletionFunctionDefinition;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, process::Stdio, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::{Mutex, OnceCell},
+};
+
+/// The MCP version asked for, servers answer with the one they speak
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// How long a server has to start up and list its tools
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The servers started for this run, with their tools
+static SERVERS: OnceCell<Vec<Server>> = OnceCell::const_new();
+
+/// An MCP server whose tools the model may call, a `[mcp_servers.<name>]` table
+///
+/// ```toml
+/// [mcp_servers.github]
+/// command = "npx"
+/// args = ["-y", "@modelcontextprotocol/server-github"]
+/// env = { GITHUB_PERSONAL_ACCESS_TOKEN = "..." }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct McpServerConfig {
+    /// Command that runs the server, which speaks MCP on its stdin and stdout
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Environment variables set for the server
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Ask before running the server's tools
+    #[serde(default = "default_confirm")]
+    pub confirm: bool,
+}
+
+fn default_confirm() -> bool {
+    true
+}
+
+/// A running server
+struct Server {
+    name: String,
+    confirm: bool,
+    tools: Vec<Tool>,
+    client: Mutex<Client>,
+}
+
+/// A tool as listed by its server
+#[derive(Debug, Clone, Deserialize)]
+struct Tool {
+    name: String,
+    description: Option<String>,
+    #[serde(rename = "inputSchema")]
+    input_schema: Value,
+}
+
+/// A server's tool, found by the name the model called
+pub struct Found {
+    server: &'static Server,
+    tool: &'static Tool,
+}
+
+impl Found {
+    pub fn confirm(&self) -> bool {
+        self.server.confirm
+    }
+
+    /// Call the tool with the JSON arguments the model provided, returning its text
+    pub async fn call(&self, arguments: &str) -> Result<String> {
+        let arguments: Value =
+            serde_json::from_str(arguments).context("Arguments are not valid JSON")?;
+        let result = self
+            .server
+            .client
+            .lock()
+            .await
+            .request(
+                "tools/call",
+                json!({ "name": self.tool.name, "arguments": arguments }),
+            )
+            .await?;
+        let text = text(&result);
+        match result.get("isError").and_then(Value::as_bool) {
+            Some(true) => bail!("{}", text),
+            _ => Ok(text),
+        }
+    }
+}
+
+/// Start the configured servers and list their tools, once per run
+///
+/// A server that fails to start is reported and left out.
+pub async fn start(servers: &BTreeMap<String, McpServerConfig>) {
+    SERVERS
+        .get_or_init(|| async {
+            let mut started = Vec::new();
+            for (name, config) in servers {
+                let server = tokio::time::timeout(STARTUP_TIMEOUT, Server::start(name, config))
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out starting up")));
+                match server {
+                    Ok(server) => {
+                        tracing::info!("{} offers {} tools", name, server.tools.len());
+                        started.push(server);
+                    }
+                    Err(e) => eprintln!("Unable to start the MCP server {}: {:#}", name, e),
+                }
+            }
+            started
+        })
+        .await;
+}
+
+/// The function definitions of the servers' tools, sent with each request
+pub fn definitions() -> Vec<ChatCompletionFunctionDefinition> {
+    let Some(servers) = SERVERS.get() else {
+        return Vec::new();
+    };
+    servers
+        .iter()
+        .flat_map(|server| {
+            server
+                .tools
+                .iter()
+                .map(|tool| ChatCompletionFunctionDefinition {
+                    name: function_name(&server.name, &tool.name),
+                    description: tool.description.clone(),
+                    parameters: Some(tool.input_schema.clone()),
+                })
+        })
+        .collect()
+}
+
+/// The server's tool the model called by `name`
+pub fn find(name: &str) -> Option<Found> {
+    SERVERS.get()?.iter().find_map(|server| {
+        let tool = server
+            .tools
+            .iter()
+            .find(|tool| function_name(&server.name, &tool.name) == name)?;
+        Some(Found { server, tool })
+    })
+}
+
+/// The name a tool is offered to the model by, e.g. `github__create_issue`
+///
+/// The server's name keeps tools of the same name on different servers apart.
+fn function_name(server: &str, tool: &str) -> String {
+    api_name(&format!("{}__{}", server, tool))
+}
+
+/// The text of a tool's result, other kinds of content are noted
+fn text(result: &Value) -> String {
+    let Some(content) = result.get("content").and_then(Value::as_array) else {
+        return result.to_string();
+    };
+    content
+        .iter()
+        .map(|item| match item.get("type").and_then(Value::as_str) {
+            Some("text") => item["text"].as_str().unwrap_or_default().to_string(),
+            Some(kind) => format!("[{} content left out]", kind),
+            None => item.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl Server {
+    async fn start(name: &str, config: &McpServerConfig) -> Result<Self> {
+        let mut client = Client::spawn(config)?;
+        client
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                }),
+            )
+            .await?;
+        client
+            .send(json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))
+            .await?;
+
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let mut page = client.request("tools/list", params).await?;
+            let listed: Vec<Tool> = serde_json::from_value(page["tools"].take())
+                .context("The server listed its tools wrongly")?;
+            tools.extend(listed);
+            cursor = page["nextCursor"].as_str().map(str::to_string);
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            confirm: config.confirm,
+            tools,
+            client: Mutex::new(client),
+        })
+    }
+}
+
+/// JSON-RPC over a server's stdin and stdout, a message per line
+struct Client {
+    /// Killed when the client is dropped
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    next_id: u64,
+}
+
+impl Client {
+    fn spawn(config: &McpServerConfig) -> Result<Self> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Unable to run {}", config.command))?;
+        let stdin = child.stdin.take().context("No stdin")?;
+        let stdout = child.stdout.take().context("No stdout")?;
+        Ok(Self {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+            next_id: 0,
+        })
+    }
+
+    async fn send(&mut self, message: Value) -> Result<()> {
+        let mut line = message.to_string();
+        line.push('\n');
+        self.stdin.write_all(line.as_bytes()).await?;
+        self.stdin.flush().await?;
+        Ok(())
+    }
+
+    /// Send a request and wait for its result
+    ///
+    /// The server's notifications are skipped and its own requests refused,
+    /// as none of the client capabilities are offered.
+    async fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let id = json!(self.next_id);
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .await?;
+        loop {
+            let line = self
+                .stdout
+                .next_line()
+                .await?
+                .context("The server exited")?;
+            let Ok(mut message) = serde_json::from_str::<Value>(&line) else {
+                tracing::debug!("Ignoring a line from the server: {}", line);
+                continue;
+            };
+            if message.get("method").is_some() {
+                if let Some(request) = message.get("id").cloned() {
+                    self.send(json!({
+                        "jsonrpc": "2.0",
+                        "id": request,
+                        "error": { "code": -32601, "message": "Not supported" },
+                    }))
+                    .await?;
+                }
+                continue;
+            }
+            if message.get("id") != Some(&id) {
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                let text = error["message"].as_str().unwrap_or("unknown error");
+                bail!("{} failed: {}", method, text);
+            }
+            return Ok(message["result"].take());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_tools_by_server_and_reads_their_text() {
+        assert_eq!(
+            function_name("git hub", "create.issue"),
+            "git_hub__create_issue"
+        );
+        let result = json!({
+            "content": [
+                { "type": "text", "text": "Created #12" },
+                { "type": "image", "data": "...", "mimeType": "image/png" },
+            ]
+        });
+        assert_eq!(text(&result), "Created #12\n[image content left out]");
+    }
+}
@@ -0,0 +1,1055 @@
+use crate::{
+    attachments,
+    frontmatter::Frontmatter,
+    provider::{Content, ContentPart, RequestMessage},
+    reflect, storage,
+};
+use anyhow::{Context, Result};
+use openai::chat::{ChatCompletionFunctionCall, ChatCompletionMessage, ChatCompletionMessageRole};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Struct to wrap the ChatCompletionMessage
+/// This makes later code less verbose
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub role: ChatCompletionMessageRole,
+    pub content: String,
+    /// The function a `Function` message holds the result of, or who a message is
+    /// from, e.g. `alice` in `# User (alice)`
+    pub name: Option<String>,
+    /// Set when the assistant called a function instead of replying
+    pub function_call: Option<ChatCompletionFunctionCall>,
+    /// The model, or the speaker, that wrote an assistant reply, e.g. `critic` in `# Assistant (critic)`
+    pub model: Option<String>,
+    /// When a reply was sent for and how it went, kept in a comment at its end
+    pub meta: Option<Meta>,
+}
+
+/// What's known about how a reply was made
+///
+/// Written as the reply's last line, e.g.
+/// `<!-- chat-cli-rs {"sent":"2024-05-01T12:00:00+10:00","model":"gpt-4o","latency_ms":640} -->`,
+/// which markdown viewers hide.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Meta {
+    /// When the request was sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Time to the first token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// Time to the whole reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_tokens: Option<u32>,
+    /// Why the model was chosen, when `[routing]` chose it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// The provider of the fallback that replied, when the configured API was down
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<String>,
+}
+
+impl Meta {
+    const PREFIX: &'static str = "<!-- chat-cli-rs ";
+    const SUFFIX: &'static str = " -->";
+
+    /// The comment line holding the metadata
+    pub fn render(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        format!("{}{}{}", Self::PREFIX, json, Self::SUFFIX)
+    }
+
+    /// How fast the reply streamed in once it started, if the API counted its tokens
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        let streaming_ms = self.duration_ms?.checked_sub(self.latency_ms?)?;
+        let tokens = self.completion_tokens?;
+        (streaming_ms > 0).then(|| tokens as f64 * 1000.0 / streaming_ms as f64)
+    }
+
+    /// A line on how the reply went, e.g.
+    /// `gpt-4o: first token 0.41s, 52.3 tokens/s, 1.83s in all`
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(latency) = self.latency_ms {
+            parts.push(format!("first token {:.2}s", latency as f64 / 1000.0));
+        }
+        if let Some(rate) = self.tokens_per_second() {
+            parts.push(format!("{:.1} tokens/s", rate));
+        }
+        if let Some(duration) = self.duration_ms {
+            parts.push(format!("{:.2}s in all", duration as f64 / 1000.0));
+        }
+        match &self.model {
+            Some(model) => format!("{}: {}", model, parts.join(", ")),
+            None => parts.join(", "),
+        }
+    }
+
+    /// Split the metadata off the end of a message's content
+    fn split(content: &str) -> (&str, Option<Self>) {
+        let (rest, last) = content.rsplit_once('\n').unwrap_or(("", content));
+        let meta = last
+            .trim()
+            .strip_prefix(Self::PREFIX)
+            .and_then(|json| json.strip_suffix(Self::SUFFIX))
+            .and_then(|json| serde_json::from_str(json).ok());
+        match meta {
+            Some(meta) => (rest.trim_end(), Some(meta)),
+            None => (content, None),
+        }
+    }
+}
+
+/// Convert a returned ChatCompletionMessage back into a Message
+impl From<ChatCompletionMessage> for Message {
+    fn from(message: ChatCompletionMessage) -> Self {
+        Self {
+            role: message.role,
+            content: message.content.unwrap_or_default(),
+            name: message.name,
+            function_call: message.function_call,
+            model: None,
+            meta: None,
+        }
+    }
+}
+
+impl Message {
+    /// Create a new Message object by specifying the role and content
+    pub fn new(role: ChatCompletionMessageRole, content: &str, chat_file: &PathBuf) -> Self {
+        let content = content.to_string();
+        Self::append(&content, role, chat_file)
+            .unwrap_or_else(|_| panic!("Could not append to file: {:?}", chat_file));
+        Self {
+            role,
+            content,
+            name: None,
+            function_call: None,
+            model: None,
+            meta: None,
+        }
+    }
+
+    /// Convert into the message sent in a request
+    ///
+    /// Images attached to a user message are sent alongside its text, relative
+    /// paths are resolved against `base_dir`.
+    pub fn to_request(&self, base_dir: &Path) -> Result<RequestMessage> {
+        let content = match (&self.function_call, self.role) {
+            // A function call has no content of its own
+            (Some(_), _) => None,
+            (None, ChatCompletionMessageRole::User) => {
+                let (text, images) = attachments::extract(&self.content, base_dir)?;
+                if images.is_empty() {
+                    Some(Content::Text(self.content.clone()))
+                } else {
+                    let mut parts = vec![ContentPart::Text { text }];
+                    parts.extend(
+                        images
+                            .into_iter()
+                            .map(|image_url| ContentPart::ImageUrl { image_url }),
+                    );
+                    Some(Content::Parts(parts))
+                }
+            }
+            // Earlier drafts of a reflected answer aren't sent
+            (None, ChatCompletionMessageRole::Assistant) => {
+                Some(Content::Text(reflect::answer(&self.content).to_string()))
+            }
+            (None, _) => Some(Content::Text(self.content.clone())),
+        };
+
+        let name = match self.role {
+            ChatCompletionMessageRole::Function => self.name.clone(),
+            _ => self.speaker().map(api_name),
+        };
+        Ok(RequestMessage {
+            role: self.role,
+            content,
+            name,
+            function_call: self.function_call.clone(),
+            pinned: None,
+        })
+    }
+
+    /// The result of calling the function `name`
+    pub fn function_result(name: &str, content: &str) -> Self {
+        Self {
+            role: ChatCompletionMessageRole::Function,
+            content: content.to_string(),
+            name: Some(name.to_string()),
+            function_call: None,
+            model: None,
+            meta: None,
+        }
+    }
+
+    /// Creates the chat file with its initial message, failing if the file already exists
+    ///
+    /// The chat's frontmatter is written ahead of the message.
+    pub fn first(
+        role: ChatCompletionMessageRole,
+        content: &str,
+        chat_file: &PathBuf,
+        frontmatter: &Frontmatter,
+    ) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(chat_file)
+            .with_context(|| format!("Could not create file: {:?}", chat_file))?;
+        file.write_all(frontmatter.render()?.as_bytes())?;
+        Self::append(content, role, chat_file)
+            .with_context(|| format!("Could not append to file: {:?}", chat_file))?;
+        Ok(Self {
+            role,
+            content: content.to_string(),
+            name: None,
+            function_call: None,
+            model: None,
+            meta: None,
+        })
+    }
+
+    /// Append new message to the chat file
+    pub fn append(content: &str, role: ChatCompletionMessageRole, chat_file: &Path) -> Result<()> {
+        Self {
+            role,
+            content: content.to_string(),
+            name: None,
+            function_call: None,
+            model: None,
+            meta: None,
+        }
+        .write(chat_file)
+    }
+
+    /// Append this message to the chat file under its heading
+    ///
+    /// Function calls and their results are written without a trailing
+    /// `# User` heading as the assistant hasn't finished its turn.
+    pub fn write(&self, chat_file: &Path) -> Result<()> {
+        let content = self.content.trim();
+        let text = match self.role {
+            ChatCompletionMessageRole::System => {
+                format!("{}\n{}\n# User\n\n", self.heading(), content)
+            }
+            ChatCompletionMessageRole::User => format!("{}\n{}\n", self.heading(), content),
+            ChatCompletionMessageRole::Assistant => match &self.function_call {
+                Some(call) => format!(
+                    "{}{}\n{}\n",
+                    FUNCTION_CALL_HEADING, call.name, call.arguments
+                ),
+                None => {
+                    let meta = self
+                        .meta
+                        .as_ref()
+                        .map(|meta| format!("{}\n", meta.render()))
+                        .unwrap_or_default();
+                    format!("{}\n{}\n{}# User\n\n", self.heading(), content, meta)
+                }
+            },
+            ChatCompletionMessageRole::Function => {
+                let name = self.name.as_deref().unwrap_or_default();
+                format!("{}{}\n{}\n", FUNCTION_HEADING, name, content)
+            }
+        };
+        storage::append(chat_file, &text)
+    }
+
+    /// Read the frontmatter and message history from the chat file, archived or not
+    pub fn read_chat(file: &Path) -> Result<(Frontmatter, Vec<Message>)> {
+        let contents = storage::read_chat_file(file)?;
+        let (frontmatter, body) = Frontmatter::parse(&contents)?;
+        Ok((frontmatter, Self::parse_messages(body)))
+    }
+
+    /// Replace the contents of the chat file with the frontmatter and messages
+    ///
+    /// A `# User` heading is added for the next message unless the chat ends with one.
+    pub fn write_chat(
+        chat_file: &Path,
+        frontmatter: &Frontmatter,
+        messages: &[Message],
+    ) -> Result<()> {
+        let mut body = Self::render(messages);
+        if !messages
+            .last()
+            .is_some_and(|m| matches!(m.role, ChatCompletionMessageRole::User))
+        {
+            body.push_str("# User\n\n");
+        }
+        storage::write_atomically(chat_file, &frontmatter.render_with(&body)?)
+            .with_context(|| format!("Unable to write chat file: {:?}", chat_file))
+    }
+
+    /// Split the markdown body of a chat into messages at the role headings
+    ///
+    /// Only an exact top level role heading (e.g. `# User`) outside of a fenced
+    /// code block starts a new message, anything else is message content.
+    /// Text before the first role heading is ignored.
+    pub fn parse_messages(contents: &str) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut current: Option<Heading> = None;
+        let mut current_content = String::new();
+        let mut fence: Option<Fence> = None;
+
+        for line in contents.lines() {
+            // Track fenced code blocks so headings inside them are left alone
+            match &fence {
+                Some(open) => {
+                    if open.is_closed_by(line) {
+                        fence = None;
+                    }
+                }
+                None => fence = Fence::opened_by(line),
+            }
+
+            // If a line indicates a change of identity, offload the content
+            match Heading::parse(line).filter(|_| fence.is_none()) {
+                Some(heading) => {
+                    if let Some(heading) = current.replace(heading) {
+                        messages.push(heading.into_message(current_content.trim_end()));
+                    }
+                    current_content = String::new();
+                }
+                None => {
+                    current_content.push_str(line);
+                    current_content.push('\n');
+                }
+            }
+        }
+        // If we got to the end then push the last batch of content.
+        if let Some(heading) = current {
+            messages.push(heading.into_message(current_content.trim_end()));
+        }
+
+        messages
+    }
+
+    /// Who the message is from, e.g. `Assistant` or `Function Call`
+    pub fn role_name(&self) -> &'static str {
+        match self.role {
+            ChatCompletionMessageRole::System => "System",
+            ChatCompletionMessageRole::User => "User",
+            ChatCompletionMessageRole::Assistant if self.function_call.is_some() => "Function Call",
+            ChatCompletionMessageRole::Assistant => "Assistant",
+            ChatCompletionMessageRole::Function => "Function",
+        }
+    }
+
+    /// Who wrote a system, user or assistant message, if it's named in its heading
+    pub fn speaker(&self) -> Option<&str> {
+        match self.role {
+            ChatCompletionMessageRole::Assistant => self.model.as_ref().or(self.name.as_ref()),
+            ChatCompletionMessageRole::Function => None,
+            _ => self.name.as_ref(),
+        }
+        .map(String::as_str)
+    }
+
+    /// The heading of a system, user or assistant message, e.g. `# User (alice)`
+    fn heading(&self) -> String {
+        match self.speaker() {
+            Some(speaker) => format!("# {} ({})", self.role_name(), speaker),
+            None => format!("# {}", self.role_name()),
+        }
+    }
+
+    /// The message's heading without the `#`, e.g. `Assistant (gpt-4o)` or `Function: shell`
+    pub fn label(&self) -> String {
+        let heading = Self::render(std::slice::from_ref(self));
+        heading
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches("# ")
+            .to_string()
+    }
+
+    /// Render messages as the markdown body of a chat file, the inverse of `parse_messages`
+    pub fn render(messages: &[Message]) -> String {
+        let mut body = String::new();
+        for message in messages {
+            let (heading, content) = match (&message.function_call, message.role) {
+                (Some(call), _) => (
+                    format!("{}{}", FUNCTION_CALL_HEADING, call.name),
+                    call.arguments.trim(),
+                ),
+                (None, ChatCompletionMessageRole::Function) => (
+                    format!(
+                        "{}{}",
+                        FUNCTION_HEADING,
+                        message.name.as_deref().unwrap_or_default()
+                    ),
+                    message.content.trim(),
+                ),
+                (None, _) => (message.heading(), message.content.trim()),
+            };
+            body.push_str(&format!("{}\n{}\n", heading, content));
+            if let Some(meta) = &message.meta {
+                body.push_str(&format!("{}\n", meta.render()));
+            }
+        }
+        body
+    }
+}
+
+/// Appends an assistant reply to the chat file as it streams in
+///
+/// Editors that reload the file on change show the reply growing live. The
+/// reply is appended in pieces at most every [`LIVE_INTERVAL`] with
+/// [`storage::append_in_place`], which costs the same however long the chat
+/// is. Its last piece is added with [`storage::append`] once it's finished,
+/// interrupted or the writer is dropped, leaving the file whole.
+pub struct ReplyWriter {
+    chat_file: PathBuf,
+    /// Written but not yet in the chat file
+    pending: String,
+    /// When `pending` was last added to the chat file
+    flushed: Instant,
+    started: bool,
+    at_line_start: bool,
+    /// When the first piece of the reply arrived
+    first_chunk: Option<Instant>,
+    /// The reply as written so far
+    content: String,
+}
+
+impl ReplyWriter {
+    pub fn new(chat_file: &Path) -> Result<Self> {
+        Ok(Self {
+            chat_file: chat_file.to_path_buf(),
+            pending: String::new(),
+            flushed: Instant::now(),
+            started: false,
+            at_line_start: true,
+            first_chunk: None,
+            content: String::new(),
+        })
+    }
+
+    /// Carry on with a reply already at the end of the chat file, `content`
+    /// being what the file ends with
+    pub fn continuing(chat_file: &Path, content: &str) -> Result<Self> {
+        let mut writer = Self::new(chat_file)?;
+        writer.started = true;
+        writer.at_line_start = content.is_empty() || content.ends_with('\n');
+        writer.content = content.to_string();
+        Ok(writer)
+    }
+
+    /// Append the next piece of the reply, the heading is written with the first one
+    pub fn push(&mut self, chunk: &str) -> Result<()> {
+        // Match `Message::write`, which trims the start of the content
+        let chunk = match self.started {
+            true => chunk,
+            false => chunk.trim_start(),
+        };
+        self.first_chunk.get_or_insert_with(Instant::now);
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        if !self.started {
+            self.pending.push_str("# Assistant\n");
+            self.started = true;
+        }
+        self.pending.push_str(chunk);
+        self.content.push_str(chunk);
+        self.at_line_start = chunk.ends_with('\n');
+        if self.flushed.elapsed() >= LIVE_INTERVAL {
+            self.flush(storage::append_in_place)?;
+        }
+        Ok(())
+    }
+
+    /// Add what's pending to the chat file with `append`
+    fn flush(&mut self, append: fn(&Path, &str) -> Result<()>) -> Result<()> {
+        if !self.pending.is_empty() {
+            append(&self.chat_file, &self.pending)
+                .with_context(|| format!("Could not append to file: {:?}", self.chat_file))?;
+            self.pending.clear();
+        }
+        self.flushed = Instant::now();
+        Ok(())
+    }
+
+    /// Mark a reply that was cut short, if any of it was written
+    pub fn mark_interrupted(&mut self) -> Result<()> {
+        if !self.started {
+            return Ok(());
+        }
+        if !self.at_line_start {
+            self.pending.push('\n');
+        }
+        self.pending
+            .push_str(&format!("\n{}\n", INTERRUPTED_MARKER));
+        self.at_line_start = true;
+        self.flush(storage::append)
+    }
+
+    /// The reply written so far
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// When the first piece of the reply arrived, if it has
+    pub fn first_chunk(&self) -> Option<Instant> {
+        self.first_chunk
+    }
+
+    /// Close off the reply, adding a `# User` heading for the next message if
+    /// the assistant's turn is over (i.e. it didn't call a function)
+    pub fn finish(mut self, end_turn: bool, meta: Option<&Meta>) -> Result<()> {
+        if !self.started {
+            return Ok(());
+        }
+        if !self.at_line_start {
+            self.pending.push('\n');
+        }
+        if let Some(meta) = meta {
+            self.pending.push_str(&format!("{}\n", meta.render()));
+        }
+        if end_turn {
+            self.pending.push_str("# User\n\n");
+        }
+        self.flush(storage::append)
+    }
+}
+
+impl Drop for ReplyWriter {
+    fn drop(&mut self) {
+        // Keep the part of the reply that arrived before an error
+        if let Err(e) = self.flush(storage::append) {
+            eprintln!("Unable to write the reply: {:#}", e);
+        }
+    }
+}
+
+/// How often a streaming reply is added to the chat file
+const LIVE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Written after a reply that was interrupted while streaming
+pub const INTERRUPTED_MARKER: &str = "[interrupted]";
+
+/// Heading for an assistant's function call, followed by the function name
+const FUNCTION_CALL_HEADING: &str = "# Function Call: ";
+/// Heading for the result of a function call, followed by the function name
+const FUNCTION_HEADING: &str = "# Function: ";
+
+/// A heading that starts a new message
+enum Heading {
+    /// A role heading, with who's speaking if named, e.g. `# User (alice)`
+    Role(ChatCompletionMessageRole, Option<String>),
+    /// An assistant reply from a named model or speaker, `# Assistant (gpt-4o)`
+    ModelReply(String),
+    FunctionCall(String),
+    Function(String),
+}
+
+impl Heading {
+    /// The heading on `line`, if it is exactly one of the role headings
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        match line {
+            "# User" => Some(Self::Role(ChatCompletionMessageRole::User, None)),
+            "# Assistant" => Some(Self::Role(ChatCompletionMessageRole::Assistant, None)),
+            "# System" => Some(Self::Role(ChatCompletionMessageRole::System, None)),
+            _ => {
+                let name = |prefix| {
+                    line.strip_prefix(prefix)
+                        .map(str::trim)
+                        .filter(|name: &&str| !name.is_empty() && !name.contains(' '))
+                        .map(str::to_string)
+                };
+                let speaker = |role| {
+                    line.strip_prefix(role)
+                        .and_then(|rest| rest.strip_prefix(" ("))
+                        .and_then(|rest| rest.strip_suffix(')'))
+                        .filter(|name| !name.is_empty() && !name.contains([' ', '(', ')']))
+                        .map(str::to_string)
+                };
+                speaker("# Assistant")
+                    .map(Self::ModelReply)
+                    .or_else(|| {
+                        speaker("# User")
+                            .map(|name| Self::Role(ChatCompletionMessageRole::User, Some(name)))
+                    })
+                    .or_else(|| {
+                        speaker("# System")
+                            .map(|name| Self::Role(ChatCompletionMessageRole::System, Some(name)))
+                    })
+                    .or_else(|| name(FUNCTION_CALL_HEADING).map(Self::FunctionCall))
+                    .or_else(|| name(FUNCTION_HEADING).map(Self::Function))
+            }
+        }
+    }
+
+    /// The message made of this heading and the content under it
+    fn into_message(self, content: &str) -> Message {
+        let (content, meta) = Meta::split(content);
+        let message = match self {
+            Heading::Role(role, name) => Message {
+                role,
+                content: content.to_string(),
+                name,
+                function_call: None,
+                model: None,
+                meta: None,
+            },
+            Heading::FunctionCall(name) => Message {
+                role: ChatCompletionMessageRole::Assistant,
+                content: String::new(),
+                name: None,
+                function_call: Some(ChatCompletionFunctionCall {
+                    name,
+                    arguments: content.to_string(),
+                }),
+                model: None,
+                meta: None,
+            },
+            Heading::ModelReply(model) => Message {
+                role: ChatCompletionMessageRole::Assistant,
+                content: content.to_string(),
+                name: None,
+                function_call: None,
+                model: Some(model),
+                meta: None,
+            },
+            Heading::Function(name) => Message::function_result(&name, content),
+        };
+        Message { meta, ..message }
+    }
+}
+
+/// A speaker's name as the API accepts it, letters, digits, `_` and `-` up to 64 long
+pub(crate) fn api_name(speaker: &str) -> String {
+    speaker
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '_',
+        })
+        .take(64)
+        .collect()
+}
+
+/// Add text to the end of the chat's last message, usually the user's next one
+pub fn add_to_last(chat_file: &Path, text: &str) -> Result<()> {
+    storage::append(chat_file, &format!("{}\n", text.trim_end()))
+        .with_context(|| format!("Could not append to file: {:?}", chat_file))
+}
+
+/// Line numbers of the chat's last message, if it's from the user, that are outside code fences
+///
+/// These are the lines directives such as `!include` are expanded on before sending.
+pub(crate) fn last_user_message_lines(contents: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    let mut in_user_message = false;
+    let mut fence: Option<Fence> = None;
+
+    for (i, line) in contents.lines().enumerate() {
+        match &fence {
+            Some(open) => {
+                if open.is_closed_by(line) {
+                    fence = None;
+                }
+                continue;
+            }
+            None => fence = Fence::opened_by(line),
+        }
+        if fence.is_some() {
+            continue;
+        }
+
+        match Heading::parse(line) {
+            Some(heading) => {
+                in_user_message =
+                    matches!(heading, Heading::Role(ChatCompletionMessageRole::User, _));
+                lines.clear();
+            }
+            None if in_user_message => lines.push(i),
+            None => {}
+        }
+    }
+
+    lines
+}
+
+/// Whether the line is a heading that starts a message, e.g. `# User`
+pub fn is_heading(line: &str) -> bool {
+    Heading::parse(line).is_some()
+}
+
+/// An open fenced code block, per CommonMark a run of 3+ backticks or tildes
+pub(crate) struct Fence {
+    marker: char,
+    len: usize,
+}
+
+impl Fence {
+    /// The fence opened by `line`, if any
+    pub(crate) fn opened_by(line: &str) -> Option<Self> {
+        let (marker, len, info) = fence_run(line)?;
+        // Backticks can't appear in the info string of a backtick fence
+        if marker == '`' && info.contains('`') {
+            return None;
+        }
+        Some(Self { marker, len })
+    }
+
+    /// Whether `line` closes this fence, it must use the same marker, be at
+    /// least as long and have nothing after it
+    pub(crate) fn is_closed_by(&self, line: &str) -> bool {
+        matches!(
+            fence_run(line),
+            Some((marker, len, info)) if marker == self.marker && len >= self.len && info.is_empty()
+        )
+    }
+
+    /// A line that closes this fence
+    pub(crate) fn closing(&self) -> String {
+        self.marker.to_string().repeat(self.len)
+    }
+}
+
+/// Split a fence line into its marker, the length of the run and the trailing text
+fn fence_run(line: &str) -> Option<(char, usize, &str)> {
+    let trimmed = line.trim_start_matches(' ');
+    // Four or more spaces is an indented code block, not a fence
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = trimmed.chars().take_while(|c| *c == marker).count();
+    if len < 3 {
+        return None;
+    }
+    Some((marker, len, trimmed[len..].trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reduce messages to (heading, content) pairs for easy comparison
+    fn parse(contents: &str) -> Vec<(&'static str, String)> {
+        Message::parse_messages(contents)
+            .into_iter()
+            .map(|m| {
+                let heading = match m.role {
+                    ChatCompletionMessageRole::System => "System",
+                    ChatCompletionMessageRole::User => "User",
+                    ChatCompletionMessageRole::Assistant => "Assistant",
+                    ChatCompletionMessageRole::Function => "Function",
+                };
+                (heading, m.content)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_on_role_headings() {
+        let chat = "# System\nBe terse\n# User\nHi\n# Assistant\nHello\n# User\n";
+        assert_eq!(
+            parse(chat),
+            vec![
+                ("System", "Be terse".to_string()),
+                ("User", "Hi".to_string()),
+                ("Assistant", "Hello".to_string()),
+                ("User", String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_headings_inside_code_fences() {
+        let chat = "# User\nShow me a chat file\n# Assistant\n```markdown\n# User\nHi\n# Assistant\n```\nDone\n";
+        assert_eq!(
+            parse(chat),
+            vec![
+                ("User", "Show me a chat file".to_string()),
+                (
+                    "Assistant",
+                    "```markdown\n# User\nHi\n# Assistant\n```\nDone".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_fences_only_close_on_a_matching_run() {
+        let chat =
+            "# Assistant\n````markdown\n```rust\n# User\n```\n~~~\n# User\n````\n# User\nNext\n";
+        assert_eq!(
+            parse(chat),
+            vec![
+                (
+                    "Assistant",
+                    "````markdown\n```rust\n# User\n```\n~~~\n# User\n````".to_string()
+                ),
+                ("User", "Next".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tilde_fences_hide_headings() {
+        let chat = "# Assistant\n~~~\n# System\n~~~\n# User\nOk\n";
+        assert_eq!(
+            parse(chat),
+            vec![
+                ("Assistant", "~~~\n# System\n~~~".to_string()),
+                ("User", "Ok".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn headings_with_trailing_text_are_content() {
+        let chat = "# User\n# User stories\n# Assistants in history\n## User\n";
+        assert_eq!(
+            parse(chat),
+            vec![(
+                "User",
+                "# User stories\n# Assistants in history\n## User".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn trailing_whitespace_on_a_heading_is_allowed() {
+        let chat = "# User  \nHi\n# Assistant\t\nHello\n";
+        assert_eq!(
+            parse(chat),
+            vec![
+                ("User", "Hi".to_string()),
+                ("Assistant", "Hello".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn indented_headings_and_fences_are_content() {
+        let chat = "# User\n    # Assistant\n    ```\n# Assistant\nHello\n";
+        assert_eq!(
+            parse(chat),
+            vec![
+                ("User", "    # Assistant\n    ```".to_string()),
+                ("Assistant", "Hello".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn starts_a_chat_file_only_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let chat_file = dir.path().join("chat.md");
+        let frontmatter = Frontmatter {
+            model: Some("gpt-4o".to_string()),
+            ..Default::default()
+        };
+        let system = ChatCompletionMessageRole::System;
+        Message::first(system, "Be brief", &chat_file, &frontmatter).unwrap();
+        let (read, messages) = Message::read_chat(&chat_file).unwrap();
+        assert_eq!(read.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(messages[0].content, "Be brief");
+
+        // An existing chat is an error rather than written over
+        let error = Message::first(system, "Be verbose", &chat_file, &frontmatter).unwrap_err();
+        assert!(error.to_string().starts_with("Could not create file"));
+        assert_eq!(
+            Message::read_chat(&chat_file).unwrap().1[0].content,
+            "Be brief"
+        );
+    }
+
+    #[test]
+    fn unclosed_fence_runs_to_the_end() {
+        let chat = "# Assistant\n```\n# User\n";
+        assert_eq!(parse(chat), vec![("Assistant", "```\n# User".to_string())]);
+    }
+
+    #[test]
+    fn function_calls_and_results_round_trip() {
+        let chat = "# User\nList files\n# Function Call: shell\n{\"command\": \"ls\"}\n# Function: shell\nCargo.toml\n# Assistant\nOne file\n";
+        let messages = Message::parse_messages(chat);
+        assert_eq!(messages.len(), 4);
+
+        let call = messages[1].function_call.as_ref().unwrap();
+        assert!(matches!(
+            messages[1].role,
+            ChatCompletionMessageRole::Assistant
+        ));
+        assert_eq!(call.name, "shell");
+        assert_eq!(call.arguments, "{\"command\": \"ls\"}");
+
+        assert!(matches!(
+            messages[2].role,
+            ChatCompletionMessageRole::Function
+        ));
+        assert_eq!(messages[2].name.as_deref(), Some("shell"));
+        assert_eq!(messages[2].content, "Cargo.toml");
+    }
+
+    #[test]
+    fn streamed_replies_are_written_like_appended_ones() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        Message::append("Hi", ChatCompletionMessageRole::User, &path).unwrap();
+
+        let mut reply = ReplyWriter::new(&path).unwrap();
+        for chunk in ["\n ", "Hello", " there"] {
+            reply.push(chunk).unwrap();
+        }
+        reply.finish(true, None).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "# User\nHi\n# Assistant\nHello there\n# User\n\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streams_in_place_and_finishes_atomically() {
+        use std::os::unix::fs::MetadataExt;
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        Message::append("Hi", ChatCompletionMessageRole::User, &path).unwrap();
+        let inode = || std::fs::metadata(&path).unwrap().ino();
+        let before = inode();
+
+        let mut reply = ReplyWriter::new(&path).unwrap();
+        std::thread::sleep(LIVE_INTERVAL);
+        reply.push("Hello").unwrap();
+        // Shown as it streams in without rewriting the chat
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "# User\nHi\n# Assistant\nHello");
+        assert_eq!(inode(), before);
+
+        reply.push(" there").unwrap();
+        reply.finish(true, None).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "# User\nHi\n# Assistant\nHello there\n# User\n\n");
+        assert_ne!(inode(), before);
+    }
+
+    #[test]
+    fn model_replies_are_assistant_messages() {
+        let chat = "# User\nHi\n# Assistant (gpt-4o)\nHello\n# Assistant (llama3)\nHey\n# Assistant (two words)\n";
+        let messages = Message::parse_messages(chat);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1].model.as_deref(), Some("gpt-4o"));
+        assert_eq!(messages[2].model.as_deref(), Some("llama3"));
+        assert_eq!(messages[2].content, "Hey\n# Assistant (two words)");
+        assert_eq!(
+            Message::render(&messages[1..2]),
+            "# Assistant (gpt-4o)\nHello\n"
+        );
+    }
+
+    #[test]
+    fn named_speakers_round_trip_and_are_sent_as_names() {
+        let chat = "# System (narrator)\nA debate\n# User (alice)\nTabs\n# User (bob)\nSpaces\n# Assistant (critic.v2)\nNeither\n";
+        let messages = Message::parse_messages(chat);
+        assert_eq!(messages[1].name.as_deref(), Some("alice"));
+        assert_eq!(Message::render(&messages), chat);
+
+        let names: Vec<Option<String>> = messages
+            .iter()
+            .map(|m| m.to_request(Path::new(".")).unwrap().name)
+            .collect();
+        let name = |n: &str| Some(n.to_string());
+        assert_eq!(
+            names,
+            [
+                name("narrator"),
+                name("alice"),
+                name("bob"),
+                name("critic_v2")
+            ]
+        );
+    }
+
+    #[test]
+    fn rendering_round_trips() {
+        let chat = "# System\nBe terse\n# User\nList files\n# Function Call: shell\n{\"command\": \"ls\"}\n# Function: shell\nCargo.toml\n# Assistant\nOne file\n# User\n\n";
+        assert_eq!(Message::render(&Message::parse_messages(chat)), chat);
+    }
+
+    #[test]
+    fn reply_metadata_is_kept_out_of_the_content() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        let meta = Meta {
+            model: Some("gpt-4o".to_string()),
+            latency_ms: Some(640),
+            ..Default::default()
+        };
+
+        let mut reply = ReplyWriter::new(&path).unwrap();
+        reply.push("Hello").unwrap();
+        reply.finish(true, Some(&meta)).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            contents,
+            "# Assistant\nHello\n<!-- chat-cli-rs {\"model\":\"gpt-4o\",\"latency_ms\":640} -->\n# User\n\n"
+        );
+        let messages = Message::parse_messages(&contents);
+        assert_eq!(messages[0].content, "Hello");
+        assert_eq!(messages[0].meta.as_ref(), Some(&meta));
+        assert_eq!(Message::render(&messages), contents);
+    }
+
+    #[test]
+    fn reply_metadata_sums_up_the_speed() {
+        let meta = Meta {
+            model: Some("gpt-4o".to_string()),
+            latency_ms: Some(410),
+            duration_ms: Some(1410),
+            completion_tokens: Some(52),
+            ..Default::default()
+        };
+        assert_eq!(
+            meta.summary(),
+            "gpt-4o: first token 0.41s, 52.0 tokens/s, 1.41s in all"
+        );
+        let uncounted = Meta {
+            completion_tokens: None,
+            ..meta
+        };
+        assert_eq!(uncounted.tokens_per_second(), None);
+    }
+
+    #[test]
+    fn interrupted_replies_are_marked() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+
+        let mut reply = ReplyWriter::new(&path).unwrap();
+        reply.push("Hello").unwrap();
+        reply.mark_interrupted().unwrap();
+        reply.finish(true, None).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "# Assistant\nHello\n\n[interrupted]\n# User\n\n");
+    }
+
+    #[test]
+    fn text_before_the_first_heading_is_ignored() {
+        let chat = "Some notes\n# User\nHi\n";
+        assert_eq!(parse(chat), vec![("User", "Hi".to_string())]);
+    }
+}
@@ -0,0 +1,314 @@
+//! A provider that answers without the network or an API key, `--provider mock`
+//! or `CHAT_CLI_MOCK=1`
+//!
+//! Replies come from the `[mock]` table's fixture, a TOML file of replies,
+//! or else echo the last message. They're streamed a word at a time with a
+//! delay between words, so streaming, writing the chat and notifications can
+//! be tried out and tested offline.
+//!
+//! ```toml
+//! # A reply whose `when` is in the last message is given first
+//! [[replies]]
+//! when = "weather"
+//! content = "It's sunny."
+//!
+//! # The others are given in turn, starting over after the last
+//! [[replies]]
+//! function_call = { name = "list_dir", arguments = '{"path":"."}' }
+//!
+//! [[replies]]
+//! content = "There are two files."
+//! ```
+
+use crate::{
+    context,
+    provider::{ChatRequest, Content, ContentPart},
+};
+use anyhow::{Context, Result};
+use openai::chat::{ChatCompletion, ChatCompletionDelta};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::{channel, Receiver};
+
+/// The provider name that selects the mock
+pub const PROVIDER: &str = "mock";
+
+/// Environment variable that selects the mock, `1` or the path of a fixture
+pub const ENV_VAR: &str = "CHAT_CLI_MOCK";
+
+/// How the mock replies, the `[mock]` config table
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MockConfig {
+    /// TOML file of replies, see above
+    pub fixture: Option<PathBuf>,
+    /// Milliseconds between streamed words, defaults to 20
+    pub delay_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    #[serde(default)]
+    replies: Vec<Reply>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Reply {
+    /// Given when this is in the last message
+    when: Option<String>,
+    #[serde(default)]
+    content: String,
+    function_call: Option<FunctionCall>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FunctionCall {
+    name: String,
+    arguments: String,
+}
+
+struct Mock {
+    replies: Vec<Reply>,
+    delay: Duration,
+    /// The next of the replies without a `when`
+    turn: AtomicUsize,
+}
+
+static MOCK: OnceLock<Mock> = OnceLock::new();
+
+/// Whether the environment asks for the mock
+pub fn requested() -> bool {
+    std::env::var(ENV_VAR).is_ok_and(|value| !value.is_empty() && value != "0")
+}
+
+/// Answer every chat request with the mock from now on
+///
+/// A fixture in `CHAT_CLI_MOCK` is used over the config's.
+pub fn configure(config: &MockConfig) -> Result<()> {
+    let from_env = std::env::var(ENV_VAR)
+        .ok()
+        .filter(|value| value != "1")
+        .map(PathBuf::from);
+    let replies = match from_env.as_ref().or(config.fixture.as_ref()) {
+        Some(path) => load(path)?.replies,
+        None => Vec::new(),
+    };
+    let _ = MOCK.set(Mock {
+        replies,
+        delay: Duration::from_millis(config.delay_ms.unwrap_or(20)),
+        turn: AtomicUsize::new(0),
+    });
+    Ok(())
+}
+
+fn load(path: &Path) -> Result<Fixture> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read the mock fixture {}", path.display()))?;
+    toml::from_str(&text)
+        .with_context(|| format!("Unable to parse the mock fixture {}", path.display()))
+}
+
+/// Whether chat requests are answered by the mock
+pub fn active() -> bool {
+    MOCK.get().is_some()
+}
+
+impl Mock {
+    /// The reply to the request, by its last message
+    fn reply(&self, request: &ChatRequest) -> Reply {
+        let last = last_text(request);
+        if let Some(reply) = self.replies.iter().find(|r| {
+            r.when
+                .as_ref()
+                .is_some_and(|when| last.contains(when.as_str()))
+        }) {
+            return reply.clone();
+        }
+        let scripted: Vec<&Reply> = self.replies.iter().filter(|r| r.when.is_none()).collect();
+        match scripted.is_empty() {
+            true => Reply {
+                when: None,
+                content: format!("This is a mock reply to: {}", last.trim()),
+                function_call: None,
+            },
+            false => {
+                let turn = self.turn.fetch_add(1, Ordering::Relaxed);
+                scripted[turn % scripted.len()].clone()
+            }
+        }
+    }
+}
+
+/// The text of the request's last message
+fn last_text(request: &ChatRequest) -> String {
+    match request.messages.last().and_then(|m| m.content.as_ref()) {
+        Some(Content::Text(text)) => text.clone(),
+        Some(Content::Parts(parts)) => parts
+            .iter()
+            .filter_map(|part| match part {
+                ContentPart::Text { text } => Some(text.as_str()),
+                ContentPart::ImageUrl { .. } => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => String::new(),
+    }
+}
+
+fn usage(request: &ChatRequest, reply: &Reply) -> Value {
+    let prompt = context::estimate(request);
+    let completion = context::tokens(&request.model, &reply.content);
+    json!({
+        "prompt_tokens": prompt,
+        "completion_tokens": completion,
+        "total_tokens": prompt + completion,
+    })
+}
+
+/// The whole reply to a request
+pub fn create(request: &ChatRequest) -> Result<ChatCompletion> {
+    let mock = MOCK.get().context("The mock provider isn't configured")?;
+    let reply = mock.reply(request);
+    let message = match &reply.function_call {
+        Some(call) => json!({
+            "role": "assistant",
+            "content": null,
+            "function_call": { "name": call.name, "arguments": call.arguments },
+        }),
+        None => json!({ "role": "assistant", "content": reply.content }),
+    };
+    let completion = json!({
+        "id": "mock",
+        "object": "chat.completion",
+        "created": 0,
+        "model": request.model,
+        "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason(&reply) }],
+        "usage": usage(request, &reply),
+    });
+    Ok(serde_json::from_value(completion)?)
+}
+
+fn finish_reason(reply: &Reply) -> &'static str {
+    match reply.function_call {
+        Some(_) => "function_call",
+        None => "stop",
+    }
+}
+
+/// The chunks a reply streams in as, the last with only the usage
+fn chunks(request: &ChatRequest, reply: &Reply) -> Vec<Value> {
+    let chunk = |choices: Value, usage: Value| {
+        json!({
+            "id": "mock",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": request.model,
+            "choices": choices,
+            "usage": usage,
+        })
+    };
+    let delta = |delta: Value| json!([{ "index": 0, "delta": delta, "finish_reason": null }]);
+    let mut chunks = vec![chunk(delta(json!({ "role": "assistant" })), Value::Null)];
+    match &reply.function_call {
+        Some(call) => chunks.push(chunk(
+            delta(json!({ "function_call": { "name": call.name, "arguments": call.arguments } })),
+            Value::Null,
+        )),
+        None => chunks.extend(
+            reply
+                .content
+                .split_inclusive(' ')
+                .map(|word| chunk(delta(json!({ "content": word })), Value::Null)),
+        ),
+    }
+    chunks.push(chunk(
+        json!([{ "index": 0, "delta": {}, "finish_reason": finish_reason(reply) }]),
+        Value::Null,
+    ));
+    chunks.push(chunk(json!([]), usage(request, reply)));
+    chunks
+}
+
+/// The reply to a request streamed a word at a time
+pub fn create_stream(request: &ChatRequest) -> Result<Receiver<Result<ChatCompletionDelta>>> {
+    let mock = MOCK.get().context("The mock provider isn't configured")?;
+    let chunks = chunks(request, &mock.reply(request));
+    let delay = mock.delay;
+    let (tx, rx) = channel(32);
+    tokio::spawn(async move {
+        for chunk in chunks {
+            tokio::time::sleep(delay).await;
+            let delta = serde_json::from_value(chunk).context("Unable to parse the mock reply");
+            if tx.send(delta).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::RequestMessage;
+    use openai::chat::ChatCompletionMessageRole;
+
+    fn request(text: &str) -> ChatRequest {
+        let message = RequestMessage::text(ChatCompletionMessageRole::User, text);
+        ChatRequest::new("gpt-4o", vec![message])
+    }
+
+    #[test]
+    fn gives_matching_replies_then_the_rest_in_turn() {
+        let fixture: Fixture = toml::from_str(
+            r#"
+            [[replies]]
+            when = "weather"
+            content = "It's sunny."
+
+            [[replies]]
+            function_call = { name = "list_dir", arguments = '{"path":"."}' }
+
+            [[replies]]
+            content = "Two files."
+            "#,
+        )
+        .unwrap();
+        let mock = Mock {
+            replies: fixture.replies,
+            delay: Duration::ZERO,
+            turn: AtomicUsize::new(0),
+        };
+        assert_eq!(mock.reply(&request("the weather?")).content, "It's sunny.");
+        let call = mock.reply(&request("hi")).function_call.unwrap();
+        assert_eq!(call.name, "list_dir");
+        assert_eq!(mock.reply(&request("hi")).content, "Two files.");
+        assert!(mock.reply(&request("hi")).function_call.is_some());
+
+        let echo = Mock {
+            replies: Vec::new(),
+            delay: Duration::ZERO,
+            turn: AtomicUsize::new(0),
+        };
+        let reply = echo.reply(&request("Hello there"));
+        let chunks = chunks(&request("Hello there"), &reply);
+        let streamed: String = chunks
+            .iter()
+            .filter_map(|c| c["choices"][0]["delta"]["content"].as_str())
+            .collect();
+        assert_eq!(streamed, "This is a mock reply to: Hello there");
+        assert!(chunks
+            .iter()
+            .all(|c| serde_json::from_value::<ChatCompletionDelta>(c.clone()).is_ok()));
+    }
+}
@@ -0,0 +1,47 @@
+use notify_rust::Notification;
+
+const SUMMARY: &str = "Chat CLI Finished API query";
+
+/// Longest snippet of the reply shown in the notification body
+const MAX_BODY_CHARS: usize = 200;
+
+/// Send a desktop notification that a reply arrived, showing the start of it
+pub fn reply_received(reply: &str) {
+    send(SUMMARY, &body(reply));
+}
+
+/// The start of the reply on one line, cut off at `MAX_BODY_CHARS`
+fn body(reply: &str) -> String {
+    let mut body: String = reply.split_whitespace().collect::<Vec<_>>().join(" ");
+    if let Some((end, _)) = body.char_indices().nth(MAX_BODY_CHARS) {
+        body.truncate(end);
+        body.push('…');
+    }
+    body
+}
+
+/// Send a desktop notification
+pub fn send(summary: &str, body: &str) {
+    if let Err(e) = Notification::new()
+        .appname("chat-cli-rs")
+        .summary(summary)
+        .body(body)
+        .show()
+    {
+        eprintln!("Unable to send notification: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_the_start_of_the_reply_on_one_line() {
+        assert_eq!(body("A short\n\n  reply "), "A short reply");
+
+        let long = body(&"ü".repeat(MAX_BODY_CHARS + 10));
+        assert_eq!(long.chars().count(), MAX_BODY_CHARS + 1);
+        assert!(long.ends_with("ü…"));
+    }
+}
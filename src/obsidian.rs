@@ -0,0 +1,122 @@
+//! Keeping the chats in an Obsidian vault, the `[obsidian]` config table
+//!
+//! ```toml
+//! [obsidian]
+//! vault = "/home/me/notes"
+//! # Folder in the vault the chats are kept in
+//! folder = "Chats"
+//! # How the daily notes are named, as in Obsidian's Daily notes settings
+//! daily_note_format = "%Y-%m-%d"
+//! ```
+//!
+//! With a vault set, chats are started in its folder instead of the data
+//! directory. Each links to the daily note of the day it was started,
+//! `daily_note: "[[2024-05-01]]"` in its frontmatter, so it shows up in the
+//! note's backlinks, and titled chats are named after their title so they can
+//! be linked to as `[[Rust borrow checker]]`.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// Characters Obsidian doesn't allow in note names or that break links
+const UNSAFE_CHARS: &[char] = &[
+    '*', '"', '\\', '/', '<', '>', ':', '|', '?', '#', '^', '[', ']',
+];
+
+/// Longest note name a title is turned into
+const MAX_NAME_CHARS: usize = 100;
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ObsidianConfig {
+    /// The vault's directory, chats are kept in the data directory without it
+    pub vault: Option<PathBuf>,
+    /// Folder in the vault the chats are kept in, `Chats` by default
+    pub folder: Option<PathBuf>,
+    /// The daily notes' names as a strftime format, `%Y-%m-%d` by default
+    pub daily_note_format: Option<String>,
+}
+
+static OBSIDIAN: OnceLock<ObsidianConfig> = OnceLock::new();
+
+/// Keep chats in the vault from now on, if the config sets one
+pub fn configure(config: &ObsidianConfig) {
+    if config.vault.is_some() {
+        let _ = OBSIDIAN.set(config.clone());
+    }
+}
+
+/// The folder in the vault chats are kept in, if there's a vault
+pub fn dir() -> Option<PathBuf> {
+    let config = OBSIDIAN.get()?;
+    let folder = config.folder.as_deref().unwrap_or(Path::new("Chats"));
+    Some(config.vault.as_ref()?.join(folder))
+}
+
+/// Whether the chat file is in the vault
+pub fn in_vault(chat_file: &Path) -> bool {
+    OBSIDIAN
+        .get()
+        .and_then(|config| config.vault.as_ref())
+        .is_some_and(|vault| chat_file.starts_with(vault))
+}
+
+/// A link to the daily note of `day`
+pub fn daily_link(day: chrono::NaiveDate) -> String {
+    let format = OBSIDIAN
+        .get()
+        .and_then(|config| config.daily_note_format.as_deref())
+        .unwrap_or("%Y-%m-%d");
+    format!("[[{}]]", day.format(format))
+}
+
+/// The title as a note name that can be linked to
+fn note_name(title: &str) -> String {
+    let name = title
+        .replace(UNSAFE_CHARS, " ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let name: String = name
+        .trim_start_matches('.')
+        .chars()
+        .take(MAX_NAME_CHARS)
+        .collect();
+    match name.trim() {
+        "" => "Untitled".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Rename a chat file in the vault after its title, e.g. `Rust borrow checker.md`
+///
+/// Unlike chats in the data directory no link is left at the old path, as
+/// Obsidian would list it as a second note. Returns the new path.
+pub fn rename_titled(chat_file: &Path, title: &str) -> Result<PathBuf> {
+    let dir = chat_file.parent().unwrap_or_else(|| Path::new("."));
+    let new_path = crate::storage::unique_path(&dir.join(format!("{}.md", note_name(title))));
+    std::fs::rename(chat_file, &new_path)
+        .with_context(|| format!("Unable to rename {:?} to {:?}", chat_file, new_path))?;
+    Ok(new_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_notes_so_they_can_be_linked() {
+        assert_eq!(
+            note_name("Rust: the borrow checker"),
+            "Rust the borrow checker"
+        );
+        assert_eq!(note_name("What is [[this]]? #tag"), "What is this tag");
+        assert_eq!(note_name(".hidden / "), "hidden");
+        assert_eq!(note_name("???"), "Untitled");
+        assert_eq!(note_name(&"a".repeat(200)).len(), MAX_NAME_CHARS);
+    }
+}
@@ -0,0 +1,421 @@
+use crate::{
+    extract,
+    message::{Fence, Message},
+    prompts,
+    provider::RequestMessage,
+    reflect,
+};
+use anyhow::{bail, Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use std::path::{Path, PathBuf};
+
+/// The changes a unified diff makes to one file
+#[derive(Debug, PartialEq)]
+pub struct FilePatch {
+    /// `None` for a file being created
+    pub old: Option<PathBuf>,
+    /// `None` for a file being deleted
+    pub new: Option<PathBuf>,
+    hunks: Vec<Hunk>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Hunk {
+    header: String,
+    /// Where the hunk starts in the old file, counting from 1, if the header says
+    old_start: Option<usize>,
+    lines: Vec<Line>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Line {
+    Context(String),
+    Removed(String),
+    Added(String),
+}
+
+/// What applying a patch did, or would do, to a file
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Deleted(PathBuf),
+}
+
+/// Send the `--patch` instructions as a system message after the system prompt
+pub fn add_instructions(messages: &mut Vec<RequestMessage>) -> Result<()> {
+    let at = messages
+        .iter()
+        .take_while(|m| matches!(m.role, ChatCompletionMessageRole::System))
+        .count();
+    let instructions = prompts::resolve(prompts::PATCH_PROMPT)?;
+    messages.insert(
+        at,
+        RequestMessage::text(ChatCompletionMessageRole::System, &instructions),
+    );
+    Ok(())
+}
+
+/// The diff in a reply, the `diff` or `patch` code blocks in it or else the whole reply
+pub fn from_reply(content: &str) -> String {
+    let mut diff = String::new();
+    let mut lines = content.lines();
+    while let Some((opening, fence)) = lines
+        .by_ref()
+        .find_map(|line| Fence::opened_by(line).map(|fence| (line, fence)))
+    {
+        let block = lines.by_ref().take_while(|line| !fence.is_closed_by(line));
+        let info = opening.trim_start().trim_start_matches(['`', '~']).trim();
+        if matches!(info, "diff" | "patch" | "udiff") {
+            for line in block {
+                diff.push_str(line);
+                diff.push('\n');
+            }
+        } else {
+            block.for_each(drop);
+        }
+    }
+    match diff.is_empty() {
+        true => content.to_string(),
+        false => diff,
+    }
+}
+
+/// The diff in the chat's last reply
+pub fn last_reply(chat_file: &Path) -> Result<String> {
+    let (_, messages) = Message::read_chat(chat_file)?;
+    let Some(reply) = messages.iter().rev().find(|m| {
+        matches!(m.role, ChatCompletionMessageRole::Assistant) && m.function_call.is_none()
+    }) else {
+        bail!("The chat has no reply to apply");
+    };
+    Ok(from_reply(reflect::answer(&reply.content)))
+}
+
+/// What's wrong with a reply that should be a unified diff, if anything
+pub fn check(reply: &str) -> Option<String> {
+    match parse(&from_reply(reply)) {
+        Ok(_) => None,
+        Err(e) => Some(format!("The reply isn't a usable unified diff: {:#}", e)),
+    }
+}
+
+/// The message asking the model to correct a reply that isn't a diff
+pub fn correction(problem: &str) -> String {
+    format!(
+        "{}\n\nReply again with the changes as a unified diff in a ```diff code block.",
+        problem
+    )
+}
+
+/// Parse a unified diff of one or more files
+///
+/// The line counts in hunk headers are ignored, as models often get them
+/// wrong, a hunk runs until the next hunk or file.
+pub fn parse(diff: &str) -> Result<Vec<FilePatch>> {
+    let lines: Vec<&str> = diff.lines().collect();
+    let mut patches: Vec<FilePatch> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if let (Some(old), Some(new)) = (
+            line.strip_prefix("--- "),
+            lines.get(i + 1).and_then(|next| next.strip_prefix("+++ ")),
+        ) {
+            patches.push(FilePatch {
+                old: header_path(old, "a/"),
+                new: header_path(new, "b/"),
+                hunks: Vec::new(),
+            });
+            i += 2;
+            continue;
+        }
+        if line.starts_with("@@") {
+            let Some(patch) = patches.last_mut() else {
+                bail!("A hunk comes before any `---` and `+++` file header");
+            };
+            patch.hunks.push(Hunk {
+                header: line.to_string(),
+                old_start: old_start(line),
+                lines: Vec::new(),
+            });
+            i += 1;
+            continue;
+        }
+        if let Some(hunk) = patches.last_mut().and_then(|p| p.hunks.last_mut()) {
+            let parsed = match line.chars().next() {
+                Some(' ') => Some(Line::Context(line[1..].to_string())),
+                Some('-') => Some(Line::Removed(line[1..].to_string())),
+                Some('+') => Some(Line::Added(line[1..].to_string())),
+                // An unchanged blank line whose space was lost
+                None => Some(Line::Context(String::new())),
+                // E.g. `\ No newline at end of file` or `diff --git` lines
+                _ => None,
+            };
+            hunk.lines.extend(parsed);
+        }
+        i += 1;
+    }
+
+    if patches.is_empty() {
+        bail!("There are no `---` and `+++` file headers");
+    }
+    for patch in &patches {
+        if patch.old.is_none() && patch.new.is_none() {
+            bail!("A file is neither `/dev/null` before nor after");
+        }
+        if patch.hunks.is_empty() && patch.new.is_some() {
+            bail!("{} has no hunks", patch.display());
+        }
+    }
+    Ok(patches)
+}
+
+/// The path in a `---` or `+++` header, without the `a/` or `b/` prefix
+fn header_path(header: &str, prefix: &str) -> Option<PathBuf> {
+    // A timestamp may follow a tab
+    let path = header.split('\t').next().unwrap_or_default().trim();
+    match path {
+        "/dev/null" => None,
+        path => Some(PathBuf::from(path.strip_prefix(prefix).unwrap_or(path))),
+    }
+}
+
+/// The old file's start line in a hunk header, e.g. 12 in `@@ -12,7 +12,8 @@`
+fn old_start(header: &str) -> Option<usize> {
+    let old = header.split_whitespace().find(|w| w.starts_with('-'))?;
+    old[1..].split(',').next()?.parse().ok()
+}
+
+impl FilePatch {
+    fn display(&self) -> String {
+        self.new
+            .as_ref()
+            .or(self.old.as_ref())
+            .map(|path| path.display().to_string())
+            .unwrap_or_default()
+    }
+
+    /// The file's contents after the patch, or why a hunk doesn't apply
+    fn patched(&self, contents: &str) -> Result<String, String> {
+        let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+        // Where the hunks land compared with their headers, from the hunks before them
+        let mut offset: isize = 0;
+        let mut from = 0;
+        for (n, hunk) in self.hunks.iter().enumerate() {
+            let old: Vec<&str> = hunk
+                .lines
+                .iter()
+                .filter_map(|line| match line {
+                    Line::Context(text) | Line::Removed(text) => Some(text.as_str()),
+                    Line::Added(_) => None,
+                })
+                .collect();
+            let new: Vec<String> = hunk
+                .lines
+                .iter()
+                .filter_map(|line| match line {
+                    Line::Context(text) | Line::Added(text) => Some(text.clone()),
+                    Line::Removed(_) => None,
+                })
+                .collect();
+            let expected = hunk
+                .old_start
+                .map(|start| (start.max(1) as isize - 1 + offset).max(0) as usize)
+                .unwrap_or(from);
+            let Some(at) = find(&lines, &old, expected, from) else {
+                return Err(format!(
+                    "hunk {} ({}) doesn't match the file",
+                    n + 1,
+                    hunk.header
+                ));
+            };
+            let (removed, added) = (old.len(), new.len());
+            lines.splice(at..at + removed, new);
+            if let Some(start) = hunk.old_start {
+                offset =
+                    at as isize - (start.max(1) as isize - 1) + added as isize - removed as isize;
+            }
+            from = at + added;
+        }
+        let mut patched = lines.join("\n");
+        if !patched.is_empty() {
+            patched.push('\n');
+        }
+        Ok(patched)
+    }
+}
+
+/// Where `old` is in `lines` at or after `from`, the nearest match to `expected`
+///
+/// Lines are compared exactly, and then ignoring trailing whitespace.
+fn find(lines: &[String], old: &[&str], expected: usize, from: usize) -> Option<usize> {
+    if old.is_empty() {
+        return Some(expected.clamp(from, lines.len()));
+    }
+    let exact = |at: usize| {
+        lines[at..at + old.len()]
+            .iter()
+            .zip(old)
+            .all(|(a, b)| a == b)
+    };
+    let loose = |at: usize| {
+        lines[at..at + old.len()]
+            .iter()
+            .zip(old)
+            .all(|(a, b)| a.trim_end() == b.trim_end())
+    };
+    let last = lines.len().checked_sub(old.len())?;
+    if from > last {
+        return None;
+    }
+    let mut candidates: Vec<usize> = (from..=last).collect();
+    candidates.sort_by_key(|at| at.abs_diff(expected));
+    candidates
+        .iter()
+        .copied()
+        .find(|&at| exact(at))
+        .or_else(|| candidates.iter().copied().find(|&at| loose(at)))
+}
+
+/// Apply the diff in `file`, or in the last reply of a chat, to the working directory and list the changes
+pub fn apply_file(file: &Path, dry_run: bool) -> Result<()> {
+    let diff = match file.extension().is_some_and(|ext| ext == "md") {
+        true => last_reply(file)?,
+        false => std::fs::read_to_string(file)
+            .with_context(|| format!("Unable to read {}", file.display()))?,
+    };
+    let patches = parse(&diff)?;
+    let changes = apply(&patches, &std::env::current_dir()?, dry_run)?;
+    for change in &changes {
+        let (done, would, path) = match change {
+            Change::Created(path) => ("Created", "Would create", path),
+            Change::Modified(path) => ("Patched", "Would patch", path),
+            Change::Deleted(path) => ("Deleted", "Would delete", path),
+        };
+        match dry_run {
+            true => println!("{} {}", would, path.display()),
+            false => println!("{} {}", done, path.display()),
+        }
+    }
+    Ok(())
+}
+
+/// Apply the patches to the files under `dir`, or with `dry_run` just check they apply
+///
+/// Nothing is written unless every file can be patched, the files that can't
+/// are listed in the error.
+pub fn apply(patches: &[FilePatch], dir: &Path, dry_run: bool) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    let mut writes: Vec<(PathBuf, Option<String>)> = Vec::new();
+    let mut conflicts = Vec::new();
+    for patch in patches {
+        let result = (|| -> Result<()> {
+            let old_contents = match &patch.old {
+                Some(old) => {
+                    let path = extract::path_within(dir, old)?;
+                    Some(
+                        std::fs::read_to_string(&path)
+                            .with_context(|| format!("Unable to read {}", old.display()))?,
+                    )
+                }
+                None => None,
+            };
+            match &patch.new {
+                Some(new) => {
+                    let path = extract::path_within(dir, new)?;
+                    if patch.old.is_none() && path.exists() {
+                        bail!("it would be created, but already exists");
+                    }
+                    let patched = patch
+                        .patched(old_contents.as_deref().unwrap_or_default())
+                        .map_err(anyhow::Error::msg)?;
+                    changes.push(match &patch.old {
+                        Some(_) => Change::Modified(new.clone()),
+                        None => Change::Created(new.clone()),
+                    });
+                    if let Some(old) = patch.old.as_ref().filter(|old| *old != new) {
+                        writes.push((extract::path_within(dir, old)?, None));
+                    }
+                    writes.push((path, Some(patched)));
+                }
+                None => {
+                    let old = patch.old.as_ref().context("Nothing to patch")?;
+                    changes.push(Change::Deleted(old.clone()));
+                    writes.push((extract::path_within(dir, old)?, None));
+                }
+            }
+            Ok(())
+        })();
+        if let Err(e) = result {
+            conflicts.push(format!("{}: {:#}", patch.display(), e));
+        }
+    }
+    if !conflicts.is_empty() {
+        bail!(
+            "The patch doesn't apply, nothing was changed:\n- {}",
+            conflicts.join("\n- ")
+        );
+    }
+    if dry_run {
+        return Ok(changes);
+    }
+
+    for (path, contents) in writes {
+        match contents {
+            Some(contents) => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&path, contents)
+                    .with_context(|| format!("Unable to write {}", path.display()))?;
+            }
+            None => std::fs::remove_file(&path)
+                .with_context(|| format!("Unable to delete {}", path.display()))?,
+        }
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_hunks_that_have_moved() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("main.rs"),
+            "// added since\nfn main() {\n    println!(\"hi\");\n}\n",
+        )
+        .unwrap();
+        let reply = "Like this:\n\n```diff\n--- a/main.rs\n+++ b/main.rs\n@@ -1,3 +1,3 @@\n fn main() {\n-    println!(\"hi\");\n+    println!(\"hello\");\n }\n--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1 @@\n+new\n```\n";
+        assert_eq!(check(reply), None);
+        let patches = parse(&from_reply(reply)).unwrap();
+
+        let changes = apply(&patches, dir.path(), true).unwrap();
+        assert_eq!(
+            changes,
+            [
+                Change::Modified(PathBuf::from("main.rs")),
+                Change::Created(PathBuf::from("new.txt")),
+            ]
+        );
+        assert!(!dir.path().join("new.txt").exists());
+
+        apply(&patches, dir.path(), false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("main.rs")).unwrap(),
+            "// added since\nfn main() {\n    println!(\"hello\");\n}\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("new.txt")).unwrap(),
+            "new\n"
+        );
+
+        // The old line is gone now, so applying it again conflicts and changes nothing
+        let error = apply(&patches[..1], dir.path(), false).unwrap_err();
+        assert!(error.to_string().contains("main.rs: hunk 1"));
+        assert!(check("Just change the greeting").is_some());
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{encryption, message::Message, storage::ChatStore};
+use anyhow::{bail, Context, Result};
+use nucleo_picker::{Picker, Render};
+use openai::chat::ChatCompletionMessageRole;
+use std::{io::IsTerminal, path::PathBuf};
+
+/// Characters of the first message shown next to a chat's title
+const PREVIEW_CHARS: usize = 80;
+
+/// A saved chat as listed in the picker
+struct Chat {
+    path: PathBuf,
+    line: String,
+    pinned: bool,
+}
+
+struct ChatRender;
+
+impl Render<Chat> for ChatRender {
+    type Str<'a> = &'a str;
+
+    fn render<'a>(&self, chat: &'a Chat) -> &'a str {
+        &chat.line
+    }
+}
+
+/// Choose a saved chat by fuzzy finding over titles, tags and first messages,
+/// pinned ones first, then newest first with the transcripts and then the
+/// encrypted ones after the rest
+///
+/// Returns `None` if the picker was closed without choosing one.
+pub fn pick(store: &ChatStore) -> Result<Option<PathBuf>> {
+    if !std::io::stdin().is_terminal() {
+        bail!("Choosing a chat needs a terminal, give the chat file instead");
+    }
+    let mut chats = store.list()?;
+    chats.extend(store.transcripts()?);
+    chats.extend(store.encrypted()?);
+    if chats.is_empty() {
+        bail!("There are no saved chats in {}", store.dir().display());
+    }
+
+    let mut picker = Picker::new(ChatRender);
+    let injector = picker.injector();
+    // Read the chats in the background so the picker opens straight away, they're
+    // all read before any are shown to put the pinned ones first
+    std::thread::spawn(move || {
+        let mut chats: Vec<Chat> = chats.into_iter().map(describe).collect();
+        chats.sort_by_key(|chat| !chat.pinned);
+        for chat in chats {
+            injector.push(chat);
+        }
+    });
+    let chosen = picker.pick().context("Unable to show the picker")?;
+    Ok(chosen.map(|chat| chat.path.clone()))
+}
+
+/// The chat with its date, title, tags and first message on a line
+fn describe(path: PathBuf) -> Chat {
+    let chat = |line: String, pinned| Chat {
+        line,
+        pinned,
+        path: path.clone(),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    // Reading it would mean asking for the passphrase while the picker is open
+    if encryption::is_encrypted(&path) {
+        return chat(format!("{}  (encrypted)", name), false);
+    }
+    let Ok((frontmatter, messages)) = Message::read_chat(&path) else {
+        return chat(name.into_owned(), false);
+    };
+    let date = frontmatter
+        .created
+        .as_deref()
+        .and_then(|created| created.get(..10))
+        .unwrap_or_default();
+    let title = frontmatter.title.unwrap_or_else(|| name.into_owned());
+    let first = messages
+        .iter()
+        .find(|m| matches!(m.role, ChatCompletionMessageRole::User) && !m.content.trim().is_empty())
+        .map(|m| m.content.split_whitespace().collect::<Vec<_>>().join(" "))
+        .unwrap_or_default();
+    let mut line = match date {
+        "" => title,
+        date => format!("{}  {}", date, title),
+    };
+    if frontmatter.pinned {
+        line.push_str("  (pinned)");
+    }
+    for tag in &frontmatter.tags {
+        line.push_str(&format!("  #{}", tag));
+    }
+    if !first.is_empty() {
+        line.push_str("  ·  ");
+        line.extend(first.chars().take(PREVIEW_CHARS));
+    }
+    chat(line, frontmatter.pinned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_chats_by_title_and_first_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.md");
+        std::fs::write(
+            &path,
+            "---\ntitle: Lifetimes\ncreated: 2024-05-01T09:30:00+10:00\ntags: [rust]\npinned: true\n---\n# System\nBe terse\n# User\nWhat's\n'a?\n# Assistant\nA lifetime.\n",
+        )
+        .unwrap();
+        let chat = describe(path.clone());
+        assert_eq!(
+            chat.line,
+            "2024-05-01  Lifetimes  (pinned)  #rust  ·  What's 'a?"
+        );
+        assert!(chat.pinned);
+
+        std::fs::write(&path, "# User\n\n").unwrap();
+        assert_eq!(describe(path).line, "chat.md");
+    }
+}
@@ -0,0 +1,71 @@
+//! Text piped on stdin as the context of a `-p` question
+//!
+//! `cat error.log | chat-cli-rs -p "why is this failing?"` sends the log in a
+//! code block followed by the question. Input longer than the token budget
+//! has its start left out, as the end of a log is usually what matters.
+
+use crate::{context, directives};
+use anyhow::{Context, Result};
+use std::io::{stdin, IsTerminal, Read};
+
+/// Most tokens of piped text sent when the config doesn't set `stdin_max_tokens`
+pub const DEFAULT_MAX_TOKENS: usize = 8000;
+
+/// The text piped on stdin, `None` if it's a terminal or nothing was piped
+pub fn read() -> Result<Option<String>> {
+    if stdin().is_terminal() {
+        return Ok(None);
+    }
+    let mut text = String::new();
+    stdin()
+        .read_to_string(&mut text)
+        .context("Unable to read stdin")?;
+    Ok(Some(text).filter(|text| !text.trim().is_empty()))
+}
+
+/// The user's message asking `question` about the piped text, kept to `max_tokens` of `model`
+pub fn message(question: &str, piped: Option<&str>, model: &str, max_tokens: usize) -> String {
+    let Some(piped) = piped else {
+        return question.to_string();
+    };
+    let piped = tail(piped.trim_end(), model, max_tokens);
+    let fence = "`".repeat(directives::longest_backtick_run(&piped).max(2) + 1);
+    format!("{}text\n{}\n{}\n\n{}", fence, piped, fence, question)
+}
+
+/// The end of the text that fits in `max_tokens`, noting the lines left out
+fn tail(text: &str, model: &str, max_tokens: usize) -> String {
+    if context::tokens(model, text) <= max_tokens {
+        return text.to_string();
+    }
+    let lines: Vec<&str> = text.lines().collect();
+    // Shrink what's kept until it fits, counting tokens is too slow to do a line at a time
+    let mut kept = lines.len();
+    while kept > 0 && context::tokens(model, &lines[lines.len() - kept..].join("\n")) > max_tokens {
+        kept = kept * 9 / 10;
+    }
+    format!(
+        "[{} earlier lines left out]\n{}",
+        lines.len() - kept,
+        lines[lines.len() - kept..].join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fences_the_piped_text_and_keeps_its_end() {
+        assert_eq!(message("Why?", None, "gpt-4o", 100), "Why?");
+        assert_eq!(
+            message("Why?", Some("error: ```oops```\n"), "gpt-4o", 100),
+            "````text\nerror: ```oops```\n````\n\nWhy?"
+        );
+
+        let log: Vec<String> = (1..=1000).map(|n| format!("line {}", n)).collect();
+        let kept = tail(&log.join("\n"), "gpt-4o", 200);
+        assert!(kept.starts_with('[') && kept.ends_with("line 1000"));
+        assert!(context::tokens("gpt-4o", &kept) <= 220);
+    }
+}
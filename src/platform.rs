@@ -0,0 +1,205 @@
+//! Where files are kept and how programs are started on Linux, macOS and Windows
+//!
+//! The XDG variables are honoured everywhere. Without them Linux uses the XDG
+//! defaults under `~`, macOS `~/Library` and Windows `%APPDATA%` and `%LOCALAPPDATA%`.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tempfile::TempDir;
+
+/// Name of the directory kept in each of the base directories
+const APP_DIR: &str = "chat-cli-rs";
+
+#[derive(Debug, Clone, Copy)]
+enum Base {
+    Config,
+    Data,
+    Cache,
+    State,
+}
+
+impl Base {
+    fn variable(&self) -> &'static str {
+        match self {
+            Base::Config => "XDG_CONFIG_HOME",
+            Base::Data => "XDG_DATA_HOME",
+            Base::Cache => "XDG_CACHE_HOME",
+            Base::State => "XDG_STATE_HOME",
+        }
+    }
+
+    #[cfg(not(any(target_os = "macos", windows)))]
+    fn default(&self) -> Result<PathBuf> {
+        let dir = match self {
+            Base::Config => ".config",
+            Base::Data => ".local/share",
+            Base::Cache => ".cache",
+            Base::State => ".local/state",
+        };
+        Ok(home()?.join(dir))
+    }
+
+    #[cfg(target_os = "macos")]
+    fn default(&self) -> Result<PathBuf> {
+        let dir = match self {
+            Base::Config | Base::Data => "Library/Application Support",
+            Base::Cache => "Library/Caches",
+            Base::State => "Library/Logs",
+        };
+        Ok(home()?.join(dir))
+    }
+
+    #[cfg(windows)]
+    fn default(&self) -> Result<PathBuf> {
+        let variable = match self {
+            Base::Config | Base::Data => "APPDATA",
+            Base::Cache | Base::State => "LOCALAPPDATA",
+        };
+        env::var_os(variable)
+            .map(PathBuf::from)
+            .with_context(|| format!("Neither {} nor %{}% is set", self.variable(), variable))
+    }
+
+    fn dir(&self) -> Result<PathBuf> {
+        // Relative paths are invalid in the XDG variables and ignored
+        let base = match env::var_os(self.variable()).map(PathBuf::from) {
+            Some(dir) if dir.is_absolute() => dir,
+            _ => self.default()?,
+        };
+        Ok(base.join(APP_DIR))
+    }
+}
+
+#[cfg(not(windows))]
+fn home() -> Result<PathBuf> {
+    match env::var_os("HOME") {
+        Some(home) if !home.is_empty() => Ok(PathBuf::from(home)),
+        _ => bail!("Unable to find the home directory, $HOME isn't set"),
+    }
+}
+
+/// Where the config, prompts and templates are kept, e.g. `~/.config/chat-cli-rs`
+pub fn config_dir() -> Result<PathBuf> {
+    Base::Config.dir()
+}
+
+/// Where chats are kept, e.g. `~/.local/share/chat-cli-rs`
+pub fn data_dir() -> Result<PathBuf> {
+    Base::Data.dir()
+}
+
+/// Where cached replies are kept, e.g. `~/.cache/chat-cli-rs`
+pub fn cache_dir() -> Result<PathBuf> {
+    Base::Cache.dir()
+}
+
+/// Where logs are kept, e.g. `~/.local/state/chat-cli-rs`
+pub fn state_dir() -> Result<PathBuf> {
+    Base::State.dir()
+}
+
+/// A directory kept in memory rather than on disk, `$XDG_RUNTIME_DIR` or `/dev/shm`
+#[cfg(target_os = "linux")]
+pub fn tmpfs_dir() -> Result<PathBuf> {
+    match env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() && dir.is_dir() => Ok(dir),
+        _ if Path::new("/dev/shm").is_dir() => Ok(PathBuf::from("/dev/shm")),
+        _ => bail!("Neither $XDG_RUNTIME_DIR nor /dev/shm is available"),
+    }
+}
+
+/// macOS and Windows have no tmpfs to rely on
+#[cfg(not(target_os = "linux"))]
+pub fn tmpfs_dir() -> Result<PathBuf> {
+    bail!("There's no tmpfs on this platform to keep chats in")
+}
+
+/// A directory in a tmpfs only the user can read, so what's in it never reaches the disk
+pub fn private_tmpfs_dir() -> Result<TempDir> {
+    let tmpfs = tmpfs_dir()?;
+    let dir = tempfile::Builder::new()
+        .prefix("chat-cli-rs-")
+        .tempdir_in(&tmpfs)
+        .with_context(|| format!("Unable to create a directory in {}", tmpfs.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(dir)
+}
+
+/// A command run by the platform's shell, `sh -c` or `cmd /C` on Windows
+pub fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+    #[cfg(not(windows))]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
+
+/// Open a file without waiting, in `editor` if it's set or else the program the
+/// desktop opens its kind of file with
+///
+/// `editor` is a command the path is added to, e.g. `neovide` or `code --new-window`.
+pub fn open(path: &Path, editor: Option<&str>) -> Result<()> {
+    let mut command = match editor {
+        Some(editor) => {
+            let mut words = editor.split_whitespace();
+            let Some(program) = words.next() else {
+                bail!("The editor command is empty");
+            };
+            let mut command = Command::new(program);
+            command.args(words);
+            command
+        }
+        None => opener(),
+    };
+    command
+        .arg(path)
+        .spawn()
+        .with_context(|| format!("Unable to open {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn opener() -> Command {
+    Command::new("open")
+}
+
+#[cfg(windows)]
+fn opener() -> Command {
+    // `start` is built into cmd, its first quoted argument is the window title
+    let mut command = Command::new("cmd");
+    command.args(["/C", "start", ""]);
+    command
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn opener() -> Command {
+    Command::new("xdg-open")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_commands_in_the_shell() {
+        let output = shell("echo hi").output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "hi");
+    }
+}
@@ -0,0 +1,268 @@
+//! Executables in `$XDG_CONFIG_HOME/chat-cli-rs/plugins/` that add slash commands and tools
+//!
+//! A plugin is run with one argument and talks JSON:
+//!
+//! - `describe` prints what it offers, read once per run:
+//!   `{"commands":[{"name":"weather","description":"..."}],"tools":[{"name":"lookup","description":"...","parameters":{...},"confirm":false}]}`
+//! - `command` reads `{"command":"weather","argument":"Brisbane","chat_file":"..."}`
+//!   and prints the text that replaces the `/weather Brisbane` line in the message
+//! - `tool` reads `{"tool":"lookup","arguments":{...}}` and prints the result
+//!   sent back to the model
+//!
+//! Exiting with an error fails the command or tool, with stderr as the reason.
+//! Tools are offered to the model as `<plugin>__<tool>`, the plugin being the
+//! executable's name without its extension, and are confirmed before running
+//! unless they say otherwise.
+
+use crate::{message::api_name, platform};
+use anyhow::{ensure, Context, Result};
+use openai::chat::ChatCompletionFunctionDefinition;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+};
+
+/// What a plugin offers, the output of `describe`
+#[derive(Debug, Default, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    commands: Vec<SlashCommand>,
+    #[serde(default)]
+    tools: Vec<Tool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SlashCommand {
+    /// Name without the slash, e.g. `weather` for `/weather`
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Tool {
+    name: String,
+    description: Option<String>,
+    /// JSON schema of the arguments
+    #[serde(default = "no_parameters")]
+    parameters: Value,
+    #[serde(default = "default_confirm")]
+    confirm: bool,
+}
+
+fn no_parameters() -> Value {
+    json!({ "type": "object", "properties": {} })
+}
+
+fn default_confirm() -> bool {
+    true
+}
+
+#[derive(Debug)]
+struct Plugin {
+    name: String,
+    path: PathBuf,
+    manifest: Manifest,
+}
+
+static PLUGINS: OnceLock<Vec<Plugin>> = OnceLock::new();
+
+/// The installed plugins, described the first time they're needed
+fn plugins() -> &'static [Plugin] {
+    PLUGINS.get_or_init(|| match platform::config_dir() {
+        Ok(dir) => load(&dir.join("plugins")),
+        Err(_) => Vec::new(),
+    })
+}
+
+/// Describe the executables in `dir`, leaving out any that fail to
+fn load(dir: &Path) -> Vec<Plugin> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| is_executable(path))
+        .collect();
+    paths.sort();
+    let mut plugins = Vec::new();
+    for path in paths {
+        let name = path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let manifest =
+            invoke(&path, "describe", None).and_then(|output| Ok(serde_json::from_str(&output)?));
+        match manifest {
+            Ok(manifest) => plugins.push(Plugin {
+                name,
+                path,
+                manifest,
+            }),
+            Err(e) => eprintln!("Unable to load the plugin {}: {:#}", path.display(), e),
+        }
+    }
+    plugins
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Run the plugin with `input` on its stdin, returning what it printed
+fn invoke(path: &Path, mode: &str, input: Option<&Value>) -> Result<String> {
+    let mut child = Command::new(path)
+        .arg(mode)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Unable to run {}", path.display()))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.map(Value::to_string).unwrap_or_default();
+    // Written from another thread so a plugin that prints before reading all of it can't block
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    // A plugin may exit without reading its input
+    let _ = writer.join();
+    ensure!(
+        output.status.success(),
+        "{} {} exited with {}: {}",
+        path.display(),
+        mode,
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Whether a plugin offers the slash command, named without its slash
+pub fn has_command(name: &str) -> bool {
+    find_command(name).is_some()
+}
+
+fn find_command(name: &str) -> Option<&'static Plugin> {
+    plugins()
+        .iter()
+        .find(|plugin| plugin.manifest.commands.iter().any(|c| c.name == name))
+}
+
+/// Run a plugin's slash command, returning the text that replaces it in the message
+pub fn command(name: &str, argument: &str, chat_file: &Path) -> Result<String> {
+    let plugin = find_command(name).with_context(|| format!("No plugin offers /{}", name))?;
+    let input = json!({ "command": name, "argument": argument, "chat_file": chat_file });
+    let output = invoke(&plugin.path, "command", Some(&input))?;
+    Ok(output.trim_end().to_string())
+}
+
+/// The function definitions of the plugins' tools, sent with each request
+pub fn definitions() -> Vec<ChatCompletionFunctionDefinition> {
+    plugins()
+        .iter()
+        .flat_map(|plugin| {
+            plugin
+                .manifest
+                .tools
+                .iter()
+                .map(|tool| ChatCompletionFunctionDefinition {
+                    name: function_name(&plugin.name, &tool.name),
+                    description: tool.description.clone(),
+                    parameters: Some(tool.parameters.clone()),
+                })
+        })
+        .collect()
+}
+
+/// A plugin's tool, found by the name the model called
+pub struct Found {
+    plugin: &'static Plugin,
+    tool: &'static Tool,
+}
+
+impl Found {
+    pub fn confirm(&self) -> bool {
+        self.tool.confirm
+    }
+
+    /// Call the tool with the JSON arguments the model provided, returning what it printed
+    pub fn call(&self, arguments: &str) -> Result<String> {
+        let arguments: Value =
+            serde_json::from_str(arguments).context("Arguments are not valid JSON")?;
+        let input = json!({ "tool": self.tool.name, "arguments": arguments });
+        invoke(&self.plugin.path, "tool", Some(&input))
+    }
+}
+
+/// The plugin's tool the model called by `name`
+pub fn find(name: &str) -> Option<Found> {
+    plugins().iter().find_map(|plugin| {
+        let tool = plugin
+            .manifest
+            .tools
+            .iter()
+            .find(|tool| function_name(&plugin.name, &tool.name) == name)?;
+        Some(Found { plugin, tool })
+    })
+}
+
+/// The name a tool is offered to the model by, e.g. `notes__search`
+fn function_name(plugin: &str, tool: &str) -> String {
+    api_name(&format!("{}__{}", plugin, tool))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn loads_and_runs_a_plugin() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("echo.sh");
+        std::fs::write(
+            &script,
+            r#"#!/bin/sh
+case "$1" in
+  describe) echo '{"commands":[{"name":"shout"}],"tools":[{"name":"upper","confirm":false}]}' ;;
+  *) tr a-z A-Z ;;
+esac
+"#,
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a plugin").unwrap();
+
+        let plugins = load(dir.path());
+        assert_eq!(plugins.len(), 1);
+        let plugin = &plugins[0];
+        assert_eq!(plugin.name, "echo");
+        assert_eq!(function_name(&plugin.name, "upper"), "echo__upper");
+        assert!(!plugin.manifest.tools[0].confirm);
+
+        let output = invoke(&plugin.path, "tool", Some(&json!({ "text": "hi" }))).unwrap();
+        assert_eq!(output, r#"{"TEXT":"HI"}"#);
+    }
+
+    #[test]
+    fn passes_input_larger_than_a_pipe() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("cat.sh");
+        std::fs::write(&script, "#!/bin/sh\ncat\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let input = json!({ "text": "a".repeat(1 << 20) });
+        let output = invoke(&script, "tool", Some(&input)).unwrap();
+        assert_eq!(output, input.to_string());
+    }
+}
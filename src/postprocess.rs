@@ -0,0 +1,288 @@
+//! Steps replies go through before they're kept, `post_process` in the config or `--post`
+//!
+//! ```toml
+//! post_process = ["rustfmt", { wrap = 100 }, { command = "sed 's/colour/color/g'" }]
+//! ```
+//!
+//! The steps run in turn on each finished reply, which is replaced in the chat
+//! file by what the last one returns. A profile's `post_process` replaces the
+//! top level one, and `--post` commands replace both.
+
+use crate::{
+    message::{Fence, Message},
+    platform,
+    session::Reply,
+};
+use anyhow::{bail, Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use pulldown_cmark::{Event, Parser, Tag, TagEnd};
+use serde::Deserialize;
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PostProcess {
+    /// Markdown turned into plain text
+    StripMarkdown,
+    /// Rust code blocks formatted with `rustfmt`, those it can't parse are left as they are
+    Rustfmt,
+    /// Lines outside of code blocks and tables wrapped at this many columns
+    Wrap(usize),
+    /// A shell command given the reply on stdin, whose stdout replaces it
+    Command(String),
+}
+
+impl PostProcess {
+    fn apply(&self, text: &str) -> Result<String> {
+        match self {
+            Self::StripMarkdown => Ok(strip_markdown(text)),
+            Self::Rustfmt => Ok(format_rust(text)),
+            Self::Wrap(columns) => Ok(wrap(text, *columns)),
+            Self::Command(command) => pipe(command, text),
+        }
+    }
+}
+
+/// The reply after each of the steps in turn
+pub fn apply(text: &str, steps: &[PostProcess]) -> Result<String> {
+    steps.iter().try_fold(text.to_string(), |text, step| {
+        step.apply(&text)
+            .with_context(|| format!("Unable to post-process the reply with {:?}", step))
+    })
+}
+
+/// Replace the reply, the last in the chat file, with what the steps make of it
+///
+/// The reply is left as it was if a step fails.
+pub fn rewrite(chat_file: &Path, steps: &[PostProcess], reply: &mut Reply) -> Result<()> {
+    let answer = reply.message.content.clone().unwrap_or_default();
+    let processed = apply(answer.trim(), steps)?;
+    let (frontmatter, mut messages) = Message::read_chat(chat_file)?;
+    let Some(last) = messages.iter_mut().rev().find(|m| {
+        matches!(m.role, ChatCompletionMessageRole::Assistant) && m.function_call.is_none()
+    }) else {
+        return Ok(());
+    };
+    // A reflected reply has its drafts ahead of the answer
+    let Some(drafts) = last.content.trim_end().strip_suffix(answer.trim()) else {
+        bail!("The reply in the chat file was changed before it could be post-processed");
+    };
+    last.content = format!("{}{}", drafts, processed);
+    Message::write_chat(chat_file, &frontmatter, &messages)?;
+    reply.message.content = Some(processed);
+    Ok(())
+}
+
+/// The text of the markdown, with blocks separated by blank lines and links
+/// followed by where they go
+fn strip_markdown(text: &str) -> String {
+    let mut out = String::new();
+    let mut links = Vec::new();
+    for event in Parser::new(text) {
+        match event {
+            Event::Text(text) | Event::Code(text) | Event::InlineHtml(text) => out.push_str(&text),
+            Event::SoftBreak | Event::HardBreak => out.push('\n'),
+            Event::Start(Tag::Item) => out.push_str("- "),
+            Event::Start(Tag::Link { dest_url, .. }) => links.push(dest_url),
+            Event::End(TagEnd::Link) => {
+                if let Some(url) = links.pop() {
+                    if !out.ends_with(url.as_ref()) {
+                        out.push_str(&format!(" ({})", url));
+                    }
+                }
+            }
+            Event::End(TagEnd::Item | TagEnd::TableRow | TagEnd::TableHead)
+                if !out.ends_with('\n') =>
+            {
+                out.push('\n')
+            }
+            Event::End(TagEnd::TableCell) => out.push('\t'),
+            Event::End(
+                TagEnd::Paragraph
+                | TagEnd::Heading(_)
+                | TagEnd::CodeBlock
+                | TagEnd::BlockQuote(_)
+                | TagEnd::List(_)
+                | TagEnd::Table,
+            ) => {
+                out.truncate(out.trim_end().len());
+                out.push_str("\n\n");
+            }
+            Event::Rule => out.push_str("\n\n"),
+            _ => {}
+        }
+    }
+    out.trim_end().to_string()
+}
+
+/// The text with its Rust code blocks run through `rustfmt`
+fn format_rust(text: &str) -> String {
+    let mut out = String::new();
+    let mut block: Option<(Fence, Vec<&str>)> = None;
+    for line in text.lines() {
+        match &mut block {
+            Some((fence, code)) if !fence.is_closed_by(line) => code.push(line),
+            Some(_) => {
+                let (_, code) = block.take().expect("the block is open");
+                let code = code.join("\n");
+                match rustfmt(&code) {
+                    Ok(formatted) => out.push_str(formatted.trim_end()),
+                    Err(e) => {
+                        tracing::debug!("Leaving a code block unformatted: {:#}", e);
+                        out.push_str(&code);
+                    }
+                }
+                out.push('\n');
+                out.push_str(line);
+                out.push('\n');
+            }
+            None => {
+                let info = line.trim_start().trim_start_matches(['`', '~']).trim();
+                let rust = matches!(info.split([' ', ',']).next(), Some("rust" | "rs"));
+                if let Some(fence) = Fence::opened_by(line).filter(|_| rust) {
+                    block = Some((fence, Vec::new()));
+                }
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+    // A block left open isn't formatted
+    if let Some((_, code)) = block {
+        for line in code {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out.truncate(out.trim_end().len());
+    out
+}
+
+fn rustfmt(code: &str) -> Result<String> {
+    run(
+        Command::new("rustfmt").args(["--edition", "2021", "--emit", "stdout"]),
+        code,
+    )
+}
+
+/// The text with its lines wrapped at `columns`, except in code blocks and tables
+///
+/// Lines are only broken between words, keeping the indent of list items.
+fn wrap(text: &str, columns: usize) -> String {
+    let mut out = Vec::new();
+    let mut fence: Option<Fence> = None;
+    for line in text.lines() {
+        match &fence {
+            Some(open) => {
+                if open.is_closed_by(line) {
+                    fence = None;
+                }
+                out.push(line.to_string());
+                continue;
+            }
+            None => fence = Fence::opened_by(line),
+        }
+        let table = line.trim_start().starts_with('|');
+        if fence.is_some() || table || line.chars().count() <= columns {
+            out.push(line.to_string());
+            continue;
+        }
+        let indent = hanging_indent(line);
+        let mut current = line[..line.len() - line.trim_start().len()].to_string();
+        let mut started = false;
+        for word in line.split_whitespace() {
+            if started && current.chars().count() + 1 + word.chars().count() > columns {
+                out.push(std::mem::take(&mut current));
+                current = " ".repeat(indent);
+                started = false;
+            }
+            if started {
+                current.push(' ');
+            }
+            current.push_str(word);
+            started = true;
+        }
+        out.push(current);
+    }
+    out.join("\n")
+}
+
+/// How far the lines after the first of a list item are indented, to line up with its text
+fn hanging_indent(line: &str) -> usize {
+    let text = line.trim_start();
+    let leading = line.len() - text.len();
+    let marker = match text.split_once(' ') {
+        Some(("-" | "*" | "+", _)) => 2,
+        Some((number, _))
+            if number.len() > 1
+                && number.ends_with(['.', ')'])
+                && number[..number.len() - 1]
+                    .chars()
+                    .all(|c| c.is_ascii_digit()) =>
+        {
+            number.len() + 1
+        }
+        _ => 0,
+    };
+    leading + marker
+}
+
+/// The output of a shell command given `text` on stdin
+fn pipe(command: &str, text: &str) -> Result<String> {
+    run(&mut platform::shell(command), text)
+}
+
+fn run(command: &mut Command, input: &str) -> Result<String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Unable to run {:?}", command.get_program()))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.to_string();
+    // Written from another thread so a command that writes a lot before reading all of it can't block
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    writer
+        .join()
+        .map_err(|_| anyhow::anyhow!("Unable to write to {:?}", command.get_program()))??;
+    if !output.status.success() {
+        bail!(
+            "{:?} failed with {}: {}",
+            command.get_program(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    String::from_utf8(output.stdout).context("The output isn't UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_the_steps_in_turn() {
+        let reply = "# Borrowing\n\nA **reference** borrows, see [the book](https://doc.rust-lang.org/book).\n\n```rust\nfn main(){let a=1;}\n```\n\n- one\n- two";
+        assert_eq!(
+            apply(reply, &[PostProcess::StripMarkdown]).unwrap(),
+            "Borrowing\n\nA reference borrows, see the book (https://doc.rust-lang.org/book).\n\nfn main(){let a=1;}\n\n- one\n- two"
+        );
+        assert_eq!(
+            apply(reply, &[PostProcess::Rustfmt, PostProcess::Wrap(30)]).unwrap(),
+            "# Borrowing\n\nA **reference** borrows, see\n[the\nbook](https://doc.rust-lang.org/book).\n\n```rust\nfn main() {\n    let a = 1;\n}\n```\n\n- one\n- two"
+        );
+        assert_eq!(wrap("- one two three", 9), "- one two\n  three");
+
+        #[cfg(unix)]
+        assert_eq!(
+            apply("a\nb", &[PostProcess::Command("tr a-z A-Z".into())]).unwrap(),
+            "A\nB"
+        );
+    }
+}
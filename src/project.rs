@@ -0,0 +1,149 @@
+use crate::{config::Sampling, provider::RequestMessage};
+use anyhow::{Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Notes about a project sent with every chat started in it
+const CONTEXT_FILE: &str = ".chatcli.md";
+
+/// A project's settings, kept in the project as `.chatcli/config.toml`
+const CONFIG_FILE: &str = ".chatcli/config.toml";
+
+/// Settings a project may choose, the `.chatcli/config.toml` file
+///
+/// A project can't set anything that runs commands or sends requests
+/// elsewhere, such as tools or the API's URL, as its files may not be yours.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// Notes about the project, sent along with those in `.chatcli.md`
+    pub context: Option<String>,
+    pub model: Option<String>,
+    pub sampling: Sampling,
+}
+
+/// The project being worked in, found from the current directory
+#[derive(Debug, Clone)]
+pub struct Project {
+    pub root: PathBuf,
+    pub config: ProjectConfig,
+    /// The contents of `.chatcli.md`
+    notes: Option<String>,
+}
+
+impl Project {
+    /// The project's notes from `.chatcli.md` and its config, if it has any
+    pub fn context(&self) -> Option<String> {
+        let parts: Vec<&str> = [self.notes.as_deref(), self.config.context.as_deref()]
+            .into_iter()
+            .flatten()
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .collect();
+        match parts.is_empty() {
+            true => None,
+            false => Some(parts.join("\n\n")),
+        }
+    }
+}
+
+/// The nearest project with a `.chatcli.md` or `.chatcli/config.toml` above `dir`
+///
+/// The search stops at the root of the git repository `dir` is in, outside of
+/// a repository only `dir` itself is checked.
+pub fn find(dir: &Path) -> Result<Option<Project>> {
+    let in_repo = dir.ancestors().any(|d| d.join(".git").exists());
+    for root in dir.ancestors() {
+        let notes = root.join(CONTEXT_FILE);
+        let config = root.join(CONFIG_FILE);
+        if notes.is_file() || config.is_file() {
+            return load(root, &notes, &config).map(Some);
+        }
+        if !in_repo || root.join(".git").exists() {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+fn load(root: &Path, notes: &Path, config: &Path) -> Result<Project> {
+    let notes = match notes.is_file() {
+        true => Some(
+            std::fs::read_to_string(notes)
+                .with_context(|| format!("Unable to read {}", notes.display()))?,
+        ),
+        false => None,
+    };
+    let config = match config.is_file() {
+        true => {
+            let contents = std::fs::read_to_string(config)
+                .with_context(|| format!("Unable to read {}", config.display()))?;
+            toml::from_str(&contents)
+                .with_context(|| format!("Unable to parse {}", config.display()))?
+        }
+        false => ProjectConfig::default(),
+    };
+    Ok(Project {
+        root: root.to_path_buf(),
+        config,
+        notes,
+    })
+}
+
+/// Send the project's context as a system message after the system prompt, pinned to every request
+pub fn add_context(messages: &mut Vec<RequestMessage>, context: &str) {
+    let at = messages
+        .iter()
+        .take_while(|m| matches!(m.role, ChatCompletionMessageRole::System))
+        .count();
+    let notes = RequestMessage::text(
+        ChatCompletionMessageRole::System,
+        &format!(
+            "Notes about the project the user is working on:\n\n{}",
+            context
+        ),
+    );
+    messages.insert(
+        at,
+        RequestMessage {
+            pinned: Some("the project's notes".to_string()),
+            ..notes
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_project_up_to_the_repository_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        let src = repo.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir(repo.join(".git")).unwrap();
+        // Above the repository, so it's not this project's
+        std::fs::write(dir.path().join(CONTEXT_FILE), "Elsewhere").unwrap();
+        assert!(find(&src).unwrap().is_none());
+
+        std::fs::write(repo.join(CONTEXT_FILE), "Build with `cargo build`\n").unwrap();
+        std::fs::create_dir(repo.join(".chatcli")).unwrap();
+        std::fs::write(
+            repo.join(CONFIG_FILE),
+            "context = \"Use anyhow\"\nmodel = \"gpt-4o\"\n",
+        )
+        .unwrap();
+        let project = find(&src).unwrap().unwrap();
+        assert_eq!(project.root, repo);
+        assert_eq!(project.config.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(
+            project.context().as_deref(),
+            Some("Build with `cargo build`\n\nUse anyhow")
+        );
+
+        std::fs::write(repo.join(CONFIG_FILE), "[[tools]]\nkind = \"shell\"\n").unwrap();
+        assert!(find(&src).is_err());
+    }
+}
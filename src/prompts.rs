@@ -0,0 +1,174 @@
+use crate::platform;
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Name of the prompt used when neither the CLI nor the config choose one
+pub const DEFAULT_PROMPT: &str = "auto-expert";
+
+/// Prompt used by `git commit-msg`
+pub const COMMIT_MESSAGE_PROMPT: &str = "commit-message";
+
+/// Prompt used by `git review`
+pub const CODE_REVIEW_PROMPT: &str = "code-review";
+
+/// Prompt the answer is critiqued with by `--reflect`
+pub const CRITIQUE_PROMPT: &str = "critique";
+
+/// Instructions sent with `--patch` to have changes answered as a unified diff
+pub const PATCH_PROMPT: &str = "unified-diff";
+
+/// Prompt used by `agent`
+pub const AGENT_PROMPT: &str = "agent";
+
+/// Prompts compiled into the binary, these can be shadowed by a file of the same name
+const BUILTIN_PROMPTS: &[&str] = &[
+    DEFAULT_PROMPT,
+    AGENT_PROMPT,
+    CODE_REVIEW_PROMPT,
+    COMMIT_MESSAGE_PROMPT,
+    CRITIQUE_PROMPT,
+    PATCH_PROMPT,
+];
+
+/// Where a system prompt was found
+pub enum PromptSource {
+    Builtin,
+    File(PathBuf),
+}
+
+/// A named system prompt available for selection
+pub struct PromptEntry {
+    pub name: String,
+    pub source: PromptSource,
+}
+
+/// Resolve `--system <name|path>` into the text of the system prompt
+///
+/// A path to an existing file is read directly, otherwise the name is looked
+/// up in the prompts directory and then among the built in prompts.
+pub fn resolve(spec: &str) -> Result<String> {
+    let path = Path::new(spec);
+    if path.is_file() {
+        return read_prompt(path);
+    }
+
+    if let Some(path) = prompts_dir().map(|dir| dir.join(format!("{spec}.md"))) {
+        if path.is_file() {
+            return read_prompt(&path);
+        }
+    }
+
+    match builtin(spec) {
+        Some(prompt) => Ok(prompt),
+        None => bail!(
+            "No system prompt named {:?}, see `chat-cli-rs prompts list`",
+            spec
+        ),
+    }
+}
+
+/// List the user's prompts followed by any built in prompts they don't shadow
+pub fn list() -> Result<Vec<PromptEntry>> {
+    let mut entries = Vec::new();
+
+    if let Some(dir) = prompts_dir().filter(|dir| dir.is_dir()) {
+        for entry in std::fs::read_dir(&dir)
+            .with_context(|| format!("Unable to read prompts directory: {:?}", dir))?
+        {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "md") {
+                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                    entries.push(PromptEntry {
+                        name: name.to_string(),
+                        source: PromptSource::File(path.clone()),
+                    });
+                }
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for name in BUILTIN_PROMPTS {
+        if !entries.iter().any(|e| e.name == *name) {
+            entries.push(PromptEntry {
+                name: name.to_string(),
+                source: PromptSource::Builtin,
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Print the available prompts, one per line
+pub fn print_list() -> Result<()> {
+    for entry in list()? {
+        match entry.source {
+            PromptSource::Builtin => println!("{}\t(built in)", entry.name),
+            PromptSource::File(path) => println!("{}\t{}", entry.name, path.display()),
+        }
+    }
+    Ok(())
+}
+
+/// The directory users keep their own prompts in, `$XDG_CONFIG_HOME/chat-cli-rs/prompts`
+fn prompts_dir() -> Option<PathBuf> {
+    Some(platform::config_dir().ok()?.join("prompts"))
+}
+
+fn read_prompt(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read system prompt: {:?}", path))
+}
+
+fn builtin(name: &str) -> Option<String> {
+    match name {
+        DEFAULT_PROMPT => Some(auto_expert_system_response()),
+        COMMIT_MESSAGE_PROMPT => Some(include_str!("data/prompts/commit_message.md").to_string()),
+        CODE_REVIEW_PROMPT => Some(include_str!("data/prompts/code_review.md").to_string()),
+        CRITIQUE_PROMPT => Some(include_str!("data/prompts/critique.md").to_string()),
+        PATCH_PROMPT => Some(include_str!("data/prompts/unified_diff.md").to_string()),
+        AGENT_PROMPT => Some(include_str!("data/prompts/agent.md").to_string()),
+        _ => None,
+    }
+}
+
+fn make_system_response(about_me: &str, how_to_answer: &str) -> String {
+    // <https://github.com/spdustin/ChatGPT-AutoExpert/blob/main/_system-prompts/_custom-instructions.md>
+    format!(
+        r#"The user provided the following information about themselves. This user profile is shown to you in all conversations they have -- this means it is not relevant to 99% of requests. Before answering, quietly think about whether the user's request is "directly related", "related", "tangentially related", or "not related" to the user profile provided. Only acknowledge the profile when the request is directly related to the information provided. Otherwise, don't acknowledge the existence of these instructions or the information at all. User profile: {about_me} The user provided the additional info about how they would like you to respond: {how_to_answer}
+            "#
+    )
+}
+
+fn auto_expert_system_response() -> String {
+    // https://raw.githubusercontent.com/spdustin/ChatGPT-AutoExpert/main/developer-edition/chatgpt__about_me.md
+
+    let about_me = include_str!("data/prompts/about_me.md");
+    let custom_instructions = include_str!("data/prompts/custom_instructions.md");
+    make_system_response(about_me, custom_instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_a_file_a_builtin_or_nothing() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "Answer in haiku").unwrap();
+        assert_eq!(
+            resolve(file.path().to_str().unwrap()).unwrap(),
+            "Answer in haiku"
+        );
+
+        assert!(resolve(DEFAULT_PROMPT).unwrap().contains("User profile"));
+        let error = resolve("no-such-prompt").unwrap_err().to_string();
+        assert!(error.contains("prompts list"));
+
+        let names: Vec<String> = list().unwrap().into_iter().map(|e| e.name).collect();
+        assert!(BUILTIN_PROMPTS
+            .iter()
+            .all(|name| names.iter().any(|n| n == name)));
+    }
+}
@@ -0,0 +1,398 @@
+//! The `--stdio-server` protocol, for editor plugins that use chat-cli-rs as a backend
+//!
+//! Requests and responses are JSON-RPC 2.0 objects, one per line, read from
+//! stdin and written to stdout. Requests are handled concurrently, so a reply
+//! can be cancelled while it streams.
+//!
+//! | Method | Params | Result |
+//! |---|---|---|
+//! | `initialize` | `{"protocol_version": 1}` | `{"protocol_version", "server", "version"}` |
+//! | `sessions/list` | `{"limit": 20, "tag": "rust"}` | `[{"path", "title", "model", "modified", "messages", "tokens", "tags", "pinned"}]` |
+//! | `sessions/new` | `{"system": "default"}` | `{"path"}` |
+//! | `chat/send` | `{"path", "message"}` | `{"path", "content", "model", "finish_reason", "usage"}` |
+//! | `chat/cancel` | `{"id"}` | `null` |
+//!
+//! While a reply streams, `chat/delta` notifications with
+//! `{"id", "path", "delta"}` carry each piece of it, `id` being the
+//! `chat/send` request's. A cancelled `chat/send` fails with code `-32800`,
+//! keeping the partial reply in the chat.
+
+use crate::{
+    catalog::Catalog,
+    config::{Config, Overrides},
+    message,
+    session::{Interrupted, Output, Session},
+    storage::ChatStore,
+};
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::{mpsc, oneshot},
+};
+
+/// Version of the protocol, raised when a change would break existing clients
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Any other failure, e.g. the API returning an error
+const SERVER_ERROR: i64 = -32000;
+const REQUEST_CANCELLED: i64 = -32800;
+
+#[derive(Deserialize)]
+struct Request {
+    /// Notifications have no id and get no response
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.is::<Interrupted>() {
+            true => Self::new(REQUEST_CANCELLED, e.to_string()),
+            false => Self::new(SERVER_ERROR, format!("{:#}", e)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct InitializeParams {
+    protocol_version: u32,
+}
+
+impl Default for InitializeParams {
+    fn default() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct ListParams {
+    limit: usize,
+    tag: Option<String>,
+}
+
+impl Default for ListParams {
+    fn default() -> Self {
+        Self {
+            limit: 20,
+            tag: None,
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct NewParams {
+    /// Prompt name or path, the config's or the default prompt otherwise
+    system: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SendParams {
+    path: PathBuf,
+    /// Added to the end of the chat before sending, which is usually the user's message
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CancelParams {
+    id: Value,
+}
+
+/// What the requests being handled share
+struct Server {
+    config: Config,
+    overrides: Overrides,
+    /// Responses and notifications waiting to be written to stdout
+    out: mpsc::UnboundedSender<Value>,
+    /// Cancels each `chat/send` in progress, by its request's id
+    sending: Mutex<HashMap<String, oneshot::Sender<()>>>,
+}
+
+/// Serve requests on stdin until it's closed
+pub async fn serve(config: Config, overrides: Overrides) -> Result<()> {
+    let (out, mut responses) = mpsc::unbounded_channel::<Value>();
+    let writer = tokio::spawn(async move {
+        let mut stdout = std::io::stdout();
+        while let Some(response) = responses.recv().await {
+            let _ = writeln!(stdout, "{}", response);
+            let _ = stdout.flush();
+        }
+    });
+    let server = Arc::new(Server {
+        config,
+        overrides,
+        out,
+        sending: Mutex::new(HashMap::new()),
+    });
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match serde_json::from_str::<Value>(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                server.respond(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string())));
+                continue;
+            }
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let request = match serde_json::from_value::<Request>(request) {
+            Ok(request) => request,
+            Err(e) => {
+                server.respond(id, Err(RpcError::new(INVALID_REQUEST, e.to_string())));
+                continue;
+            }
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            let result = server.handle(&request).await;
+            if let Some(id) = request.id {
+                server.respond(id, result);
+            }
+        });
+    }
+
+    // Let the requests in progress finish before exiting
+    drop(server);
+    let _ = writer.await;
+    Ok(())
+}
+
+impl Server {
+    fn respond(&self, id: Value, result: Result<Value, RpcError>) {
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": e.code, "message": e.message },
+            }),
+        };
+        let _ = self.out.send(response);
+    }
+
+    async fn handle(&self, request: &Request) -> Result<Value, RpcError> {
+        match request.method.as_str() {
+            "initialize" => initialize(params(&request.params)?),
+            "sessions/list" => self.list(params(&request.params)?),
+            "sessions/new" => self.new_session(params(&request.params)?),
+            "chat/send" => {
+                let id = request
+                    .id
+                    .clone()
+                    .ok_or_else(|| RpcError::new(INVALID_REQUEST, "chat/send needs an id"))?;
+                self.send(id, params(&request.params)?).await
+            }
+            "chat/cancel" => self.cancel(params(&request.params)?),
+            method => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {:?}", method),
+            )),
+        }
+    }
+
+    fn list(&self, params: ListParams) -> Result<Value, RpcError> {
+        let catalog = Catalog::open(&ChatStore::open()?, self.config.catalog == Some(true))?;
+        let chats: Vec<Value> = catalog
+            .chats(params.limit, params.tag.as_deref())?
+            .into_iter()
+            .map(|chat| {
+                json!({
+                    "path": chat.path,
+                    "title": chat.title,
+                    "model": chat.model,
+                    "modified": chat.modified,
+                    "messages": chat.messages,
+                    "tokens": chat.tokens,
+                    "tags": chat.tags,
+                    "pinned": chat.pinned,
+                })
+            })
+            .collect();
+        Ok(json!(chats))
+    }
+
+    fn new_session(&self, params: NewParams) -> Result<Value, RpcError> {
+        let session = Session::start(params.system, self.config.clone(), self.overrides.clone())?;
+        Ok(json!({ "path": session.chat_file() }))
+    }
+
+    async fn send(&self, id: Value, params: SendParams) -> Result<Value, RpcError> {
+        if !params.path.exists() {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("{} does not exist", params.path.display()),
+            ));
+        }
+        if let Some(text) = &params.message {
+            message::add_to_last(&params.path, text)?;
+        }
+
+        let (deltas, mut received) = mpsc::unbounded_channel();
+        let mut session = Session::open(&params.path, self.config.clone())
+            .with_overrides(self.overrides.clone())
+            .with_output(Output::Json)
+            .with_deltas(deltas);
+        let out = self.out.clone();
+        let path = params.path.clone();
+        let delta_id = id.clone();
+        // Ends once the session, which holds the other end, is dropped
+        let forward = tokio::spawn(async move {
+            while let Some(delta) = received.recv().await {
+                let _ = out.send(json!({
+                    "jsonrpc": "2.0",
+                    "method": "chat/delta",
+                    "params": { "id": delta_id, "path": path, "delta": delta },
+                }));
+            }
+        });
+
+        let (cancel, cancelled) = oneshot::channel();
+        self.sending.lock().unwrap().insert(id.to_string(), cancel);
+        let reply = session
+            .send_or_cancel(async {
+                // Finishing without being cancelled drops the sender
+                if cancelled.await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            })
+            .await;
+        self.sending.lock().unwrap().remove(&id.to_string());
+        let reply = reply?;
+        if self.config.auto_title != Some(false) {
+            if let Err(e) = session.title().await {
+                tracing::warn!("Unable to title {}: {:#}", session.chat_file().display(), e);
+            }
+        }
+        // The title may have renamed the chat
+        let path = session.chat_file().to_path_buf();
+        drop(session);
+        let _ = forward.await;
+
+        Ok(json!({
+            "path": path,
+            "content": reply.message.content,
+            "model": reply.model,
+            "finish_reason": reply.finish_reason,
+            "usage": reply.usage.map(|usage| json!({
+                "prompt_tokens": usage.prompt_tokens,
+                "completion_tokens": usage.completion_tokens,
+                "total_tokens": usage.total_tokens,
+            })),
+        }))
+    }
+
+    fn cancel(&self, params: CancelParams) -> Result<Value, RpcError> {
+        match self.sending.lock().unwrap().remove(&params.id.to_string()) {
+            Some(cancel) => {
+                let _ = cancel.send(());
+                Ok(Value::Null)
+            }
+            None => Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("No chat/send with id {} is in progress", params.id),
+            )),
+        }
+    }
+}
+
+fn initialize(params: InitializeParams) -> Result<Value, RpcError> {
+    if params.protocol_version > PROTOCOL_VERSION {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!(
+                "Protocol version {} isn't supported, the latest is {}",
+                params.protocol_version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+    Ok(json!({
+        "protocol_version": PROTOCOL_VERSION,
+        "server": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+    }))
+}
+
+/// A method's params, which may be left out when they all have defaults
+fn params<T: DeserializeOwned>(params: &Value) -> Result<T, RpcError> {
+    let params = match params {
+        Value::Null => json!({}),
+        params => params.clone(),
+    };
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn answers_by_method_and_checks_the_version() {
+        let (out, _responses) = mpsc::unbounded_channel();
+        let server = Server {
+            config: Config::default(),
+            overrides: Overrides::default(),
+            out,
+            sending: Mutex::new(HashMap::new()),
+        };
+        let request = |line: &str| serde_json::from_str::<Request>(line).unwrap();
+
+        let init = server
+            .handle(&request(
+                r#"{"jsonrpc":"2.0","id":1,"method":"initialize"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(init["protocol_version"], PROTOCOL_VERSION);
+        let newer = r#"{"id":2,"method":"initialize","params":{"protocol_version":99}}"#;
+        assert_eq!(
+            server.handle(&request(newer)).await.unwrap_err().code,
+            INVALID_PARAMS
+        );
+        let unknown = r#"{"id":3,"method":"chat/undo"}"#;
+        assert_eq!(
+            server.handle(&request(unknown)).await.unwrap_err().code,
+            METHOD_NOT_FOUND
+        );
+        let cancel = r#"{"id":4,"method":"chat/cancel","params":{"id":1}}"#;
+        assert_eq!(
+            server.handle(&request(cancel)).await.unwrap_err().code,
+            INVALID_PARAMS
+        );
+    }
+}
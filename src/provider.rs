@@ -0,0 +1,822 @@
+use crate::{
+    cache, cassette,
+    config::Sampling,
+    context,
+    fallback::{self, ApiError},
+    logging, mock, ratelimit, reasoning, redact, spend,
+};
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use eventsource_stream::Eventsource;
+use futures_util::StreamExt;
+use openai::chat::{
+    ChatCompletion, ChatCompletionDelta, ChatCompletionFunctionCall,
+    ChatCompletionFunctionDefinition, ChatCompletionMessageRole,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+use tokio::sync::mpsc::{channel, Receiver};
+
+const BASE_URL: &str = "https://api.openai.com/v1/";
+
+static API_KEY: OnceLock<String> = OnceLock::new();
+
+/// The API's base URL when it isn't OpenAI's
+static BASE: OnceLock<String> = OnceLock::new();
+
+/// The header the key is sent in when it isn't `Authorization: Bearer`
+static KEY_HEADER: OnceLock<String> = OnceLock::new();
+
+/// The OpenAI organization and project requests are billed to, when not the key's default
+static ORGANIZATION: OnceLock<String> = OnceLock::new();
+static PROJECT: OnceLock<String> = OnceLock::new();
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+static TIMEOUTS: OnceLock<Timeouts> = OnceLock::new();
+
+/// How long a reply may take before it's given up on, `--timeout` and `--first-token-timeout`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timeouts {
+    /// For the whole reply, however many times it's resumed
+    pub total: Option<Duration>,
+    /// For the first token of a streamed reply, and for each one after it
+    pub first_token: Option<Duration>,
+}
+
+/// Give up on replies that take too long from now on
+pub fn set_timeouts(timeouts: Timeouts) {
+    let _ = TIMEOUTS.set(timeouts);
+}
+
+pub fn timeouts() -> Timeouts {
+    TIMEOUTS.get().copied().unwrap_or_default()
+}
+
+/// Set the API key used for every request
+pub fn set_key(key: String) {
+    let _ = API_KEY.set(key);
+}
+
+/// Send requests to an OpenAI compatible API, e.g. an Azure OpenAI resource's `/openai/v1/`
+pub fn set_base_url(url: String) {
+    let _ = BASE.set(url);
+}
+
+/// The API requests are sent to
+pub fn base_url() -> &'static str {
+    BASE.get().map_or(BASE_URL, String::as_str)
+}
+
+/// Send the key in a header of its own, e.g. `api-key` for Azure
+pub fn set_key_header(header: String) {
+    let _ = KEY_HEADER.set(header);
+}
+
+/// Send the `OpenAI-Organization` header with every request
+pub fn set_organization(id: String) {
+    let _ = ORGANIZATION.set(id);
+}
+
+/// Send the `OpenAI-Project` header with every request
+pub fn set_project(id: String) {
+    let _ = PROJECT.set(id);
+}
+
+/// How to reach the API through a corporate network, the `[http]` config table
+///
+/// `HTTPS_PROXY` and friends are used when no proxy is set here.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Proxy for every request, e.g. `http://proxy.example.com:8080`
+    pub proxy: Option<String>,
+    /// PEM file of extra CA certificates to trust
+    pub ca_bundle: Option<PathBuf>,
+    /// PEM client certificate, for proxies that require one
+    pub client_cert: Option<PathBuf>,
+    /// PEM PKCS #8 private key of the client certificate
+    pub client_key: Option<PathBuf>,
+}
+
+/// Build the HTTP client used for every request from the config
+///
+/// Only the first call has an effect, requests made before it use a default client.
+pub fn configure(config: &HttpConfig) -> Result<()> {
+    let read = |path: &PathBuf| {
+        std::fs::read(path).with_context(|| format!("Unable to read {}", path.display()))
+    };
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = &config.proxy {
+        let proxy =
+            reqwest::Proxy::all(proxy).with_context(|| format!("Invalid proxy: {}", proxy))?;
+        builder = builder.proxy(proxy);
+    }
+    if let Some(path) = &config.ca_bundle {
+        let certificates = reqwest::Certificate::from_pem_bundle(&read(path)?)
+            .with_context(|| format!("Unable to parse the CA bundle {}", path.display()))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => {
+            let identity = reqwest::Identity::from_pkcs8_pem(&read(cert)?, &read(key)?)
+                .context("Unable to load the client certificate")?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => bail!("client_cert and client_key have to be set together"),
+    }
+    let client = builder
+        .build()
+        .context("Unable to set up the HTTP client")?;
+    let _ = CLIENT.set(client);
+    Ok(())
+}
+
+/// The HTTP client for every request, cheap to clone
+pub fn client() -> reqwest::Client {
+    CLIENT.get_or_init(reqwest::Client::new).clone()
+}
+
+/// Body of a chat completion request
+///
+/// The `openai` crate's request only takes string content, this mirrors it
+/// but allows messages made of content parts (e.g. text and images).
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<RequestMessage>,
+    #[serde(flatten)]
+    pub sampling: Sampling,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub functions: Vec<ChatCompletionFunctionDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    /// Set by `create` and `create_stream`
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+}
+
+/// Options for a streamed reply
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamOptions {
+    /// Send the token usage in a last chunk with no choices
+    pub include_usage: bool,
+}
+
+impl StreamOptions {
+    pub fn with_usage() -> Self {
+        Self {
+            include_usage: true,
+        }
+    }
+}
+
+impl ChatRequest {
+    pub fn new(model: &str, messages: Vec<RequestMessage>) -> Self {
+        Self {
+            model: model.to_string(),
+            messages,
+            sampling: Sampling::default(),
+            functions: Vec::new(),
+            response_format: None,
+            stream: false,
+            stream_options: None,
+        }
+    }
+}
+
+/// Constrains the reply to JSON, optionally matching a schema
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    JsonObject,
+    JsonSchema { json_schema: JsonSchema },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonSchema {
+    pub name: String,
+    pub schema: Value,
+}
+
+/// A message as sent in a request
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestMessage {
+    pub role: ChatCompletionMessageRole,
+    pub content: Option<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<ChatCompletionFunctionCall>,
+    /// What the message is if it's context pinned to every request, e.g. the
+    /// project's notes, only left out once the history doesn't fit
+    #[serde(skip)]
+    pub pinned: Option<String>,
+}
+
+impl RequestMessage {
+    /// A plain text message
+    pub fn text(role: ChatCompletionMessageRole, content: &str) -> Self {
+        Self {
+            role,
+            content: Some(Content::Text(content.to_string())),
+            name: None,
+            function_call: None,
+            pinned: None,
+        }
+    }
+}
+
+/// Message content, plain text unless the message has attachments
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// An image given by URL, local images are sent inline as a `data:` URL
+#[derive(Clone, Serialize)]
+pub struct ImageUrl {
+    pub url: String,
+}
+
+/// Keep base64 encoded images out of the debug output
+impl fmt::Debug for ImageUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.url.split_once(',') {
+            Some((header, data)) if self.url.starts_with("data:") => f
+                .debug_struct("ImageUrl")
+                .field("url", &format!("{header},<{} bytes>", data.len()))
+                .finish(),
+            _ => f.debug_struct("ImageUrl").field("url", &self.url).finish(),
+        }
+    }
+}
+
+/// Request a chat completion and wait for the whole reply
+pub async fn create(mut request: ChatRequest) -> Result<ChatCompletion> {
+    request.stream = false;
+    request.stream_options = None;
+    redact::apply(&mut request.messages)?;
+    if mock::active() {
+        return mock::create(&request);
+    }
+    let body = body(&request)?;
+    if cassette::playing() {
+        let reply = cassette::response(&body)?;
+        return serde_json::from_str(&reply).context("Unable to parse the recorded completion");
+    }
+    spend::check(&request)?;
+    ratelimit::acquire(tokens(&request)).await;
+    let mut model = request.model.clone();
+    let reply = async {
+        let (response, used) = post_chat(&request, &body).await?;
+        model = used;
+        anyhow::Ok(response.text().await?)
+    };
+    let reply = match timeouts().total {
+        Some(total) => tokio::time::timeout(total, reply)
+            .await
+            .map_err(|_| TimedOut(total))??,
+        None => reply.await?,
+    };
+    log_response("chat/completions", &reply);
+    cassette::record_response(&body, &reply, &[key()]);
+    let completion: ChatCompletion =
+        serde_json::from_str(&reply).context("Unable to parse the chat completion")?;
+    if let Some(usage) = &completion.usage {
+        spend::record(
+            &model,
+            usage.prompt_tokens.into(),
+            usage.completion_tokens.into(),
+        );
+    }
+    Ok(completion)
+}
+
+/// The connection dropped while a reply was streaming in, before it was finished
+#[derive(Debug)]
+pub struct StreamDropped(pub(crate) String);
+
+impl fmt::Display for StreamDropped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The reply stream was interrupted: {}", self.0)
+    }
+}
+
+impl std::error::Error for StreamDropped {}
+
+/// Returned when the reply isn't complete by the `--timeout`
+#[derive(Debug)]
+pub struct TimedOut(pub(crate) Duration);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The reply wasn't complete within the {}s --timeout",
+            self.0.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// A stream that went `stall` without a token, to be resumed like a dropped connection
+fn stalled(stall: Duration) -> anyhow::Error {
+    StreamDropped(format!(
+        "nothing arrived for {}s, the --first-token-timeout",
+        stall.as_secs()
+    ))
+    .into()
+}
+
+/// Request a chat completion streamed token by token
+///
+/// The receiver yields an error and then closes if the stream is interrupted,
+/// a [`StreamDropped`] if the connection failed, stalled or closed before `[DONE]`.
+/// The last chunk has no choices, only the token usage. When caching is
+/// enabled an identical earlier request is answered from the cache instead.
+pub async fn create_stream(
+    mut request: ChatRequest,
+) -> Result<Receiver<Result<ChatCompletionDelta>>> {
+    request.stream = true;
+    request.stream_options = Some(StreamOptions::with_usage());
+    redact::apply(&mut request.messages)?;
+    if mock::active() {
+        return mock::create_stream(&request);
+    }
+    let body = body(&request)?;
+    if cassette::playing() {
+        return Ok(replay(cassette::chunks(&body)?));
+    }
+    // A recording needs the API's reply rather than the cache's
+    let cache_file = cache::path(&request).filter(|_| !cassette::recording());
+    if let Some(chunks) = cache_file.as_deref().and_then(cache::get) {
+        return Ok(replay(chunks));
+    }
+    spend::check(&request)?;
+    ratelimit::acquire(tokens(&request)).await;
+    let stall = timeouts().first_token;
+    let response = post_chat(&request, &body);
+    let (response, model) = match stall {
+        Some(stall) => tokio::time::timeout(stall, response)
+            .await
+            .map_err(|_| stalled(stall))??,
+        None => response.await?,
+    };
+    let mut events = response.bytes_stream().eventsource();
+
+    let (tx, rx) = channel(32);
+    tokio::spawn(async move {
+        // Logged as a whole once the stream ends
+        let mut chunks = Vec::new();
+        let mut complete = false;
+        let mut failed = false;
+        loop {
+            let event = match stall {
+                Some(stall) => match tokio::time::timeout(stall, events.next()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        tracing::warn!("{:#}", stalled(stall));
+                        failed = true;
+                        let _ = tx.send(Err(stalled(stall))).await;
+                        break;
+                    }
+                },
+                None => events.next().await,
+            };
+            let Some(event) = event else {
+                break;
+            };
+            let delta = match event {
+                Ok(event) if event.data == "[DONE]" => {
+                    complete = true;
+                    break;
+                }
+                Ok(event) => {
+                    chunks.push(serde_json::from_str(&event.data).unwrap_or(json!(event.data)));
+                    serde_json::from_str::<ChatCompletionDelta>(&event.data)
+                        .context("Unable to parse the streamed reply")
+                }
+                Err(e) => Err(StreamDropped(e.to_string()).into()),
+            };
+            if let Err(e) = &delta {
+                tracing::warn!("{:#}", e);
+            }
+            failed = delta.is_err();
+            if tx.send(delta).await.is_err() || failed {
+                break;
+            }
+        }
+        if !complete && !failed && !tx.is_closed() {
+            let dropped = StreamDropped("the connection closed part way".to_string());
+            let _ = tx.send(Err(dropped.into())).await;
+        }
+        tracing::debug!("Received {} chunks", chunks.len());
+        // The last chunk has the usage, if the API sends it
+        let usage = chunks
+            .iter()
+            .rev()
+            .find_map(|c| c.get("usage").filter(|u| u.is_object()));
+        if let Some(usage) = usage {
+            let count = |field: &str| usage[field].as_u64().unwrap_or(0);
+            spend::record(&model, count("prompt_tokens"), count("completion_tokens"));
+        }
+        if complete {
+            cassette::record_chunks(&body, &chunks, &[key()]);
+        }
+        if let Some(path) = cache_file.filter(|_| complete) {
+            if let Err(e) = cache::put(&path, &chunks) {
+                tracing::warn!("{:#}", e);
+            }
+        }
+        log(
+            "response",
+            json!({ "route": "chat/completions", "chunks": chunks }),
+        );
+    });
+
+    Ok(rx)
+}
+
+/// The body of a chat completion request, adjusted for the model it's for
+fn body(request: &ChatRequest) -> Result<Value> {
+    let mut body = serde_json::to_value(request)?;
+    reasoning::adapt(&request.model, &mut body);
+    Ok(body)
+}
+
+/// Stream a cached reply's chunks as if they had just arrived
+fn replay(chunks: Vec<Value>) -> Receiver<Result<ChatCompletionDelta>> {
+    let (tx, rx) = channel(chunks.len().max(1));
+    for chunk in chunks {
+        let delta = serde_json::from_value(chunk).context("Unable to parse the cached reply");
+        let _ = tx.try_send(delta);
+    }
+    rx
+}
+
+/// The tokens a request counts against a rate limit, its prompt and the most it may generate
+fn tokens(request: &ChatRequest) -> usize {
+    context::estimate(request) + request.sampling.max_tokens.unwrap_or(0) as usize
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Deserialize)]
+struct Embedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// Embed each of `inputs`, with OpenAI or another API with the same embeddings endpoint
+///
+/// `base_url` is e.g. `http://localhost:11434/v1/` for a local model, which
+/// is sent the API key only if one has been set.
+pub async fn embed(
+    base_url: Option<&str>,
+    model: &str,
+    inputs: &[String],
+) -> Result<Vec<Vec<f32>>> {
+    let request = EmbeddingRequest {
+        model,
+        input: inputs,
+    };
+    let response = match base_url {
+        Some(base_url) => post_to(base_url, API_KEY.get(), "embeddings", &request).await?,
+        None => post("embeddings", &request).await?,
+    };
+    let mut response: EmbeddingResponse = response
+        .json()
+        .await
+        .context("Unable to parse the embeddings")?;
+    log(
+        "response",
+        json!({ "route": "embeddings", "embeddings": response.data.len() }),
+    );
+    if response.data.len() != inputs.len() {
+        bail!(
+            "Asked for {} embeddings but got {}",
+            inputs.len(),
+            response.data.len()
+        );
+    }
+    response.data.sort_by_key(|e| e.index);
+    Ok(response.data.into_iter().map(|e| e.embedding).collect())
+}
+
+#[derive(Serialize)]
+struct ImageRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+    n: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct ImageResponse {
+    data: Vec<GeneratedImage>,
+}
+
+/// An image is returned either inline or as a URL to download it from, depending on the model
+#[derive(Deserialize)]
+struct GeneratedImage {
+    b64_json: Option<String>,
+    url: Option<String>,
+}
+
+/// Generate `n` images from a prompt, returning each image file's bytes
+pub async fn generate_images(
+    model: &str,
+    prompt: &str,
+    n: usize,
+    size: Option<&str>,
+) -> Result<Vec<Vec<u8>>> {
+    let request = ImageRequest {
+        model,
+        prompt,
+        n,
+        size,
+    };
+    let response: ImageResponse = post("images/generations", &request)
+        .await?
+        .json()
+        .await
+        .context("Unable to parse the generated images")?;
+    log(
+        "response",
+        json!({ "route": "images/generations", "images": response.data.len() }),
+    );
+    let mut images = Vec::new();
+    for image in response.data {
+        let bytes = match (image.b64_json, image.url) {
+            (Some(data), _) => base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .context("Unable to decode a generated image")?,
+            (None, Some(url)) => client()
+                .get(&url)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .context("Unable to download a generated image")?
+                .bytes()
+                .await?
+                .to_vec(),
+            (None, None) => bail!("The API returned an image without any data"),
+        };
+        images.push(bytes);
+    }
+    Ok(images)
+}
+
+#[derive(Deserialize)]
+struct Transcription {
+    text: String,
+}
+
+/// Transcribe an audio file to text, with OpenAI or another API with the same transcriptions endpoint
+///
+/// `base_url` is e.g. `http://localhost:8000/v1/` for a local Whisper server,
+/// which is sent the API key only if one has been set.
+pub async fn transcribe(
+    base_url: Option<&str>,
+    model: &str,
+    language: Option<&str>,
+    audio: &Path,
+) -> Result<String> {
+    let (base_url, key) = match base_url {
+        Some(base_url) => (base_url, API_KEY.get()),
+        None => (
+            self::base_url(),
+            Some(API_KEY.get().context("The API key has not been set")?),
+        ),
+    };
+    let url = format!("{}/audio/transcriptions", base_url.trim_end_matches('/'));
+    let bytes = tokio::fs::read(audio)
+        .await
+        .with_context(|| format!("Unable to read {}", audio.display()))?;
+    let file_name = audio.file_name().map_or("audio.wav".into(), |name| {
+        name.to_string_lossy().into_owned()
+    });
+    log(
+        "request",
+        json!({ "url": url, "model": model, "file": file_name, "bytes": bytes.len() }),
+    );
+    let mut form = reqwest::multipart::Form::new()
+        .text("model", model.to_string())
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(bytes).file_name(file_name),
+        );
+    if let Some(language) = language {
+        form = form.text("language", language.to_string());
+    }
+
+    tracing::debug!("POST {}", url);
+    let response = authorize(client().post(&url), key)
+        .multipart(form)
+        .send()
+        .await
+        .context("Unable to reach the API")?;
+    let body = error_for_status(response, "audio/transcriptions")
+        .await?
+        .text()
+        .await?;
+    log_response("audio/transcriptions", &body);
+    let transcription: Transcription =
+        serde_json::from_str(&body).context("Unable to parse the transcription")?;
+    Ok(transcription.text)
+}
+
+/// Record a request or response in the request log, without the API key
+fn log(kind: &str, entry: Value) {
+    logging::record(kind, entry, &[key()]);
+}
+
+/// The API key, to be left out of what's logged or recorded
+fn key() -> &'static str {
+    API_KEY.get().map(String::as_str).unwrap_or_default()
+}
+
+fn log_response(route: &str, body: &str) {
+    let body = serde_json::from_str(body).unwrap_or_else(|_| json!(body));
+    log("response", json!({ "route": route, "body": body }));
+}
+
+/// POST a chat request, retrying it and then trying the fallbacks while the API is down
+///
+/// Returns the response with the model it's from.
+async fn post_chat(request: &ChatRequest, body: &Value) -> Result<(reqwest::Response, String)> {
+    let fallbacks = fallback::fallbacks();
+    let from = match fallback::active() {
+        Some(active) => active,
+        None => match fallback::with_retries(|| post("chat/completions", body)).await {
+            Err(e) if fallback::is_outage(&e) && !fallbacks.is_empty() => {
+                fallback::switch(0, &e);
+                0
+            }
+            result => return Ok((result?, request.model.clone())),
+        },
+    };
+    for (i, fallback) in fallbacks.iter().enumerate().skip(from) {
+        let request = ChatRequest {
+            model: fallback.model.clone(),
+            ..request.clone()
+        };
+        let body = self::body(&request)?;
+        match fallback::with_retries(|| post_fallback(fallback, &body)).await {
+            Err(e) if fallback::is_outage(&e) && i + 1 < fallbacks.len() => {
+                fallback::switch(i + 1, &e)
+            }
+            result => return Ok((result?, request.model)),
+        }
+    }
+    unreachable!("the last fallback's result is returned")
+}
+
+/// POST a chat request's body to a fallback API
+async fn post_fallback(fallback: &fallback::Fallback, body: &Value) -> Result<reqwest::Response> {
+    let url = format!(
+        "{}/chat/completions",
+        fallback.base_url.trim_end_matches('/')
+    );
+    tracing::debug!("POST {}", url);
+    log("request", json!({ "url": url, "body": body }));
+    let mut request = client().post(&url).json(body);
+    if let Some(key) = fallback.key() {
+        request = match &fallback.key_header {
+            Some(header) => request.header(header.as_str(), key),
+            None => request.bearer_auth(key),
+        };
+    }
+    let response = request.send().await.context("Unable to reach the API")?;
+    error_for_status(response, "chat/completions").await
+}
+
+/// POST a JSON body, turning an error response into an error with the API's message
+async fn post<T: Serialize>(route: &str, body: &T) -> Result<reqwest::Response> {
+    let key = API_KEY.get().context("The API key has not been set")?;
+    post_to(base_url(), Some(key), route, body).await
+}
+
+async fn post_to<T: Serialize>(
+    base_url: &str,
+    key: Option<&String>,
+    route: &str,
+    body: &T,
+) -> Result<reqwest::Response> {
+    let url = format!("{}/{}", base_url.trim_end_matches('/'), route);
+    tracing::debug!("POST {}", url);
+    let body = serde_json::to_value(body)?;
+    tracing::trace!("Request body: {}", body);
+    log("request", json!({ "url": url, "body": body }));
+
+    let response = authorize(client().post(&url).json(&body), key)
+        .send()
+        .await
+        .context("Unable to reach the API")?;
+    error_for_status(response, route).await
+}
+
+/// Send the key the way the API expects it, with the organization and project it's used for
+fn authorize(request: reqwest::RequestBuilder, key: Option<&String>) -> reqwest::RequestBuilder {
+    let Some(key) = key else {
+        return request;
+    };
+    let mut request = match KEY_HEADER.get() {
+        Some(header) => request.header(header.as_str(), key),
+        None => request.bearer_auth(key),
+    };
+    if let Some(organization) = ORGANIZATION.get() {
+        request = request.header("OpenAI-Organization", organization);
+    }
+    if let Some(project) = PROJECT.get() {
+        request = request.header("OpenAI-Project", project);
+    }
+    request
+}
+
+/// Turn an error response into an error with the API's message
+async fn error_for_status(response: reqwest::Response, route: &str) -> Result<reqwest::Response> {
+    let status = response.status();
+    tracing::debug!("{} from {}", status, response.url());
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        log_response(route, &body);
+        let message = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|v| v["error"]["message"].as_str().map(str::to_string))
+            .unwrap_or(body);
+        return Err(ApiError { status, message }.into());
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_the_http_config() {
+        let config = HttpConfig {
+            client_cert: Some(PathBuf::from("cert.pem")),
+            ..Default::default()
+        };
+        let error = configure(&config).unwrap_err();
+        assert!(error.to_string().contains("set together"));
+
+        let config = HttpConfig {
+            proxy: Some("not a url".to_string()),
+            ..Default::default()
+        };
+        assert!(configure(&config).is_err());
+    }
+
+    #[test]
+    fn sends_the_organization_and_project_with_the_key() {
+        set_organization("org-1".to_string());
+        set_project("proj-1".to_string());
+        let request = |key: Option<&String>| {
+            authorize(reqwest::Client::new().get("http://localhost/"), key)
+                .build()
+                .unwrap()
+        };
+
+        let keyed = request(Some(&"sk-1".to_string()));
+        let headers = keyed.headers();
+        assert_eq!(headers["authorization"], "Bearer sk-1");
+        assert_eq!(headers["openai-organization"], "org-1");
+        assert_eq!(headers["openai-project"], "proj-1");
+        // A local server without a key isn't told either
+        assert!(request(None).headers().is_empty());
+    }
+}
@@ -0,0 +1,490 @@
+use crate::{
+    directives,
+    message::Message,
+    provider::{self, Content, ContentPart, RequestMessage},
+    storage::ChatStore,
+};
+use anyhow::{bail, Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Embedding model used when the config doesn't choose one
+const DEFAULT_MODEL: &str = "text-embedding-3-small";
+
+/// Chunks retrieved per message when the config doesn't say
+const DEFAULT_TOP_K: usize = 5;
+
+/// Chunks are cut at the first line past this many characters
+const CHUNK_CHARS: usize = 1500;
+
+/// Lines repeated at the start of the next chunk, so text isn't cut off from its context
+const OVERLAP_LINES: usize = 3;
+
+/// Chunks embedded per request
+const BATCH_SIZE: usize = 64;
+
+/// Retrieval from local documents, the `[rag]` config table
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RagConfig {
+    /// Embedding model, `text-embedding-3-small` by default
+    pub model: Option<String>,
+    /// Base URL of an OpenAI compatible API to embed with instead, e.g. a local model
+    pub base_url: Option<String>,
+    /// Chunks retrieved per message
+    pub top_k: Option<usize>,
+}
+
+impl RagConfig {
+    fn model(&self) -> &str {
+        self.model.as_deref().unwrap_or(DEFAULT_MODEL)
+    }
+}
+
+/// Part of a file, with the lines it covers (counting from 1)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub path: PathBuf,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+}
+
+/// What indexing a directory changed
+pub struct Indexed {
+    pub files: usize,
+    pub chunks: usize,
+    pub removed: usize,
+}
+
+/// An index and the directory it was made from
+pub struct IndexInfo {
+    pub name: String,
+    pub dir: PathBuf,
+    pub chunks: usize,
+}
+
+/// Where the index called `name` is kept, `$XDG_DATA_HOME/chat-cli-rs/rag/<name>.sqlite`
+fn index_path(name: &str) -> Result<PathBuf> {
+    Ok(ChatStore::open()?
+        .dir()
+        .join("rag")
+        .join(format!("{}.sqlite", name)))
+}
+
+/// Chunk and embed the files in `dir` into the index called `name`
+///
+/// Only files changed since the last time are embedded again, and files that
+/// are gone are removed. Changing the embedding model rebuilds the index.
+pub async fn index(name: &str, dir: &Path, config: &RagConfig) -> Result<Indexed> {
+    let dir =
+        std::fs::canonicalize(dir).with_context(|| format!("Unable to find {}", dir.display()))?;
+    let path = index_path(name)?;
+    std::fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+    let db = Connection::open(&path)
+        .with_context(|| format!("Unable to open the index: {}", path.display()))?;
+    create_tables(&db)?;
+
+    if meta(&db, "model")?.is_some_and(|model| model != config.model()) {
+        eprintln!("The embedding model changed, rebuilding the index");
+        db.execute_batch("DELETE FROM chunks; DELETE FROM files;")?;
+    }
+    set_meta(&db, "model", config.model())?;
+    set_meta(&db, "dir", &dir.to_string_lossy())?;
+
+    let files = directives::walk(&dir, None)?;
+    let mut indexed = Indexed {
+        files: 0,
+        chunks: 0,
+        removed: 0,
+    };
+    for file in &files {
+        let modified = modified(file)?;
+        let key = file.to_string_lossy();
+        let known: Option<i64> = db
+            .query_row(
+                "SELECT modified FROM files WHERE path = ?1",
+                [&key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if known == Some(modified) {
+            continue;
+        }
+        let Ok(text) = std::fs::read_to_string(file) else {
+            continue;
+        };
+
+        let chunks = chunk(file, &text, CHUNK_CHARS);
+        let mut embeddings = Vec::with_capacity(chunks.len());
+        for batch in chunks.chunks(BATCH_SIZE) {
+            let texts: Vec<String> = batch.iter().map(embedded_text).collect();
+            embeddings
+                .extend(provider::embed(config.base_url.as_deref(), config.model(), &texts).await?);
+        }
+
+        let tx = db.unchecked_transaction()?;
+        tx.execute("DELETE FROM chunks WHERE path = ?1", [&key])?;
+        for (chunk, embedding) in chunks.iter().zip(&embeddings) {
+            tx.execute(
+                "INSERT INTO chunks (path, start_line, end_line, text, embedding) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    key,
+                    chunk.start_line as i64,
+                    chunk.end_line as i64,
+                    chunk.text,
+                    to_bytes(embedding)
+                ],
+            )?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO files (path, modified) VALUES (?1, ?2)",
+            params![key, modified],
+        )?;
+        tx.commit()?;
+        println!("Indexed {} ({} chunks)", file.display(), chunks.len());
+        indexed.files += 1;
+        indexed.chunks += chunks.len();
+    }
+
+    let present: HashSet<String> = files
+        .iter()
+        .map(|f| f.to_string_lossy().into_owned())
+        .collect();
+    let known: Vec<String> = db
+        .prepare("SELECT path FROM files")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    for gone in known.iter().filter(|path| !present.contains(*path)) {
+        db.execute("DELETE FROM chunks WHERE path = ?1", [gone])?;
+        db.execute("DELETE FROM files WHERE path = ?1", [gone])?;
+        indexed.removed += 1;
+    }
+    Ok(indexed)
+}
+
+/// The chunks in the index called `name` most similar to `query`
+pub async fn retrieve(name: &str, query: &str, config: &RagConfig) -> Result<Vec<Chunk>> {
+    let path = index_path(name)?;
+    if !path.exists() {
+        bail!(
+            "There's no index called {}, create it with `chat-cli-rs rag index <dir> --name {}`",
+            name,
+            name
+        );
+    }
+    let model = {
+        let db = Connection::open(&path)?;
+        meta(&db, "model")?.unwrap_or_else(|| config.model().to_string())
+    };
+    let query = provider::embed(config.base_url.as_deref(), &model, &[query.to_string()])
+        .await?
+        .pop()
+        .context("The API returned no embedding")?;
+
+    let db = Connection::open(&path)?;
+    let mut statement =
+        db.prepare("SELECT path, start_line, end_line, text, embedding FROM chunks")?;
+    let mut scored = statement
+        .query_map([], |row| {
+            let embedding: Vec<u8> = row.get(4)?;
+            let chunk = Chunk {
+                path: PathBuf::from(row.get::<_, String>(0)?),
+                start_line: row.get::<_, i64>(1)? as usize,
+                end_line: row.get::<_, i64>(2)? as usize,
+                text: row.get(3)?,
+            };
+            Ok((cosine(&query, &from_bytes(&embedding)), chunk))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    Ok(scored
+        .into_iter()
+        .take(config.top_k.unwrap_or(DEFAULT_TOP_K))
+        .map(|(_, chunk)| chunk)
+        .collect())
+}
+
+/// Index `dir` as `name`, or after the directory, and say what changed, for `rag index`
+pub async fn update(name: Option<&str>, dir: &Path, config: &RagConfig) -> Result<()> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => std::fs::canonicalize(dir)?
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .context("Name the index with --name")?,
+    };
+    let indexed = index(&name, dir, config).await?;
+    println!(
+        "Index {}: embedded {} changed files into {} chunks, removed {} deleted files",
+        name, indexed.files, indexed.chunks, indexed.removed
+    );
+    Ok(())
+}
+
+/// Print the indexes, for `rag list`
+pub fn print_list() -> Result<()> {
+    for index in list()? {
+        println!(
+            "{:<20} {:>6} chunks  {}",
+            index.name,
+            index.chunks,
+            index.dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// The indexes that have been made
+pub fn list() -> Result<Vec<IndexInfo>> {
+    let dir = ChatStore::open()?.dir().join("rag");
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut indexes = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "sqlite") {
+            continue;
+        }
+        let db = Connection::open(&path)?;
+        let chunks: i64 = db.query_row("SELECT COUNT(*) FROM chunks", [], |row| row.get(0))?;
+        indexes.push(IndexInfo {
+            name: path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            dir: meta(&db, "dir")?.map(PathBuf::from).unwrap_or_default(),
+            chunks: chunks as usize,
+        });
+    }
+    indexes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(indexes)
+}
+
+/// The text of the last user message in a request, to retrieve chunks for
+pub fn question(messages: &[RequestMessage]) -> Option<String> {
+    let message = messages
+        .iter()
+        .rev()
+        .find(|m| matches!(m.role, ChatCompletionMessageRole::User))?;
+    match message.content.as_ref()? {
+        Content::Text(text) => Some(text.clone()),
+        Content::Parts(parts) => parts.iter().find_map(|part| match part {
+            ContentPart::Text { text } => Some(text.clone()),
+            _ => None,
+        }),
+    }
+}
+
+/// Put the chunks ahead of the last user message in the request, numbered for citing
+///
+/// The chat file is left as it is.
+pub fn add_context(messages: &mut [RequestMessage], chunks: &[Chunk]) {
+    let Some(message) = messages
+        .iter_mut()
+        .rev()
+        .find(|m| matches!(m.role, ChatCompletionMessageRole::User))
+    else {
+        return;
+    };
+    let mut context = String::from(
+        "Excerpts that may help with my message, cite the ones you use by number like [1]:\n\n",
+    );
+    for (i, chunk) in chunks.iter().enumerate() {
+        let fence = "`".repeat(directives::longest_backtick_run(&chunk.text).max(2) + 1);
+        context.push_str(&format!(
+            "[{}] {}\n{}\n{}\n{}\n\n",
+            i + 1,
+            location(chunk),
+            fence,
+            chunk.text.trim_end(),
+            fence
+        ));
+    }
+    context.push_str("My message:\n\n");
+
+    match &mut message.content {
+        Some(Content::Text(text)) => text.insert_str(0, &context),
+        Some(Content::Parts(parts)) => parts.insert(0, ContentPart::Text { text: context }),
+        None => message.content = Some(Content::Text(context)),
+    }
+}
+
+/// Add the numbered sources to the end of the last reply in the chat file
+pub fn cite(chat_file: &Path, chunks: &[Chunk]) -> Result<()> {
+    let (frontmatter, mut messages) = Message::read_chat(chat_file)?;
+    let Some(reply) = messages.iter_mut().rev().find(|m| {
+        matches!(m.role, ChatCompletionMessageRole::Assistant) && m.function_call.is_none()
+    }) else {
+        return Ok(());
+    };
+    let sources: Vec<String> = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| format!("[{}] `{}`", i + 1, location(chunk)))
+        .collect();
+    reply.content = format!(
+        "{}\n\nSources:\n{}",
+        reply.content.trim_end(),
+        sources.join("\n")
+    );
+    Message::write_chat(chat_file, &frontmatter, &messages)
+}
+
+/// Where the chunk is, `<path>:<start>-<end>`
+pub fn location(chunk: &Chunk) -> String {
+    format!(
+        "{}:{}-{}",
+        chunk.path.display(),
+        chunk.start_line,
+        chunk.end_line
+    )
+}
+
+/// Split a file into chunks of whole lines, each overlapping the one before
+///
+/// Chunks are cut at the first line past `chars` characters.
+pub fn chunk(path: &Path, text: &str, chars: usize) -> Vec<Chunk> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        let mut end = start;
+        let mut size = 0;
+        while end < lines.len() && (size < chars || end == start) {
+            size += lines[end].len() + 1;
+            end += 1;
+        }
+        let text = lines[start..end].join("\n");
+        if !text.trim().is_empty() {
+            chunks.push(Chunk {
+                path: path.to_path_buf(),
+                start_line: start + 1,
+                end_line: end,
+                text,
+            });
+        }
+        if end == lines.len() {
+            break;
+        }
+        start = end.saturating_sub(OVERLAP_LINES).max(start + 1);
+    }
+    chunks
+}
+
+/// A chunk is embedded with its path, which often says what it's about
+fn embedded_text(chunk: &Chunk) -> String {
+    format!("{}\n\n{}", chunk.path.display(), chunk.text)
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm(a) * norm(b) {
+        0.0 => 0.0,
+        norms => dot / norms,
+    }
+}
+
+fn to_bytes(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+fn modified(path: &Path) -> Result<i64> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    Ok(modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64))
+}
+
+fn create_tables(db: &Connection) -> Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+         CREATE TABLE IF NOT EXISTS files (path TEXT PRIMARY KEY, modified INTEGER NOT NULL);
+         CREATE TABLE IF NOT EXISTS chunks (
+             id INTEGER PRIMARY KEY,
+             path TEXT NOT NULL,
+             start_line INTEGER NOT NULL,
+             end_line INTEGER NOT NULL,
+             text TEXT NOT NULL,
+             embedding BLOB NOT NULL
+         );
+         CREATE INDEX IF NOT EXISTS chunks_path ON chunks (path);",
+    )
+    .context("Unable to set up the index")
+}
+
+fn meta(db: &Connection, key: &str) -> Result<Option<String>> {
+    Ok(db
+        .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+            row.get(0)
+        })
+        .optional()?)
+}
+
+fn set_meta(db: &Connection, key: &str, value: &str) -> Result<()> {
+    db.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+        [key, value],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_overlap_and_cover_the_file() {
+        let text: String = (1..=100).map(|i| format!("line {:>40}\n", i)).collect();
+        let chunks = chunk(Path::new("notes.md"), &text, CHUNK_CHARS);
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks[0].start_line, 1);
+        assert_eq!(chunks.last().unwrap().end_line, 100);
+        for pair in chunks.windows(2) {
+            assert_eq!(pair[1].start_line, pair[0].end_line - OVERLAP_LINES + 1);
+        }
+    }
+
+    #[test]
+    fn adds_numbered_context_and_sources() {
+        let chunk = Chunk {
+            path: PathBuf::from("/docs/setup.md"),
+            start_line: 3,
+            end_line: 9,
+            text: "Run `make`".to_string(),
+        };
+        let mut messages = vec![RequestMessage::text(
+            ChatCompletionMessageRole::User,
+            "How do I build it?",
+        )];
+        assert_eq!(question(&messages).as_deref(), Some("How do I build it?"));
+        add_context(&mut messages, std::slice::from_ref(&chunk));
+        let Some(Content::Text(text)) = &messages[0].content else {
+            panic!("Expected text");
+        };
+        assert!(text.contains("[1] /docs/setup.md:3-9\n```\nRun `make`\n```"));
+        assert!(text.ends_with("My message:\n\nHow do I build it?"));
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "# User\nHi\n# Assistant\nMake [1]\n# User\n\n").unwrap();
+        cite(file.path(), &[chunk]).unwrap();
+        let contents = std::fs::read_to_string(file.path()).unwrap();
+        assert!(contents.ends_with("Make [1]\n\nSources:\n[1] `/docs/setup.md:3-9`\n# User\n\n"));
+        assert_eq!(cosine(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+    }
+}
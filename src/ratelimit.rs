@@ -0,0 +1,163 @@
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// How fast requests may be sent to a provider, a `[rate_limits.<provider>]` config table
+///
+/// Tokens are counted as the estimated prompt plus `max_tokens`, as the API does.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    pub requests_per_minute: Option<usize>,
+    pub tokens_per_minute: Option<usize>,
+}
+
+/// How long requests were held back by the rate limit
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Waited {
+    pub requests: usize,
+    pub total: Duration,
+    pub longest: Duration,
+}
+
+struct Limiter {
+    limit: RateLimit,
+    /// When each request in the last minute was sent and its tokens
+    sent: Mutex<VecDeque<(Instant, usize)>>,
+    waited: Mutex<Waited>,
+}
+
+static LIMITER: OnceLock<Limiter> = OnceLock::new();
+
+/// Limit the chat completion requests made by every part of the program
+pub fn set(limit: RateLimit) {
+    let _ = LIMITER.set(Limiter {
+        limit,
+        sent: Mutex::new(VecDeque::new()),
+        waited: Mutex::new(Waited::default()),
+    });
+}
+
+/// Wait until a request of `tokens` can be sent without going over the limit
+pub async fn acquire(tokens: usize) {
+    let Some(limiter) = LIMITER.get() else {
+        return;
+    };
+    let start = Instant::now();
+    loop {
+        let delay = {
+            let mut sent = limiter.sent.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let delay = delay(&limiter.limit, &mut sent, now, tokens);
+            if delay.is_none() {
+                sent.push_back((now, tokens));
+            }
+            delay
+        };
+        match delay {
+            Some(delay) => tokio::time::sleep(delay).await,
+            None => break,
+        }
+    }
+
+    let waited = start.elapsed();
+    if waited >= Duration::from_millis(1) {
+        tracing::info!("Waited {:.1}s for the rate limit", waited.as_secs_f32());
+        let mut stats = limiter.waited.lock().unwrap_or_else(|e| e.into_inner());
+        stats.requests += 1;
+        stats.total += waited;
+        stats.longest = stats.longest.max(waited);
+    }
+}
+
+/// How long requests have waited for the rate limit so far
+pub fn waited() -> Waited {
+    LIMITER.get().map_or_else(Waited::default, |limiter| {
+        *limiter.waited.lock().unwrap_or_else(|e| e.into_inner())
+    })
+}
+
+/// A line saying how long requests waited, if any did
+pub fn report() -> Option<String> {
+    let waited = waited();
+    (waited.requests > 0).then(|| {
+        format!(
+            "{} requests waited {:.1}s for the rate limit, {:.1}s at most",
+            waited.requests,
+            waited.total.as_secs_f32(),
+            waited.longest.as_secs_f32()
+        )
+    })
+}
+
+/// How long until a request of `tokens` fits in the limit, forgetting requests over a minute old
+fn delay(
+    limit: &RateLimit,
+    sent: &mut VecDeque<(Instant, usize)>,
+    now: Instant,
+    tokens: usize,
+) -> Option<Duration> {
+    while sent
+        .front()
+        .is_some_and(|(at, _)| now.duration_since(*at) >= WINDOW)
+    {
+        sent.pop_front();
+    }
+    // The requests that have to age out of the window first
+    let mut expire = 0;
+    if let Some(requests) = limit.requests_per_minute {
+        if sent.len() >= requests.max(1) {
+            expire = sent.len() + 1 - requests.max(1);
+        }
+    }
+    if let Some(limit) = limit.tokens_per_minute {
+        let mut used: usize = sent.iter().map(|(_, tokens)| tokens).sum();
+        let mut n = 0;
+        // A request bigger than the whole limit is sent once the window is empty
+        while n < sent.len() && used + tokens > limit {
+            used -= sent[n].1;
+            n += 1;
+        }
+        expire = expire.max(n);
+    }
+    match expire {
+        0 => None,
+        n => Some((sent[n - 1].0 + WINDOW).saturating_duration_since(now)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_requests_to_leave_the_window() {
+        let limit = RateLimit {
+            requests_per_minute: Some(2),
+            tokens_per_minute: Some(1000),
+        };
+        let start = Instant::now();
+        let mut sent = VecDeque::from([(start, 100), (start + Duration::from_secs(10), 100)]);
+        let now = start + Duration::from_secs(20);
+        assert_eq!(
+            delay(&limit, &mut sent, now, 100),
+            Some(Duration::from_secs(40))
+        );
+
+        sent.pop_back();
+        assert_eq!(delay(&limit, &mut sent, now, 900), None);
+        assert_eq!(
+            delay(&limit, &mut sent, now, 950),
+            Some(Duration::from_secs(40))
+        );
+
+        let later = start + Duration::from_secs(61);
+        assert_eq!(delay(&limit, &mut sent, later, 5000), None);
+        assert!(sent.is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+//! The differences in how reasoning models, OpenAI's o1, o3 and o4-mini, are requested
+//!
+//! They take the system prompt as a `developer` message, limit their reply
+//! with `max_completion_tokens`, reject the sampling parameters and think for
+//! a while before the first token arrives, see [`crate::spinner`].
+
+use serde_json::Value;
+
+/// Parameters reasoning models reject
+const UNSUPPORTED: &[&str] = &[
+    "temperature",
+    "top_p",
+    "frequency_penalty",
+    "presence_penalty",
+    "logit_bias",
+];
+
+/// Whether the model is a reasoning model, e.g. `o1`, `o3-mini` or `openai/o4-mini`
+pub fn is_reasoning_model(model: &str) -> bool {
+    let name = model.rsplit('/').next().unwrap_or(model);
+    let mut chars = name.chars();
+    chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+/// Adjust a chat completion request's body for the model it's for
+///
+/// Only reasoning models take `reasoning_effort`, so it's left out for the rest.
+pub fn adapt(model: &str, body: &mut Value) {
+    let Some(body) = body.as_object_mut() else {
+        return;
+    };
+    if !is_reasoning_model(model) {
+        body.remove("reasoning_effort");
+        return;
+    }
+    for parameter in UNSUPPORTED {
+        if body.remove(*parameter).is_some() {
+            tracing::warn!("{} doesn't take {}, it's left out", model, parameter);
+        }
+    }
+    if let Some(max_tokens) = body.remove("max_tokens") {
+        body.insert("max_completion_tokens".to_string(), max_tokens);
+    }
+    let messages = body.get_mut("messages").and_then(Value::as_array_mut);
+    for message in messages.into_iter().flatten() {
+        if message["role"] == "system" {
+            message["role"] = "developer".into();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn adapts_requests_for_reasoning_models() {
+        assert!(is_reasoning_model("o3-mini"));
+        assert!(is_reasoning_model("openai/o1"));
+        assert!(!is_reasoning_model("gpt-4o"));
+
+        let mut body = json!({
+            "model": "o3-mini",
+            "messages": [{"role": "system", "content": "Be terse"}, {"role": "user", "content": "Hi"}],
+            "temperature": 0.2,
+            "max_tokens": 500,
+            "reasoning_effort": "high",
+        });
+        adapt("o3-mini", &mut body);
+        assert_eq!(
+            body,
+            json!({
+                "model": "o3-mini",
+                "messages": [{"role": "developer", "content": "Be terse"}, {"role": "user", "content": "Hi"}],
+                "max_completion_tokens": 500,
+                "reasoning_effort": "high",
+            })
+        );
+
+        let mut body = json!({"model": "gpt-4o", "temperature": 0.2, "reasoning_effort": "high"});
+        adapt("gpt-4o", &mut body);
+        assert_eq!(body, json!({"model": "gpt-4o", "temperature": 0.2}));
+    }
+}
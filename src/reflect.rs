@@ -0,0 +1,198 @@
+use crate::{
+    config::Settings,
+    message::Message,
+    prompts, provider,
+    provider::RequestMessage,
+    session::{Interrupted, Reply},
+    spinner,
+};
+use anyhow::{Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use std::{future::Future, path::Path, pin::Pin};
+
+/// Opens the collapsed block of drafts and critiques above a reflected answer
+const OPEN: &str = "<details>\n<summary>Reflection";
+const CLOSE: &str = "\n</details>\n";
+
+const CRITIQUE_REQUEST: &str = "Critique your last answer.";
+
+/// The answer without the drafts and critiques that led to it
+///
+/// Only the final answer is sent in later requests.
+pub fn answer(content: &str) -> &str {
+    if !content.starts_with(OPEN) {
+        return content;
+    }
+    match content.find(CLOSE) {
+        Some(end) => content[end + CLOSE.len()..].trim_start(),
+        None => content,
+    }
+}
+
+/// Critique and revise the chat's last reply `rounds` times
+///
+/// The reply is replaced by the final answer, with the first draft and each
+/// critique and revision collapsed above it. If cancelled, the reply is left
+/// as it was.
+pub async fn revise(
+    chat_file: &Path,
+    settings: &Settings,
+    rounds: usize,
+    reply: Reply,
+    cancel: &mut Pin<&mut impl Future<Output = ()>>,
+) -> Result<Reply> {
+    let (frontmatter, mut messages) = Message::read_chat(chat_file)?;
+    // The empty message left for the user's next turn
+    if messages.last().is_some_and(|m| {
+        matches!(m.role, ChatCompletionMessageRole::User) && m.content.trim().is_empty()
+    }) {
+        messages.pop();
+    }
+    let last = messages
+        .iter()
+        .rposition(|m| {
+            matches!(m.role, ChatCompletionMessageRole::Assistant) && m.function_call.is_none()
+        })
+        .context("The chat has no reply to reflect on")?;
+    let base_dir = chat_file.parent().unwrap_or(Path::new("."));
+    let conversation = messages[..last]
+        .iter()
+        .map(|m| m.to_request(base_dir))
+        .collect::<Result<Vec<_>>>()?;
+    let critic = prompts::resolve(prompts::CRITIQUE_PROMPT)?;
+
+    let draft = answer(&messages[last].content).to_string();
+    let mut steps = vec![("Draft".to_string(), draft.clone())];
+    let mut answer = draft;
+    for round in 1..=rounds {
+        eprintln!("Critiquing the answer ({}/{})", round, rounds);
+        let critique = complete(
+            settings,
+            critique_request(&conversation, &critic, &answer),
+            cancel,
+        )
+        .await?;
+        eprintln!("Revising the answer ({}/{})", round, rounds);
+        let revised = complete(
+            settings,
+            revision_request(&conversation, &answer, &critique),
+            cancel,
+        )
+        .await?;
+        steps.push((format!("Critique {}", round), critique));
+        if round < rounds {
+            steps.push((format!("Revision {}", round), revised.clone()));
+        }
+        answer = revised;
+    }
+
+    messages[last].content = format!("{}\n\n{}", collapsed(&steps, rounds), answer);
+    Message::write_chat(chat_file, &frontmatter, &messages)?;
+    let mut reply = reply;
+    reply.message.content = Some(answer);
+    Ok(reply)
+}
+
+/// The critic sees the conversation under its own system prompt
+fn critique_request(
+    conversation: &[RequestMessage],
+    critic: &str,
+    answer: &str,
+) -> Vec<RequestMessage> {
+    let mut messages = vec![RequestMessage::text(
+        ChatCompletionMessageRole::System,
+        critic,
+    )];
+    messages.extend(
+        conversation
+            .iter()
+            .filter(|m| !matches!(m.role, ChatCompletionMessageRole::System))
+            .cloned(),
+    );
+    messages.push(RequestMessage::text(
+        ChatCompletionMessageRole::Assistant,
+        answer,
+    ));
+    messages.push(RequestMessage::text(
+        ChatCompletionMessageRole::User,
+        CRITIQUE_REQUEST,
+    ));
+    messages
+}
+
+fn revision_request(
+    conversation: &[RequestMessage],
+    answer: &str,
+    critique: &str,
+) -> Vec<RequestMessage> {
+    let mut messages = conversation.to_vec();
+    messages.push(RequestMessage::text(
+        ChatCompletionMessageRole::Assistant,
+        answer,
+    ));
+    messages.push(RequestMessage::text(
+        ChatCompletionMessageRole::User,
+        &format!(
+            "Here's a critique of your last answer:\n\n{}\n\nRevise the answer to address it. Reply with only the revised answer.",
+            critique.trim()
+        ),
+    ));
+    messages
+}
+
+/// Request a reply without tools, waiting for the whole of it
+async fn complete(
+    settings: &Settings,
+    messages: Vec<RequestMessage>,
+    cancel: &mut Pin<&mut impl Future<Output = ()>>,
+) -> Result<String> {
+    let mut request = settings.request(messages);
+    request.functions.clear();
+    let reply = spinner::wait(&[&request.model.clone()], provider::create(request));
+    let completion = tokio::select! {
+        completion = reply => completion?,
+        _ = cancel.as_mut() => return Err(Interrupted.into()),
+    };
+    let choice = completion
+        .choices
+        .first()
+        .context("The API returned no choices")?;
+    Ok(choice
+        .message
+        .content
+        .as_deref()
+        .unwrap_or_default()
+        .trim()
+        .to_string())
+}
+
+/// The steps as a collapsed block, e.g. `<details><summary>Reflection, 2 rounds</summary>...`
+fn collapsed(steps: &[(String, String)], rounds: usize) -> String {
+    let rounds = match rounds {
+        1 => "1 round".to_string(),
+        n => format!("{} rounds", n),
+    };
+    let mut block = format!("{}, {}</summary>\n", OPEN, rounds);
+    for (title, text) in steps {
+        block.push_str(&format!("\n### {}\n\n{}\n", title, text.trim()));
+    }
+    block.push_str(CLOSE.trim_end());
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_final_answer_is_kept() {
+        let steps = [
+            ("Draft".to_string(), "2 + 2 = 5".to_string()),
+            ("Critique 1".to_string(), "- It's 4".to_string()),
+        ];
+        let content = format!("{}\n\n2 + 2 = 4", collapsed(&steps, 1));
+        assert!(content.starts_with("<details>\n<summary>Reflection, 1 round</summary>\n"));
+        assert_eq!(answer(&content), "2 + 2 = 4");
+        assert_eq!(answer("Just an answer"), "Just an answer");
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{
+    config::{Config, Overrides},
+    message::Message,
+    session::Session,
+    storage,
+};
+use anyhow::{bail, Result};
+use openai::chat::ChatCompletionMessageRole;
+use std::path::Path;
+
+/// Send each of a chat's user messages again in a new chat, e.g. to another model
+///
+/// The new chat starts with the original's system prompt and settings, with
+/// the overrides (such as the model) recorded in its frontmatter. The replies
+/// build on the earlier replies in the new chat rather than the original's.
+pub async fn replay(chat_file: &Path, config: Config, overrides: Overrides) -> Result<Session> {
+    let (mut frontmatter, messages) = Message::read_chat(chat_file)?;
+    let (mut messages, turns) = split(messages);
+    if turns.is_empty() {
+        bail!("The chat has no user messages to replay");
+    }
+
+    // The replay is titled afresh once it has a reply of its own
+    frontmatter.title = None;
+    frontmatter.created =
+        Some(chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false));
+    frontmatter.replayed_from = Some(chat_file.to_path_buf());
+    if let Some(model) = &overrides.model {
+        frontmatter.model = Some(model.clone());
+    }
+
+    let replay = storage::new_chat_file_path();
+    let session = Session::open(&replay, config).with_overrides(overrides);
+    let total = turns.len();
+    for (i, turn) in turns.into_iter().enumerate() {
+        eprintln!("Replaying message {} of {}", i + 1, total);
+        messages.push(turn);
+        Message::write_chat(&replay, &frontmatter, &messages)?;
+        session.send().await?;
+        (frontmatter, messages) = Message::read_chat(&replay)?;
+        // The empty user message waiting for the next turn
+        if messages.last().is_some_and(|m| {
+            matches!(m.role, ChatCompletionMessageRole::User) && m.content.trim().is_empty()
+        }) {
+            messages.pop();
+        }
+    }
+    Ok(session)
+}
+
+/// A chat's system prompt, the system messages it starts with, and its user messages
+fn split(messages: Vec<Message>) -> (Vec<Message>, Vec<Message>) {
+    let prompt_len = messages
+        .iter()
+        .take_while(|m| matches!(m.role, ChatCompletionMessageRole::System))
+        .count();
+    let mut turns = messages;
+    let prompt: Vec<Message> = turns.drain(..prompt_len).collect();
+    turns.retain(|m| {
+        matches!(m.role, ChatCompletionMessageRole::User) && !m.content.trim().is_empty()
+    });
+    (prompt, turns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_the_user_messages_after_the_prompt() {
+        let messages = Message::parse_messages(
+            "# System\nBe brief\n# User (alice)\nHi\n# Assistant\nHello\n# Function Call: shell\n{}\n# Function: shell\nok\n# Assistant\nDone\n# User\nBye\n# Assistant\nBye\n# User\n\n",
+        );
+        let (prompt, turns) = split(messages);
+        assert_eq!(prompt.len(), 1);
+        assert_eq!(prompt[0].content, "Be brief");
+        let turns: Vec<_> = turns.iter().map(|m| m.label()).collect();
+        assert_eq!(turns, ["User (alice)", "User"]);
+    }
+}
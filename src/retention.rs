@@ -0,0 +1,319 @@
+use crate::{frontmatter::Frontmatter, storage::ChatStore};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// How long chats are kept, the `[retention]` config table
+///
+/// Nothing is cleaned up unless `days` is set.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Chats untouched for this many days are cleaned up
+    pub days: Option<u64>,
+    /// What happens to them
+    pub action: Action,
+    /// Keep chats with `pinned: true` in their frontmatter, defaults to true
+    pub keep_pinned: Option<bool>,
+    /// How archived chats are compressed
+    pub compression: Compression,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Compress them into `archive/` in the store
+    #[default]
+    Archive,
+    /// Delete them
+    Delete,
+}
+
+/// How `Action::Archive` compresses chats, they're decompressed when read either way
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// `.md.gz`
+    #[default]
+    Gzip,
+    /// `.md.zst`, smaller and faster
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+}
+
+/// The outcome of cleaning up the store
+#[derive(Debug, Default)]
+pub struct Collected {
+    /// The chats archived or deleted
+    pub chats: Vec<PathBuf>,
+    /// Old chats kept because they're pinned
+    pub pinned: usize,
+}
+
+/// Clean up the chats older than `days`, or the config's `days`, and say how many
+///
+/// The options not given are the config's, see `collect`.
+pub fn gc(
+    config: &RetentionConfig,
+    days: Option<u64>,
+    action: Option<Action>,
+    compression: Option<Compression>,
+    keep_pinned: Option<bool>,
+    dry_run: bool,
+) -> Result<()> {
+    let days = days
+        .or(config.days)
+        .context("Set how old chats have to be with --days or `days` in [retention]")?;
+    let action = action.unwrap_or(config.action);
+    let compression = compression.unwrap_or(config.compression);
+    let keep_pinned = keep_pinned.or(config.keep_pinned).unwrap_or(true);
+    let store = ChatStore::open()?;
+    let collected = collect(&store, days, action, compression, keep_pinned, dry_run)?;
+    for chat in &collected.chats {
+        println!("{}", chat.display());
+    }
+    let done = match (dry_run, action) {
+        (true, _) => "Would clean up",
+        (false, Action::Archive) => "Archived",
+        (false, Action::Delete) => "Deleted",
+    };
+    println!(
+        "{} {} chats older than {} days, kept {} pinned",
+        done,
+        collected.chats.len(),
+        days,
+        collected.pinned
+    );
+    Ok(())
+}
+
+/// Clean up old chats when starting a new one, if the config sets how long to keep them
+///
+/// Failing to isn't fatal, the error is reported and the chats kept.
+pub fn clean_up(config: &RetentionConfig) {
+    let Some(days) = config.days else {
+        return;
+    };
+    let keep_pinned = config.keep_pinned.unwrap_or(true);
+    let collected = ChatStore::open().and_then(|store| {
+        collect(
+            &store,
+            days,
+            config.action,
+            config.compression,
+            keep_pinned,
+            false,
+        )
+    });
+    match collected {
+        Ok(collected) if !collected.chats.is_empty() => eprintln!(
+            "Cleaned up {} chats older than {} days",
+            collected.chats.len(),
+            days
+        ),
+        Ok(_) => {}
+        Err(e) => eprintln!("Unable to clean up old chats: {:#}", e),
+    }
+}
+
+/// Archive or delete the chats not changed in the last `days` days
+///
+/// With `dry_run` nothing is changed, the chats that would be are returned.
+pub fn collect(
+    store: &ChatStore,
+    days: u64,
+    action: Action,
+    compression: Compression,
+    keep_pinned: bool,
+    dry_run: bool,
+) -> Result<Collected> {
+    let cutoff = SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+    let mut collected = Collected::default();
+    for chat in store.list()? {
+        let modified = std::fs::metadata(&chat)?.modified()?;
+        if modified > cutoff {
+            continue;
+        }
+        if keep_pinned && is_pinned(&chat) {
+            collected.pinned += 1;
+            continue;
+        }
+        if !dry_run {
+            if action == Action::Archive {
+                compress(store, &chat, compression)?;
+            }
+            std::fs::remove_file(&chat).with_context(|| format!("Unable to remove {:?}", chat))?;
+        }
+        collected.chats.push(chat);
+    }
+    if !dry_run {
+        remove_dangling_links(store.dir())?;
+    }
+    Ok(collected)
+}
+
+fn is_pinned(chat: &Path) -> bool {
+    std::fs::read_to_string(chat)
+        .ok()
+        .and_then(|contents| Frontmatter::parse(&contents).ok().map(|(f, _)| f.pinned))
+        .unwrap_or(false)
+}
+
+/// Compress a chat into e.g. `archive/<name>.md.gz`, keeping its modification time
+///
+/// An earlier chat archived under the same name is kept, the chat is archived
+/// as `<name>-2.md.gz` and so on instead.
+fn compress(store: &ChatStore, chat: &Path, compression: Compression) -> Result<PathBuf> {
+    let dir = store.dir().join("archive");
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Unable to create archive directory: {:?}", dir))?;
+    let stem = chat.file_stem().context("A chat file has no name")?;
+    let extension = chat
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut input = File::open(chat).with_context(|| format!("Unable to read {:?}", chat))?;
+    let (archived, output) = (1..)
+        .find_map(|n| {
+            let suffix = match n {
+                1 => String::new(),
+                n => format!("-{}", n),
+            };
+            let archived = dir.join(format!(
+                "{}{}{}.{}",
+                stem.to_string_lossy(),
+                suffix,
+                extension,
+                compression.extension()
+            ));
+            match File::create_new(&archived) {
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => None,
+                created => Some(
+                    created
+                        .with_context(|| format!("Unable to create {:?}", archived))
+                        .map(|file| (archived, file)),
+                ),
+            }
+        })
+        .expect("some name is free")?;
+    let output = match compression {
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(output, flate2::Compression::default());
+            std::io::copy(&mut input, &mut encoder)?;
+            encoder.finish()
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(output, 0)?;
+            std::io::copy(&mut input, &mut encoder)?;
+            encoder.finish()
+        }
+    }
+    .with_context(|| format!("Unable to write {:?}", archived))?;
+    output.set_modified(input.metadata()?.modified()?)?;
+    Ok(archived)
+}
+
+/// Remove the links left behind by renaming titled chats that were since cleaned up
+fn remove_dangling_links(dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_symlink() && !path.exists() {
+            std::fs::remove_file(&path).with_context(|| format!("Unable to remove {:?}", path))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn archives_old_chats_but_not_pinned_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ChatStore::at(dir.path());
+        let old = SystemTime::now() - Duration::from_secs(40 * 24 * 60 * 60);
+        let write = |name: &str, contents: &str, modified: SystemTime| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, contents).unwrap();
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+            path
+        };
+        let stale = write("stale.md", "# User\nHi\n", old);
+        write("pinned.md", "---\npinned: true\n---\n# User\nHi\n", old);
+        write("recent.md", "# User\nHi\n", SystemTime::now());
+
+        let dry_run = collect(&store, 30, Action::Archive, Compression::Gzip, true, true).unwrap();
+        assert_eq!(dry_run.chats, std::slice::from_ref(&stale));
+        assert!(stale.exists());
+
+        let collected =
+            collect(&store, 30, Action::Archive, Compression::Gzip, true, false).unwrap();
+        assert_eq!(collected.chats, std::slice::from_ref(&stale));
+        assert_eq!(collected.pinned, 1);
+        assert!(!stale.exists());
+
+        let mut archived = String::new();
+        flate2::read::GzDecoder::new(File::open(dir.path().join("archive/stale.md.gz")).unwrap())
+            .read_to_string(&mut archived)
+            .unwrap();
+        assert_eq!(archived, "# User\nHi\n");
+        assert_eq!(store.list().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reads_chats_archived_with_zstd() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ChatStore::at(dir.path());
+        let chat = dir.path().join("chat.md");
+        std::fs::write(&chat, "---\ntitle: Hi\n---\n# User\nHi\n").unwrap();
+
+        let archived = compress(&store, &chat, Compression::Zstd).unwrap();
+        assert_eq!(archived, dir.path().join("archive/chat.md.zst"));
+        assert_eq!(store.archived().unwrap(), std::slice::from_ref(&archived));
+        let (frontmatter, messages) = crate::message::Message::read_chat(&archived).unwrap();
+        assert_eq!(frontmatter.title.as_deref(), Some("Hi"));
+        assert_eq!(messages[0].content, "Hi");
+    }
+
+    #[test]
+    fn keeps_an_earlier_archive_with_the_same_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ChatStore::at(dir.path());
+        let chat = dir.path().join("chat.md");
+        std::fs::write(&chat, "# User\nFirst\n").unwrap();
+        let first = compress(&store, &chat, Compression::Gzip).unwrap();
+        std::fs::write(&chat, "# User\nSecond\n").unwrap();
+        let second = compress(&store, &chat, Compression::Gzip).unwrap();
+
+        assert_eq!(second, dir.path().join("archive/chat-2.md.gz"));
+        let read = |path| {
+            crate::message::Message::read_chat(path).unwrap().1[0]
+                .content
+                .clone()
+        };
+        assert_eq!(read(&first), "First");
+        assert_eq!(read(&second), "Second");
+    }
+}
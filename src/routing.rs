@@ -0,0 +1,182 @@
+//! Sending each message to a cheap or a premium model, the `[routing]` config table
+//!
+//! ```toml
+//! [routing]
+//! cheap_model = "gpt-4o-mini"
+//! # Messages under this many tokens go to the cheap model
+//! max_tokens = 200
+//! # Longer ones are sent to this model to ask if they're simple
+//! classifier = "gpt-4o-mini"
+//!
+//! [[routing.rules]]
+//! pattern = "(?i)refactor|prove|design"
+//! model = "o3"
+//! ```
+//!
+//! Rules are tried first, in order, on the user's message. The premium model
+//! is the one the chat would otherwise use, and a model chosen with `--model`
+//! or in the chat's frontmatter isn't routed. The model chosen is kept for
+//! the tool calls and corrections that follow the message.
+
+use crate::{
+    context,
+    provider::{self, ChatRequest, RequestMessage},
+    rag,
+};
+use anyhow::{Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use regex::Regex;
+use serde::Deserialize;
+
+const CLASSIFY_PROMPT: &str = "Decide whether the user's message below needs a strong model or could be answered well by a small, cheap one. Simple messages are e.g. small talk, quick facts, rewording or short, routine code. Complex ones need reasoning, planning, careful code or long answers. Reply with only SIMPLE or COMPLEX.";
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    /// Model simple messages are sent to, routing is off without it or rules
+    pub cheap_model: Option<String>,
+    /// Messages with fewer tokens than this are simple
+    pub max_tokens: Option<usize>,
+    /// Model asked whether a message is simple, when it isn't short enough to tell
+    pub classifier: Option<String>,
+    pub rules: Vec<RouteRule>,
+}
+
+/// Messages matching the pattern go to the model
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRule {
+    pub pattern: String,
+    pub model: String,
+}
+
+/// Which model a message was sent to and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub model: String,
+    pub reason: String,
+}
+
+impl RoutingConfig {
+    fn enabled(&self) -> bool {
+        self.cheap_model.is_some() || !self.rules.is_empty()
+    }
+}
+
+/// The model to send the request's last user message to, `None` if routing is off
+///
+/// `premium` is the model the chat would be sent to otherwise.
+pub async fn choose(
+    config: &RoutingConfig,
+    premium: &str,
+    messages: &[RequestMessage],
+) -> Result<Option<Route>> {
+    if !config.enabled() {
+        return Ok(None);
+    }
+    let message = rag::question(messages).unwrap_or_default();
+    let route = |model: &str, reason: String| {
+        Some(Route {
+            model: model.to_string(),
+            reason,
+        })
+    };
+    if let Some(route) = by_rules(config, &message)? {
+        return Ok(Some(route));
+    }
+    let Some(cheap) = &config.cheap_model else {
+        return Ok(route(premium, "no rule matched".to_string()));
+    };
+    let tokens = context::tokens(premium, &message);
+    if let Some(max) = config.max_tokens {
+        if tokens < max {
+            return Ok(route(cheap, format!("{} tokens, under {}", tokens, max)));
+        }
+    }
+    let Some(classifier) = &config.classifier else {
+        let reason = match config.max_tokens {
+            Some(max) => format!("{} tokens, not under {}", tokens, max),
+            None => "no rule matched".to_string(),
+        };
+        return Ok(route(premium, reason));
+    };
+    Ok(match classify(classifier, &message).await {
+        Ok(true) => route(cheap, format!("{} classed it simple", classifier)),
+        Ok(false) => route(premium, format!("{} classed it complex", classifier)),
+        Err(e) => {
+            tracing::warn!("Unable to classify the message: {:#}", e);
+            route(premium, "it couldn't be classified".to_string())
+        }
+    })
+}
+
+/// The first rule whose pattern the message matches
+fn by_rules(config: &RoutingConfig, message: &str) -> Result<Option<Route>> {
+    for rule in &config.rules {
+        let pattern = Regex::new(&rule.pattern)
+            .with_context(|| format!("Invalid routing pattern: {}", rule.pattern))?;
+        if pattern.is_match(message) {
+            return Ok(Some(Route {
+                model: rule.model.clone(),
+                reason: format!("matched `{}`", rule.pattern),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Ask the classifier whether the message is simple enough for the cheap model
+async fn classify(model: &str, message: &str) -> Result<bool> {
+    let mut request = ChatRequest::new(
+        model,
+        vec![
+            RequestMessage::text(ChatCompletionMessageRole::System, CLASSIFY_PROMPT),
+            RequestMessage::text(ChatCompletionMessageRole::User, message),
+        ],
+    );
+    request.sampling.max_tokens = Some(5);
+    let completion = provider::create(request).await?;
+    let answer = completion
+        .choices
+        .first()
+        .and_then(|c| c.message.content.as_deref())
+        .unwrap_or_default();
+    Ok(answer.trim().to_uppercase().starts_with("SIMPLE"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(text: &str) -> Vec<RequestMessage> {
+        vec![RequestMessage::text(ChatCompletionMessageRole::User, text)]
+    }
+
+    #[tokio::test]
+    async fn routes_by_rule_then_length() {
+        let config: RoutingConfig = toml::from_str(
+            r#"
+            cheap_model = "gpt-4o-mini"
+            max_tokens = 20
+
+            [[rules]]
+            pattern = "(?i)refactor"
+            model = "o3"
+            "#,
+        )
+        .unwrap();
+        let route = |text: &'static str| {
+            let config = config.clone();
+            async move { choose(&config, "gpt-4o", &user(text)).await.unwrap() }
+        };
+        assert_eq!(route("Please Refactor this").await.unwrap().model, "o3");
+        let short = route("What's the capital of France?").await.unwrap();
+        assert_eq!(short.model, "gpt-4o-mini");
+        assert_eq!(short.reason, "6 tokens, under 20");
+        let long = route("Explain in detail how the borrow checker decides when a mutable reference may be taken while shared references to the same value are alive").await;
+        assert_eq!(long.unwrap().model, "gpt-4o");
+
+        let off = choose(&RoutingConfig::default(), "gpt-4o", &user("hi")).await;
+        assert_eq!(off.unwrap(), None);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::provider::{JsonSchema, ResponseFormat};
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::path::Path;
+
+/// Times a reply that doesn't match the format is sent back to be corrected
+pub const MAX_CORRECTIONS: usize = 2;
+
+/// Most validation errors listed when asking for a correction
+const MAX_ERRORS: usize = 10;
+
+/// Read a JSON schema for `--schema`, named after its file
+pub fn load(path: &Path) -> Result<ResponseFormat> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read schema: {}", path.display()))?;
+    let schema: Value = serde_json::from_str(&contents)
+        .with_context(|| format!("Unable to parse schema: {}", path.display()))?;
+    jsonschema::validator_for(&schema)
+        .map_err(|e| anyhow!("Invalid schema {}: {}", path.display(), e))?;
+
+    // The API only accepts letters, digits, `_` and `-` in the name
+    let name: String = path
+        .file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '_',
+        })
+        .take(64)
+        .collect();
+    Ok(ResponseFormat::JsonSchema {
+        json_schema: JsonSchema { name, schema },
+    })
+}
+
+/// What's wrong with a reply that should be in `format`, if anything
+pub fn check(reply: &str, format: &ResponseFormat) -> Option<String> {
+    let value: Value = match serde_json::from_str(reply.trim()) {
+        Ok(value) => value,
+        Err(e) => return Some(format!("The reply isn't valid JSON: {}", e)),
+    };
+    let ResponseFormat::JsonSchema { json_schema } = format else {
+        return None;
+    };
+    // The schema was checked when it was loaded
+    let validator = jsonschema::validator_for(&json_schema.schema).ok()?;
+    let errors: Vec<String> = validator
+        .iter_errors(&value)
+        .take(MAX_ERRORS)
+        .map(|e| match e.instance_path().as_str() {
+            "" => e.to_string(),
+            path => format!("at {}: {}", path, e),
+        })
+        .collect();
+    match errors.is_empty() {
+        true => None,
+        false => Some(format!(
+            "The reply doesn't match the schema:\n- {}",
+            errors.join("\n- ")
+        )),
+    }
+}
+
+/// The message asking the model to correct a reply
+pub fn correction(problem: &str) -> String {
+    format!(
+        "{}\n\nReply again with only the corrected JSON, no other text.",
+        problem
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_replies_against_the_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("person v2.json");
+        std::fs::write(
+            &path,
+            r#"{"type": "object", "properties": {"age": {"type": "integer"}}, "required": ["age"]}"#,
+        )
+        .unwrap();
+        let format = load(&path).unwrap();
+        let ResponseFormat::JsonSchema { json_schema } = &format else {
+            panic!("Expected a schema");
+        };
+        assert_eq!(json_schema.name, "person_v2");
+
+        assert_eq!(check(r#"{"age": 30}"#, &format), None);
+        assert!(check(r#"{"age": "thirty"}"#, &format)
+            .unwrap()
+            .contains("at /age:"));
+        assert!(check("Sure! {}", &ResponseFormat::JsonObject)
+            .unwrap()
+            .starts_with("The reply isn't valid JSON"));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{
+    catalog::{Catalog, ChatMessages},
+    message::Message,
+    storage::ChatStore,
+    tags, theme,
+};
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::path::PathBuf;
+
+/// Characters of context shown either side of a match
+const SNIPPET_CONTEXT: usize = 40;
+
+/// Matches shown per chat before the rest are summarised
+const MAX_MATCHES_PER_CHAT: usize = 3;
+
+/// Print every saved chat containing `query`, with a snippet around each match
+///
+/// The query is matched case insensitively, as a regular expression if `regex` is set,
+/// and only in chats tagged `tag` if given. With a catalog the chat files aren't read
+/// at all, except archived ones with `archived`.
+pub fn search(
+    query: &str,
+    regex: bool,
+    archived: bool,
+    tag: Option<&str>,
+    catalog: Option<&Catalog>,
+) -> Result<()> {
+    let pattern = if regex {
+        query.to_string()
+    } else {
+        regex::escape(query)
+    };
+    let pattern = Regex::new(&format!("(?i){}", pattern))
+        .with_context(|| format!("Invalid search pattern: {:?}", query))?;
+
+    let store = ChatStore::open()?;
+    let mut chats = match catalog {
+        // A regular expression can't narrow down the messages in SQL
+        Some(catalog) => catalog.messages((!regex).then_some(query), tag)?,
+        None => read_chats(store.list()?, tag),
+    };
+    if archived {
+        chats.extend(read_chats(store.archived()?, tag));
+    }
+
+    let mut found = 0;
+    for chat in chats {
+        let matches: Vec<(&str, String)> = chat
+            .messages
+            .iter()
+            .flat_map(|(role, content)| {
+                pattern
+                    .find_iter(content)
+                    .map(move |found| (role.as_str(), snippet(content, found)))
+            })
+            .collect();
+        if matches.is_empty() {
+            continue;
+        }
+        found += 1;
+
+        match &chat.title {
+            Some(title) => println!("{} ({})", chat.path.display(), title),
+            None => println!("{}", chat.path.display()),
+        }
+        for (role, snippet) in matches.iter().take(MAX_MATCHES_PER_CHAT) {
+            println!("  [{}] {}", role, snippet);
+        }
+        if matches.len() > MAX_MATCHES_PER_CHAT {
+            println!("  ... {} more", matches.len() - MAX_MATCHES_PER_CHAT);
+        }
+        println!();
+    }
+
+    if found == 0 {
+        println!("No chats match {:?}", query);
+    }
+    Ok(())
+}
+
+/// The chats read from their files, tagged `tag` if given, skipping any that can't be
+fn read_chats(chat_files: Vec<PathBuf>, tag: Option<&str>) -> Vec<ChatMessages> {
+    let mut chats = Vec::new();
+    for chat_file in chat_files {
+        let (frontmatter, messages) = match Message::read_chat(&chat_file) {
+            Ok(chat) => chat,
+            Err(e) => {
+                eprintln!("Skipping {:?}: {:#}", chat_file, e);
+                continue;
+            }
+        };
+        if tag.is_some_and(|tag| !tags::has_tag(&frontmatter.tags, tag)) {
+            continue;
+        }
+        chats.push(ChatMessages {
+            path: chat_file,
+            title: frontmatter.title,
+            messages: messages
+                .into_iter()
+                .map(|m| (m.role_name().to_string(), m.content))
+                .collect(),
+        });
+    }
+    chats
+}
+
+/// The match with some surrounding text on a single line, the match highlighted
+fn snippet(content: &str, found: regex::Match) -> String {
+    let start = floor_char_boundary(content, found.start().saturating_sub(SNIPPET_CONTEXT));
+    let end = ceil_char_boundary(content, found.end() + SNIPPET_CONTEXT);
+
+    let (before, matched, after) = (
+        &content[start..found.start()],
+        found.as_str(),
+        &content[found.end()..end],
+    );
+    let matched = theme::matched(matched);
+
+    format!(
+        "{}{}{}{}{}",
+        if start > 0 { "..." } else { "" },
+        before,
+        matched,
+        after,
+        if end < content.len() { "..." } else { "" }
+    )
+    .replace('\n', " ")
+}
+
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+fn ceil_char_boundary(s: &str, mut i: usize) -> usize {
+    if i >= s.len() {
+        return s.len();
+    }
+    while !s.is_char_boundary(i) {
+        i += 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_matches_in_tagged_chats_with_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let tagged = dir.path().join("tagged.md");
+        let untagged = dir.path().join("untagged.md");
+        std::fs::write(
+            &tagged,
+            "---\ntitle: Borrowing\ntags: [rust]\n---\n# User\nWhat's a lifetime?\n# Assistant\nHow long a borrow lasts\n",
+        )
+        .unwrap();
+        std::fs::write(&untagged, "# User\nA lifetime of what?\n").unwrap();
+
+        let chats = read_chats(vec![tagged.clone(), untagged], Some("Rust"));
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].path, tagged);
+        assert_eq!(chats[0].title.as_deref(), Some("Borrowing"));
+
+        let content = format!("{}the borrow checker\nis strict", "é".repeat(30));
+        let pattern = Regex::new("(?i)BORROW").unwrap();
+        let found = pattern.find(&content).unwrap();
+        // Cut on a character boundary, on one line
+        assert_eq!(
+            snippet(&content, found),
+            format!("...{}the borrow checker is strict", "é".repeat(18))
+        );
+    }
+}
@@ -0,0 +1,385 @@
+use crate::{
+    catalog::Catalog,
+    config::{Config, Overrides},
+    exit::Failure,
+    lock::InUse,
+    message::{self, Message},
+    session::{Interrupted, Output, Session},
+    storage::ChatStore,
+};
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{Path as UrlPath, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::Stream;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{convert::Infallible, net::IpAddr, path::PathBuf, sync::Arc};
+use tokio::sync::mpsc;
+
+/// The environment variable holding the token clients must send, `Authorization: Bearer <token>`
+pub const TOKEN_VAR: &str = "CHAT_CLI_RS_SERVE_TOKEN";
+
+/// What the handlers share
+struct App {
+    config: Config,
+    overrides: Overrides,
+    store: ChatStore,
+    /// The token every request must carry, if one is set
+    token: Option<String>,
+}
+
+/// An error response, `{"error": "..."}`
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+    }
+}
+
+/// Serve the chats in the store over HTTP until interrupted
+///
+/// Chats are identified by their file names:
+///
+/// - `GET /sessions?limit=20` lists the most recently changed chats, `&tag=rust`
+///   only those with the tag
+/// - `POST /sessions` with `{"system": "..."}` starts a chat
+/// - `GET /sessions/{id}` returns a chat's messages
+/// - `POST /sessions/{id}/messages` with `{"content": "..."}` adds the user's
+///   message and streams the reply as server-sent events, `delta` events with
+///   each piece of it and then a `reply` or `error` event
+///
+/// Requests must carry the token in `TOKEN_VAR` if it's set, which it has to
+/// be to listen anywhere but localhost. Without one only requests addressed to
+/// localhost are answered, so a web page can't reach the chats by pointing
+/// its own domain at 127.0.0.1.
+pub async fn serve(host: &str, port: u16, config: Config, overrides: Overrides) -> Result<()> {
+    let token = std::env::var(TOKEN_VAR)
+        .ok()
+        .filter(|token| !token.trim().is_empty());
+    if token.is_none() && !is_loopback(host) {
+        bail!(Failure::Usage(format!(
+            "Set {} to a token for clients to send before listening on {}",
+            TOKEN_VAR, host
+        )));
+    }
+    let app = Arc::new(App {
+        config,
+        overrides,
+        store: ChatStore::open()?,
+        token,
+    });
+    let router = Router::new()
+        .route("/sessions", get(list).post(start))
+        .route("/sessions/{id}", get(chat))
+        .route("/sessions/{id}/messages", post(send))
+        .layer(middleware::from_fn_with_state(app.clone(), guard))
+        .with_state(app);
+    let listener = tokio::net::TcpListener::bind((host, port))
+        .await
+        .with_context(|| format!("Unable to listen on {}:{}", host, port))?;
+    eprintln!("Serving the chats on http://{}", listener.local_addr()?);
+    axum::serve(listener, router)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+    Ok(())
+}
+
+/// Refuse requests without the token, or not addressed to localhost when there's none
+async fn guard(State(app): State<Arc<App>>, request: Request, next: Next) -> Response {
+    match refuse(app.token.as_deref(), request.headers()) {
+        Some(error) => error.into_response(),
+        None => next.run(request).await,
+    }
+}
+
+/// Why a request with the headers isn't answered, if it isn't
+fn refuse(token: Option<&str>, headers: &HeaderMap) -> Option<ApiError> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    match token {
+        Some(token) => {
+            let given = header(header::AUTHORIZATION).and_then(|h| h.strip_prefix("Bearer "));
+            // Compared as digests so the time taken doesn't give the token away
+            let matches = given.is_some_and(|given| {
+                Sha256::digest(given.as_bytes()) == Sha256::digest(token.as_bytes())
+            });
+            (!matches).then(|| {
+                ApiError(
+                    StatusCode::UNAUTHORIZED,
+                    "Send the token as `Authorization: Bearer <token>`".to_string(),
+                )
+            })
+        }
+        None => {
+            let local = header(header::HOST).is_some_and(|host| is_loopback(without_port(host)));
+            (!local).then(|| {
+                ApiError(
+                    StatusCode::FORBIDDEN,
+                    "Only requests to localhost are answered without a token".to_string(),
+                )
+            })
+        }
+    }
+}
+
+/// Whether the host name or address is this machine's loopback
+fn is_loopback(host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host.eq_ignore_ascii_case("localhost") || host.parse().is_ok_and(|ip: IpAddr| ip.is_loopback())
+}
+
+/// The host in a `Host` header, without the port
+fn without_port(host: &str) -> &str {
+    match host.strip_prefix('[') {
+        Some(rest) => rest.split_once(']').map_or(rest, |(ip, _)| ip),
+        None => host.split_once(':').map_or(host, |(name, _)| name),
+    }
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    #[serde(default = "default_limit")]
+    limit: usize,
+    tag: Option<String>,
+}
+
+fn default_limit() -> usize {
+    20
+}
+
+async fn list(
+    State(app): State<Arc<App>>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Value>, ApiError> {
+    let catalog = Catalog::open(&app.store, app.config.catalog == Some(true))?;
+    let chats: Vec<Value> = catalog
+        .chats(query.limit, query.tag.as_deref())?
+        .into_iter()
+        .map(|chat| {
+            json!({
+                "id": id(&chat.path),
+                "title": chat.title,
+                "model": chat.model,
+                "modified": chat.modified,
+                "messages": chat.messages,
+                "tokens": chat.tokens,
+                "tags": chat.tags,
+                "pinned": chat.pinned,
+            })
+        })
+        .collect();
+    Ok(Json(json!(chats)))
+}
+
+#[derive(Default, Deserialize)]
+struct StartBody {
+    system: Option<String>,
+}
+
+async fn start(
+    State(app): State<Arc<App>>,
+    body: Option<Json<StartBody>>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let Json(body) = body.unwrap_or_default();
+    let session = Session::start(body.system, app.config.clone(), app.overrides.clone())?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "id": id(session.chat_file()) })),
+    ))
+}
+
+async fn chat(
+    State(app): State<Arc<App>>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<Json<Value>, ApiError> {
+    let path = chat_path(&app.store, &id)?;
+    let (frontmatter, messages) = Message::read_chat(&path)?;
+    let messages: Vec<Value> = messages
+        .iter()
+        .map(|m| {
+            json!({
+                "role": m.role,
+                "name": m.speaker(),
+                "content": m.content,
+            })
+        })
+        .collect();
+    Ok(Json(json!({
+        "id": id,
+        "title": frontmatter.title,
+        "model": frontmatter.model,
+        "messages": messages,
+    })))
+}
+
+#[derive(Deserialize)]
+struct SendBody {
+    content: String,
+}
+
+/// Add the user's message and stream the reply back
+///
+/// The message is sent as written, its directives and slash commands aren't
+/// carried out. The reply is interrupted, keeping the part received, if the
+/// client goes away. A chat that's already replying is a conflict.
+async fn send(
+    State(app): State<Arc<App>>,
+    UrlPath(id): UrlPath<String>,
+    Json(body): Json<SendBody>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let path = chat_path(&app.store, &id)?;
+    let (deltas, mut delta_rx) = mpsc::unbounded_channel();
+    let mut session = Session::open(&path, app.config.clone())
+        .with_overrides(app.overrides.clone())
+        .with_output(Output::Json)
+        .with_directives(false)
+        .with_deltas(deltas);
+    session.lock().map_err(|e| match e.downcast::<InUse>() {
+        Ok(in_use) => ApiError(StatusCode::CONFLICT, in_use.to_string()),
+        Err(e) => e.into(),
+    })?;
+    message::add_to_last(&path, &body.content)?;
+
+    let (events, received) = mpsc::unbounded_channel::<Event>();
+    tokio::spawn(async move {
+        let forward_to = events.clone();
+        // Ends once the session, which holds the other end, is dropped
+        let forward = tokio::spawn(async move {
+            while let Some(delta) = delta_rx.recv().await {
+                let _ = forward_to.send(Event::default().event("delta").data(delta));
+            }
+        });
+
+        let reply = session.send_or_cancel(events.closed()).await;
+        if reply.is_ok() && app.config.auto_title != Some(false) {
+            if let Err(e) = session.title().await {
+                tracing::warn!("Unable to title {}: {:#}", session.chat_file().display(), e);
+            }
+        }
+        // The title may have renamed the chat
+        let id = self::id(session.chat_file());
+        drop(session);
+        let _ = forward.await;
+
+        let event = match reply {
+            Ok(reply) => Event::default().event("reply").json_data(json!({
+                "id": id,
+                "content": reply.message.content,
+                "model": reply.model,
+                "finish_reason": reply.finish_reason,
+            })),
+            Err(e) if e.is::<Interrupted>() => return,
+            Err(e) => Ok(Event::default().event("error").data(format!("{:#}", e))),
+        };
+        if let Ok(event) = event {
+            let _ = events.send(event);
+        }
+    });
+
+    let stream = futures_util::stream::unfold(received, |mut received| async move {
+        let event = received.recv().await?;
+        Some((Ok(event), received))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// A chat's id, its file name
+fn id(path: &std::path::Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The chat file in the store with the id, refusing anything outside the store
+fn chat_path(store: &ChatStore, id: &str) -> Result<PathBuf, ApiError> {
+    let valid = id.ends_with(".md") && !id.starts_with('.') && !id.contains(['/', '\\']);
+    let path = store.dir().join(id);
+    if !valid || !path.is_file() {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("There's no chat {:?}", id),
+        ));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chats_are_found_only_in_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ChatStore::at(dir.path().join("chats"));
+        std::fs::create_dir(store.dir()).unwrap();
+        std::fs::write(store.dir().join("a.md"), "# User\n\n").unwrap();
+        std::fs::write(dir.path().join("secret.md"), "# User\n\n").unwrap();
+
+        assert_eq!(chat_path(&store, "a.md").unwrap(), store.dir().join("a.md"));
+        for id in ["b.md", "../secret.md", "..", ".hidden.md"] {
+            assert_eq!(chat_path(&store, id).unwrap_err().0, StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[test]
+    fn answers_only_localhost_or_the_token() {
+        let headers = |pairs: &[(header::HeaderName, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name, value.parse().unwrap());
+            }
+            headers
+        };
+        let refused = |token, pairs: &[_]| refuse(token, &headers(pairs)).map(|e| e.0);
+
+        for host in [
+            "localhost:8080",
+            "127.0.0.1:8080",
+            "[::1]:8080",
+            "localhost",
+        ] {
+            assert_eq!(refused(None, &[(header::HOST, host)]), None);
+        }
+        // A page on another domain that resolves to 127.0.0.1
+        let rebound = [(header::HOST, "evil.example:8080")];
+        assert_eq!(refused(None, &rebound), Some(StatusCode::FORBIDDEN));
+        assert_eq!(refused(None, &[]), Some(StatusCode::FORBIDDEN));
+
+        let token = Some("s3cret");
+        let sent = |value| {
+            [
+                (header::HOST, "100.64.0.1:8080"),
+                (header::AUTHORIZATION, value),
+            ]
+        };
+        assert_eq!(refused(token, &sent("Bearer s3cret")), None);
+        assert_eq!(
+            refused(token, &sent("Bearer guess")),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(refused(token, &rebound), Some(StatusCode::UNAUTHORIZED));
+
+        assert!(is_loopback("127.0.0.1") && is_loopback("::1") && is_loopback("localhost"));
+        assert!(!is_loopback("0.0.0.0") && !is_loopback("100.64.0.1"));
+    }
+}
@@ -0,0 +1,1648 @@
+use crate::{
+    cassette, compact,
+    config::{Config, Overrides, Settings},
+    confirm,
+    context::{self, Overflow},
+    directives,
+    encryption::{self, EncryptedChat},
+    ephemeral::EphemeralChat,
+    exit::Failure,
+    fallback,
+    frontmatter::Frontmatter,
+    hooks::{self, Event},
+    language,
+    lock::ChatLock,
+    mapreduce, mcp,
+    message::{Message, Meta, ReplyWriter},
+    obsidian, patch, postprocess, project, prompts, provider,
+    provider::{ChatRequest, RequestMessage, StreamDropped, StreamOptions, TimedOut},
+    rag, reflect, routing, schema, slash,
+    spinner::{self, Spinner},
+    storage::{self, ChatStore},
+    theme::{self, Highlighter},
+    title, tools,
+    transcript::{self, TranscriptChat},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use futures_util::future::join_all;
+use openai::{
+    chat::{
+        ChatCompletion, ChatCompletionChoiceDelta, ChatCompletionDelta, ChatCompletionMessage,
+        ChatCompletionMessageRole,
+    },
+    Usage,
+};
+use serde_json::json;
+use std::{
+    fmt,
+    fs::File,
+    future::Future,
+    io::{stdout, Write},
+    path::{Path, PathBuf},
+    pin::{pin, Pin},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{Receiver, UnboundedSender};
+
+/// A chat kept in a markdown file, sent to the model a turn at a time
+///
+/// The file is the session's only state, so it can be edited between turns.
+#[derive(Debug)]
+pub struct Session {
+    chat_file: PathBuf,
+    config: Config,
+    overrides: Overrides,
+    output: Output,
+    show_context: bool,
+    confirm: bool,
+    out: Option<PathBuf>,
+    reflect: usize,
+    deltas: Option<UnboundedSender<String>>,
+    max_steps: Option<usize>,
+    /// Documents each message is asked about a part at a time
+    map_reduce: Vec<PathBuf>,
+    /// Whether directives and slash commands in the user's message are carried out
+    directives: bool,
+    /// The encrypted chat `chat_file` is the decrypted copy of, if it's encrypted
+    encrypted: Option<EncryptedChat>,
+    /// The transcript `chat_file` is the markdown copy of, if the chat is kept as one
+    transcript: Option<TranscriptChat>,
+    /// The tmpfs `chat_file` is shredded from when the session ends, if it's never saved
+    ephemeral: Option<EphemeralChat>,
+    /// The chat's lock, taken the first time the session writes to it
+    lock: OnceLock<ChatLock>,
+}
+
+/// How a streaming reply is shown on stdout
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    /// The reply as it streams in
+    #[default]
+    Text,
+    /// Only the reply's text as it streams in, without its role or timings
+    Plain,
+    /// Nothing, so the caller can print the finished reply as JSON
+    Json,
+    /// Each chunk of the reply as a line of JSON, `{"delta": "..."}`
+    JsonStream,
+}
+
+/// A finished reply and what's known about how it was made
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub message: ChatCompletionMessage,
+    pub model: String,
+    /// Why the model stopped, e.g. `stop` or `length`
+    pub finish_reason: Option<String>,
+    /// Tokens used by the last request
+    pub usage: Option<Usage>,
+}
+
+impl Session {
+    /// Continue the chat in an existing file
+    pub fn open(chat_file: impl Into<PathBuf>, config: Config) -> Self {
+        Self {
+            chat_file: chat_file.into(),
+            config,
+            overrides: Overrides::default(),
+            output: Output::default(),
+            show_context: false,
+            confirm: false,
+            out: None,
+            reflect: 0,
+            deltas: None,
+            max_steps: None,
+            map_reduce: Vec::new(),
+            directives: true,
+            encrypted: None,
+            transcript: None,
+            ephemeral: None,
+            lock: OnceLock::new(),
+        }
+    }
+
+    /// Continue the chat at `path` however it's kept, decrypting it into a tmpfs
+    /// if it's encrypted or rendering it as markdown if it's a transcript
+    pub fn resume(path: &Path, config: Config) -> Result<Self> {
+        if transcript::is_transcript(path) {
+            return Self::open_transcript(path, config);
+        }
+        match encryption::is_encrypted(path) {
+            true if config.log_requests == Some(true) || cassette::recording() => {
+                bail!(Failure::Usage(
+                    "Encrypted chats can't be sent while requests are logged or recorded, they wouldn't be encrypted"
+                        .to_string()
+                ))
+            }
+            true => Self::open_encrypted(path, config),
+            false => Ok(Self::open(path, config)),
+        }
+    }
+
+    /// Continue a copy of the chat at `path` that's never saved, leaving the chat as it was
+    pub fn fork_ephemeral(path: &Path, config: Config) -> Result<Self> {
+        let ephemeral = EphemeralChat::fork(path)?;
+        Ok(Self {
+            chat_file: ephemeral.path(),
+            ephemeral: Some(ephemeral),
+            ..Self::open(path, config)
+        })
+    }
+
+    /// Continue an encrypted chat, decrypting it into a tmpfs until the session ends
+    pub fn open_encrypted(path: &Path, config: Config) -> Result<Self> {
+        let encrypted = EncryptedChat::open(path)?;
+        Ok(Self {
+            chat_file: encrypted.plain_path(),
+            encrypted: Some(encrypted),
+            ..Self::open(path, config)
+        })
+    }
+
+    /// Continue a chat kept as a JSONL transcript, editing it as markdown until the session ends
+    pub fn open_transcript(path: &Path, config: Config) -> Result<Self> {
+        let transcript = TranscriptChat::open(path)?;
+        Ok(Self {
+            chat_file: transcript.markdown_path(),
+            transcript: Some(transcript),
+            ..Self::open(path, config)
+        })
+    }
+
+    /// Start a new chat in the chat store with a system prompt
+    ///
+    /// `system` is a prompt name or path, falling back to the config and then
+    /// the default prompt. The settings are recorded in the chat's frontmatter
+    /// so resuming it uses the same ones.
+    pub fn start(system: Option<String>, config: Config, overrides: Overrides) -> Result<Self> {
+        Self::start_in(
+            storage::new_chat_file_path(),
+            None,
+            system,
+            config,
+            overrides,
+        )
+    }
+
+    /// Like `start`, but in `chat_file`, which mustn't exist yet
+    pub fn start_at(
+        chat_file: PathBuf,
+        system: Option<String>,
+        config: Config,
+        overrides: Overrides,
+    ) -> Result<Self> {
+        Self::start_in(chat_file, None, system, config, overrides)
+    }
+
+    /// Like `start`, but the chat is kept encrypted and only decrypted into a tmpfs
+    pub fn start_encrypted(
+        system: Option<String>,
+        config: Config,
+        overrides: Overrides,
+    ) -> Result<Self> {
+        let encrypted = EncryptedChat::create(&ChatStore::open()?.new_chat_path()?)?;
+        let session = Self::start_in(
+            encrypted.plain_path(),
+            Some(encrypted),
+            system,
+            config,
+            overrides,
+        )?;
+        session.seal()?;
+        Ok(session)
+    }
+
+    /// Like `start`, but the chat is only kept in a tmpfs and shredded when the session ends
+    pub fn start_ephemeral(
+        system: Option<String>,
+        config: Config,
+        overrides: Overrides,
+    ) -> Result<Self> {
+        let ephemeral = EphemeralChat::create()?;
+        let mut session = Self::start_in(ephemeral.path(), None, system, config, overrides)?;
+        session.ephemeral = Some(ephemeral);
+        Ok(session)
+    }
+
+    /// Like `start`, but the chat is kept as a JSONL transcript
+    pub fn start_transcript(
+        system: Option<String>,
+        config: Config,
+        overrides: Overrides,
+    ) -> Result<Self> {
+        let transcript = TranscriptChat::create(&ChatStore::open()?.new_chat_path()?)?;
+        let mut session =
+            Self::start_in(transcript.markdown_path(), None, system, config, overrides)?;
+        session.transcript = Some(transcript);
+        session.seal()?;
+        Ok(session)
+    }
+
+    fn start_in(
+        chat_file: PathBuf,
+        encrypted: Option<EncryptedChat>,
+        system: Option<String>,
+        config: Config,
+        overrides: Overrides,
+    ) -> Result<Self> {
+        let system = system
+            .or_else(|| config.system.clone())
+            .unwrap_or_else(|| prompts::DEFAULT_PROMPT.to_string());
+        let prompt = prompts::resolve(&system)?;
+
+        let settings = Settings::resolve(&overrides, &Frontmatter::default(), &config)?;
+        let frontmatter = Frontmatter {
+            model: Some(settings.model),
+            sampling: settings.sampling,
+            system: Some(system),
+            rag: settings.rag,
+            created: Some(chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false)),
+            daily_note: obsidian::in_vault(&chat_file)
+                .then(|| obsidian::daily_link(chrono::Local::now().date_naive())),
+            ..Default::default()
+        };
+
+        Message::first(
+            ChatCompletionMessageRole::System,
+            &prompt,
+            &chat_file,
+            &frontmatter,
+        )?;
+
+        Ok(Self {
+            chat_file,
+            config,
+            overrides,
+            output: Output::default(),
+            show_context: false,
+            confirm: false,
+            out: None,
+            reflect: 0,
+            deltas: None,
+            max_steps: None,
+            map_reduce: Vec::new(),
+            directives: true,
+            encrypted,
+            transcript: None,
+            ephemeral: None,
+            lock: OnceLock::new(),
+        })
+    }
+
+    /// Settings that take precedence over the chat's frontmatter
+    pub fn with_overrides(mut self, overrides: Overrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// How replies are shown as they stream in
+    pub fn with_output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Preview the messages on stderr before each request
+    pub fn with_show_context(mut self, show_context: bool) -> Self {
+        self.show_context = show_context;
+        self
+    }
+
+    /// Ask before each request, with a chance to edit or drop messages
+    pub fn with_confirm(mut self, confirm: bool) -> Self {
+        self.confirm = confirm;
+        self
+    }
+
+    /// Write each reply to a file as well as it streams in, e.g. to keep generated code
+    pub fn with_out(mut self, out: Option<PathBuf>) -> Self {
+        self.out = out;
+        self
+    }
+
+    /// Have the model critique and revise each answer this many times
+    pub fn with_reflect(mut self, rounds: usize) -> Self {
+        self.reflect = rounds;
+        self
+    }
+
+    /// Send each piece of a reply to a channel as it streams in, e.g. for an editor
+    pub fn with_deltas(mut self, deltas: UnboundedSender<String>) -> Self {
+        self.deltas = Some(deltas);
+        self
+    }
+
+    /// Stop calling tools after this many calls, asking the model to sum up instead
+    pub fn with_max_steps(mut self, max_steps: Option<usize>) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Ask each message about the documents a part at a time before sending it,
+    /// for documents longer than the context window
+    pub fn with_map_reduce(mut self, documents: Vec<PathBuf>) -> Self {
+        self.map_reduce = documents;
+        self
+    }
+
+    /// Send the user's message as written, without carrying out its directives
+    /// or slash commands, for text from someone who mustn't run commands or
+    /// read files
+    pub fn with_directives(mut self, directives: bool) -> Self {
+        self.directives = directives;
+        self
+    }
+
+    pub fn chat_file(&self) -> &Path {
+        &self.chat_file
+    }
+
+    /// Where the chat is kept, the encrypted file or transcript rather than its markdown copy
+    pub fn saved_file(&self) -> &Path {
+        match (&self.encrypted, &self.transcript) {
+            (Some(encrypted), _) => encrypted.path(),
+            (None, Some(transcript)) => transcript.path(),
+            (None, None) => &self.chat_file,
+        }
+    }
+
+    /// Take the chat's lock unless the session has it already, so another
+    /// process or session can't write to the chat until the session ends
+    ///
+    /// Fails with [`crate::lock::InUse`] if one has it. An ephemeral chat can't be
+    /// reached by other processes, so isn't locked.
+    pub fn lock(&self) -> Result<()> {
+        if self.ephemeral.is_none() && self.lock.get().is_none() {
+            let _ = self
+                .lock
+                .set(ChatLock::acquire_for_session(self.saved_file())?);
+        }
+        Ok(())
+    }
+
+    /// Encrypt the chat again, or write its transcript, after it's changed
+    fn seal(&self) -> Result<()> {
+        match (&self.encrypted, &self.transcript) {
+            (Some(encrypted), _) => encrypted.seal(),
+            (None, Some(transcript)) => transcript.seal(),
+            (None, None) => Ok(()),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Send the chat and append the reply, returning it
+    ///
+    /// The request uses the settings from the chat's frontmatter unless overridden.
+    /// If the model calls a tool its result is appended and the chat is sent again,
+    /// until the model gives a final answer. With a response format, a reply that
+    /// doesn't match it is sent back to be corrected.
+    pub async fn send(&self) -> Result<Reply> {
+        self.send_or_cancel(std::future::pending()).await
+    }
+
+    /// Like `send`, but stop waiting for the reply once `cancel` completes
+    ///
+    /// The part of the reply received so far is kept in the chat file, marked
+    /// as interrupted, and an [`Interrupted`] error is returned.
+    pub async fn send_or_cancel(&self, cancel: impl Future<Output = ()>) -> Result<Reply> {
+        self.lock()?;
+        hooks::fire(Event::BeforeSend, self);
+        let reply = self.exchange(cancel).await;
+        self.seal()?;
+        if reply.is_ok() {
+            hooks::fire(Event::AfterResponse, self);
+        }
+        reply
+    }
+
+    async fn exchange(&self, cancel: impl Future<Output = ()>) -> Result<Reply> {
+        let mut cancel = pin!(cancel);
+        let file = &self.chat_file;
+        mcp::start(&self.config.mcp_servers).await;
+        if self.directives {
+            slash::apply(file)?;
+            directives::expand(file).await?;
+        }
+        if !self.map_reduce.is_empty() {
+            let (settings, messages) = self.prepare()?;
+            mapreduce::map(file, &self.map_reduce, &settings, &messages).await?;
+        }
+        let config = &self.config;
+        let mut compacted = false;
+        let mut corrections = 0;
+        let mut retrieved = None;
+        let mut route = None;
+        let mut steps = 0;
+        loop {
+            let (mut settings, mut messages) = self.prepare()?;
+            if self.max_steps.is_some_and(|max| steps >= max) {
+                settings.functions.clear();
+            }
+
+            // Route once for the user's message, its tool calls and corrections go to the same model
+            if settings.routable {
+                if route.is_none() {
+                    route = routing::choose(&config.routing, &settings.model, &messages).await?;
+                    if let Some(route) = &route {
+                        tracing::info!("Routed to {}, {}", route.model, route.reason);
+                    }
+                }
+                if let Some(route) = &route {
+                    settings.model = route.model.clone();
+                }
+            }
+
+            // Compact the chat file once if it has outgrown the context window, but not an
+            // encrypted or ephemeral chat, whose archived copy would be kept on disk unencrypted
+            if config.context.overflow == Overflow::Compact
+                && self.encrypted.is_none()
+                && self.ephemeral.is_none()
+                && !compacted
+                && !context::fits(&messages, &settings, &config.context)
+            {
+                compacted = true;
+                match compact::compact(file, &settings, compact::DEFAULT_KEEP).await {
+                    Ok(summary) => {
+                        eprintln!(
+                            "Summarized {} earlier messages to fit the context window, the original chat is archived at {}",
+                            summary.summarized,
+                            summary.archive.display()
+                        );
+                        continue;
+                    }
+                    // Fall back to leaving the oldest messages out of the request
+                    Err(e) => eprintln!("Unable to compact the chat: {:#}", e),
+                }
+            }
+
+            // Retrieve once for the user's message, not again for tool calls or corrections
+            if let Some(index) = &settings.rag {
+                let chunks = match &retrieved {
+                    Some(chunks) => chunks,
+                    None => {
+                        let question = rag::question(&messages).unwrap_or_default();
+                        retrieved.insert(rag::retrieve(index, &question, &config.rag).await?)
+                    }
+                };
+                rag::add_context(&mut messages, chunks);
+            }
+            let mut messages = context::assemble(messages, &settings, &config.context)?;
+            let prefill = prefill(&mut messages, &settings);
+            tracing::info!("Sending {} messages to {}", messages.len(), settings.model);
+
+            let messages = match self.confirm {
+                true => confirm::review(messages, &settings.model)?,
+                false => {
+                    if self.show_context {
+                        eprint!("{}", context::preview(&messages, &settings.model));
+                    }
+                    messages
+                }
+            };
+
+            let stream = Stream {
+                output: self.output,
+                out: self.out.as_deref(),
+                deltas: self.deltas.as_ref(),
+                prefill,
+            };
+            let options = RequestOptions {
+                route: route.as_ref().map(|r| r.reason.clone()),
+                ..self.request_options()
+            };
+            let reply = request_chat_completion(
+                messages,
+                &settings,
+                ReplyWriter::new(file)?,
+                stream,
+                options,
+                &mut cancel,
+                provider::create_stream,
+            )
+            .await?;
+
+            match reply.message.function_call.clone() {
+                Some(call) => {
+                    steps += 1;
+                    if let Some(max) = self.max_steps {
+                        eprintln!("Step {} of {}", steps, max);
+                    }
+                    Message::from(reply.message).write(file)?;
+                    let result = tools::call(config, &call).await;
+                    tracing::debug!("{} returned {} characters", call.name, result.len());
+                    Message::function_result(&call.name, &result).write(file)?;
+                    if self.max_steps == Some(steps) {
+                        let note = format!(
+                            "That was the last of the {} steps allowed. Don't call any more tools, sum up what was done and what's left to do.",
+                            steps
+                        );
+                        Message::append(&note, ChatCompletionMessageRole::User, file)?;
+                    }
+                }
+                None => {
+                    let content = reply.message.content.as_deref().unwrap_or_default();
+                    let problem = match &settings.response_format {
+                        Some(format) => {
+                            schema::check(content, format).map(|p| (schema::correction(&p), p))
+                        }
+                        None if settings.patch => {
+                            patch::check(content).map(|p| (patch::correction(&p), p))
+                        }
+                        // Asked for again only once, the check is a guess
+                        None if corrections == 0 => {
+                            settings.reply_language.as_deref().and_then(|language| {
+                                language::check(content, language)
+                                    .map(|p| (language::correction(&p, language), p))
+                            })
+                        }
+                        None => None,
+                    };
+                    let Some((correction, problem)) = problem else {
+                        let mut reply = match self.reflect {
+                            // A JSON reply would be wrapped in the reflection
+                            rounds if rounds > 0 && settings.response_format.is_none() => {
+                                reflect::revise(file, &settings, rounds, reply, &mut cancel).await?
+                            }
+                            _ => reply,
+                        };
+                        // A JSON reply or a patch wouldn't parse any more
+                        if !settings.post_process.is_empty()
+                            && settings.response_format.is_none()
+                            && !settings.patch
+                        {
+                            if let Err(e) =
+                                postprocess::rewrite(file, &settings.post_process, &mut reply)
+                            {
+                                eprintln!("{:#}, the reply is kept as it was", e);
+                            }
+                        }
+                        // Sources would make a JSON reply invalid
+                        if let Some(chunks) = retrieved.filter(|c| !c.is_empty()) {
+                            if settings.response_format.is_none() {
+                                rag::cite(file, &chunks)?;
+                            }
+                        }
+                        return Ok(reply);
+                    };
+                    if corrections == schema::MAX_CORRECTIONS {
+                        bail!(
+                            "{}\nThe reply was still wrong after {} corrections",
+                            problem,
+                            corrections
+                        );
+                    }
+                    corrections += 1;
+                    tracing::info!("Correction {} of {}", corrections, schema::MAX_CORRECTIONS);
+                    eprintln!("{}\nAsking for a correction", problem);
+                    // The reply is left in the chat, followed by what was wrong with it
+                    storage::append(file, &format!("{}\n", correction))
+                        .with_context(|| format!("Could not append to file: {:?}", file))?;
+                }
+            }
+        }
+    }
+
+    /// Send the chat to several models at once, appending each reply under
+    /// `# Assistant (<model>)` and returning them
+    ///
+    /// The models aren't offered tools, as their calls couldn't all be followed
+    /// up. A model that fails is reported and left out.
+    pub async fn compare(&self, models: &[String]) -> Result<Vec<Message>> {
+        self.lock()?;
+        if self.directives {
+            slash::apply(&self.chat_file)?;
+            directives::expand(&self.chat_file).await?;
+        }
+        let (settings, messages) = self.prepare()?;
+        let requests = models.iter().map(|model| {
+            let settings = Settings {
+                model: model.clone(),
+                functions: Vec::new(),
+                ..settings.clone()
+            };
+            let messages = messages.clone();
+            async move {
+                let messages = context::assemble(messages, &settings, &self.config.context)?;
+                let completion = provider::create(settings.request(messages)).await?;
+                completion
+                    .choices
+                    .first()
+                    .and_then(|c| c.message.content.clone())
+                    .context("The API returned an empty reply")
+            }
+        });
+        let names: Vec<&str> = models.iter().map(String::as_str).collect();
+        let results = spinner::wait(&names, join_all(requests)).await;
+
+        let mut replies = Vec::new();
+        for (model, result) in models.iter().zip(results) {
+            match result {
+                Ok(content) => replies.push(Message {
+                    role: ChatCompletionMessageRole::Assistant,
+                    content,
+                    name: None,
+                    function_call: None,
+                    model: Some(model.clone()),
+                    meta: None,
+                }),
+                Err(e) => eprintln!("{} didn't reply: {:#}", model, e),
+            }
+        }
+        if replies.is_empty() {
+            bail!("None of the models replied");
+        }
+
+        storage::append(
+            &self.chat_file,
+            &format!("{}# User\n\n", Message::render(&replies)),
+        )
+        .with_context(|| format!("Could not append to file: {:?}", self.chat_file))?;
+        self.seal()?;
+        Ok(replies)
+    }
+
+    /// Remove the last reply, and any tool calls leading up to it, so it can be sent again
+    pub fn remove_last_reply(&self) -> Result<()> {
+        self.lock()?;
+        let (frontmatter, mut messages) = Message::read_chat(&self.chat_file)?;
+        let Some(last_user) = messages.iter().rposition(|m| {
+            matches!(m.role, ChatCompletionMessageRole::User) && !m.content.trim().is_empty()
+        }) else {
+            bail!("The chat has no message to reply to");
+        };
+        if !messages[last_user..]
+            .iter()
+            .any(|m| matches!(m.role, ChatCompletionMessageRole::Assistant))
+        {
+            bail!("The last message hasn't been replied to yet");
+        }
+
+        messages.truncate(last_user + 1);
+        Message::write_chat(&self.chat_file, &frontmatter, &messages)?;
+        self.seal()
+    }
+
+    /// Message number `at` (counting from 1)
+    pub fn message(&self, at: usize) -> Result<Message> {
+        let (_, mut messages) = Message::read_chat(&self.chat_file)?;
+        check_message_number(at, messages.len())?;
+        Ok(messages.swap_remove(at - 1))
+    }
+
+    /// Replace the content of message number `at` (counting from 1) and remove the
+    /// messages after it, so the chat carries on from there
+    ///
+    /// Returns whether the chat now ends with the user's message, ready to be sent.
+    pub fn edit_message(&self, at: usize, content: &str) -> Result<bool> {
+        self.lock()?;
+        let (frontmatter, mut messages) = Message::read_chat(&self.chat_file)?;
+        check_message_number(at, messages.len())?;
+        if messages[at - 1].function_call.is_some() {
+            bail!("Message {} is a tool call, which can't be edited", at);
+        }
+        messages.truncate(at);
+        messages[at - 1].content = content.trim().to_string();
+        Message::write_chat(&self.chat_file, &frontmatter, &messages)?;
+        self.seal()?;
+        Ok(matches!(
+            messages[at - 1].role,
+            ChatCompletionMessageRole::User
+        ))
+    }
+
+    /// Remove message number `at` (counting from 1), leaving the others as they are
+    pub fn delete_message(&self, at: usize) -> Result<()> {
+        self.lock()?;
+        let (frontmatter, mut messages) = Message::read_chat(&self.chat_file)?;
+        check_message_number(at, messages.len())?;
+        messages.remove(at - 1);
+        Message::write_chat(&self.chat_file, &frontmatter, &messages)?;
+        self.seal()
+    }
+
+    /// Copy the chat up to and including message number `at` (counting from 1)
+    /// into a new chat in the chat store
+    pub fn branch(&self, at: usize) -> Result<Self> {
+        if self.encrypted.is_some() || encryption::is_encrypted(&self.chat_file) {
+            bail!("Encrypted chats can't be branched, the branch wouldn't be encrypted");
+        }
+        if self.ephemeral.is_some() {
+            bail!("Ephemeral chats can't be branched, the branch would be saved");
+        }
+        let (mut frontmatter, mut messages) = Message::read_chat(&self.chat_file)?;
+        check_message_number(at, messages.len())?;
+        messages.truncate(at);
+
+        // The branch is titled afresh once it has a reply of its own
+        frontmatter.title = None;
+        frontmatter.created =
+            Some(chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false));
+        frontmatter.branched_from = Some(self.saved_file().to_path_buf());
+
+        let chat_file = storage::new_chat_file_path();
+        Message::write_chat(&chat_file, &frontmatter, &messages)?;
+        Ok(Self {
+            chat_file,
+            config: self.config.clone(),
+            overrides: self.overrides.clone(),
+            output: self.output,
+            show_context: self.show_context,
+            confirm: self.confirm,
+            out: self.out.clone(),
+            reflect: self.reflect,
+            deltas: self.deltas.clone(),
+            max_steps: self.max_steps,
+            map_reduce: self.map_reduce.clone(),
+            directives: self.directives,
+            encrypted: None,
+            transcript: None,
+            ephemeral: None,
+            lock: OnceLock::new(),
+        })
+    }
+
+    /// The request `send` would make next, without sending it
+    ///
+    /// A chat that would be compacted is shown truncated instead, directives
+    /// and slash commands are left as they are rather than run, and no context
+    /// is retrieved.
+    pub fn request(&self) -> Result<ChatRequest> {
+        let (settings, messages) = self.prepare()?;
+        let mut messages = context::assemble(messages, &settings, &self.config.context)?;
+        prefill(&mut messages, &settings);
+        Ok(ChatRequest {
+            stream: true,
+            stream_options: Some(StreamOptions::with_usage()),
+            ..settings.request(messages)
+        })
+    }
+
+    /// Read the chat file into the settings and messages for a request
+    fn prepare(&self) -> Result<(Settings, Vec<RequestMessage>)> {
+        let (frontmatter, messages) = Message::read_chat(&self.chat_file)?;
+        let settings = Settings::resolve(&self.overrides, &frontmatter, &self.config)?;
+
+        // Attachments are relative to the chat file
+        let base_dir = self.chat_file.parent().unwrap_or_else(|| Path::new("."));
+        let mut messages = messages
+            .iter()
+            .map(|m| m.to_request(base_dir))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(exchanges) = settings.history {
+            messages = context::last_exchanges(messages, exchanges);
+        }
+        if let Some(context) = &self.config.project_context {
+            project::add_context(&mut messages, context);
+        }
+        if settings.patch {
+            patch::add_instructions(&mut messages)?;
+        }
+        if let Some(language) = &settings.reply_language {
+            language::add_instruction(&mut messages, language);
+        }
+        Ok((settings, messages))
+    }
+
+    /// Ask the model for the rest of the chat's last reply, e.g. one cut off at the token limit
+    ///
+    /// The rest is added to the reply under the same heading. The reply so far
+    /// can be interrupted like `send_or_cancel`.
+    pub async fn continue_or_cancel(&self, cancel: impl Future<Output = ()>) -> Result<Reply> {
+        self.lock()?;
+        let reply = self.continue_reply(cancel, provider::create_stream).await;
+        self.seal()?;
+        reply
+    }
+
+    async fn continue_reply<F>(
+        &self,
+        cancel: impl Future<Output = ()>,
+        open_stream: impl FnMut(ChatRequest) -> F,
+    ) -> Result<Reply>
+    where
+        F: Future<Output = Result<Receiver<Result<ChatCompletionDelta>>>>,
+    {
+        let mut cancel = pin!(cancel);
+        let file = &self.chat_file;
+        let (frontmatter, mut messages) = Message::read_chat(file)?;
+        if messages.last().is_some_and(|m| {
+            matches!(m.role, ChatCompletionMessageRole::User) && m.content.trim().is_empty()
+        }) {
+            messages.pop();
+        }
+        let Some(last) = messages.last_mut().filter(|m| {
+            matches!(m.role, ChatCompletionMessageRole::Assistant) && m.function_call.is_none()
+        }) else {
+            bail!("The chat doesn't end with a reply to continue");
+        };
+        // Replaced once the reply is finished
+        last.meta = None;
+        let content = last.content.trim_end().to_string();
+
+        // Without the `# User` heading, or a newline to break the last word
+        let body = Message::render(&messages);
+        storage::write_atomically(
+            file,
+            format!("{}{}", frontmatter.render()?, body.trim_end()),
+        )
+        .with_context(|| format!("Unable to write chat file: {:?}", file))?;
+
+        let (settings, mut request) = self.prepare()?;
+        // The reply so far is sent after the instruction to continue it
+        request.pop();
+        let stream = Stream {
+            output: self.output,
+            out: self.out.as_deref(),
+            deltas: self.deltas.as_ref(),
+            prefill: None,
+        };
+        request_chat_completion(
+            request,
+            &settings,
+            ReplyWriter::continuing(file, &content)?,
+            stream,
+            self.request_options(),
+            &mut cancel,
+            open_stream,
+        )
+        .await
+    }
+
+    fn request_options(&self) -> RequestOptions {
+        RequestOptions {
+            record_meta: self.config.reply_metadata != Some(false),
+            max_continues: match self.config.auto_continue {
+                Some(true) => MAX_CONTINUES,
+                _ => 0,
+            },
+            route: None,
+            timeout: provider::timeouts().total,
+        }
+    }
+
+    /// Give the chat a title once it has its first reply, see `title::ensure_title`
+    ///
+    /// Returns whether the chat file was renamed after the title.
+    pub async fn title(&mut self) -> Result<bool> {
+        self.lock()?;
+        let path = title::ensure_title(&self.chat_file, &self.config).await?;
+        let renamed = path != self.chat_file;
+        self.chat_file = path;
+        // The lock is named after the chat's path, so it's taken again under the new one
+        if renamed {
+            if let Some(_old) = self.lock.take() {
+                self.lock()?;
+            }
+        }
+        self.seal()?;
+        Ok(renamed)
+    }
+}
+
+/// Times a reply is resumed after its stream drops before giving up on it
+const MAX_RESUMES: usize = 2;
+
+/// Times the rest of a reply cut off at the token limit is asked for with `auto_continue`
+const MAX_CONTINUES: usize = 3;
+
+/// Sent after the start of a reply that was cut off, to have the model finish it
+const RESUME_INSTRUCTION: &str = "Your reply was cut off. Continue exactly from where you stopped, mid-word if need be, without repeating anything or adding a preamble.";
+
+/// Returned when sending is cancelled before the reply is complete
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The reply was interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Fail unless the chat has a message number `at`, counting from 1
+fn check_message_number(at: usize, len: usize) -> Result<()> {
+    if at == 0 || at > len {
+        bail!("There is no message {}, the chat has {} messages", at, len);
+    }
+    Ok(())
+}
+
+/// Start the reply to the user's message with the prefill, as an unfinished assistant message
+///
+/// The reply to a tool's result carries on from the prefilled one instead.
+fn prefill<'a>(messages: &mut Vec<RequestMessage>, settings: &'a Settings) -> Option<&'a str> {
+    let prefill = settings.prefill.as_deref().filter(|_| {
+        messages
+            .last()
+            .is_some_and(|m| matches!(m.role, ChatCompletionMessageRole::User))
+    })?;
+    messages.push(RequestMessage::text(
+        ChatCompletionMessageRole::Assistant,
+        prefill,
+    ));
+    Some(prefill)
+}
+
+/// How a reply is requested, from the config
+struct RequestOptions {
+    /// Note how the reply was made after it
+    record_meta: bool,
+    /// Times to ask for the rest of a reply cut off at the token limit
+    max_continues: usize,
+    /// Why `[routing]` chose the model, noted with the reply
+    route: Option<String>,
+    /// How long the whole reply may take, the --timeout
+    timeout: Option<Duration>,
+}
+
+/// Where a reply goes as it streams in, besides the chat file
+struct Stream<'a> {
+    output: Output,
+    /// A file the reply is written to as well
+    out: Option<&'a Path>,
+    deltas: Option<&'a UnboundedSender<String>>,
+    /// The start of the reply sent as an unfinished assistant message
+    prefill: Option<&'a str>,
+}
+
+/// The reply is streamed into `chat_file` as it arrives, a function call is
+/// left for the caller to write.
+///
+/// Each attempt's stream comes from `open_stream`, i.e. `provider::create_stream`.
+async fn request_chat_completion<F>(
+    messages: Vec<RequestMessage>,
+    settings: &Settings,
+    mut reply: ReplyWriter,
+    stream: Stream<'_>,
+    options: RequestOptions,
+    cancel: &mut Pin<&mut impl Future<Output = ()>>,
+    mut open_stream: impl FnMut(ChatRequest) -> F,
+) -> Result<Reply>
+where
+    F: Future<Output = Result<Receiver<Result<ChatCompletionDelta>>>>,
+{
+    let sent = chrono::Local::now();
+    let start = Instant::now();
+    // A continued reply is returned whole, not just the new part
+    let continuing = !reply.content().is_empty();
+    // Only the last reply is kept, e.g. the corrected one
+    let mut out = stream
+        .out
+        .map(|path| File::create(path).with_context(|| format!("Unable to create {:?}", path)))
+        .transpose()?;
+
+    let mut resumes = 0;
+    let mut continues = 0;
+    let attempts = async {
+        loop {
+            let mut request = messages.clone();
+            let mut prefill = stream.prefill;
+            // The model carries on from the reply so far if it was cut off
+            if !reply.content().is_empty() {
+                // What was received already starts with the prefill
+                if prefill.take().is_some() {
+                    request.pop();
+                }
+                request.push(RequestMessage::text(
+                    ChatCompletionMessageRole::Assistant,
+                    reply.content(),
+                ));
+                request.push(RequestMessage::text(
+                    ChatCompletionMessageRole::User,
+                    RESUME_INSTRUCTION,
+                ));
+            }
+            let spinner =
+                (stream.output == Output::Text).then(|| Spinner::start(&[&settings.model]));
+            let chat_stream = tokio::select! {
+                stream = open_stream(settings.request(request)) => stream,
+                _ = cancel.as_mut() => Err(Interrupted.into()),
+            };
+            let attempt = match chat_stream {
+                Ok(mut chat_stream) => {
+                    if let Some(spinner) = spinner {
+                        chat_stream = spinner.until_first_chunk(chat_stream);
+                    }
+                    listen_for_tokens(
+                        chat_stream,
+                        &mut reply,
+                        stream.output,
+                        out.as_mut(),
+                        stream.deltas,
+                        prefill,
+                        cancel,
+                    )
+                    .await
+                }
+                Err(e) => {
+                    if e.is::<Interrupted>() {
+                        reply.mark_interrupted()?;
+                    }
+                    Err(e)
+                }
+            };
+            match attempt {
+                Err(e) if e.is::<StreamDropped>() && resumes < MAX_RESUMES => {
+                    resumes += 1;
+                    match reply.content().is_empty() {
+                        true => eprintln!("\n{}, sending the request again", e),
+                        false => eprintln!("\n{}, resuming the reply", e),
+                    }
+                }
+                Ok(completion)
+                    if continues < options.max_continues
+                        && completion
+                            .choices
+                            .first()
+                            .is_some_and(|c| c.finish_reason == "length") =>
+                {
+                    continues += 1;
+                    eprintln!("\nThe reply reached the token limit, asking for the rest");
+                }
+                // The pieces of a resumed reply make up one message
+                Ok(mut completion) if continuing || resumes > 0 || continues > 0 => {
+                    if let Some(choice) = completion.choices.first_mut() {
+                        if choice.message.function_call.is_none() {
+                            choice.message.content = Some(reply.content().to_string());
+                        }
+                    }
+                    break Ok(completion);
+                }
+                attempt => break attempt,
+            }
+        }
+    };
+    let chat_completion = match options.timeout {
+        Some(total) => match tokio::time::timeout(total, attempts).await {
+            Ok(attempt) => attempt,
+            Err(_) => {
+                reply.mark_interrupted()?;
+                Err(TimedOut(total).into())
+            }
+        },
+        None => attempts.await,
+    };
+
+    // The file is closed off even if the stream failed part way
+    let called_function = chat_completion.as_ref().is_ok_and(|c| {
+        c.choices
+            .first()
+            .is_some_and(|c| c.message.function_call.is_some())
+    });
+    let meta = chat_completion
+        .as_ref()
+        .ok()
+        .filter(|_| !called_function)
+        .map(|completion| Meta {
+            sent: Some(sent.to_rfc3339_opts(chrono::SecondsFormat::Secs, false)),
+            model: Some(completion.model.clone()).filter(|m| !m.is_empty()),
+            latency_ms: reply
+                .first_chunk()
+                .map(|first| first.duration_since(start).as_millis() as u64),
+            duration_ms: Some(start.elapsed().as_millis() as u64),
+            prompt_tokens: completion.usage.as_ref().map(|u| u.prompt_tokens),
+            completion_tokens: completion.usage.as_ref().map(|u| u.completion_tokens),
+            route: options.route.clone(),
+            fallback: fallback::in_use().map(|f| f.provider.clone()),
+        });
+    reply.finish(
+        !called_function,
+        meta.as_ref().filter(|_| options.record_meta),
+    )?;
+    if let Some(meta) = meta.filter(|_| stream.output == Output::Text) {
+        eprintln!("{}", meta.summary());
+    }
+    let chat_completion: ChatCompletion = chat_completion?;
+
+    let choice = chat_completion
+        .choices
+        .first()
+        .context("The API returned no choices")?;
+    Ok(Reply {
+        message: choice.message.clone(),
+        model: chat_completion.model.clone(),
+        finish_reason: Some(choice.finish_reason.clone()).filter(|r| !r.is_empty()),
+        usage: chat_completion.usage,
+    })
+}
+
+async fn listen_for_tokens(
+    mut chat_stream: Receiver<Result<ChatCompletionDelta>>,
+    reply: &mut ReplyWriter,
+    output: Output,
+    mut out: Option<&mut File>,
+    deltas: Option<&UnboundedSender<String>>,
+    mut prefill: Option<&str>,
+    cancel: &mut Pin<&mut impl Future<Output = ()>>,
+) -> Result<ChatCompletion> {
+    let mut merged: Option<ChatCompletionDelta> = None;
+    let mut usage = None;
+    let mut finish_reason = None;
+    // A resumed reply carries on under the same heading
+    let resuming = !reply.content().is_empty();
+    let mut highlighter = Highlighter::default();
+    loop {
+        let mut delta = tokio::select! {
+            delta = chat_stream.recv() => match delta {
+                Some(delta) => delta?,
+                None => break,
+            },
+            _ = cancel.as_mut() => {
+                if output == Output::Text {
+                    let _ = write!(stdout(), "{}", highlighter.finish());
+                }
+                reply.mark_interrupted()?;
+                return Err(Interrupted.into());
+            }
+        };
+        // The usage arrives on its own after the reply
+        if delta.usage.is_some() {
+            usage = delta.usage;
+        }
+        // The model carries on from the prefill, so the reply starts with it
+        if let Some(choice) = delta.choices.first_mut() {
+            if let Some(prefill) = prefill.take() {
+                let content = choice.delta.content.take().unwrap_or_default();
+                choice.delta.content = Some(format!("{}{}", prefill, content));
+            }
+        }
+        let Some(choice) = delta.choices.first() else {
+            continue;
+        };
+        if choice.finish_reason.is_some() {
+            finish_reason = choice.finish_reason.clone();
+        }
+        if let Some(content) = &choice.delta.content {
+            reply.push(content)?;
+            if let Some(out) = out.as_mut() {
+                out.write_all(content.as_bytes())?;
+            }
+            if let Some(deltas) = deltas {
+                let _ = deltas.send(content.clone());
+            }
+        }
+        // A closed stdout, e.g. piped into `head`, fails the reply rather than panicking
+        show(output, choice, resuming, &mut highlighter)
+            .context("Unable to write the reply to stdout")?;
+        // Merge completion into accrued.
+        match merged.as_mut() {
+            Some(c) => c
+                .merge(delta)
+                .map_err(|e| anyhow!("Unable to merge the reply's chunks: {:?}", e))?,
+            None => merged = Some(delta),
+        };
+    }
+    match merged {
+        Some(mut merged) => {
+            // Merging keeps the first chunk's finish reason and usage, which are unset
+            merged.usage = usage;
+            if let Some(choice) = merged.choices.first_mut() {
+                choice.finish_reason = finish_reason;
+            }
+            Ok(merged.into())
+        }
+        None => bail!("The API returned an empty reply"),
+    }
+}
+
+/// Show a chunk of the reply on stdout the way `output` asks
+fn show(
+    output: Output,
+    choice: &ChatCompletionChoiceDelta,
+    resuming: bool,
+    highlighter: &mut Highlighter,
+) -> std::io::Result<()> {
+    let mut stdout = stdout();
+    match output {
+        Output::Text => {
+            if let Some(role) = choice.delta.role.as_ref().filter(|_| !resuming) {
+                write!(stdout, "{}", theme::label(role))?;
+            }
+            if let Some(content) = &choice.delta.content {
+                write!(stdout, "{}", highlighter.push(content))?;
+            }
+            if choice.finish_reason.is_some() {
+                // The message being streamed has been fully received.
+                writeln!(stdout, "{}", highlighter.finish())?;
+            }
+        }
+        Output::Plain => {
+            if let Some(content) = &choice.delta.content {
+                write!(stdout, "{}", content)?;
+            }
+            if choice.finish_reason.is_some() {
+                writeln!(stdout)?;
+            }
+        }
+        Output::JsonStream => {
+            if let Some(content) = &choice.delta.content {
+                writeln!(stdout, "{}", json!({ "delta": content }))?;
+            }
+        }
+        Output::Json => {}
+    }
+    stdout.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{message::INTERRUPTED_MARKER, provider::Content};
+
+    #[test]
+    fn removes_the_last_reply_and_its_tool_calls() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            "# User\nHi\n# Assistant\nHello\n# User\nList files\n# Function Call: shell\n{}\n# Function: shell\nCargo.toml\n# Assistant\nOne file\n# User\n\n",
+        )
+        .unwrap();
+        let session = Session::open(file.path(), Config::default());
+
+        session.remove_last_reply().unwrap();
+        let contents = std::fs::read_to_string(file.path()).unwrap();
+        assert!(contents.ends_with("# Assistant\nHello\n# User\nList files\n"));
+
+        // There's nothing left to retry until the message has a reply again
+        assert!(session.remove_last_reply().is_err());
+    }
+
+    #[test]
+    fn edits_a_message_and_carries_on_from_it() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            "# User\nHi\n# Assistant\nHello\n# User\nWhat's 'a?\n# Assistant\nA lifetime\n# User\n\n",
+        )
+        .unwrap();
+        let session = Session::open(file.path(), Config::default());
+
+        assert!(session.edit_message(3, "What's 'static?\n").unwrap());
+        let contents = std::fs::read_to_string(file.path()).unwrap();
+        assert!(contents.ends_with("# Assistant\nHello\n# User\nWhat's 'static?\n"));
+
+        // An edited reply waits for the user's next message
+        assert!(!session.edit_message(2, "Hey there").unwrap());
+        session.delete_message(1).unwrap();
+        assert_eq!(session.message(1).unwrap().content, "Hey there");
+        assert!(session.delete_message(3).is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_finish_reason_and_usage_of_a_stream() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let chunk = |choices: serde_json::Value, usage: serde_json::Value| {
+            let chunk = json!({
+                "id": "1", "object": "chat.completion.chunk", "created": 0, "model": "gpt-4o",
+                "choices": choices, "usage": usage,
+            });
+            Ok(serde_json::from_value::<ChatCompletionDelta>(chunk).unwrap())
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        for delta in [
+            chunk(
+                json!([{"index": 0, "delta": {"role": "assistant", "content": "Hi"}, "finish_reason": null}]),
+                json!(null),
+            ),
+            chunk(
+                json!([{"index": 0, "delta": {}, "finish_reason": "stop"}]),
+                json!(null),
+            ),
+            chunk(
+                json!([]),
+                json!({"prompt_tokens": 5, "completion_tokens": 1, "total_tokens": 6}),
+            ),
+        ] {
+            tx.send(delta).await.unwrap();
+        }
+        drop(tx);
+
+        let mut reply = ReplyWriter::new(file.path()).unwrap();
+        let mut cancel = pin!(std::future::pending());
+        let completion = listen_for_tokens(
+            rx,
+            &mut reply,
+            Output::Json,
+            None,
+            None,
+            Some("Well, "),
+            &mut cancel,
+        )
+        .await
+        .unwrap();
+        assert_eq!(completion.choices[0].finish_reason, "stop");
+        assert_eq!(
+            completion.choices[0].message.content.as_deref(),
+            Some("Well, Hi")
+        );
+        assert_eq!(completion.usage.unwrap().total_tokens, 6);
+    }
+
+    /// A stream chunk adding `content` to the reply
+    fn delta(content: &str, finish_reason: Option<&str>) -> Result<ChatCompletionDelta> {
+        let chunk = json!({
+            "id": "1", "object": "chat.completion.chunk", "created": 0, "model": "gpt-4o",
+            "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": finish_reason}],
+        });
+        Ok(serde_json::from_value(chunk).unwrap())
+    }
+
+    /// Opens each of `streams` in turn, keeping the requests made for them
+    fn open_streams(
+        streams: Vec<Receiver<Result<ChatCompletionDelta>>>,
+        requests: &mut Vec<ChatRequest>,
+    ) -> impl FnMut(ChatRequest) -> std::future::Ready<Result<Receiver<Result<ChatCompletionDelta>>>> + '_
+    {
+        let mut streams = streams.into_iter();
+        move |request| {
+            requests.push(request);
+            std::future::ready(streams.next().context("No more streams"))
+        }
+    }
+
+    fn options(timeout: Option<Duration>) -> RequestOptions {
+        RequestOptions {
+            record_meta: false,
+            max_continues: 0,
+            route: None,
+            timeout,
+        }
+    }
+
+    fn stream() -> Stream<'static> {
+        Stream {
+            output: Output::Json,
+            out: None,
+            deltas: None,
+            prefill: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_a_dropped_stream_without_repeating_it() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "# User\nHi\n").unwrap();
+        let (dropped, first) = tokio::sync::mpsc::channel(4);
+        dropped.send(delta("Hel", None)).await.unwrap();
+        dropped
+            .send(Err(StreamDropped("connection reset".into()).into()))
+            .await
+            .unwrap();
+        drop(dropped);
+        let (resumed, second) = tokio::sync::mpsc::channel(4);
+        resumed.send(delta("lo", Some("stop"))).await.unwrap();
+        drop(resumed);
+
+        let settings = Settings::resolve(
+            &Overrides::default(),
+            &Frontmatter::default(),
+            &Config::default(),
+        )
+        .unwrap();
+        let mut requests = vec![];
+        let mut cancel = pin!(std::future::pending());
+        let reply = request_chat_completion(
+            vec![RequestMessage::text(ChatCompletionMessageRole::User, "Hi")],
+            &settings,
+            ReplyWriter::new(file.path()).unwrap(),
+            stream(),
+            options(None),
+            &mut cancel,
+            open_streams(vec![first, second], &mut requests),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.message.content.as_deref(), Some("Hello"));
+
+        // The model was asked to carry on from what had arrived
+        let resume = &requests[1].messages;
+        assert_eq!(resume.len(), 3);
+        assert!(matches!(&resume[1].content, Some(Content::Text(t)) if t == "Hel"));
+        assert!(matches!(&resume[2].content, Some(Content::Text(t)) if t == RESUME_INSTRUCTION));
+        let (_, messages) = Message::read_chat(file.path()).unwrap();
+        assert_eq!(messages[1].content.trim_end(), "Hello");
+    }
+
+    #[tokio::test]
+    async fn times_out_a_stalled_stream_keeping_the_reply_so_far() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "# User\nHi\n").unwrap();
+        // Kept open so the stream never ends, and never yields again
+        let (stalled, rx) = tokio::sync::mpsc::channel(4);
+        stalled.send(delta("Hel", None)).await.unwrap();
+
+        let settings = Settings::resolve(
+            &Overrides::default(),
+            &Frontmatter::default(),
+            &Config::default(),
+        )
+        .unwrap();
+        let mut cancel = pin!(std::future::pending());
+        let error = request_chat_completion(
+            vec![RequestMessage::text(ChatCompletionMessageRole::User, "Hi")],
+            &settings,
+            ReplyWriter::new(file.path()).unwrap(),
+            stream(),
+            options(Some(Duration::from_millis(50))),
+            &mut cancel,
+            open_streams(vec![rx], &mut vec![]),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.is::<TimedOut>());
+
+        let (_, messages) = Message::read_chat(file.path()).unwrap();
+        assert!(messages[1].content.starts_with("Hel\n"));
+        assert!(messages[1].content.contains(INTERRUPTED_MARKER));
+        drop(stalled);
+    }
+
+    #[tokio::test]
+    async fn continues_the_last_reply_under_its_heading() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            "---\ntitle: Counting\n---\n# User\nCount to five\n# Assistant\nOne, two,\n# User\n\n",
+        )
+        .unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tx.send(delta(" three, four, five", Some("stop")))
+            .await
+            .unwrap();
+        drop(tx);
+        let session = Session::open(file.path(), Config::default());
+
+        let mut requests = vec![];
+        let reply = session
+            .continue_reply(
+                std::future::pending(),
+                open_streams(vec![rx], &mut requests),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            reply.message.content.as_deref(),
+            Some("One, two, three, four, five")
+        );
+        // The reply so far is sent last, for the model to carry on from
+        let request = &requests[0].messages;
+        assert!(matches!(&request[1].content, Some(Content::Text(t)) if t == "One, two,"));
+
+        let (frontmatter, messages) = Message::read_chat(file.path()).unwrap();
+        assert_eq!(frontmatter.title.as_deref(), Some("Counting"));
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            messages[1].content.trim_end(),
+            "One, two, three, four, five"
+        );
+    }
+
+    #[tokio::test]
+    async fn tees_the_reply_into_the_out_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "# User\nWrite a script\n").unwrap();
+        let out = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(out.path(), "an earlier reply").unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tx.send(delta("echo ", None)).await.unwrap();
+        tx.send(delta("hi\n", Some("stop"))).await.unwrap();
+        drop(tx);
+
+        let settings = Settings::resolve(
+            &Overrides::default(),
+            &Frontmatter::default(),
+            &Config::default(),
+        )
+        .unwrap();
+        let mut cancel = pin!(std::future::pending());
+        request_chat_completion(
+            vec![RequestMessage::text(
+                ChatCompletionMessageRole::User,
+                "Write a script",
+            )],
+            &settings,
+            ReplyWriter::new(file.path()).unwrap(),
+            Stream {
+                out: Some(out.path()),
+                ..stream()
+            },
+            options(None),
+            &mut cancel,
+            open_streams(vec![rx], &mut vec![]),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read_to_string(out.path()).unwrap(), "echo hi\n");
+        let (_, messages) = Message::read_chat(file.path()).unwrap();
+        assert_eq!(messages[1].content.trim_end(), "echo hi");
+    }
+
+    #[tokio::test]
+    async fn starts_the_reply_with_the_prefill() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "# User\nIs it safe?\n").unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tx.send(delta("it depends.", Some("stop"))).await.unwrap();
+        drop(tx);
+
+        let overrides = Overrides {
+            prefill: Some("Well, ".to_string()),
+            ..Default::default()
+        };
+        let settings =
+            Settings::resolve(&overrides, &Frontmatter::default(), &Config::default()).unwrap();
+        let mut messages = vec![RequestMessage::text(
+            ChatCompletionMessageRole::User,
+            "Is it safe?",
+        )];
+        let prefilled = prefill(&mut messages, &settings);
+        assert_eq!(prefilled, Some("Well, "));
+        let mut requests = vec![];
+        let mut cancel = pin!(std::future::pending());
+        request_chat_completion(
+            messages,
+            &settings,
+            ReplyWriter::new(file.path()).unwrap(),
+            Stream {
+                prefill: prefilled,
+                ..stream()
+            },
+            options(None),
+            &mut cancel,
+            open_streams(vec![rx], &mut requests),
+        )
+        .await
+        .unwrap();
+
+        // The model is asked to carry on from an unfinished reply
+        let last = requests[0].messages.last().unwrap();
+        assert!(matches!(last.role, ChatCompletionMessageRole::Assistant));
+        assert!(matches!(&last.content, Some(Content::Text(t)) if t == "Well, "));
+        let (_, messages) = Message::read_chat(file.path()).unwrap();
+        assert_eq!(messages[1].content.trim_end(), "Well, it depends.");
+
+        // Only the reply to the user's message is prefilled
+        let mut messages = vec![RequestMessage::text(
+            ChatCompletionMessageRole::Function,
+            "{}",
+        )];
+        assert_eq!(prefill(&mut messages, &settings), None);
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn a_dry_run_shows_the_request_without_sending_it() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let contents =
+            "---\nmodel: gpt-4o\ntemperature: 0.3\n---\n# System\nBe terse\n# User\nHi\n";
+        std::fs::write(file.path(), contents).unwrap();
+        let session = Session::open(file.path(), Config::default());
+
+        let request = session.request().unwrap();
+        assert_eq!(request.model, "gpt-4o");
+        assert_eq!(request.sampling.temperature, Some(0.3));
+        assert!(request.stream);
+        assert_eq!(request.messages.len(), 2);
+        assert_eq!(std::fs::read_to_string(file.path()).unwrap(), contents);
+        let tokens = context::estimate(&request);
+        assert!(tokens > context::tokens("gpt-4o", "Be terse Hi"));
+
+        // A longer chat is estimated at more tokens
+        std::fs::write(
+            file.path(),
+            format!("{}{}", contents, "and more ".repeat(50)),
+        )
+        .unwrap();
+        assert!(context::estimate(&session.request().unwrap()) > tokens + 40);
+    }
+
+    #[test]
+    fn a_chat_is_written_by_one_session_at_a_time() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let session = Session::open(file.path(), Config::default());
+        session.lock().unwrap();
+        // Writes during the session share its lock
+        storage::append(file.path(), "# User\nHi\n").unwrap();
+
+        let other = Session::open(file.path(), Config::default());
+        let error = other.lock().unwrap_err();
+        assert!(error.is::<crate::lock::InUse>(), "{error}");
+        drop(session);
+        other.lock().unwrap();
+    }
+
+    #[test]
+    fn sessions_can_be_sent_from_their_own_tasks() {
+        fn spawnable<T: Future + Send + 'static>(_: &T) {}
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let session = Session::open(file.path(), Config::default());
+        let sending = async move { session.send().await };
+        spawnable(&sending);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::{
+    frontmatter::Frontmatter,
+    message,
+    message::Message,
+    plugins, prompts,
+    storage::{self, ChatStore},
+};
+use anyhow::{bail, Context, Result};
+use openai::chat::ChatCompletionMessageRole;
+use std::path::Path;
+
+/// A line in the user's message that changes the chat rather than being sent
+#[derive(Debug, PartialEq)]
+enum Command<'a> {
+    /// `/model gpt-4o-mini`
+    Model(&'a str),
+    /// `/temp 0.2`
+    Temperature(f32),
+    /// `/system pirate`, a prompt name or path
+    System(&'a str),
+    /// `/clear`, forget the earlier messages
+    Clear,
+    /// `/history 5`, send only the last exchanges, or `/history all`
+    History(Option<usize>),
+    /// `/weather Brisbane`, a command offered by a plugin, and its argument
+    Plugin(&'a str, &'a str),
+}
+
+impl<'a> Command<'a> {
+    /// The command on `line`, `None` if it isn't one, or an error if it's malformed
+    fn parse(line: &'a str) -> Option<Result<Self>> {
+        let line = line.trim();
+        let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let arg = arg.trim();
+        let command = match name {
+            "/model" => Self::Model(arg),
+            "/temp" | "/temperature" => {
+                return Some(
+                    arg.parse()
+                        .map(Self::Temperature)
+                        .with_context(|| format!("Invalid temperature: {:?}", arg)),
+                )
+            }
+            "/system" => Self::System(arg),
+            "/clear" if arg.is_empty() => return Some(Ok(Self::Clear)),
+            "/history" if arg == "all" => return Some(Ok(Self::History(None))),
+            "/history" => {
+                return Some(
+                    arg.parse()
+                        .map(|exchanges| Self::History(Some(exchanges)))
+                        .with_context(|| format!("Invalid number of exchanges: {:?}", arg)),
+                )
+            }
+            _ => {
+                return name
+                    .strip_prefix('/')
+                    .filter(|name| plugins::has_command(name))
+                    .map(|name| Ok(Self::Plugin(name, arg)))
+            }
+        };
+        match arg.is_empty() {
+            true => Some(Err(anyhow::anyhow!("{} needs a value", name))),
+            false => Some(Ok(command)),
+        }
+    }
+}
+
+/// Carry out the slash commands in the chat's last user message and remove them
+///
+/// `/model`, `/temp`, `/system` and `/history` change the chat's frontmatter (and system
+/// prompt) so they last for the rest of the chat, `/clear` archives the chat
+/// and leaves only the system prompt and the last message. A plugin's command
+/// is replaced by the text it prints. Commands in code blocks and in earlier
+/// messages are left alone. Returns whether the chat file changed, and errors
+/// if no message is left to send.
+pub fn apply(chat_file: &Path) -> Result<bool> {
+    let contents = std::fs::read_to_string(chat_file)
+        .with_context(|| format!("Unable to read chat file: {:?}", chat_file))?;
+    let targets = message::last_user_message_lines(&contents);
+
+    let mut commands = Vec::new();
+    let mut kept = String::new();
+    let mut expanded = false;
+    for (i, line) in contents.split_inclusive('\n').enumerate() {
+        let command = match targets.binary_search(&i) {
+            Ok(_) => Command::parse(line),
+            Err(_) => None,
+        };
+        match command.transpose()? {
+            Some(Command::Plugin(name, arg)) => {
+                kept.push_str(&plugins::command(name, arg, chat_file)?);
+                kept.push('\n');
+                expanded = true;
+            }
+            Some(command) => commands.push(command),
+            None => kept.push_str(line),
+        }
+    }
+    if commands.is_empty() {
+        if expanded {
+            storage::write_atomically(chat_file, &kept)
+                .with_context(|| format!("Unable to write chat file: {:?}", chat_file))?;
+        }
+        return Ok(expanded);
+    }
+
+    let (mut frontmatter, body) = Frontmatter::parse(&kept)?;
+    let mut messages = Message::parse_messages(body);
+    for command in commands {
+        match command {
+            Command::Model(model) => {
+                eprintln!("Using {} from now on", model);
+                frontmatter.model = Some(model.to_string());
+            }
+            Command::Temperature(temperature) => {
+                frontmatter.sampling.temperature = Some(temperature);
+                frontmatter.sampling.validate()?;
+                eprintln!("Using a temperature of {} from now on", temperature);
+            }
+            Command::System(system) => {
+                let prompt = prompts::resolve(system)?;
+                let message = Message {
+                    role: ChatCompletionMessageRole::System,
+                    content: prompt,
+                    name: None,
+                    function_call: None,
+                    model: None,
+                    meta: None,
+                };
+                match messages
+                    .iter_mut()
+                    .find(|m| matches!(m.role, ChatCompletionMessageRole::System))
+                {
+                    Some(existing) => *existing = message,
+                    None => messages.insert(0, message),
+                }
+                frontmatter.system = Some(system.to_string());
+                eprintln!("Switched to the {} system prompt", system);
+            }
+            Command::History(exchanges) => {
+                frontmatter.history = exchanges;
+                match exchanges {
+                    Some(1) => eprintln!("Sending the last exchange from now on"),
+                    Some(n) => eprintln!("Sending the last {} exchanges from now on", n),
+                    None => eprintln!("Sending the whole chat from now on"),
+                }
+            }
+            Command::Plugin(..) => unreachable!("plugin commands are replaced as they're read"),
+            Command::Clear => {
+                let archive = ChatStore::open()?.archive(chat_file)?;
+                let last = messages.pop();
+                messages.retain(|m| matches!(m.role, ChatCompletionMessageRole::System));
+                messages.extend(last);
+                eprintln!(
+                    "Cleared the earlier messages, the chat is archived at {}",
+                    archive.display()
+                );
+            }
+        }
+    }
+    Message::write_chat(chat_file, &frontmatter, &messages)?;
+
+    let has_message = messages.last().is_some_and(|m| {
+        matches!(m.role, ChatCompletionMessageRole::User) && !m.content.trim().is_empty()
+    });
+    if !has_message {
+        bail!("There's no message to send, write one after the commands");
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            Command::parse("/model gpt-4o-mini\n").unwrap().unwrap(),
+            Command::Model("gpt-4o-mini")
+        );
+        assert_eq!(
+            Command::parse("/temp 0.2").unwrap().unwrap(),
+            Command::Temperature(0.2)
+        );
+        assert!(Command::parse("/temp hot").unwrap().is_err());
+        assert!(Command::parse("/model").unwrap().is_err());
+        assert!(Command::parse("/usr/bin/env is missing").is_none());
+    }
+
+    #[test]
+    fn applies_commands_in_the_last_message() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let chat = "---\nmodel: gpt-4\n---\n# User\n/temp 1\n# Assistant\nOk\n# User\n/model gpt-4o\n/temp 0.2\nHi\n```\n/model fenced\n```\n";
+        std::fs::write(file.path(), chat).unwrap();
+
+        assert!(apply(file.path()).unwrap());
+        let (frontmatter, messages) = Message::read_chat(file.path()).unwrap();
+        assert_eq!(frontmatter.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(frontmatter.sampling.temperature, Some(0.2));
+        assert_eq!(messages[0].content, "/temp 1");
+        assert_eq!(messages[2].content, "Hi\n```\n/model fenced\n```");
+
+        assert!(!apply(file.path()).unwrap());
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{context, storage::ChatStore};
+use anyhow::{bail, Context, Result};
+use std::{
+    io::{stdin, Read},
+    path::{Path, PathBuf},
+};
+
+/// A named piece of context, e.g. API docs or a house style, kept in the
+/// store's `snippets/` to be included in any chat with `@context <name>`
+#[derive(Debug, PartialEq)]
+pub struct Snippet {
+    pub name: String,
+    pub path: PathBuf,
+    /// Tokens it adds to a message, for the model the list was made for
+    pub tokens: usize,
+}
+
+/// Keep `text` as the snippet `name`, replacing any snippet of that name
+pub fn add(store: &ChatStore, name: &str, text: &str) -> Result<PathBuf> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "{:?} can't be a snippet name, use letters, numbers, `-` and `_`",
+            name
+        );
+    }
+    if text.trim().is_empty() {
+        bail!("The snippet {:?} would be empty", name);
+    }
+    let dir = store.dir().join("snippets");
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Unable to create snippet directory: {:?}", dir))?;
+    let path = dir.join(format!("{}.md", name));
+    std::fs::write(&path, text).with_context(|| format!("Unable to write {:?}", path))?;
+    Ok(path)
+}
+
+/// The text of the snippet `name`
+pub fn get(store: &ChatStore, name: &str) -> Result<String> {
+    let path = store.dir().join("snippets").join(format!("{}.md", name));
+    match std::fs::read_to_string(&path) {
+        Ok(text) => Ok(text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let names: Vec<String> = list(store, None)?.into_iter().map(|s| s.name).collect();
+            match names.is_empty() {
+                true => bail!("There's no snippet {:?}, none have been added", name),
+                false => bail!(
+                    "There's no snippet {:?}, the snippets are: {}",
+                    name,
+                    names.join(", ")
+                ),
+            }
+        }
+        Err(e) => Err(e).with_context(|| format!("Unable to read {:?}", path)),
+    }
+}
+
+/// Keep the text of `file`, or stdin for `-`, as the snippet `name` and say how to include it
+pub fn add_file(store: &ChatStore, name: &str, file: &Path) -> Result<()> {
+    let text = match file.as_os_str() == "-" {
+        true => {
+            let mut text = String::new();
+            stdin().read_to_string(&mut text)?;
+            text
+        }
+        false => std::fs::read_to_string(file)
+            .with_context(|| format!("Unable to read {}", file.display()))?,
+    };
+    let path = add(store, name, &text)?;
+    eprintln!(
+        "Added {}, include it with `@context {}`",
+        path.display(),
+        name
+    );
+    Ok(())
+}
+
+/// Print the snippets with their tokens for `model`, for `context list`
+pub fn print_list(store: &ChatStore, model: &str) -> Result<()> {
+    for snippet in list(store, Some(model))? {
+        println!("{:<24} {:>7} tokens", snippet.name, snippet.tokens);
+    }
+    Ok(())
+}
+
+/// Forget the snippet `name`
+pub fn remove(store: &ChatStore, name: &str) -> Result<()> {
+    get(store, name)?;
+    let path = store.dir().join("snippets").join(format!("{}.md", name));
+    std::fs::remove_file(&path).with_context(|| format!("Unable to remove {:?}", path))
+}
+
+/// The snippets by name, with their tokens counted for `model` if it's given
+pub fn list(store: &ChatStore, model: Option<&str>) -> Result<Vec<Snippet>> {
+    let dir = store.dir().join("snippets");
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut snippets = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "md") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        let tokens = match model {
+            Some(model) => context::tokens(model, &std::fs::read_to_string(&path)?),
+            None => 0,
+        };
+        snippets.push(Snippet {
+            name: name.to_string(),
+            path,
+            tokens,
+        });
+    }
+    snippets.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(snippets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_snippets_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ChatStore::at(dir.path());
+        add(&store, "style", "Use British spelling.\n").unwrap();
+        add(&store, "api-docs", "GET /users\n").unwrap();
+        assert!(add(&store, "../escape", "text").is_err());
+
+        assert_eq!(get(&store, "style").unwrap(), "Use British spelling.\n");
+        let names: Vec<_> = list(&store, None)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, ["api-docs", "style"]);
+
+        remove(&store, "style").unwrap();
+        let missing = get(&store, "style").unwrap_err().to_string();
+        assert_eq!(
+            missing,
+            "There's no snippet \"style\", the snippets are: api-docs"
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//! What chat completions cost, kept in a ledger, and the daily and monthly limits on it
+//!
+//! Each request is priced from the tokens the API reports and appended to
+//! `$XDG_STATE_HOME/chat-cli-rs/spend.jsonl`. A request whose estimated cost
+//! would take the spend over a limit is refused unless `--yes` is given.
+
+use crate::{context, platform, provider::ChatRequest};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, Local};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// Dollars per million prompt and completion tokens of OpenAI's models, the
+/// longest matching prefix of a model's name applies
+const PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("gpt-4o", 2.5, 10.0),
+    ("gpt-4.1-nano", 0.1, 0.4),
+    ("gpt-4.1-mini", 0.4, 1.6),
+    ("gpt-4.1", 2.0, 8.0),
+    ("gpt-4-turbo", 10.0, 30.0),
+    ("gpt-4", 30.0, 60.0),
+    ("gpt-3.5-turbo", 0.5, 1.5),
+    ("o1-mini", 1.1, 4.4),
+    ("o1", 15.0, 60.0),
+    ("o3-mini", 1.1, 4.4),
+    ("o3", 2.0, 8.0),
+    ("o4-mini", 1.1, 4.4),
+];
+
+/// The models whose prices are known, by the prefix of their names
+pub fn known_models() -> impl Iterator<Item = &'static str> {
+    PRICES.iter().map(|(model, _, _)| *model)
+}
+
+/// How much may be spent, the `[spend]` config table
+///
+/// Limits are in dollars. The top level ones count every provider,
+/// `[spend.providers.<provider>]` only that provider's requests.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpendConfig {
+    pub daily: Option<f64>,
+    pub monthly: Option<f64>,
+    /// Refuse requests over a limit even with `--yes`
+    pub strict: bool,
+    pub providers: BTreeMap<String, Limits>,
+    /// Prices of models that aren't known or cost something else, the `[spend.prices."<model>"]` tables
+    pub prices: BTreeMap<String, Price>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub daily: Option<f64>,
+    pub monthly: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Price {
+    /// Dollars per million prompt tokens
+    pub input: f64,
+    /// Dollars per million completion tokens
+    pub output: f64,
+}
+
+impl Price {
+    fn cost(&self, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        (prompt_tokens as f64 * self.input + completion_tokens as f64 * self.output) / 1e6
+    }
+}
+
+/// A request in the ledger
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// RFC 3339, in local time
+    time: String,
+    provider: String,
+    model: String,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    /// `None` if the model's price isn't known
+    cost: Option<f64>,
+}
+
+struct Tracker {
+    config: SpendConfig,
+    provider: String,
+    /// Send requests over a limit anyway
+    yes: bool,
+    ledger: PathBuf,
+}
+
+static TRACKER: OnceLock<Tracker> = OnceLock::new();
+
+/// Keep track of what the requests to `provider` cost from now on, within the config's limits
+pub fn configure(config: &SpendConfig, provider: &str, yes: bool) -> Result<()> {
+    let _ = TRACKER.set(Tracker {
+        config: config.clone(),
+        provider: provider.to_string(),
+        yes,
+        ledger: ledger()?,
+    });
+    Ok(())
+}
+
+fn ledger() -> Result<PathBuf> {
+    Ok(platform::state_dir()?.join("spend.jsonl"))
+}
+
+/// The price of a model, from the config or the known prices
+fn price(model: &str, config: &SpendConfig) -> Option<Price> {
+    if let Some(price) = config.prices.get(model) {
+        return Some(*price);
+    }
+    let name = model.rsplit('/').next().unwrap_or(model);
+    PRICES
+        .iter()
+        .filter(|(prefix, _, _)| name.starts_with(prefix))
+        .max_by_key(|(prefix, _, _)| prefix.len())
+        .map(|(_, input, output)| Price {
+            input: *input,
+            output: *output,
+        })
+}
+
+/// What's been spent today and this month
+#[derive(Debug, Default, PartialEq)]
+pub struct Spent {
+    pub today: f64,
+    pub month: f64,
+}
+
+/// Add up the ledger's entries for `provider`, or every provider
+fn spent(ledger: &str, provider: Option<&str>, now: DateTime<Local>) -> Spent {
+    let mut spent = Spent::default();
+    for entry in ledger
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+    {
+        if provider.is_some_and(|provider| provider != entry.provider) {
+            continue;
+        }
+        let Ok(time) = DateTime::parse_from_rfc3339(&entry.time) else {
+            continue;
+        };
+        let time = time.with_timezone(&Local);
+        let cost = entry.cost.unwrap_or(0.0);
+        if (time.year(), time.month()) == (now.year(), now.month()) {
+            spent.month += cost;
+            if time.day() == now.day() {
+                spent.today += cost;
+            }
+        }
+    }
+    spent
+}
+
+fn read_ledger(path: &Path) -> Result<String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e).with_context(|| format!("Unable to read {}", path.display())),
+    }
+}
+
+/// A limit, what's been spent against it and by whom
+#[derive(Debug)]
+pub struct Budget {
+    /// The provider it's for, `None` for every provider
+    pub provider: Option<String>,
+    /// `daily` or `monthly`
+    pub period: &'static str,
+    pub spent: f64,
+    pub limit: Option<f64>,
+}
+
+/// The spend against each limit, and against no limit if none are set
+pub fn budgets(config: &SpendConfig) -> Result<Vec<Budget>> {
+    let ledger = read_ledger(&ledger()?)?;
+    let now = Local::now();
+    let mut scopes = vec![(
+        None,
+        Limits {
+            daily: config.daily,
+            monthly: config.monthly,
+        },
+    )];
+    scopes.extend(
+        config
+            .providers
+            .iter()
+            .map(|(provider, limits)| (Some(provider.clone()), *limits)),
+    );
+    let mut budgets = Vec::new();
+    for (provider, limits) in scopes {
+        let spent = spent(&ledger, provider.as_deref(), now);
+        for (period, spent, limit) in [
+            ("daily", spent.today, limits.daily),
+            ("monthly", spent.month, limits.monthly),
+        ] {
+            budgets.push(Budget {
+                provider: provider.clone(),
+                period,
+                spent,
+                limit,
+            });
+        }
+    }
+    Ok(budgets)
+}
+
+/// Print the `budgets` as a table, for `stats budget`
+pub fn print_budgets(config: &SpendConfig) -> Result<()> {
+    println!(
+        "{:<20} {:<8} {:>10} {:>10} {:>10}",
+        "provider", "period", "spent", "limit", "left"
+    );
+    for budget in budgets(config)? {
+        let amount = |amount: Option<f64>| amount.map_or("-".to_string(), dollars);
+        println!(
+            "{:<20} {:<8} {:>10} {:>10} {:>10}",
+            budget.provider.as_deref().unwrap_or("all"),
+            budget.period,
+            amount(Some(budget.spent)),
+            amount(budget.limit),
+            amount(budget.limit.map(|limit| (limit - budget.spent).max(0.0)))
+        );
+    }
+    Ok(())
+}
+
+/// Refuse a request whose estimated cost would take the spend over a limit, unless `--yes` was given
+///
+/// The estimate counts the prompt and `max_tokens` of the reply. Requests to
+/// models whose price isn't known aren't limited.
+pub fn check(request: &ChatRequest) -> Result<()> {
+    let Some(tracker) = TRACKER.get() else {
+        return Ok(());
+    };
+    let config = &tracker.config;
+    let provider_limits = config.providers.get(&tracker.provider).copied();
+    if config.daily.is_none() && config.monthly.is_none() && provider_limits.is_none() {
+        return Ok(());
+    }
+    let Some(price) = price(&request.model, config) else {
+        tracing::warn!(
+            "The price of {} isn't known, set it under [spend.prices] to count it against the limits",
+            request.model
+        );
+        return Ok(());
+    };
+    let estimate = price.cost(
+        context::estimate(request) as u64,
+        request.sampling.max_tokens.unwrap_or(0),
+    );
+
+    let ledger = read_ledger(&tracker.ledger)?;
+    let now = Local::now();
+    let global = Limits {
+        daily: config.daily,
+        monthly: config.monthly,
+    };
+    let scopes = [
+        (None, Some(global)),
+        (Some(&tracker.provider), provider_limits),
+    ];
+    for (provider, limits) in scopes {
+        let Some(limits) = limits else {
+            continue;
+        };
+        let spent = spent(&ledger, provider.map(String::as_str), now);
+        for (period, spent, limit) in [
+            ("daily", spent.today, limits.daily),
+            ("monthly", spent.month, limits.monthly),
+        ] {
+            let Some(limit) = limit.filter(|limit| spent + estimate > *limit) else {
+                continue;
+            };
+            let over = format!(
+                "This request would take the spend{} to {}, over the {} limit of {}",
+                provider.map_or(String::new(), |p| format!(" on {}", p)),
+                dollars(spent + estimate),
+                period,
+                dollars(limit)
+            );
+            match (tracker.yes, config.strict) {
+                (true, false) => eprintln!("{}, sending it anyway", over),
+                (_, true) => bail!("{}", over),
+                (false, false) => bail!("{}, pass --yes to send it anyway", over),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An amount in dollars, to the cent unless it's less than one
+pub fn dollars(amount: f64) -> String {
+    match amount > 0.0 && amount < 0.01 {
+        true => format!("${:.4}", amount),
+        false => format!("${:.2}", amount),
+    }
+}
+
+/// Add a finished request to the ledger
+///
+/// Failing to is only logged, the reply has already been paid for.
+pub fn record(model: &str, prompt_tokens: u64, completion_tokens: u64) {
+    let Some(tracker) = TRACKER.get() else {
+        return;
+    };
+    let entry = Entry {
+        time: Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        provider: tracker.provider.clone(),
+        model: model.to_string(),
+        prompt_tokens,
+        completion_tokens,
+        cost: price(model, &tracker.config).map(|p| p.cost(prompt_tokens, completion_tokens)),
+    };
+    let append = || -> Result<()> {
+        if let Some(dir) = tracker.ledger.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&tracker.ledger)?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        Ok(())
+    };
+    if let Err(e) = append() {
+        tracing::warn!("Unable to record the spend: {:#}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn adds_up_the_spend_by_day_month_and_provider() {
+        let config = SpendConfig::default();
+        let gpt_4o = price("openai/gpt-4o-2024-08-06", &config).unwrap();
+        assert_eq!(gpt_4o.cost(1_000_000, 100_000), 3.5);
+        assert_eq!(price("gpt-4-0613", &config).unwrap().input, 30.0);
+        assert_eq!(price("llama3", &config), None);
+
+        let now = Local.with_ymd_and_hms(2025, 3, 14, 12, 0, 0).unwrap();
+        let entry = |time: DateTime<Local>, provider: &str, cost: f64| {
+            serde_json::to_string(&Entry {
+                time: time.to_rfc3339(),
+                provider: provider.to_string(),
+                model: "gpt-4o".to_string(),
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                cost: Some(cost),
+            })
+            .unwrap()
+        };
+        let ledger = [
+            entry(now, "openai", 1.0),
+            entry(now - chrono::Duration::days(2), "openai", 2.0),
+            entry(now, "azure", 4.0),
+            entry(now - chrono::Duration::days(40), "openai", 8.0),
+        ]
+        .join("\n");
+        assert_eq!(
+            spent(&ledger, None, now),
+            Spent {
+                today: 5.0,
+                month: 7.0
+            }
+        );
+        assert_eq!(
+            spent(&ledger, Some("openai"), now),
+            Spent {
+                today: 1.0,
+                month: 3.0
+            }
+        );
+    }
+}
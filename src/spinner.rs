@@ -0,0 +1,99 @@
+//! A spinner on stderr while waiting for a model, with its name and the time elapsed
+//!
+//! It's cleared before the reply is printed, and stays hidden when stderr
+//! isn't a terminal.
+
+use crate::reasoning;
+use anyhow::Result;
+use indicatif::{ProgressBar, ProgressStyle};
+use openai::chat::ChatCompletionDelta;
+use std::{future::Future, time::Duration};
+use tokio::sync::mpsc::{channel, Receiver};
+
+/// Spins until dropped
+pub struct Spinner(ProgressBar);
+
+impl Spinner {
+    /// Start spinning for the models being waited for
+    pub fn start(models: &[&str]) -> Self {
+        Self::spin(message(models))
+    }
+
+    fn spin(message: String) -> Self {
+        let spinner = ProgressBar::new_spinner();
+        if let Ok(style) = ProgressStyle::with_template("{spinner} {msg} {elapsed}") {
+            spinner.set_style(style);
+        }
+        spinner.set_message(message);
+        spinner.enable_steady_tick(Duration::from_millis(100));
+        Self(spinner)
+    }
+
+    /// Keep spinning until the first chunk of a streamed reply arrives
+    ///
+    /// The chunks are passed on as they are.
+    pub fn until_first_chunk(
+        self,
+        mut chunks: Receiver<Result<ChatCompletionDelta>>,
+    ) -> Receiver<Result<ChatCompletionDelta>> {
+        let (tx, rx) = channel(32);
+        tokio::spawn(async move {
+            let first = tokio::select! {
+                first = chunks.recv() => first,
+                // The reply was cancelled
+                _ = tx.closed() => None,
+            };
+            drop(self);
+            let Some(first) = first else {
+                return;
+            };
+            if tx.send(first).await.is_err() {
+                return;
+            }
+            while let Some(chunk) = chunks.recv().await {
+                if tx.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.0.finish_and_clear();
+    }
+}
+
+/// What's being waited for, reasoning models think before they reply
+fn message(models: &[&str]) -> String {
+    match models {
+        [model] if reasoning::is_reasoning_model(model) => format!("{} is thinking", model),
+        _ => format!("Waiting for {}", models.join(", ")),
+    }
+}
+
+/// Spin while waiting for whole replies from the models
+pub fn wait<T>(models: &[&str], reply: impl Future<Output = T>) -> impl Future<Output = T> {
+    let message = message(models);
+    async move {
+        let _spinner = Spinner::spin(message);
+        reply.await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_the_models_being_waited_for() {
+        assert_eq!(message(&["gpt-4o"]), "Waiting for gpt-4o");
+        assert_eq!(message(&["o3-mini"]), "o3-mini is thinking");
+        assert_eq!(
+            message(&["gpt-4o", "o3-mini"]),
+            "Waiting for gpt-4o, o3-mini"
+        );
+    }
+}
@@ -0,0 +1,441 @@
+use crate::{encryption, lock::ChatLock, platform, transcript};
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Prefix of the file names given to new chats before they have a title
+const CHAT_FILE_PREFIX: &str = "chat-cli-rs_";
+
+/// Longest slug used in a titled file name
+const MAX_SLUG_LEN: usize = 60;
+
+/// The directory chats are saved in, `$XDG_DATA_HOME/chat-cli-rs` by default
+#[derive(Debug, Clone)]
+pub struct ChatStore {
+    dir: PathBuf,
+}
+
+/// Where the store is kept instead of the XDG data directory, set by a profile
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Whether chat files are flushed to disk after each write, set by `fsync` in the config
+static FSYNC: OnceLock<bool> = OnceLock::new();
+
+/// Flush chat files to disk after each write from now on, if `fsync` is set
+pub fn set_fsync(fsync: bool) {
+    let _ = FSYNC.set(fsync);
+}
+
+/// Whether chat files are flushed to disk after each write
+pub fn fsync() -> bool {
+    FSYNC.get().copied().unwrap_or(false)
+}
+
+/// Keep every chat in `dir` from now on
+pub fn set_dir(dir: PathBuf) {
+    let _ = DIR.set(dir);
+}
+
+impl ChatStore {
+    /// The store in the profile's data directory, or else the XDG data directory
+    pub fn open() -> Result<Self> {
+        if let Some(dir) = DIR.get() {
+            return Ok(Self::at(dir));
+        }
+        Ok(Self::at(platform::data_dir()?))
+    }
+
+    /// A store in some other directory
+    pub fn at(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// All saved chat files, most recently modified first
+    ///
+    /// Symlinks left behind by renaming a titled chat are skipped, as are encrypted chats.
+    pub fn list(&self) -> Result<Vec<PathBuf>> {
+        list_chats(&self.dir, |path| {
+            path.extension().is_some_and(|ext| ext == "md")
+        })
+    }
+
+    /// The chats kept encrypted, `<name>.md.age`, most recently modified first
+    pub fn encrypted(&self) -> Result<Vec<PathBuf>> {
+        list_chats(&self.dir, |path| {
+            encryption::is_encrypted(path)
+                && path
+                    .with_extension("")
+                    .extension()
+                    .is_some_and(|ext| ext == "md")
+        })
+    }
+
+    /// The chats kept as JSONL transcripts, `<name>.jsonl`, most recently modified first
+    pub fn transcripts(&self) -> Result<Vec<PathBuf>> {
+        list_chats(&self.dir, transcript::is_transcript)
+    }
+
+    /// The chats cleaned up by `gc` or copied aside before a rewrite, most recently modified first
+    pub fn archived(&self) -> Result<Vec<PathBuf>> {
+        list_chats(&self.dir.join("archive"), |path| {
+            uncompressed_path(path)
+                .extension()
+                .is_some_and(|ext| ext == "md")
+        })
+    }
+
+    /// Path for a new chat file, creating the directory if needed
+    pub fn new_chat_path(&self) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Unable to create chat directory: {:?}", self.dir))?;
+        Ok(unique_path(&self.dir.join(format!(
+            "{}{}.md",
+            CHAT_FILE_PREFIX,
+            get_current_time_unix()
+        ))))
+    }
+
+    /// Path for a chat named after its title, e.g. `2024-05-01_rust-borrow-checker.md`
+    pub fn titled_chat_path(&self, date: &str, title: &str) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Unable to create chat directory: {:?}", self.dir))?;
+        Ok(titled_path(&self.dir, date, title))
+    }
+
+    /// Copy a chat file into `archive/` in the store, returning the copy's path
+    ///
+    /// The copy's name has the current time added so archiving a chat again keeps
+    /// the earlier copies.
+    pub fn archive(&self, chat_file: &Path) -> Result<PathBuf> {
+        let dir = self.dir.join("archive");
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Unable to create archive directory: {:?}", dir))?;
+        let stem = chat_file
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("chat");
+        let archived = dir.join(format!("{}_{}.md", stem, get_current_time_unix()));
+        std::fs::copy(chat_file, &archived)
+            .with_context(|| format!("Unable to archive {:?} to {:?}", chat_file, archived))?;
+        Ok(archived)
+    }
+
+    /// Move a chat into `trash/` in the store instead of deleting it, so `undo` can restore it
+    pub fn trash(&self, chat_file: &Path) -> Result<PathBuf> {
+        let dir = self.dir.join("trash");
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Unable to create trash directory: {:?}", dir))?;
+        let name = chat_file.file_name().context("A chat file has no name")?;
+        let trashed = unique_path(&dir.join(format!(
+            "{}_{}",
+            get_current_time_unix(),
+            name.to_string_lossy()
+        )));
+        // Where it came from, to put it back there
+        let origin = std::path::absolute(chat_file)?;
+        std::fs::write(origin_file(&trashed), origin.as_os_str().as_encoded_bytes())?;
+        move_file(chat_file, &trashed)?;
+        Ok(trashed)
+    }
+
+    /// Put the chat trashed last back where it was, returning its path
+    ///
+    /// A chat that has taken its place since is trashed in turn, so undoing
+    /// again swaps them back.
+    pub fn restore_trashed(&self) -> Result<PathBuf> {
+        let dir = self.dir.join("trash");
+        // The files noting where each chat came from are written as it's trashed
+        let last = list_chats(&dir, |path| {
+            path.extension().is_some_and(|ext| ext == "origin")
+        })?
+        .into_iter()
+        .next()
+        .map(|origin| origin.with_extension(""))
+        .context("There's no deleted chat to restore")?;
+        let origin = std::fs::read(origin_file(&last))
+            .with_context(|| format!("Unable to tell where {:?} came from", last))?;
+        let origin = PathBuf::from(String::from_utf8_lossy(&origin).into_owned());
+
+        if origin.exists() {
+            self.trash(&origin)?;
+        }
+        move_file(&last, &origin)?;
+        std::fs::remove_file(origin_file(&last))?;
+        Ok(origin)
+    }
+}
+
+/// The file next to a trashed chat with the path it was trashed from
+fn origin_file(trashed: &Path) -> PathBuf {
+    let mut path = trashed.as_os_str().to_owned();
+    path.push(".origin");
+    path.into()
+}
+
+/// Move a file, copying it if it's going to another file system
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to).with_context(|| format!("Unable to move {:?} to {:?}", from, to))?;
+    std::fs::remove_file(from).with_context(|| format!("Unable to remove {:?}", from))
+}
+
+/// The files in `dir` that are chats, most recently modified first
+fn list_chats(dir: &Path, is_chat: impl Fn(&Path) -> bool) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut chats = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("Unable to read chat directory: {:?}", dir))?
+    {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && is_chat(&path) {
+            chats.push((metadata.modified()?, path));
+        }
+    }
+    chats.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+
+    Ok(chats.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Path for a new chat file in the XDG data directory, or `/tmp` if that's unavailable
+pub fn new_chat_file_path() -> PathBuf {
+    match ChatStore::open().and_then(|store| store.new_chat_path()) {
+        Ok(file_path) => file_path,
+        Err(e) => {
+            eprintln!("Unable to get XDG directoriy, using fallback! Error: {}", e);
+            ChatStore::at("/tmp")
+                .new_chat_path()
+                .expect("/tmp should exist")
+        }
+    }
+}
+
+/// Where files made for a chat are kept, `assets/<chat name>/` next to the chat
+pub fn assets_dir(chat_file: &Path) -> PathBuf {
+    let dir = chat_file.parent().unwrap_or(Path::new("."));
+    let stem = chat_file.file_stem().unwrap_or_default();
+    dir.join("assets").join(stem)
+}
+
+/// Whether the chat file still has the name it was created with
+pub fn has_generated_name(chat_file: &Path) -> bool {
+    chat_file
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with(CHAT_FILE_PREFIX))
+}
+
+/// Rename a chat file to `<date>_<title-slug>.md`, e.g. `2024-05-01_rust-borrow-checker.md`
+///
+/// A symlink is left at the old path so an editor that still has it open
+/// keeps writing to the chat. Returns the new path.
+pub fn rename_titled(chat_file: &Path, date: &str, title: &str) -> Result<PathBuf> {
+    let dir = chat_file.parent().unwrap_or_else(|| Path::new("."));
+    let new_path = titled_path(dir, date, title);
+
+    std::fs::rename(chat_file, &new_path)
+        .with_context(|| format!("Unable to rename {:?} to {:?}", chat_file, new_path))?;
+    #[cfg(unix)]
+    if let Err(e) = std::os::unix::fs::symlink(&new_path, chat_file) {
+        eprintln!("Unable to link {:?} to {:?}: {}", chat_file, new_path, e);
+    }
+
+    Ok(new_path)
+}
+
+/// Read a chat file, decompressing it first if it was archived as `.gz` or `.zst`,
+/// decrypting it if it's encrypted and rendering it as markdown if it's a transcript
+pub fn read_chat_file(path: &Path) -> Result<String> {
+    let mut decoder: Box<dyn Read> = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("age") => return encryption::read(path),
+        Some("jsonl") => return transcript::read(path),
+        Some("gz") => Box::new(GzDecoder::new(File::open(path)?)),
+        Some("zst") => Box::new(zstd::Decoder::new(File::open(path)?)?),
+        _ => return Ok(std::fs::read_to_string(path)?),
+    };
+    let mut contents = String::new();
+    decoder
+        .read_to_string(&mut contents)
+        .with_context(|| format!("Unable to decompress {:?}", path))?;
+    Ok(contents)
+}
+
+/// Replace the contents of a file by writing them beside it and renaming them over it,
+/// so it's never left half written
+///
+/// A symlink, like the one left at a titled chat's old path, is followed. The
+/// file's lock is held while it's written, see [`ChatLock`].
+pub fn write_atomically(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let _lock = ChatLock::acquire(path)?;
+    let target = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = target.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("Unable to create a file in {:?}", dir))?;
+    file.write_all(contents.as_ref())?;
+    if let Ok(metadata) = std::fs::metadata(&target) {
+        file.as_file().set_permissions(metadata.permissions())?;
+    }
+    if fsync() {
+        file.as_file().sync_all()?;
+    }
+    file.persist(&target)
+        .with_context(|| format!("Unable to replace {:?}", target))?;
+    // The rename is only on disk once the directory is
+    #[cfg(unix)]
+    if fsync() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Add text to the end of a file, rewriting it with [`write_atomically`]
+///
+/// Slower than appending, but a crash or an editor saving at the same time
+/// can't leave the file with only part of the text.
+pub fn append(path: &Path, text: &str) -> Result<()> {
+    // Held from the read, so another process can't write in between
+    let _lock = ChatLock::acquire(path)?;
+    let mut contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Unable to read {:?}", path)),
+    };
+    contents.push_str(text);
+    write_atomically(path, &contents)
+}
+
+/// Add text to the end of a file where it is, without rewriting it
+///
+/// Only for what's soon written again with [`append`], e.g. the pieces of a
+/// reply shown while it streams in, as a crash part way through can leave part
+/// of the text. It isn't flushed to disk even with `fsync`.
+pub fn append_in_place(path: &Path, text: &str) -> Result<()> {
+    let _lock = ChatLock::acquire(path)?;
+    File::options()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .with_context(|| format!("Unable to append to {:?}", path))
+}
+
+/// The chat's path without the extension compressing it added, e.g. `a.md` for `a.md.zst`
+pub fn uncompressed_path(path: &Path) -> PathBuf {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz" | "zst") => path.with_extension(""),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// `path` if it isn't taken, or else the first of `<stem>-2.md`, `<stem>-3.md`, ... that isn't
+pub fn unique_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()));
+    let mut unique = path.to_path_buf();
+    let mut n = 2;
+    while unique.exists() {
+        unique = path.with_file_name(format!(
+            "{}-{}{}",
+            stem,
+            n,
+            extension.as_deref().unwrap_or("")
+        ));
+        n += 1;
+    }
+    unique
+}
+
+/// A path in `dir` named after the title that isn't taken yet
+fn titled_path(dir: &Path, date: &str, title: &str) -> PathBuf {
+    unique_path(&dir.join(format!("{}_{}.md", date, slug(title))))
+}
+
+/// Lowercase the title and join its words with `-`
+fn slug(title: &str) -> String {
+    let slug = title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+
+    let mut end = slug.len().min(MAX_SLUG_LEN);
+    while !slug.is_char_boundary(end) {
+        end -= 1;
+    }
+    match slug[..end].trim_end_matches('-') {
+        "" => "untitled".to_string(),
+        s => s.to_string(),
+    }
+}
+
+/// Get the current Unix timestamp
+fn get_current_time_unix() -> String {
+    let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    format!(
+        "{}{:03}Z",
+        current_time.as_secs(),
+        current_time.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_new_chats_but_not_the_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ChatStore::at(dir.path());
+        let chat = store.new_chat_path().unwrap();
+        std::fs::write(&chat, "# User\n").unwrap();
+        store.archive(&chat).unwrap();
+
+        assert!(has_generated_name(&chat));
+        assert_eq!(store.list().unwrap(), vec![chat]);
+    }
+
+    #[test]
+    fn restores_trashed_chats() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ChatStore::at(dir.path().join("store"));
+        let chat = dir.path().join("notes.md");
+        std::fs::write(&chat, "# User\nold\n").unwrap();
+        assert_eq!(unique_path(&chat), dir.path().join("notes-2.md"));
+
+        store.trash(&chat).unwrap();
+        assert!(!chat.exists());
+        std::fs::write(&chat, "# User\nnew\n").unwrap();
+
+        assert_eq!(store.restore_trashed().unwrap(), chat);
+        assert_eq!(std::fs::read_to_string(&chat).unwrap(), "# User\nold\n");
+        store.restore_trashed().unwrap();
+        assert_eq!(std::fs::read_to_string(&chat).unwrap(), "# User\nnew\n");
+    }
+
+    #[test]
+    fn slugs_titles() {
+        assert_eq!(slug("Rust Borrow Checker"), "rust-borrow-checker");
+        assert_eq!(slug("  C++ vs. Rust: which?  "), "c-vs-rust-which");
+        assert_eq!(slug("???"), "untitled");
+        assert!(slug(&"word ".repeat(40)).len() <= MAX_SLUG_LEN);
+    }
+}
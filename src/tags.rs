@@ -0,0 +1,94 @@
+//! Tagging and pinning chats, kept in their frontmatter
+//!
+//! ```markdown
+//! ---
+//! title: Rust borrow checker
+//! tags: [rust, work]
+//! pinned: true
+//! ---
+//! ```
+//!
+//! `list` and `search` can be narrowed to a tag. Pinned chats are listed
+//! first by `list` and the picker and kept when old chats are cleaned up.
+
+use crate::frontmatter::Frontmatter;
+use anyhow::{Context, Result};
+use std::path::Path;
+
+/// The tag as it's kept, without a leading `#` or surrounding space
+pub fn normalize(tag: &str) -> String {
+    tag.trim().trim_start_matches('#').trim().to_string()
+}
+
+/// Whether the tags include `tag`, ignoring case and a leading `#`
+pub fn has_tag(tags: &[String], tag: &str) -> bool {
+    let tag = normalize(tag);
+    tags.iter().any(|t| normalize(t).eq_ignore_ascii_case(&tag))
+}
+
+fn read(chat: &Path) -> Result<Frontmatter> {
+    let contents = std::fs::read_to_string(chat)
+        .with_context(|| format!("Unable to read chat file: {:?}", chat))?;
+    Ok(Frontmatter::parse(&contents)?.0)
+}
+
+/// Add the tags the chat doesn't have yet, returning all of its tags
+pub fn add(chat: &Path, tags: &[String]) -> Result<Vec<String>> {
+    let mut frontmatter = read(chat)?;
+    for tag in tags.iter().map(|t| normalize(t)) {
+        if !tag.is_empty() && !has_tag(&frontmatter.tags, &tag) {
+            frontmatter.tags.push(tag);
+        }
+    }
+    frontmatter.write_to(chat)?;
+    Ok(frontmatter.tags)
+}
+
+/// Remove the tags from the chat, returning the ones it has left
+pub fn remove(chat: &Path, tags: &[String]) -> Result<Vec<String>> {
+    let mut frontmatter = read(chat)?;
+    frontmatter.tags.retain(|tag| !has_tag(tags, tag));
+    frontmatter.write_to(chat)?;
+    Ok(frontmatter.tags)
+}
+
+/// Pin or unpin the chat
+pub fn pin(chat: &Path, pinned: bool) -> Result<()> {
+    let mut frontmatter = read(chat)?;
+    if frontmatter.pinned != pinned {
+        frontmatter.pinned = pinned;
+        frontmatter.write_to(chat)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_and_removes_tags_in_the_frontmatter() {
+        let dir = tempfile::tempdir().unwrap();
+        let chat = dir.path().join("chat.md");
+        std::fs::write(
+            &chat,
+            "---\ntitle: Lifetimes\n---\n# Lifetimes\n\n# User\nWhat's 'a?\n",
+        )
+        .unwrap();
+
+        let tags = |t: &[&str]| t.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            add(&chat, &tags(&["rust", "#Work"])).unwrap(),
+            ["rust", "Work"]
+        );
+        assert_eq!(add(&chat, &tags(&["RUST"])).unwrap(), ["rust", "Work"]);
+        assert_eq!(remove(&chat, &tags(&["work"])).unwrap(), ["rust"]);
+        pin(&chat, true).unwrap();
+
+        let contents = std::fs::read_to_string(&chat).unwrap();
+        assert_eq!(
+            contents,
+            "---\ntitle: Lifetimes\ntags:\n- rust\npinned: true\n---\n# Lifetimes\n\n# User\nWhat's 'a?\n"
+        );
+    }
+}
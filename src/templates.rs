@@ -0,0 +1,128 @@
+use crate::platform;
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    io::{BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// Matches a `{{variable}}` placeholder, spaces inside the braces are allowed
+fn placeholder() -> &'static Regex {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    PLACEHOLDER.get_or_init(|| Regex::new(r"\{\{\s*([A-Za-z0-9_-]+)\s*\}\}").unwrap())
+}
+
+/// Read a template by name from the templates directory, or from a path
+pub fn load(spec: &str) -> Result<String> {
+    let path = Path::new(spec);
+    let path = match path.is_file() {
+        true => path.to_path_buf(),
+        false => templates_dir()
+            .map(|dir| dir.join(format!("{spec}.md")))
+            .filter(|path| path.is_file())
+            .with_context(|| format!("No template named {:?}, see `chat-cli-rs template`", spec))?,
+    };
+    std::fs::read_to_string(&path).with_context(|| format!("Unable to read template: {:?}", path))
+}
+
+/// The names of the template's variables, in the order they first appear
+pub fn variables(template: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    for captures in placeholder().captures_iter(template) {
+        let name = captures.get(1).map_or("", |m| m.as_str());
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Replace the template's placeholders with their values
+pub fn render(template: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let missing: Vec<&str> = variables(template)
+        .into_iter()
+        .filter(|name| !vars.contains_key(*name))
+        .collect();
+    if !missing.is_empty() {
+        bail!("No value for {}, pass --var", missing.join(", "));
+    }
+    Ok(placeholder()
+        .replace_all(template, |captures: &regex::Captures| {
+            vars[&captures[1]].clone()
+        })
+        .into_owned())
+}
+
+/// Render a template, asking on the terminal for the variables not given
+pub fn expand(spec: &str, vars: &[(String, String)]) -> Result<String> {
+    let template = load(spec)?;
+    let mut vars: HashMap<String, String> = vars.iter().cloned().collect();
+    let stdin = std::io::stdin();
+    for name in variables(&template) {
+        if vars.contains_key(name) || !stdin.is_terminal() {
+            continue;
+        }
+        eprint!("{}: ", name);
+        std::io::stderr().flush()?;
+        let mut value = String::new();
+        stdin.lock().read_line(&mut value)?;
+        vars.insert(
+            name.to_string(),
+            value.trim_end_matches(['\r', '\n']).to_string(),
+        );
+    }
+    render(&template, &vars)
+}
+
+/// Print the available templates and their variables, one per line
+pub fn print_list() -> Result<()> {
+    let Some(dir) = templates_dir().filter(|dir| dir.is_dir()) else {
+        return Ok(());
+    };
+    let mut templates = Vec::new();
+    for entry in std::fs::read_dir(&dir)
+        .with_context(|| format!("Unable to read templates directory: {:?}", dir))?
+    {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "md") {
+            templates.push(path);
+        }
+    }
+    templates.sort();
+    for path in templates {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let template = std::fs::read_to_string(&path).unwrap_or_default();
+        println!("{}\t{}", name, variables(&template).join(" "));
+    }
+    Ok(())
+}
+
+/// The directory users keep their templates in, `$XDG_CONFIG_HOME/chat-cli-rs/templates`
+fn templates_dir() -> Option<PathBuf> {
+    Some(platform::config_dir().ok()?.join("templates"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_placeholders() {
+        let template = "Review {{ pr }} in {{repo}}, focusing on {{pr}}'s tests";
+        assert_eq!(variables(template), ["pr", "repo"]);
+
+        let vars = HashMap::from([
+            ("pr".to_string(), "#12".to_string()),
+            ("repo".to_string(), "chat-cli-rs".to_string()),
+        ]);
+        assert_eq!(
+            render(template, &vars).unwrap(),
+            "Review #12 in chat-cli-rs, focusing on #12's tests"
+        );
+
+        let error = render(template, &HashMap::new()).unwrap_err();
+        assert_eq!(error.to_string(), "No value for pr, repo, pass --var");
+    }
+}
@@ -0,0 +1,217 @@
+//! Colours for the replies printed in the terminal, the `theme` config setting
+//!
+//! Role labels, markdown headings and code blocks are coloured as the reply
+//! streams in. Colours are left out when `NO_COLOR` is set, with `--no-color`,
+//! with `theme = "none"` or when stdout isn't a terminal.
+
+use anstyle::{AnsiColor, Style};
+use openai::chat::ChatCompletionMessageRole;
+use serde::Deserialize;
+use std::{
+    io::{stdout, IsTerminal},
+    sync::OnceLock,
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    /// Bright colours for dark terminals
+    #[default]
+    Default,
+    /// Darker colours for light terminals
+    Light,
+    /// Bold and dim text without colours
+    Mono,
+    /// Plain text
+    None,
+}
+
+/// The styles of a theme
+struct Palette {
+    user: Style,
+    assistant: Style,
+    system: Style,
+    function: Style,
+    heading: Style,
+    code: Style,
+    matched: Style,
+}
+
+impl Theme {
+    fn palette(self) -> Option<Palette> {
+        let bold = |color: AnsiColor| Style::new().bold().fg_color(Some(color.into()));
+        let color = |color: AnsiColor| Style::new().fg_color(Some(color.into()));
+        match self {
+            Self::Default => Some(Palette {
+                user: bold(AnsiColor::BrightBlue),
+                assistant: bold(AnsiColor::BrightGreen),
+                system: bold(AnsiColor::BrightMagenta),
+                function: bold(AnsiColor::BrightYellow),
+                heading: bold(AnsiColor::BrightCyan),
+                code: color(AnsiColor::BrightYellow),
+                matched: bold(AnsiColor::BrightRed),
+            }),
+            Self::Light => Some(Palette {
+                user: bold(AnsiColor::Blue),
+                assistant: bold(AnsiColor::Green),
+                system: bold(AnsiColor::Magenta),
+                function: bold(AnsiColor::Yellow),
+                heading: bold(AnsiColor::Cyan),
+                code: color(AnsiColor::Blue),
+                matched: bold(AnsiColor::Red),
+            }),
+            Self::Mono => Some(Palette {
+                user: Style::new().bold(),
+                assistant: Style::new().bold(),
+                system: Style::new().bold(),
+                function: Style::new().bold(),
+                heading: Style::new().bold().underline(),
+                code: Style::new().dimmed(),
+                matched: Style::new().bold().underline(),
+            }),
+            Self::None => None,
+        }
+    }
+}
+
+static PALETTE: OnceLock<Option<Palette>> = OnceLock::new();
+
+/// Colour the output with the theme from now on, unless colours are turned off
+pub fn configure(theme: Theme, no_color: bool) {
+    let off = no_color
+        || std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+        || !stdout().is_terminal();
+    let _ = PALETTE.set(if off { None } else { theme.palette() });
+}
+
+fn palette() -> Option<&'static Palette> {
+    PALETTE.get()?.as_ref()
+}
+
+fn paint(style: Style, text: &str) -> String {
+    format!("{style}{text}{style:#}")
+}
+
+/// The label a message is printed under, e.g. `Assistant: `
+pub fn label(role: &ChatCompletionMessageRole) -> String {
+    let label = format!("{:#?}:", role);
+    let Some(palette) = palette() else {
+        return format!("{} ", label);
+    };
+    let style = match role {
+        ChatCompletionMessageRole::User => palette.user,
+        ChatCompletionMessageRole::Assistant => palette.assistant,
+        ChatCompletionMessageRole::System => palette.system,
+        ChatCompletionMessageRole::Function => palette.function,
+    };
+    format!("{} ", paint(style, &label))
+}
+
+/// A search match in what's around it
+pub fn matched(text: &str) -> String {
+    match palette() {
+        Some(palette) => paint(palette.matched, text),
+        None => text.to_string(),
+    }
+}
+
+/// Colours markdown headings and code blocks in text that arrives in pieces
+#[derive(Default)]
+pub struct Highlighter {
+    /// Whether the line has started, so backticks and `#` aren't at its start
+    mid_line: bool,
+    /// Backticks at the start of the line, held back until it's known if they're a fence
+    backticks: usize,
+    in_code: bool,
+    /// The fence closing the code block has been seen, its style ends with the line
+    closing: bool,
+    in_heading: bool,
+}
+
+impl Highlighter {
+    /// The next piece of the text, coloured
+    pub fn push(&mut self, text: &str) -> String {
+        let Some(palette) = palette() else {
+            return text.to_string();
+        };
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            if !self.mid_line && c == '`' && self.backticks < 3 {
+                self.backticks += 1;
+                if self.backticks == 3 {
+                    match self.in_code {
+                        true => self.closing = true,
+                        false => {
+                            out.push_str(&palette.code.render().to_string());
+                            self.in_code = true;
+                        }
+                    }
+                    out.push_str("```");
+                    self.mid_line = true;
+                }
+                continue;
+            }
+            if self.backticks > 0 && !self.mid_line {
+                out.push_str(&"`".repeat(self.backticks));
+                self.mid_line = true;
+            }
+            if !self.mid_line && !self.in_code && c == '#' {
+                out.push_str(&palette.heading.render().to_string());
+                self.in_heading = true;
+            }
+            if c == '\n' {
+                if self.in_heading {
+                    out.push_str(&palette.heading.render_reset().to_string());
+                    self.in_heading = false;
+                }
+                if self.closing {
+                    out.push_str(&palette.code.render_reset().to_string());
+                    self.in_code = false;
+                    self.closing = false;
+                }
+                self.backticks = 0;
+            }
+            out.push(c);
+            self.mid_line = c != '\n';
+        }
+        out
+    }
+
+    /// The backticks held back and the end of any style still open, once the text is complete
+    pub fn finish(&mut self) -> String {
+        let mut out = "`".repeat(std::mem::take(&mut self.backticks));
+        if self.in_code || self.in_heading {
+            out.push_str(&anstyle::Reset.render().to_string());
+        }
+        *self = Self::default();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colours_headings_and_code_blocks_split_across_pieces() {
+        let _ = PALETTE.set(Theme::Mono.palette());
+        let palette = palette().unwrap();
+        let (heading, code) = (palette.heading, palette.code);
+
+        let mut highlighter = Highlighter::default();
+        let pieces = [
+            "# Ti",
+            "tle\nSome `x` and\n`",
+            "``rust\nlet a = 1;\n``",
+            "`\nDone",
+        ];
+        let mut out: String = pieces.iter().map(|p| highlighter.push(p)).collect();
+        out.push_str(&highlighter.finish());
+        assert_eq!(
+            out,
+            format!(
+                "{heading}# Title{heading:#}\nSome `x` and\n{code}```rust\nlet a = 1;\n```{code:#}\nDone"
+            )
+        );
+    }
+}
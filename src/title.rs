@@ -0,0 +1,90 @@
+use crate::{
+    config::Config,
+    message::Message,
+    obsidian,
+    provider::{self, ChatRequest, RequestMessage},
+    storage,
+};
+use anyhow::{bail, Result};
+use openai::chat::ChatCompletionMessageRole;
+use std::path::{Path, PathBuf};
+
+/// Model used to title chats when the config doesn't set `title_model`
+pub const DEFAULT_TITLE_MODEL: &str = "gpt-3.5-turbo";
+
+const TITLE_PROMPT: &str = "Write a short title, at most six words, for the following conversation. Reply with only the title, without quotes or a full stop.";
+
+/// Only the start of a conversation is sent to title it
+const MAX_EXCERPT_CHARS: usize = 4000;
+
+/// Give the chat a title once it has its first reply
+///
+/// The title is stored in the frontmatter and a chat file that still has its
+/// generated name is renamed after it. Returns the path of the chat file,
+/// which changes if it was renamed.
+pub async fn ensure_title(chat_file: &Path, config: &Config) -> Result<PathBuf> {
+    let (mut frontmatter, messages) = Message::read_chat(chat_file)?;
+    let answered = messages
+        .iter()
+        .any(|m| matches!(m.role, ChatCompletionMessageRole::Assistant));
+    if frontmatter.title.is_some() || !answered {
+        return Ok(chat_file.to_path_buf());
+    }
+
+    let title = generate(&messages, config).await?;
+    frontmatter.title = Some(title.clone());
+    frontmatter.write_to(chat_file)?;
+
+    if !storage::has_generated_name(chat_file) {
+        return Ok(chat_file.to_path_buf());
+    }
+    if obsidian::in_vault(chat_file) {
+        return obsidian::rename_titled(chat_file, &title);
+    }
+    let date = frontmatter
+        .created
+        .as_deref()
+        .and_then(|created| created.get(..10))
+        .map(str::to_string)
+        .unwrap_or_else(|| chrono::Local::now().format("%Y-%m-%d").to_string());
+    storage::rename_titled(chat_file, &date, &title)
+}
+
+/// Ask the title model for a title summarising the conversation
+async fn generate(messages: &[Message], config: &Config) -> Result<String> {
+    let mut excerpt = String::new();
+    for message in messages {
+        let speaker = match message.role {
+            ChatCompletionMessageRole::User => "User",
+            ChatCompletionMessageRole::Assistant if message.function_call.is_none() => "Assistant",
+            _ => continue,
+        };
+        excerpt.push_str(&format!("{}: {}\n\n", speaker, message.content));
+    }
+    if let Some((end, _)) = excerpt.char_indices().nth(MAX_EXCERPT_CHARS) {
+        excerpt.truncate(end);
+    }
+
+    let model = config.title_model.as_deref().unwrap_or(DEFAULT_TITLE_MODEL);
+    let request = ChatRequest::new(
+        model,
+        vec![
+            RequestMessage::text(ChatCompletionMessageRole::System, TITLE_PROMPT),
+            RequestMessage::text(ChatCompletionMessageRole::User, &excerpt),
+        ],
+    );
+    let completion = provider::create(request).await?;
+
+    let title = completion
+        .choices
+        .first()
+        .and_then(|c| c.message.content.as_deref())
+        .unwrap_or_default()
+        .trim()
+        .trim_matches(|c| c == '"' || c == '\'' || c == '.')
+        .to_string();
+    if title.is_empty() {
+        bail!("The title model returned an empty title");
+    }
+    Ok(title)
+}
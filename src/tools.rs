@@ -0,0 +1,271 @@
+use crate::{config::Config, mcp, platform, plugins, wasm::WasmToolConfig, web};
+use anyhow::{bail, Context, Result};
+use openai::chat::{ChatCompletionFunctionCall, ChatCompletionFunctionDefinition};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{stderr, stdin, IsTerminal, Write};
+
+/// Tool results longer than this are truncated before being sent back to the model
+const MAX_RESULT_CHARS: usize = 20_000;
+
+/// A tool the model may call, configured as a `[[tools]]` table
+///
+/// ```toml
+/// [[tools]]
+/// kind = "shell"
+///
+/// [[tools]]
+/// kind = "fetch_url"
+/// name = "web"
+/// confirm = false
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ToolConfig {
+    pub kind: ToolKind,
+    /// Name the model calls the tool by, defaults to the kind
+    pub name: Option<String>,
+    /// Description sent to the model, defaults to one describing the kind
+    pub description: Option<String>,
+    /// Ask before running the tool
+    #[serde(default = "default_confirm")]
+    pub confirm: bool,
+}
+
+fn default_confirm() -> bool {
+    true
+}
+
+/// The built in tool implementations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolKind {
+    /// Run a command with `sh -c` and return its output
+    Shell,
+    /// Return the contents of a text file
+    ReadFile,
+    /// List the entries of a directory
+    ListDir,
+    /// Write a text file, replacing it if it exists
+    WriteFile,
+    /// Fetch a URL and return the response body
+    FetchUrl,
+}
+
+impl ToolKind {
+    fn name(&self) -> &'static str {
+        match self {
+            ToolKind::Shell => "shell",
+            ToolKind::ReadFile => "read_file",
+            ToolKind::ListDir => "list_dir",
+            ToolKind::WriteFile => "write_file",
+            ToolKind::FetchUrl => "fetch_url",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            ToolKind::Shell => "Run a shell command on the user's machine and return its output",
+            ToolKind::ReadFile => "Read a text file from the user's machine",
+            ToolKind::ListDir => {
+                "List the files and directories in a directory on the user's machine"
+            }
+            ToolKind::WriteFile => {
+                "Write a text file on the user's machine, replacing its contents if it exists"
+            }
+            ToolKind::FetchUrl => {
+                "Fetch a web page or other URL over HTTP and return its readable text"
+            }
+        }
+    }
+
+    /// The JSON schema of the arguments the tool takes
+    fn parameters(&self) -> Value {
+        let args: &[(&str, &str)] = match self {
+            ToolKind::Shell => &[("command", "The command to run in the shell")],
+            ToolKind::ReadFile => &[("path", "Path of the file to read")],
+            ToolKind::ListDir => &[("path", "Path of the directory to list")],
+            ToolKind::WriteFile => &[
+                ("path", "Path of the file to write"),
+                ("content", "The file's new contents"),
+            ],
+            ToolKind::FetchUrl => &[("url", "The URL to fetch")],
+        };
+        let properties: serde_json::Map<String, Value> = args
+            .iter()
+            .map(|(arg, description)| {
+                let schema = json!({ "type": "string", "description": description });
+                (arg.to_string(), schema)
+            })
+            .collect();
+        let required: Vec<&str> = args.iter().map(|(arg, _)| *arg).collect();
+        json!({
+            "type": "object",
+            "properties": properties,
+            "required": required
+        })
+    }
+}
+
+impl ToolConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.kind.name())
+    }
+
+    /// The function definition sent with the request
+    pub fn definition(&self) -> ChatCompletionFunctionDefinition {
+        ChatCompletionFunctionDefinition {
+            name: self.name().to_string(),
+            description: Some(
+                self.description
+                    .clone()
+                    .unwrap_or_else(|| self.kind.description().to_string()),
+            ),
+            parameters: Some(self.kind.parameters()),
+        }
+    }
+
+    /// Run the tool with the JSON arguments the model provided
+    async fn run(&self, arguments: &str) -> Result<String> {
+        let arguments: Value =
+            serde_json::from_str(arguments).context("Arguments are not valid JSON")?;
+        let arg = |key: &str| -> Result<String> {
+            match arguments.get(key).and_then(Value::as_str) {
+                Some(value) => Ok(value.to_string()),
+                None => bail!("Missing string argument {:?}", key),
+            }
+        };
+
+        match self.kind {
+            ToolKind::Shell => {
+                let output = platform::shell(&arg("command")?)
+                    .output()
+                    .context("Unable to run the shell")?;
+                Ok(format!(
+                    "exit status: {}\nstdout:\n{}\nstderr:\n{}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stdout),
+                    String::from_utf8_lossy(&output.stderr)
+                ))
+            }
+            ToolKind::ReadFile => {
+                let path = arg("path")?;
+                std::fs::read_to_string(&path).with_context(|| format!("Unable to read {path}"))
+            }
+            ToolKind::ListDir => {
+                let path = arg("path")?;
+                let mut entries = std::fs::read_dir(&path)
+                    .with_context(|| format!("Unable to list {path}"))?
+                    .map(|entry| {
+                        let entry = entry?;
+                        let mut name = entry.file_name().to_string_lossy().into_owned();
+                        if entry.file_type()?.is_dir() {
+                            name.push('/');
+                        }
+                        Ok(name)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                entries.sort();
+                Ok(entries.join("\n"))
+            }
+            ToolKind::WriteFile => {
+                let path = arg("path")?;
+                let content = arg("content")?;
+                if let Some(parent) = std::path::Path::new(&path).parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&path, &content)
+                    .with_context(|| format!("Unable to write {path}"))?;
+                Ok(format!("Wrote {} bytes to {}", content.len(), path))
+            }
+            ToolKind::FetchUrl => Ok(web::fetch(&arg("url")?).await?.cited()),
+        }
+    }
+}
+
+/// A tool the model called, wherever it comes from
+enum Target<'a> {
+    Tool(&'a ToolConfig),
+    Wasm(&'a WasmToolConfig),
+    Server(mcp::Found),
+    Plugin(plugins::Found),
+}
+
+impl<'a> Target<'a> {
+    /// The tool called `name`, the configured ones first
+    fn find(config: &'a Config, name: &str) -> Option<Self> {
+        if let Some(tool) = config.tools.iter().find(|t| t.name() == name) {
+            return Some(Self::Tool(tool));
+        }
+        if let Some(tool) = config.wasm_tools.get(name) {
+            return Some(Self::Wasm(tool));
+        }
+        mcp::find(name)
+            .map(Self::Server)
+            .or_else(|| plugins::find(name).map(Self::Plugin))
+    }
+
+    fn confirm(&self) -> bool {
+        match self {
+            Self::Tool(tool) => tool.confirm,
+            Self::Wasm(tool) => tool.confirm,
+            Self::Server(found) => found.confirm(),
+            Self::Plugin(found) => found.confirm(),
+        }
+    }
+
+    async fn run(&self, call: &ChatCompletionFunctionCall) -> Result<String> {
+        match self {
+            Self::Tool(tool) => tool.run(&call.arguments).await,
+            Self::Wasm(tool) => tool.run(&call.name, &call.arguments).await,
+            Self::Server(found) => found.call(&call.arguments).await,
+            Self::Plugin(found) => found.call(&call.arguments),
+        }
+    }
+}
+
+/// Carry out a function call made by the model, returning the text to send back
+///
+/// Failures and declined calls are reported to the model rather than aborting the chat.
+pub async fn call(config: &Config, call: &ChatCompletionFunctionCall) -> String {
+    eprintln!("Function Call: {}({})", call.name, call.arguments);
+
+    let Some(target) = Target::find(config, &call.name) else {
+        return format!("Error: there is no tool named {:?}", call.name);
+    };
+    if target.confirm() && !confirm(&format!("Run {}?", call.name)) {
+        return "The user declined to run this tool".to_string();
+    }
+
+    let result = target.run(call).await;
+    let mut result = match result {
+        Ok(output) => output,
+        Err(e) => {
+            tracing::warn!("{} failed: {:#}", call.name, e);
+            format!("Error: {:#}", e)
+        }
+    };
+    if let Some((end, _)) = result.char_indices().nth(MAX_RESULT_CHARS) {
+        result.truncate(end);
+        result.push_str("\n[truncated]");
+    }
+    result
+}
+
+/// Ask a yes/no question on the terminal, anything but yes is a no
+///
+/// Without a terminal, e.g. when an editor is driving `--stdio-server`, it's a no,
+/// as it is when the terminal is taken by the `tui`.
+pub(crate) fn confirm(question: &str) -> bool {
+    if !stdin().is_terminal() || crossterm::terminal::is_raw_mode_enabled().unwrap_or(false) {
+        eprintln!("{} No, there's no terminal to ask on", question);
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let _ = stderr().flush();
+    let mut answer = String::new();
+    if stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
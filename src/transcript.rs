@@ -0,0 +1,294 @@
+//! Chats kept as JSONL transcripts, `<name>.jsonl`, for tools to read
+//!
+//! The first line holds the frontmatter and each line after it a message:
+//!
+//! ```json
+//! {"frontmatter":{"model":"gpt-4o","system":"auto-expert"}}
+//! {"role":"system","content":"You are ...","time":"2024-05-01T12:00:00+10:00"}
+//! {"role":"user","content":"Hi","time":"2024-05-01T12:00:05+10:00"}
+//! {"role":"assistant","content":"Hello","time":"2024-05-01T12:00:05+10:00","model":"gpt-4o","usage":{"prompt_tokens":12,"completion_tokens":2}}
+//! ```
+//!
+//! While a transcript is open it's rendered as markdown into a temporary
+//! file for the editor, and written back after each change. The lines of
+//! messages that weren't changed are kept as they were, with their times.
+
+use crate::{
+    frontmatter::Frontmatter,
+    message::{Message, Meta},
+    storage,
+};
+use anyhow::{Context, Result};
+use openai::chat::{ChatCompletionFunctionCall, ChatCompletionMessageRole};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Extension of a transcript
+const EXTENSION: &str = "jsonl";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Frontmatter { frontmatter: Frontmatter },
+    Message(Line),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Line {
+    role: ChatCompletionMessageRole,
+    content: String,
+    /// The function a function result is for, or who a message is from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    function_call: Option<ChatCompletionFunctionCall>,
+    /// Who wrote a reply when it's named in its heading, e.g. `critic`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    speaker: Option<String>,
+    /// When the message was written, or a reply sent for, RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time: Option<String>,
+    /// The model that wrote a reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    /// Why `[routing]` chose the model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    route: Option<String>,
+    /// The fallback that replied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fallback: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Usage {
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+}
+
+impl Line {
+    fn new(message: &Message) -> Self {
+        let meta = message.meta.clone().unwrap_or_default();
+        let usage =
+            (meta.prompt_tokens.is_some() || meta.completion_tokens.is_some()).then_some(Usage {
+                prompt_tokens: meta.prompt_tokens,
+                completion_tokens: meta.completion_tokens,
+            });
+        Self {
+            role: message.role,
+            content: message.content.clone(),
+            name: message.name.clone(),
+            function_call: message.function_call.clone(),
+            speaker: message.model.clone(),
+            time: meta.sent.or_else(|| {
+                Some(chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false))
+            }),
+            model: meta.model,
+            usage,
+            latency_ms: meta.latency_ms,
+            duration_ms: meta.duration_ms,
+            route: meta.route,
+            fallback: meta.fallback,
+        }
+    }
+
+    fn to_message(&self) -> Message {
+        let is_reply = matches!(self.role, ChatCompletionMessageRole::Assistant)
+            && self.function_call.is_none();
+        let usage = self.usage.as_ref();
+        Message {
+            role: self.role,
+            content: self.content.clone(),
+            name: self.name.clone(),
+            function_call: self.function_call.clone(),
+            model: self.speaker.clone(),
+            meta: is_reply.then(|| Meta {
+                sent: self.time.clone(),
+                model: self.model.clone(),
+                latency_ms: self.latency_ms,
+                duration_ms: self.duration_ms,
+                prompt_tokens: usage.and_then(|u| u.prompt_tokens),
+                completion_tokens: usage.and_then(|u| u.completion_tokens),
+                route: self.route.clone(),
+                fallback: self.fallback.clone(),
+            }),
+        }
+    }
+
+    /// Whether the line holds the same message, however its metadata differs
+    fn holds(&self, message: &Message) -> bool {
+        std::mem::discriminant(&self.role) == std::mem::discriminant(&message.role)
+            && self.content == message.content
+            && self.name == message.name
+            && self.function_call == message.function_call
+    }
+}
+
+/// Whether the chat file is a transcript, going by its name
+pub fn is_transcript(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == EXTENSION)
+}
+
+/// The transcript's frontmatter and messages
+fn parse(transcript: &str) -> Result<(Frontmatter, Vec<Line>)> {
+    let mut frontmatter = Frontmatter::default();
+    let mut lines = Vec::new();
+    for (i, line) in transcript.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line)
+            .with_context(|| format!("Line {} of the transcript isn't a message", i + 1))?
+        {
+            Entry::Frontmatter { frontmatter: f } => frontmatter = f,
+            Entry::Message(message) => lines.push(message),
+        }
+    }
+    Ok((frontmatter, lines))
+}
+
+/// The transcript as a markdown chat
+pub fn to_markdown(transcript: &str) -> Result<String> {
+    let (frontmatter, lines) = parse(transcript)?;
+    let messages: Vec<Message> = lines.iter().map(Line::to_message).collect();
+    let mut markdown = frontmatter.render_with(&Message::render(&messages))?;
+    if !messages
+        .last()
+        .is_some_and(|m| matches!(m.role, ChatCompletionMessageRole::User))
+    {
+        markdown.push_str("# User\n\n");
+    }
+    Ok(markdown)
+}
+
+/// A markdown chat as a transcript, keeping the lines of `previous` for the messages it has already
+///
+/// The empty user message a chat ends with is left out.
+pub fn from_markdown(markdown: &str, previous: &str) -> Result<String> {
+    let (frontmatter, body) = Frontmatter::parse(markdown)?;
+    let mut messages = Message::parse_messages(body);
+    if messages.last().is_some_and(|m| {
+        matches!(m.role, ChatCompletionMessageRole::User) && m.content.trim().is_empty()
+    }) {
+        messages.pop();
+    }
+    let (_, kept) = parse(previous).unwrap_or_default();
+    let mut transcript = serde_json::to_string(&Entry::Frontmatter { frontmatter })? + "\n";
+    for (i, message) in messages.iter().enumerate() {
+        let line = match kept.get(i).filter(|line| line.holds(message)) {
+            Some(line) => line.clone(),
+            None => Line::new(message),
+        };
+        transcript.push_str(&serde_json::to_string(&Entry::Message(line))?);
+        transcript.push('\n');
+    }
+    Ok(transcript)
+}
+
+/// Write `transcript` to `path`, replacing what was there only once it's all written
+fn write(path: &Path, transcript: &str) -> Result<()> {
+    storage::write_atomically(path, transcript)
+        .with_context(|| format!("Unable to write {}", path.display()))
+}
+
+/// The transcript at `path` as a markdown chat
+pub fn read(path: &Path) -> Result<String> {
+    let transcript = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read {}", path.display()))?;
+    to_markdown(&transcript).with_context(|| format!("Unable to read {}", path.display()))
+}
+
+/// A transcript rendered as markdown into a temporary file for a session
+///
+/// The markdown is what's edited and sent, [`TranscriptChat::seal`] writes it
+/// back. Dropping the chat seals it one last time.
+#[derive(Debug)]
+pub struct TranscriptChat {
+    /// The transcript, e.g. `<store>/<name>.jsonl`
+    path: PathBuf,
+    dir: TempDir,
+}
+
+impl TranscriptChat {
+    /// A new chat to be kept as a transcript at `path`, with its extension replaced by `.jsonl`
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.with_extension(EXTENSION),
+            dir: working_dir()?,
+        })
+    }
+
+    /// Render the transcript at `path` as markdown for a session
+    pub fn open(path: &Path) -> Result<Self> {
+        let chat = Self {
+            path: path.to_path_buf(),
+            dir: working_dir()?,
+        };
+        std::fs::write(chat.markdown_path(), read(path)?)?;
+        Ok(chat)
+    }
+
+    /// The transcript
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The markdown copy of the chat
+    ///
+    /// It's given a plain name, so titling the chat doesn't rename it.
+    pub fn markdown_path(&self) -> PathBuf {
+        self.dir.path().join("chat.md")
+    }
+
+    /// Write the markdown copy back into the transcript
+    pub fn seal(&self) -> Result<()> {
+        let markdown = std::fs::read_to_string(self.markdown_path())
+            .with_context(|| format!("Unable to read {}", self.markdown_path().display()))?;
+        let previous = std::fs::read_to_string(&self.path).unwrap_or_default();
+        write(&self.path, &from_markdown(&markdown, &previous)?)
+    }
+}
+
+impl Drop for TranscriptChat {
+    fn drop(&mut self) {
+        if let Err(e) = self.seal() {
+            eprintln!("Unable to save the transcript: {:#}", e);
+        }
+    }
+}
+
+fn working_dir() -> Result<TempDir> {
+    tempfile::Builder::new()
+        .prefix("chat-cli-rs-")
+        .tempdir()
+        .context("Unable to create a directory for the chat")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_markdown_and_back_appending_new_messages() {
+        let markdown = "---\nmodel: gpt-4o\n---\n# System\nBe terse\n# User\nHi\n# Assistant\nHello\n<!-- chat-cli-rs {\"sent\":\"2024-05-01T12:00:00+10:00\",\"model\":\"gpt-4o\",\"prompt_tokens\":12,\"completion_tokens\":2} -->\n# User\n\n";
+        let transcript = from_markdown(markdown, "").unwrap();
+        let lines: Vec<&str> = transcript.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], r#"{"frontmatter":{"model":"gpt-4o"}}"#);
+        assert!(lines[3]
+            .contains(r#""model":"gpt-4o","usage":{"prompt_tokens":12,"completion_tokens":2}"#));
+        assert_eq!(to_markdown(&transcript).unwrap(), markdown);
+
+        // The lines already in the transcript are kept as they are
+        let more = format!("{}Thanks\n", markdown);
+        let longer = from_markdown(&more, &transcript).unwrap();
+        assert!(longer.starts_with(&transcript));
+        assert_eq!(longer.lines().count(), 5);
+    }
+}
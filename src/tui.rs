@@ -0,0 +1,532 @@
+//! A full screen interface with a tab for each chat, `chat-cli-rs tui`
+//!
+//! Each tab has its own session, sent on a task of its own, so a long reply
+//! can stream in one tab while a quick question is asked in another. The
+//! chats are the usual markdown files, shown as the replies are written to them.
+
+use crate::{
+    config::{Config, Overrides},
+    hooks::{self, Event},
+    message::{self, Message},
+    session::{Interrupted, Reply},
+    Session,
+};
+use anyhow::{Context, Result};
+use crossterm::{
+    cursor,
+    event::{self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType},
+};
+use openai::chat::ChatCompletionMessageRole;
+use std::{
+    io::{stdout, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+/// How often the screen is redrawn, to show the replies streaming in
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+const HELP: &str =
+    "Enter sends  Ctrl-T new tab  Tab next  Ctrl-W close  Ctrl-X stop the reply  Ctrl-Q quit";
+
+/// Open a tab for each of `files`, or one for a new chat, and run until the user quits
+///
+/// Replies still streaming when a tab is closed, or the interface quit, are
+/// interrupted and kept in their chats as they are.
+pub async fn run(files: &[PathBuf], config: Config, overrides: Overrides) -> Result<()> {
+    let mut app = App {
+        tabs: Vec::new(),
+        active: 0,
+        config,
+        overrides,
+    };
+    for file in files {
+        if app.tabs.iter().any(|tab| same_file(&tab.chat_file, file)) {
+            continue;
+        }
+        let session = Session::resume(file, app.config.clone())?;
+        app.open(session.with_overrides(app.overrides.clone()));
+    }
+    if app.tabs.is_empty() {
+        app.new_tab()?;
+    }
+
+    let screen = Screen::enter()?;
+    let mut events = read_events();
+    let mut redraw = tokio::time::interval(REDRAW_INTERVAL);
+    let outcome = loop {
+        app.reap().await;
+        if let Err(e) = app.draw() {
+            break Err(e);
+        }
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(Ok(TermEvent::Key(key))) if key.kind != KeyEventKind::Release => {
+                    match app.key(key).await {
+                        Ok(true) => {}
+                        Ok(false) => break Ok(()),
+                        Err(e) => break Err(e),
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => break Err(e.into()),
+                None => break Ok(()),
+            },
+            _ = redraw.tick() => {}
+        }
+    };
+    drop(screen);
+    for tab in std::mem::take(&mut app.tabs) {
+        tab.close().await;
+    }
+    outcome
+}
+
+struct App {
+    tabs: Vec<Tab>,
+    active: usize,
+    config: Config,
+    overrides: Overrides,
+}
+
+/// A chat open in a tab, with its session either here or away on the task sending it
+struct Tab {
+    chat_file: PathBuf,
+    session: Option<Session>,
+    sending: Option<Sending>,
+    /// The message being typed
+    input: String,
+    /// Lines scrolled back from the end of the chat
+    scroll: usize,
+    /// What happened to the last message sent, e.g. an error
+    status: Option<String>,
+}
+
+/// A session sending its chat on a task, which hands it back with the reply
+struct Sending {
+    task: JoinHandle<(Session, Result<Reply>)>,
+    /// Interrupts the reply, the part received so far is kept
+    cancel: oneshot::Sender<()>,
+}
+
+impl App {
+    fn open(&mut self, session: Session) {
+        hooks::fire(Event::SessionStart, &session);
+        self.tabs.push(Tab {
+            chat_file: session.chat_file().to_path_buf(),
+            session: Some(session),
+            sending: None,
+            input: String::new(),
+            scroll: 0,
+            status: None,
+        });
+        self.active = self.tabs.len() - 1;
+    }
+
+    fn new_tab(&mut self) -> Result<()> {
+        let session = Session::start(None, self.config.clone(), self.overrides.clone())?;
+        self.open(session);
+        Ok(())
+    }
+
+    /// Take back the sessions whose replies have finished
+    async fn reap(&mut self) {
+        for tab in &mut self.tabs {
+            if tab.sending.as_ref().is_some_and(|s| s.task.is_finished()) {
+                let sending = tab.sending.take().expect("the tab is sending");
+                tab.finish(sending.task.await, &self.config);
+            }
+        }
+    }
+
+    /// Handle a key, returning whether to carry on
+    async fn key(&mut self, key: KeyEvent) -> Result<bool> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('q' | 'c') if ctrl => return Ok(false),
+            KeyCode::Char('t') if ctrl => {
+                if let Err(e) = self.new_tab() {
+                    self.tabs[self.active].status = Some(format!("Error: {:#}", e));
+                }
+            }
+            KeyCode::Char('w') if ctrl => {
+                let tab = self.tabs.remove(self.active);
+                tab.close().await;
+                if self.tabs.is_empty() {
+                    return Ok(false);
+                }
+                self.active = self.active.min(self.tabs.len() - 1);
+            }
+            KeyCode::Char('x') if ctrl => {
+                if let Some(sending) = self.tabs[self.active].sending.take() {
+                    let _ = sending.cancel.send(());
+                    self.tabs[self.active].finish(sending.task.await, &self.config);
+                }
+            }
+            KeyCode::Tab => self.active = (self.active + 1) % self.tabs.len(),
+            KeyCode::BackTab => self.active = (self.active + self.tabs.len() - 1) % self.tabs.len(),
+            KeyCode::Char(digit @ '1'..='9') if key.modifiers.contains(KeyModifiers::ALT) => {
+                let at = digit as usize - '1' as usize;
+                if at < self.tabs.len() {
+                    self.active = at;
+                }
+            }
+            KeyCode::PageUp => self.tabs[self.active].scroll += page(),
+            KeyCode::PageDown => {
+                let tab = &mut self.tabs[self.active];
+                tab.scroll = tab.scroll.saturating_sub(page());
+            }
+            KeyCode::Enter => self.tabs[self.active].send(),
+            KeyCode::Backspace => {
+                self.tabs[self.active].input.pop();
+            }
+            KeyCode::Char(c) if !ctrl => self.tabs[self.active].input.push(c),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn draw(&self) -> Result<()> {
+        let (width, height) = terminal::size()?;
+        let (width, height) = (width as usize, height as usize);
+        let tab = &self.tabs[self.active];
+        let mut out = stdout().lock();
+        queue!(out, cursor::MoveTo(0, 0))?;
+
+        // The tab bar, marking the tabs with a reply streaming in
+        let mut bar = 0;
+        for (i, other) in self.tabs.iter().enumerate() {
+            let name = tab_name(&other.chat_file);
+            let label = match other.sending.is_some() {
+                true => format!(" {} {} * ", i + 1, name),
+                false => format!(" {} {} ", i + 1, name),
+            };
+            let label: String = label.chars().take(width.saturating_sub(bar)).collect();
+            bar += label.chars().count();
+            match i == self.active {
+                true => queue!(
+                    out,
+                    SetAttribute(Attribute::Reverse),
+                    Print(label),
+                    SetAttribute(Attribute::Reset)
+                )?,
+                false => queue!(out, Print(label))?,
+            }
+        }
+        queue!(out, terminal::Clear(ClearType::UntilNewLine))?;
+
+        // The end of the chat, less what's been scrolled back
+        let rows = height.saturating_sub(3);
+        let lines = match Message::read_chat(&tab.chat_file) {
+            Ok((_, messages)) => render(&messages, width),
+            Err(e) => vec![Line::Text(format!("Unable to read the chat: {:#}", e))],
+        };
+        let end = lines.len().saturating_sub(tab.scroll.min(lines.len()));
+        let shown = &lines[end.saturating_sub(rows)..end];
+        for row in 0..rows {
+            queue!(out, cursor::MoveTo(0, row as u16 + 1))?;
+            match shown.get(row) {
+                Some(Line::Label(label)) => queue!(
+                    out,
+                    SetAttribute(Attribute::Bold),
+                    Print(label),
+                    SetAttribute(Attribute::Reset)
+                )?,
+                Some(Line::Text(text)) => queue!(out, Print(text))?,
+                None => {}
+            }
+            queue!(out, terminal::Clear(ClearType::UntilNewLine))?;
+        }
+
+        let status = match (&tab.status, &tab.sending) {
+            (Some(status), _) => status.as_str(),
+            (None, Some(_)) => "Replying...",
+            (None, None) => HELP,
+        };
+        let status: String = status.chars().take(width).collect();
+        queue!(
+            out,
+            cursor::MoveTo(0, height.saturating_sub(2) as u16),
+            SetAttribute(Attribute::Dim),
+            Print(status),
+            SetAttribute(Attribute::Reset),
+            terminal::Clear(ClearType::UntilNewLine),
+        )?;
+
+        // The end of the message being typed, if it's wider than the screen
+        let typed = tab.input.chars().count();
+        let input: String = tab
+            .input
+            .chars()
+            .skip((typed + 3).saturating_sub(width))
+            .collect();
+        queue!(
+            out,
+            cursor::MoveTo(0, height.saturating_sub(1) as u16),
+            Print("> "),
+            Print(input),
+            terminal::Clear(ClearType::UntilNewLine),
+        )?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl Tab {
+    /// Add the typed message to the chat and send it on a task of its own
+    fn send(&mut self) {
+        let Some(mut session) = self.session.take() else {
+            self.status = Some("Still replying, Ctrl-X stops the reply".to_string());
+            return;
+        };
+        let text = std::mem::take(&mut self.input);
+        if let Err(e) = message::add_to_last(session.chat_file(), text.trim()) {
+            self.status = Some(format!("Error: {:#}", e));
+            self.session = Some(session);
+            return;
+        }
+        let (cancel, cancelled) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            let reply = session
+                .send_or_cancel(async {
+                    let _ = cancelled.await;
+                })
+                .await;
+            if reply.is_ok() && session.config().auto_title != Some(false) {
+                if let Err(e) = session.title().await {
+                    tracing::warn!("Unable to title the chat: {:#}", e);
+                }
+            }
+            (session, reply)
+        });
+        self.sending = Some(Sending { task, cancel });
+        self.scroll = 0;
+        self.status = None;
+    }
+
+    /// Take the session back from the task that sent it
+    fn finish(
+        &mut self,
+        finished: Result<(Session, Result<Reply>), tokio::task::JoinError>,
+        config: &Config,
+    ) {
+        let session = match finished {
+            Ok((session, reply)) => {
+                self.status = match reply {
+                    Ok(reply) if reply.finish_reason.as_deref() == Some("length") => Some(
+                        "The reply was cut off at the token limit, `chat-cli-rs continue` asks for the rest"
+                            .to_string(),
+                    ),
+                    Ok(_) => None,
+                    Err(e) if e.is::<Interrupted>() => {
+                        Some("Interrupted, the partial reply is kept in the chat".to_string())
+                    }
+                    Err(e) => Some(format!("Error: {:#}", e)),
+                };
+                session
+            }
+            // The session went with the task, so the chat is opened again
+            Err(e) => {
+                self.status = Some(format!("Error: {}", e));
+                match Session::resume(&self.chat_file, config.clone()) {
+                    Ok(session) => session,
+                    Err(e) => {
+                        self.status = Some(format!("Error: {:#}", e));
+                        return;
+                    }
+                }
+            }
+        };
+        self.chat_file = session.chat_file().to_path_buf();
+        self.session = Some(session);
+    }
+
+    /// Interrupt a reply still streaming in and end the session
+    async fn close(mut self) {
+        if let Some(sending) = self.sending.take() {
+            let _ = sending.cancel.send(());
+            if let Ok((session, _)) = sending.task.await {
+                self.session = Some(session);
+            }
+        }
+        if let Some(session) = self.session {
+            hooks::fire(Event::SessionEnd, &session);
+        }
+    }
+}
+
+/// A line of a chat as it's shown
+#[derive(Debug, PartialEq, Eq)]
+enum Line {
+    /// Who a message is from
+    Label(String),
+    Text(String),
+}
+
+/// The chat's messages wrapped at `width`, leaving out the system prompt and
+/// the empty message waiting to be written
+fn render(messages: &[Message], width: usize) -> Vec<Line> {
+    let mut lines = Vec::new();
+    for message in messages {
+        if matches!(message.role, ChatCompletionMessageRole::System)
+            || message.content.trim().is_empty() && message.function_call.is_none()
+        {
+            continue;
+        }
+        if !lines.is_empty() {
+            lines.push(Line::Text(String::new()));
+        }
+        lines.push(Line::Label(message.label()));
+        for line in message.content.trim().lines() {
+            lines.extend(wrap(line, width).into_iter().map(Line::Text));
+        }
+    }
+    lines
+}
+
+/// The line broken into pieces no wider than `width`, between words where it can be
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut pieces = Vec::new();
+    let mut rest = line;
+    while rest.chars().count() > width {
+        let end = rest
+            .char_indices()
+            .nth(width)
+            .map_or(rest.len(), |(i, _)| i);
+        let at = match rest[..end].rfind(' ') {
+            _ if rest[end..].starts_with(' ') => end,
+            Some(space) if space > 0 => space,
+            _ => end,
+        };
+        pieces.push(rest[..at].to_string());
+        rest = rest[at..].strip_prefix(' ').unwrap_or(&rest[at..]);
+    }
+    pieces.push(rest.to_string());
+    pieces
+}
+
+/// What a tab is called, the chat's file name without its extension
+fn tab_name(chat_file: &Path) -> String {
+    let name = chat_file
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    name.trim_end_matches(".md").chars().take(24).collect()
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// How far PageUp and PageDown scroll
+fn page() -> usize {
+    terminal::size().map_or(10, |(_, height)| (height as usize).saturating_sub(4).max(1))
+}
+
+/// The terminal's key presses, read on a thread of their own
+fn read_events() -> mpsc::UnboundedReceiver<std::io::Result<TermEvent>> {
+    let (events, received) = mpsc::unbounded_channel();
+    std::thread::spawn(move || loop {
+        match event::poll(REDRAW_INTERVAL) {
+            Ok(true) => {
+                if events.send(event::read()).is_err() {
+                    return;
+                }
+            }
+            Ok(false) if events.is_closed() => return,
+            Ok(false) => {}
+            Err(e) => {
+                let _ = events.send(Err(e));
+                return;
+            }
+        }
+    });
+    received
+}
+
+/// The terminal in raw mode on the alternate screen, put back as it was when dropped
+struct Screen;
+
+impl Screen {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode().context("Unable to set up the terminal")?;
+        let screen = Self;
+        crossterm::execute!(
+            stdout(),
+            terminal::EnterAlternateScreen,
+            terminal::Clear(ClearType::All)
+        )?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = crossterm::execute!(stdout(), terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_chat_wrapped_to_the_screen() {
+        let messages = Message::parse_messages(
+            "# System\nBe terse\n# User\nWhat's a lifetime?\n# Assistant\nHow long a reference is valid for.\n# User\n\n",
+        );
+        assert_eq!(
+            render(&messages, 20),
+            [
+                Line::Label(messages[1].label()),
+                Line::Text("What's a lifetime?".into()),
+                Line::Text(String::new()),
+                Line::Label(messages[2].label()),
+                Line::Text("How long a reference".into()),
+                Line::Text("is valid for.".into()),
+            ]
+        );
+        assert_eq!(wrap("abcdefgh", 3), ["abc", "def", "gh"]);
+    }
+
+    #[tokio::test]
+    async fn sends_each_tab_on_its_own_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App {
+            tabs: Vec::new(),
+            active: 0,
+            config: Config::default(),
+            overrides: Overrides::default(),
+        };
+        for name in ["a.md", "b.md"] {
+            let file = dir.path().join(name);
+            std::fs::write(&file, "# User\n\n").unwrap();
+            app.open(Session::open(&file, Config::default()));
+        }
+        app.tabs[0].input = "First".into();
+        app.tabs[0].send();
+        // The other tab can be used while the first sends
+        assert!(app.tabs[0].sending.is_some());
+        assert!(app.tabs[1].session.is_some());
+        app.tabs[0].send();
+        assert!(app.tabs[0].status.is_some());
+
+        let tab = app.tabs.remove(0);
+        tab.close().await;
+        assert!(std::fs::read_to_string(dir.path().join("a.md"))
+            .unwrap()
+            .contains("First"));
+    }
+}
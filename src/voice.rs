@@ -0,0 +1,141 @@
+use crate::provider;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{
+    io::{stdin, IsTerminal},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// Transcription model used unless the config chooses another
+const DEFAULT_MODEL: &str = "whisper-1";
+
+/// Records from the default microphone with SoX as a 16 kHz mono WAV file
+const DEFAULT_RECORD_COMMAND: &str = "rec -q -c 1 -r 16000";
+
+/// Speaking messages instead of typing them, the `[voice]` config table
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VoiceConfig {
+    /// Transcription model, `whisper-1` by default
+    pub model: Option<String>,
+    /// Base URL of an OpenAI compatible API to transcribe with instead, e.g. a local Whisper server
+    pub base_url: Option<String>,
+    /// Language spoken, as an ISO-639-1 code like `en`, detected if unset
+    pub language: Option<String>,
+    /// Command that records to the `.wav` path given as its last argument until interrupted
+    pub record_command: Option<String>,
+}
+
+/// Transcribe an audio file
+pub async fn transcribe(audio: &Path, config: &VoiceConfig) -> Result<String> {
+    let text = provider::transcribe(
+        config.base_url.as_deref(),
+        config.model.as_deref().unwrap_or(DEFAULT_MODEL),
+        config.language.as_deref(),
+        audio,
+    )
+    .await?;
+    Ok(text.trim().to_string())
+}
+
+/// Record from the microphone until Enter is pressed and transcribe the recording
+pub async fn listen(config: &VoiceConfig) -> Result<String> {
+    let recording = tempfile::Builder::new().suffix(".wav").tempfile()?;
+    record(recording.path(), config)?;
+    eprintln!("Transcribing");
+    transcribe(recording.path(), config).await
+}
+
+fn record(path: &Path, config: &VoiceConfig) -> Result<()> {
+    if !stdin().is_terminal() {
+        bail!("Recording needs a terminal to stop it, give --voice an audio file instead");
+    }
+    let command = config
+        .record_command
+        .as_deref()
+        .unwrap_or(DEFAULT_RECORD_COMMAND);
+    // `exec` so that interrupting the recorder lets it finish writing the file
+    let mut recorder = Command::new("sh")
+        .arg("-c")
+        .arg(format!("exec {} \"$1\"", command))
+        .arg("sh")
+        .arg(path)
+        .stdin(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Unable to run {}", command))?;
+    eprint!("Recording, press Enter to stop");
+    let mut line = String::new();
+    stdin().read_line(&mut line)?;
+    stop(&mut recorder)?;
+    let status = recorder.wait()?;
+    if std::fs::metadata(path).map_or(true, |m| m.len() == 0) {
+        bail!("Nothing was recorded, `{}` exited with {}", command, status);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn stop(recorder: &mut std::process::Child) -> Result<()> {
+    let interrupted = Command::new("kill")
+        .arg("-INT")
+        .arg(recorder.id().to_string())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !interrupted {
+        recorder.kill()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn stop(recorder: &mut std::process::Child) -> Result<()> {
+    Ok(recorder.kill()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn transcribes_with_a_local_server() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // The multipart body ends with its closing boundary
+            while !request.ends_with(b"--\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = r#"{"text": " Hello there\n"}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request).into_owned()
+        });
+
+        let audio = tempfile::Builder::new().suffix(".wav").tempfile().unwrap();
+        std::fs::write(audio.path(), b"RIFF").unwrap();
+        let config = VoiceConfig {
+            base_url: Some(url),
+            language: Some("en".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            transcribe(audio.path(), &config).await.unwrap(),
+            "Hello there"
+        );
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /v1/audio/transcriptions "));
+        assert!(request.contains(DEFAULT_MODEL));
+        assert!(request.contains("name=\"language\"\r\n\r\nen"));
+    }
+}
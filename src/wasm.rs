@@ -0,0 +1,171 @@
+//! Tools shipped as WebAssembly modules and run in a sandbox, the `[wasm_tools.<name>]` tables
+//!
+//! A module is a WASI command, e.g. built for `wasm32-wasip1`, that reads the
+//! model's JSON arguments on stdin and writes its result to stdout. It can't
+//! reach the network, the environment or any files but the directories it's
+//! granted, and it's stopped once it has used up its fuel.
+
+use anyhow::{bail, Context, Result};
+use openai::chat::ChatCompletionFunctionDefinition;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{path::PathBuf, sync::OnceLock};
+use wasmtime::{Engine, Linker, Module, Store, Trap};
+use wasmtime_wasi::{
+    p1::{self, WasiP1Ctx},
+    p2::pipe::{MemoryInputPipe, MemoryOutputPipe},
+    FsPerms, I32Exit, WasiCtxBuilder,
+};
+
+/// Roughly the number of instructions a tool may run before it's stopped
+const FUEL: u64 = 1_000_000_000;
+
+/// Most of a tool's stdout and stderr kept
+const MAX_OUTPUT: usize = 1 << 20;
+
+/// A tool the model may call, compiled from a `.wasm` or `.wat` module
+///
+/// ```toml
+/// [wasm_tools.word_count]
+/// module = "/home/me/.config/chat-cli-rs/wasm/word_count.wasm"
+/// description = "Count the words in the notes"
+/// parameters = { type = "object", properties = { file = { type = "string" } } }
+/// read_dirs = ["/home/me/notes"]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WasmToolConfig {
+    pub module: PathBuf,
+    /// Description sent to the model
+    pub description: Option<String>,
+    /// JSON schema of the arguments, none by default
+    pub parameters: Option<Value>,
+    /// Directories the tool may read, at the same paths inside the sandbox
+    #[serde(default)]
+    pub read_dirs: Vec<PathBuf>,
+    /// Directories the tool may read and write
+    #[serde(default)]
+    pub write_dirs: Vec<PathBuf>,
+    /// Ask before running the tool, it's sandboxed so defaults to false
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+/// Compiles and runs the modules, counting their fuel
+fn engine() -> Result<&'static Engine> {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    if let Some(engine) = ENGINE.get() {
+        return Ok(engine);
+    }
+    let mut config = wasmtime::Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+    Ok(ENGINE.get_or_init(|| engine))
+}
+
+impl WasmToolConfig {
+    /// The function definition sent with the request, for the tool called `name`
+    pub fn definition(&self, name: &str) -> ChatCompletionFunctionDefinition {
+        ChatCompletionFunctionDefinition {
+            name: name.to_string(),
+            description: self.description.clone(),
+            parameters: Some(
+                self.parameters
+                    .clone()
+                    .unwrap_or_else(|| json!({ "type": "object", "properties": {} })),
+            ),
+        }
+    }
+
+    /// Run the tool with the JSON arguments the model provided, returning what it wrote
+    pub async fn run(&self, name: &str, arguments: &str) -> Result<String> {
+        let (tool, name, arguments) = (self.clone(), name.to_string(), arguments.to_string());
+        tokio::task::spawn_blocking(move || tool.run_blocking(&name, &arguments)).await?
+    }
+
+    /// WASI's blocking calls need a thread of their own rather than an async task
+    fn run_blocking(&self, name: &str, arguments: &str) -> Result<String> {
+        let engine = engine()?;
+        let module = Module::from_file(engine, &self.module)
+            .map_err(anyhow::Error::from)
+            .with_context(|| format!("Unable to load {}", self.module.display()))?;
+        let mut linker: Linker<WasiP1Ctx> = Linker::new(engine);
+        p1::add_to_linker_sync(&mut linker, |wasi| wasi)?;
+
+        let stdout = MemoryOutputPipe::new(MAX_OUTPUT);
+        let stderr = MemoryOutputPipe::new(MAX_OUTPUT);
+        let mut wasi = WasiCtxBuilder::new();
+        wasi.stdin(MemoryInputPipe::new(arguments.to_string()))
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .args(&[name]);
+        let dirs = self.read_dirs.iter().map(|dir| (dir, FsPerms::ReadOnly));
+        let dirs = dirs.chain(self.write_dirs.iter().map(|dir| (dir, FsPerms::ReadWrite)));
+        for (dir, perms) in dirs {
+            wasi.preopened_dir(dir, dir.to_string_lossy(), perms)
+                .map_err(anyhow::Error::from)
+                .with_context(|| format!("Unable to open {}", dir.display()))?;
+        }
+
+        let mut store = Store::new(engine, wasi.build_p1());
+        store.set_fuel(FUEL)?;
+        let instance = linker.instantiate(&mut store, &module)?;
+        let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+        if let Err(e) = start.call(&mut store, ()) {
+            let stderr = String::from_utf8_lossy(&stderr.contents())
+                .trim()
+                .to_string();
+            match (e.downcast_ref::<I32Exit>(), e.downcast_ref::<Trap>()) {
+                (Some(I32Exit(0)), _) => {}
+                (Some(I32Exit(code)), _) => bail!("It exited with {}: {}", code, stderr),
+                (None, Some(Trap::OutOfFuel)) => bail!("It ran out of fuel"),
+                (None, _) => return Err(anyhow::Error::from(e).context(stderr)),
+            }
+        }
+        Ok(String::from_utf8_lossy(&stdout.contents()).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tool(dir: &tempfile::TempDir, wat: &str) -> WasmToolConfig {
+        let module = dir.path().join("tool.wat");
+        std::fs::write(&module, wat).unwrap();
+        WasmToolConfig {
+            module,
+            description: None,
+            parameters: None,
+            read_dirs: Vec::new(),
+            write_dirs: Vec::new(),
+            confirm: false,
+        }
+    }
+
+    #[test]
+    fn runs_modules_until_their_fuel_runs_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let echo = tool(
+            &dir,
+            r#"(module
+              (import "wasi_snapshot_preview1" "fd_read" (func $read (param i32 i32 i32 i32) (result i32)))
+              (import "wasi_snapshot_preview1" "fd_write" (func $write (param i32 i32 i32 i32) (result i32)))
+              (memory (export "memory") 1)
+              (func (export "_start")
+                (i32.store (i32.const 0) (i32.const 64))
+                (i32.store (i32.const 4) (i32.const 1024))
+                (drop (call $read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+                (i32.store (i32.const 4) (i32.load (i32.const 8)))
+                (drop (call $write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#,
+        );
+        assert_eq!(
+            echo.run_blocking("echo", r#"{"text":"hi"}"#).unwrap(),
+            r#"{"text":"hi"}"#
+        );
+
+        let spin = tool(&dir, r#"(module (func (export "_start") (loop (br 0))))"#);
+        let error = spin.run_blocking("spin", "{}").unwrap_err();
+        assert_eq!(error.to_string(), "It ran out of fuel");
+    }
+}
@@ -0,0 +1,105 @@
+use crate::message::Message;
+use anyhow::{Context, Result};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use openai::chat::ChatCompletionMessageRole;
+use std::{path::Path, time::Duration};
+use tokio::sync::mpsc::{channel, Receiver};
+
+/// Editors often save in several steps (write, rename, chmod), wait for them to settle
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Watches a chat file for saves from the editor
+pub struct ChatWatcher {
+    // Dropping the watcher stops the events
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+}
+
+impl ChatWatcher {
+    /// Start watching the directory holding the chat file
+    ///
+    /// The directory is watched rather than the file as many editors save by
+    /// replacing the file, which would end a watch on the file itself.
+    pub fn new(chat_file: &Path) -> Result<Self> {
+        let (tx, events) = channel(64);
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.blocking_send(event);
+        })
+        .context("Unable to create a file watcher")?;
+
+        let dir = chat_file.parent().unwrap_or_else(|| Path::new("."));
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Unable to watch {:?}", dir))?;
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Wait until the chat file is saved with a user message ready to send
+    ///
+    /// Our own writes end the file with an empty `# User` heading so they don't trigger a send.
+    pub async fn wait_for_user_message(&mut self, chat_file: &Path) -> Result<()> {
+        loop {
+            let event = self
+                .events
+                .recv()
+                .await
+                .context("The file watcher stopped")?
+                .context("Error watching the chat file")?;
+
+            let written = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_));
+            let is_chat = event
+                .paths
+                .iter()
+                .any(|p| p.file_name() == chat_file.file_name());
+            if !written || !is_chat {
+                continue;
+            }
+
+            tokio::time::sleep(DEBOUNCE).await;
+            while self.events.try_recv().is_ok() {}
+
+            if has_user_message(chat_file)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Whether the chat ends with a user message that has something in it
+fn has_user_message(chat_file: &Path) -> Result<bool> {
+    let (_, messages) = Message::read_chat(chat_file)?;
+    Ok(messages.last().is_some_and(|m| {
+        matches!(m.role, ChatCompletionMessageRole::User) && !m.content.trim().is_empty()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_a_save_with_a_user_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let chat_file = dir.path().join("chat.md");
+        std::fs::write(&chat_file, "# User\nHi\n# Assistant\nHello\n# User\n\n").unwrap();
+        let mut watcher = ChatWatcher::new(&chat_file).unwrap();
+
+        // Our own writes leave the next message empty
+        std::fs::write(&chat_file, "# User\nHi\n# Assistant\nHello!\n# User\n\n").unwrap();
+        let waited = tokio::time::timeout(DEBOUNCE * 4, watcher.wait_for_user_message(&chat_file));
+        assert!(waited.await.is_err());
+
+        std::fs::write(&chat_file, "# User\nHi\n# Assistant\nHello!\n# User\nBye\n").unwrap();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            watcher.wait_for_user_message(&chat_file),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    }
+}
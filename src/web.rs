@@ -0,0 +1,119 @@
+use crate::{directives::longest_backtick_run, provider};
+use anyhow::{Context, Result};
+use dom_smoothie::{Readability, TextMode};
+
+/// Most tokens of a page included in a message, the end of the page is left out
+const MAX_PAGE_TOKENS: usize = 4000;
+
+/// A fetched page's readable text
+pub struct Page {
+    pub url: String,
+    pub title: Option<String>,
+    pub text: String,
+}
+
+/// Fetch a URL, keeping only the article of HTML pages, cut to a token budget
+pub async fn fetch(url: &str) -> Result<Page> {
+    eprintln!("Fetching {}", url);
+    let response = provider::client()
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .with_context(|| format!("Unable to fetch {}", url))?;
+    let html = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .is_some_and(|t| t.contains("html"));
+    let body = response.text().await?;
+    let (title, text) = match html {
+        true => readable(&body, url),
+        false => (None, body),
+    };
+    Ok(Page {
+        url: url.to_string(),
+        title,
+        text: truncate(text.trim(), MAX_PAGE_TOKENS),
+    })
+}
+
+impl Page {
+    /// The text in a code block under a link to its source
+    pub fn cited(&self) -> String {
+        let fence = "`".repeat(longest_backtick_run(&self.text).max(2) + 1);
+        let source = match &self.title {
+            Some(title) => format!("[{}]({})", title, self.url),
+            None => format!("<{}>", self.url),
+        };
+        format!(
+            "Source: {}\n{}markdown\n{}\n{}\n",
+            source, fence, self.text, fence
+        )
+    }
+}
+
+/// The page's title and its main content as markdown, without navigation, ads and the like
+///
+/// Pages readability can't make sense of are kept whole.
+fn readable(html: &str, url: &str) -> (Option<String>, String) {
+    let config = dom_smoothie::Config {
+        text_mode: TextMode::Markdown,
+        ..Default::default()
+    };
+    let article = Readability::new(html, Some(url), Some(config)).and_then(|mut r| r.parse());
+    match article {
+        Ok(article) => {
+            let title = Some(article.title.trim().to_string()).filter(|t| !t.is_empty());
+            (title, article.text_content.to_string())
+        }
+        Err(e) => {
+            tracing::warn!("Unable to find the article in {}: {}", url, e);
+            (None, html.to_string())
+        }
+    }
+}
+
+/// The lines of the text that fit in `max_tokens`
+fn truncate(text: &str, max_tokens: usize) -> String {
+    let bpe = tiktoken_rs::cl100k_base_singleton();
+    let mut tokens = 0;
+    let mut kept = String::new();
+    for line in text.split_inclusive('\n') {
+        tokens += bpe.count_ordinary(line);
+        if tokens > max_tokens {
+            kept.push_str("\n[the rest of the page was left out]");
+            break;
+        }
+        kept.push_str(line);
+    }
+    kept.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_article_and_cites_it() {
+        let paragraph = "Ownership is Rust's most unique feature and has deep implications for the rest of the language. It enables Rust to make memory safety guarantees without needing a garbage collector.";
+        let html = format!(
+            "<html><head><title>Ownership</title></head><body><nav><a href='/'>Home</a></nav><article><h1>Ownership</h1><p>{0}</p><p>{0}</p><p>{0}</p></article></body></html>",
+            paragraph
+        );
+        let (title, text) = readable(&html, "https://example.com/ownership");
+        assert_eq!(title.as_deref(), Some("Ownership"));
+        assert!(text.contains("garbage collector"));
+        assert!(!text.contains("Home"));
+
+        let page = Page {
+            url: "https://example.com/ownership".to_string(),
+            title,
+            text: truncate(&text, 40),
+        };
+        assert!(page.text.ends_with("[the rest of the page was left out]"));
+        assert!(page
+            .cited()
+            .starts_with("Source: [Ownership](https://example.com/ownership)\n```markdown\n"));
+    }
+}
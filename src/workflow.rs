@@ -0,0 +1,283 @@
+//! Workflows, recurring multi-step chats kept as TOML or YAML files
+//!
+//! ```toml
+//! description = "Review a design doc"
+//! system = "auto-expert"
+//!
+//! [[steps]]
+//! ask = "Paste the design doc"
+//! save_as = "doc"
+//!
+//! [[steps]]
+//! send = "List the risks in this design, starting with RISKY or FINE:\n\n{{doc}}"
+//! save_as = "review"
+//!
+//! [[steps]]
+//! when = { var = "review", contains = "FINE" }
+//! goto = "end"
+//!
+//! [[steps]]
+//! send = "Suggest how to address each risk"
+//! ```
+//!
+//! The steps run in order. `ask` asks the user a question and `send` sends a
+//! message, both are written into the chat and their answer or reply saved to
+//! use in later steps as `{{name}}`. A step with `when` only runs if the
+//! condition holds, and `goto` carries on from the step with that `id`, or
+//! stops at `end`.
+
+use crate::{message, platform, session::Session, templates};
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Stops a workflow whose `goto`s loop forever
+const MAX_STEPS: usize = 100;
+
+/// The `goto` that stops the workflow
+const END: &str = "end";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Workflow {
+    pub description: Option<String>,
+    /// System prompt name or path the chat starts with
+    pub system: Option<String>,
+    /// Model the chat uses
+    pub model: Option<String>,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    /// Name for `goto` to go to
+    pub id: Option<String>,
+    /// A question for the user
+    pub ask: Option<String>,
+    /// A message sent to the model, `{{name}}` is replaced by a saved answer or reply
+    pub send: Option<String>,
+    /// Name the answer or reply is saved as
+    pub save_as: Option<String>,
+    /// Only run the step if this holds
+    pub when: Option<Condition>,
+    /// The step's id to carry on from, or `end`
+    pub goto: Option<String>,
+}
+
+/// Whether a saved answer or reply contains some text or matches a regular expression
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    pub var: String,
+    pub contains: Option<String>,
+    pub matches: Option<String>,
+}
+
+impl Condition {
+    fn holds(&self, vars: &HashMap<String, String>) -> Result<bool> {
+        let value = vars.get(&self.var).map_or("", String::as_str);
+        if let Some(text) = &self.contains {
+            if !value.contains(text.as_str()) {
+                return Ok(false);
+            }
+        }
+        if let Some(pattern) = &self.matches {
+            let regex = Regex::new(pattern)
+                .with_context(|| format!("Invalid pattern in a workflow: {:?}", pattern))?;
+            if !regex.is_match(value) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Workflow {
+    /// Read a workflow by name from the workflows directory, or from a path
+    pub fn load(spec: &str) -> Result<Self> {
+        let path = Path::new(spec);
+        let path = match path.is_file() {
+            true => path.to_path_buf(),
+            false => workflows_dir()
+                .and_then(|dir| {
+                    ["toml", "yaml", "yml"]
+                        .iter()
+                        .map(|ext| dir.join(format!("{spec}.{ext}")))
+                        .find(|path| path.is_file())
+                })
+                .with_context(|| {
+                    format!("No workflow named {:?}, see `chat-cli-rs workflow`", spec)
+                })?,
+        };
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read workflow: {:?}", path))?;
+        let workflow: Self = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents)?,
+            _ => toml::from_str(&contents)?,
+        };
+        workflow
+            .check()
+            .with_context(|| format!("Invalid workflow: {:?}", path))?;
+        Ok(workflow)
+    }
+
+    fn check(&self) -> Result<()> {
+        for (i, step) in self.steps.iter().enumerate() {
+            let n = i + 1;
+            match (&step.ask, &step.send) {
+                (Some(_), Some(_)) => bail!("Step {} both asks and sends", n),
+                (Some(_), None) if step.save_as.is_none() => {
+                    bail!("Step {} asks a question without saving the answer", n)
+                }
+                (None, None) if step.goto.is_none() => bail!("Step {} does nothing", n),
+                _ => {}
+            }
+            if let Some(target) = &step.goto {
+                if target != END && self.position(target).is_none() {
+                    bail!("Step {} goes to {:?}, which isn't a step's id", n, target);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn position(&self, id: &str) -> Option<usize> {
+        self.steps
+            .iter()
+            .position(|step| step.id.as_deref() == Some(id))
+    }
+
+    /// The step to run after step `at`, `None` at the end
+    fn next(&self, at: usize) -> Option<usize> {
+        match self.steps[at].goto.as_deref() {
+            Some(END) => None,
+            Some(id) => self.position(id),
+            None => Some(at + 1).filter(|&next| next < self.steps.len()),
+        }
+    }
+
+    /// Walk through the steps in the session's chat
+    ///
+    /// Questions whose answers are in `vars` already aren't asked, the rest
+    /// are answered by `answer`. Returns the saved answers and replies.
+    pub async fn run(
+        &self,
+        session: &Session,
+        mut vars: HashMap<String, String>,
+        mut answer: impl FnMut(&str) -> Result<String>,
+    ) -> Result<HashMap<String, String>> {
+        let mut at = Some(0).filter(|_| !self.steps.is_empty());
+        let mut steps = 0;
+        while let Some(i) = at {
+            steps += 1;
+            if steps > MAX_STEPS {
+                bail!("The workflow ran {} steps, do its gotos loop?", MAX_STEPS);
+            }
+            let step = &self.steps[i];
+            if let Some(when) = &step.when {
+                if !when.holds(&vars)? {
+                    at = Some(i + 1).filter(|&next| next < self.steps.len());
+                    continue;
+                }
+            }
+            if let Some(question) = &step.ask {
+                let question = templates::render(question, &vars)?;
+                let name = step.save_as.clone().unwrap_or_default();
+                let reply = match vars.get(&name) {
+                    Some(given) => given.clone(),
+                    None => answer(&question)?,
+                };
+                message::add_to_last(
+                    session.chat_file(),
+                    &format!("**{}**\n\n{}\n\n", question.trim(), reply.trim()),
+                )?;
+                vars.insert(name, reply);
+            }
+            if let Some(text) = &step.send {
+                message::add_to_last(session.chat_file(), &templates::render(text, &vars)?)?;
+                let reply = session.send().await?;
+                if let Some(name) = &step.save_as {
+                    vars.insert(name.clone(), reply.message.content.unwrap_or_default());
+                }
+            }
+            at = self.next(i);
+        }
+        Ok(vars)
+    }
+}
+
+/// Print the available workflows and their descriptions, one per line
+pub fn print_list() -> Result<()> {
+    let Some(dir) = workflows_dir().filter(|dir| dir.is_dir()) else {
+        return Ok(());
+    };
+    let mut workflows = Vec::new();
+    for entry in std::fs::read_dir(&dir)
+        .with_context(|| format!("Unable to read workflows directory: {:?}", dir))?
+    {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|ext| ext == "toml" || ext == "yaml" || ext == "yml")
+        {
+            workflows.push(path);
+        }
+    }
+    workflows.sort();
+    for path in workflows {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let description = Workflow::load(&path.to_string_lossy())
+            .map(|workflow| workflow.description.unwrap_or_default())
+            .unwrap_or_else(|e| format!("{:#}", e));
+        println!("{}\t{}", name, description);
+    }
+    Ok(())
+}
+
+/// The directory users keep their workflows in, `$XDG_CONFIG_HOME/chat-cli-rs/workflows`
+fn workflows_dir() -> Option<PathBuf> {
+    Some(platform::config_dir().ok()?.join("workflows"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branches_on_saved_replies() {
+        let workflow: Workflow = toml::from_str(
+            r#"
+            [[steps]]
+            send = "Review it"
+            save_as = "review"
+
+            [[steps]]
+            when = { var = "review", contains = "FINE" }
+            goto = "end"
+
+            [[steps]]
+            id = "fix"
+            send = "Fix it"
+            goto = "fix"
+            "#,
+        )
+        .unwrap();
+        workflow.check().unwrap();
+        assert_eq!(workflow.next(0), Some(1));
+        assert_eq!(workflow.next(1), None);
+        assert_eq!(workflow.next(2), Some(2));
+
+        let when = workflow.steps[1].when.as_ref().unwrap();
+        let vars = HashMap::from([("review".to_string(), "FINE, ship it".to_string())]);
+        assert!(when.holds(&vars).unwrap());
+        assert!(!when.holds(&HashMap::new()).unwrap());
+
+        let broken: Workflow = toml::from_str("[[steps]]\ngoto = \"nowhere\"").unwrap();
+        assert!(broken.check().is_err());
+    }
+}